@@ -0,0 +1,169 @@
+use crate::{
+    crypto::{Crypto, KeyPair},
+    error::{WhatsAppError, WhatsAppResult},
+    proto::{self, Encoder},
+};
+
+/// Prefix of the data signed by the primary device's account key
+const ACCOUNT_SIGNATURE_PREFIX: [u8; 2] = [6, 0];
+
+/// Prefix of the data signed by the companion device's identity key
+const DEVICE_SIGNATURE_PREFIX: [u8; 2] = [6, 1];
+
+/// Identity the primary device assigns to a companion (`ADVDeviceIdentity`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub raw_id: u32,
+    pub timestamp: u64,
+    pub key_index: u32,
+}
+
+impl DeviceIdentity {
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        let mut identity = Self { raw_id: 0, timestamp: 0, key_index: 0 };
+
+        for (field, value) in proto::decode_fields(data)? {
+            match field {
+                1 => identity.raw_id = proto::field_u64(&value)? as u32,
+                2 => identity.timestamp = proto::field_u64(&value)?,
+                3 => identity.key_index = proto::field_u64(&value)? as u32,
+                _ => {},
+            }
+        }
+
+        Ok(identity)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        Encoder::new()
+            .u64(1, self.raw_id as u64)
+            .u64(2, self.timestamp)
+            .u64(3, self.key_index as u64)
+            .finish()
+    }
+}
+
+/// Device identity signed by the account and the companion (`ADVSignedDeviceIdentity`)
+///
+/// Both signatures are XEdDSA signatures by Curve25519 identity keys.
+#[derive(Debug, Clone)]
+pub struct SignedDeviceIdentity {
+    /// Encoded `ADVDeviceIdentity`, kept as received since it is what gets signed
+    pub details: Vec<u8>,
+    pub account_signature_key: Vec<u8>,
+    pub account_signature: Vec<u8>,
+    pub device_signature: Option<Vec<u8>>,
+}
+
+impl SignedDeviceIdentity {
+    /// Sign a device identity with the account key, as the primary device does
+    pub fn new(account: &KeyPair, details: &DeviceIdentity, device_identity_key: &[u8]) -> WhatsAppResult<Self> {
+        let details = details.encode();
+        let account_signature = Crypto::xeddsa_sign(&account.private, &[&ACCOUNT_SIGNATURE_PREFIX[..], &details, device_identity_key].concat())?;
+
+        Ok(Self {
+            details,
+            account_signature_key: account.public.clone(),
+            account_signature,
+            device_signature: None,
+        })
+    }
+
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        let (mut details, mut account_signature_key, mut account_signature, mut device_signature) = (None, None, None, None);
+
+        for (field, value) in proto::decode_fields(data)? {
+            match field {
+                1 => details = Some(proto::field_bytes(&value)?.to_vec()),
+                2 => account_signature_key = Some(proto::field_bytes(&value)?.to_vec()),
+                3 => account_signature = Some(proto::field_bytes(&value)?.to_vec()),
+                4 => device_signature = Some(proto::field_bytes(&value)?.to_vec()),
+                _ => {},
+            }
+        }
+
+        match (details, account_signature_key, account_signature) {
+            (Some(details), Some(account_signature_key), Some(account_signature)) => Ok(Self {
+                details,
+                account_signature_key,
+                account_signature,
+                device_signature,
+            }),
+            _ => Err(WhatsAppError::AuthError("Incomplete signed device identity".to_string())),
+        }
+    }
+
+    /// Encode the identity, leaving out the account signature key if requested
+    ///
+    /// The identity sent back to the server at the end of pairing omits the
+    /// key, which the server already knows.
+    pub fn encode(&self, include_account_key: bool) -> Vec<u8> {
+        let mut encoder = Encoder::new().bytes(1, &self.details);
+        if include_account_key {
+            encoder = encoder.bytes(2, &self.account_signature_key);
+        }
+        encoder = encoder.bytes(3, &self.account_signature);
+        if let Some(signature) = &self.device_signature {
+            encoder = encoder.bytes(4, signature);
+        }
+        encoder.finish()
+    }
+
+    /// Decode the signed details
+    pub fn device_identity(&self) -> WhatsAppResult<DeviceIdentity> {
+        DeviceIdentity::decode(&self.details)
+    }
+
+    /// Check that the account signed these details for our identity key
+    pub fn verify_account_signature(&self, identity_key: &[u8]) -> WhatsAppResult<()> {
+        let message = [&ACCOUNT_SIGNATURE_PREFIX[..], &self.details, identity_key].concat();
+
+        Crypto::xeddsa_verify(&self.account_signature_key, &message, &self.account_signature)
+            .map_err(|_| WhatsAppError::AuthError("Invalid account signature on device identity".to_string()))
+    }
+
+    /// Countersign the identity with our identity key
+    pub fn sign_device(&mut self, identity: &KeyPair) -> WhatsAppResult<()> {
+        let message = [&DEVICE_SIGNATURE_PREFIX[..], &self.details, &identity.public, &self.account_signature_key].concat();
+        self.device_signature = Some(Crypto::xeddsa_sign(&identity.private, &message)?);
+        Ok(())
+    }
+
+    /// Check the companion's signature, as the primary device does
+    pub fn verify_device_signature(&self, identity_key: &[u8]) -> WhatsAppResult<()> {
+        let signature = self.device_signature.as_deref()
+            .ok_or_else(|| WhatsAppError::AuthError("Device identity is not signed by the device".to_string()))?;
+        let message = [&DEVICE_SIGNATURE_PREFIX[..], &self.details, identity_key, &self.account_signature_key].concat();
+
+        Crypto::xeddsa_verify(identity_key, &message, signature)
+            .map_err(|_| WhatsAppError::AuthError("Invalid device signature on device identity".to_string()))
+    }
+}
+
+/// Validate the device identity received when pairing succeeds and countersign it
+///
+/// The container is an `ADVSignedDeviceIdentityHMAC` authenticated with the
+/// ADV secret shared through the QR code. The returned identity carries our
+/// device signature and is ready to be sent back to the server.
+pub fn validate_pairing(container: &[u8], adv_secret: &[u8], identity: &KeyPair) -> WhatsAppResult<SignedDeviceIdentity> {
+    let (mut details, mut hmac) = (None, None);
+    for (field, value) in proto::decode_fields(container)? {
+        match field {
+            1 => details = Some(proto::field_bytes(&value)?.to_vec()),
+            2 => hmac = Some(proto::field_bytes(&value)?.to_vec()),
+            _ => {},
+        }
+    }
+
+    let (Some(details), Some(hmac)) = (details, hmac) else {
+        return Err(WhatsAppError::AuthError("Incomplete device identity container".to_string()));
+    };
+    Crypto::verify_hmac_sha256(adv_secret, &details, &hmac)
+        .map_err(|_| WhatsAppError::AuthError("Invalid device identity HMAC".to_string()))?;
+
+    let mut signed = SignedDeviceIdentity::decode(&details)?;
+    signed.verify_account_signature(&identity.public)?;
+    signed.sign_device(identity)?;
+
+    Ok(signed)
+}
@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+    message::MessageKey,
+};
+
+pub mod hash;
+
+/// App state collections that mutations are synced through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PatchName {
+    CriticalBlock,
+    CriticalUnblockLow,
+    RegularHigh,
+    Regular,
+    RegularLow,
+}
+
+impl PatchName {
+    /// Name of the collection on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PatchName::CriticalBlock => "critical_block",
+            PatchName::CriticalUnblockLow => "critical_unblock_low",
+            PatchName::RegularHigh => "regular_high",
+            PatchName::Regular => "regular",
+            PatchName::RegularLow => "regular_low",
+        }
+    }
+}
+
+/// Whether a mutation sets or removes its index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MutationOperation {
+    Set,
+    Remove,
+}
+
+/// Value carried by an app state mutation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncAction {
+    /// Mark a chat as read or unread up to the given message timestamp
+    MarkChatAsRead {
+        read: bool,
+        last_message_timestamp: u64,
+    },
+    /// Star or unstar a message
+    Star {
+        starred: bool,
+    },
+    /// Delete a message from our devices only
+    DeleteMessageForMe {
+        delete_media: bool,
+        message_timestamp: u64,
+    },
+    /// Delete every message of a chat up to the given timestamp, keeping the chat
+    ClearChat {
+        last_message_timestamp: u64,
+    },
+}
+
+/// A single app state mutation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mutation {
+    pub operation: MutationOperation,
+    pub index: Vec<String>,
+    pub version: u32,
+    pub action: SyncAction,
+    pub timestamp: u64,
+}
+
+impl Mutation {
+    /// Get the key of the message a `star` or `deleteMessageForMe` mutation applies to
+    pub fn message_key(&self) -> Option<MessageKey> {
+        let [_, chat, id, from_me, sender] = self.index.as_slice() else {
+            return None;
+        };
+
+        Some(MessageKey {
+            chat_jid: chat.parse().ok()?,
+            id: id.clone(),
+            from_me: from_me == "1",
+            sender_jid: if sender == "0" { None } else { sender.parse().ok() },
+        })
+    }
+}
+
+/// A set of mutations applied to one app state collection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Patch {
+    pub name: PatchName,
+    pub mutations: Vec<Mutation>,
+}
+
+impl Patch {
+    /// Build the patch marking a chat as read or unread
+    pub fn mark_chat_as_read(chat: &JID, read: bool, last_message_timestamp: u64) -> Self {
+        Self {
+            name: PatchName::RegularLow,
+            mutations: vec![Mutation {
+                operation: MutationOperation::Set,
+                index: vec!["markChatAsRead".to_string(), chat.to_string()],
+                version: 3,
+                action: SyncAction::MarkChatAsRead {
+                    read,
+                    last_message_timestamp,
+                },
+                timestamp: now_millis(),
+            }],
+        }
+    }
+
+    /// Build the patch starring or unstarring a message
+    pub fn star(key: &MessageKey, starred: bool) -> Self {
+        Self {
+            name: PatchName::RegularHigh,
+            mutations: vec![Mutation {
+                operation: MutationOperation::Set,
+                index: message_index("star", key),
+                version: 2,
+                action: SyncAction::Star { starred },
+                timestamp: now_millis(),
+            }],
+        }
+    }
+
+    /// Build the patch deleting a message from our devices only
+    pub fn delete_message_for_me(key: &MessageKey, message_timestamp: u64) -> Self {
+        Self {
+            name: PatchName::RegularHigh,
+            mutations: vec![Mutation {
+                operation: MutationOperation::Set,
+                index: message_index("deleteMessageForMe", key),
+                version: 3,
+                action: SyncAction::DeleteMessageForMe {
+                    delete_media: true,
+                    message_timestamp,
+                },
+                timestamp: now_millis(),
+            }],
+        }
+    }
+
+    /// Build the patch deleting every message of a chat from our devices
+    pub fn clear_chat(chat: &JID, last_message_timestamp: u64) -> Self {
+        Self {
+            name: PatchName::RegularHigh,
+            mutations: vec![Mutation {
+                operation: MutationOperation::Set,
+                // Starred messages and media aren't kept
+                index: vec!["clearChat".to_string(), chat.to_string(), "0".to_string(), "0".to_string()],
+                version: 6,
+                action: SyncAction::ClearChat { last_message_timestamp },
+                timestamp: now_millis(),
+            }],
+        }
+    }
+
+    /// Convert the patch to JSON format for sending
+    pub fn to_json(&self) -> WhatsAppResult<String> {
+        serde_json::to_string(self)
+            .map_err(|e| WhatsAppError::SerializationError(e.to_string()))
+    }
+}
+
+/// Index of a mutation applying to a message
+fn message_index(kind: &str, key: &MessageKey) -> Vec<String> {
+    vec![
+        kind.to_string(),
+        key.chat_jid.to_string(),
+        key.id.clone(),
+        if key.from_me { "1" } else { "0" }.to_string(),
+        key.sender_jid.as_ref().map_or("0".to_string(), JID::to_string),
+    ]
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,196 @@
+use super::{MutationOperation, PatchName};
+use crate::{
+    crypto::{Crypto, SecretBytes},
+    error::WhatsAppResult,
+};
+
+/// Size of an LTHash value
+pub const LT_HASH_SIZE: usize = 128;
+
+/// HKDF info string items are expanded with before being added to the hash
+const PATCH_INTEGRITY_INFO: &[u8] = b"WhatsApp Patch Integrity";
+
+/// Homomorphic lattice hash over the value MACs of an app state collection
+///
+/// Each item is expanded to 64 little-endian 16-bit words that are added to
+/// or subtracted from the hash with wrapping arithmetic, so the hash of a
+/// collection can be updated by a patch without rehashing all of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LTHash([u8; LT_HASH_SIZE]);
+
+impl Default for LTHash {
+    fn default() -> Self {
+        Self([0; LT_HASH_SIZE])
+    }
+}
+
+impl LTHash {
+    /// Hash of an empty collection
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_bytes(bytes: [u8; LT_HASH_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; LT_HASH_SIZE] {
+        &self.0
+    }
+
+    /// Add an item to the hash
+    pub fn add(&mut self, item: &[u8]) -> WhatsAppResult<()> {
+        self.apply(item, u16::wrapping_add)
+    }
+
+    /// Remove an item previously added to the hash
+    pub fn subtract(&mut self, item: &[u8]) -> WhatsAppResult<()> {
+        self.apply(item, u16::wrapping_sub)
+    }
+
+    /// Apply a patch: remove the values it overwrites, then add its new values
+    pub fn subtract_then_add(&mut self, subtract: &[&[u8]], add: &[&[u8]]) -> WhatsAppResult<()> {
+        for item in subtract {
+            self.subtract(item)?;
+        }
+        for item in add {
+            self.add(item)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, item: &[u8], op: fn(u16, u16) -> u16) -> WhatsAppResult<()> {
+        let expanded = Crypto::hkdf(item, PATCH_INTEGRITY_INFO, LT_HASH_SIZE)?;
+
+        for (word, other) in self.0.chunks_exact_mut(2).zip(expanded.chunks_exact(2)) {
+            let result = op(u16::from_le_bytes([word[0], word[1]]), u16::from_le_bytes([other[0], other[1]]));
+            word.copy_from_slice(&result.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+/// Keys expanded from an app state sync key
+pub struct MutationKeys {
+    pub index: SecretBytes,
+    pub value_encryption: SecretBytes,
+    pub value_mac: SecretBytes,
+    pub snapshot_mac: SecretBytes,
+    pub patch_mac: SecretBytes,
+}
+
+impl MutationKeys {
+    pub fn expand(key_data: &[u8]) -> WhatsAppResult<Self> {
+        let expanded = Crypto::hkdf(key_data, b"WhatsApp Mutation Keys", 160)?;
+
+        Ok(Self {
+            index: expanded[..32].into(),
+            value_encryption: expanded[32..64].into(),
+            value_mac: expanded[64..96].into(),
+            snapshot_mac: expanded[96..128].into(),
+            patch_mac: expanded[128..].into(),
+        })
+    }
+}
+
+/// MAC of a collection's state at a version, using the snapshot MAC key
+pub fn snapshot_mac(hash: &LTHash, version: u64, name: PatchName, key: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let data = [&hash.0[..], &version.to_be_bytes(), name.as_str().as_bytes()].concat();
+    Crypto::hmac_sha256(key, &data)
+}
+
+/// MAC of a patch over its snapshot MAC and the value MACs of its mutations, using the patch MAC key
+pub fn patch_mac(snapshot_mac: &[u8], value_macs: &[&[u8]], version: u64, name: PatchName, key: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let mut data = snapshot_mac.to_vec();
+    for value_mac in value_macs {
+        data.extend_from_slice(value_mac);
+    }
+    data.extend_from_slice(&version.to_be_bytes());
+    data.extend_from_slice(name.as_str().as_bytes());
+
+    Crypto::hmac_sha512_trunc(key, &data, 32)
+}
+
+/// MAC of an encrypted mutation value, using the value MAC key
+pub fn content_mac(operation: MutationOperation, data: &[u8], key_id: &[u8], key: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let operation = match operation {
+        MutationOperation::Set => 1,
+        MutationOperation::Remove => 2,
+    };
+    let input = [&[operation][..], key_id, data, &(key_id.len() as u64 + 1).to_be_bytes()].concat();
+
+    Crypto::hmac_sha512_trunc(key, &input, 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_WORLD: &str = "cb2b3363b7dd0981610c9c72eb2923b2e64b82e85a63141f43e156fdabcd11b1\
+        8411bfe48dbfc56bfa8964e816a7d4ce33fbcaa87218f6a1bb91bd02529809ea\
+        71f8903733d597370dcd99df5bbd37241ece3da397d0fc92da2ea4d481c15221\
+        6cdf7492ee6845692fcbd90de1b7d40d33177be5edfe90e5dd251555920d1bbd";
+
+    fn key() -> Vec<u8> {
+        (0..32).collect()
+    }
+
+    #[test]
+    fn add_matches_known_vector() {
+        let mut hash = LTHash::new();
+        hash.add(b"hello").unwrap();
+        hash.add(b"world").unwrap();
+
+        assert_eq!(hex::encode(hash.as_bytes()), HELLO_WORLD);
+    }
+
+    #[test]
+    fn subtract_undoes_add() {
+        let mut hash = LTHash::new();
+        hash.subtract_then_add(&[], &[b"hello", b"world"]).unwrap();
+        hash.subtract(b"hello").unwrap();
+
+        let mut expected = LTHash::new();
+        expected.add(b"world").unwrap();
+        assert_eq!(hash, expected);
+        assert_eq!(hex::encode(hash.as_bytes()), "08a94b722d2dd66d499da17c97608f28317f565b74791f7897336f9c3553f489\
+            47746c06b7374ad1842f2767c448b460c7a58e75b9a84719f232f027ba0267fe\
+            7864f9db8831e6b8300640b172d93629b771bd348c95a7bf69e5ddaa5d620d49\
+            03033003664a4b3e7cbed8226058d7b3e4a0d17094217b597fc4742f8abecd48");
+
+        hash.subtract(b"world").unwrap();
+        assert_eq!(hash, LTHash::new());
+    }
+
+    #[test]
+    fn snapshot_mac_matches_known_vector() {
+        let mut hash = LTHash::new();
+        hash.add(b"hello").unwrap();
+        hash.add(b"world").unwrap();
+
+        let mac = snapshot_mac(&hash, 1, PatchName::RegularLow, &key()).unwrap();
+        assert_eq!(hex::encode(mac), "96828091884723c27221de72717c5a1412673c347842705b33db7d442fd99b8f");
+    }
+
+    #[test]
+    fn patch_mac_matches_known_vector() {
+        let value_mac = [1u8; 32];
+        let mac = patch_mac(&[0; 32], &[&value_mac], 2, PatchName::RegularLow, &key()).unwrap();
+        assert_eq!(hex::encode(mac), "c016d9d01be64a61f3313aef8529cbca74604a0e09f78dbd19f8039b7bd0a717");
+    }
+
+    #[test]
+    fn content_mac_matches_known_vector() {
+        let mac = content_mac(MutationOperation::Set, b"data", b"key-id", &key()).unwrap();
+        assert_eq!(hex::encode(mac), "9a3a782f9428cf8d6d4b63cbdd627126f5dad6f3d5c02c84d347125956b29f07");
+    }
+
+    #[test]
+    fn expands_mutation_keys() {
+        let keys = MutationKeys::expand(&key()).unwrap();
+        assert_eq!(hex::encode(&keys.index[..]), "61387bcf643616a68bd611a45516b3980418323087d78bf08c615645549434b4");
+        assert_eq!(hex::encode(&keys.patch_mac[..]), "693845bdd996652aca9ca0b96d0f081abf29943303c5eb19bdb84f38b24c32ab");
+    }
+}
@@ -0,0 +1,406 @@
+use crate::error::{WhatsAppError, WhatsAppResult};
+
+const LIST_EMPTY: u8 = 0;
+const DICTIONARY_0: u8 = 236;
+const DICTIONARY_3: u8 = 239;
+const AD_JID: u8 = 247;
+const LIST_8: u8 = 248;
+const LIST_16: u8 = 249;
+const JID_PAIR: u8 = 250;
+const HEX_8: u8 = 251;
+const BINARY_8: u8 = 252;
+const BINARY_20: u8 = 253;
+const BINARY_32: u8 = 254;
+const NIBBLE_8: u8 = 255;
+
+/// Leading entries of the single byte token dictionary, a token encoding as its index
+const SINGLE_BYTE_TOKENS: &[&str] = &[
+    "", "xmlstreamstart", "xmlstreamend", "s.whatsapp.net", "type", "participant", "from",
+    "receipt", "id", "notification", "disappearing_mode", "status", "jid", "broadcast", "user",
+    "devices", "device_hash", "to", "offline", "message", "result", "class", "xmlns", "duration",
+    "notify", "iq", "t", "ack", "g.us", "enc", "urn:xmpp:ping", "remove", "endpoint", "prekey",
+    "w:profile:picture", "props", "picture", "w:p", "1", "update", "hash", "add", "2", "count",
+    "status@broadcast", "content", "retry", "v", "mediatype", "participants", "registration",
+    "call", "relay", "mkey", "edge_routing", "encrypt", "chatstate", "text", "frequency", "lid",
+    "error", "identity", "expiration", "key", "routing_info",
+];
+
+/// Content of a binary node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeContent {
+    None,
+    Nodes(Vec<Node>),
+    Bytes(Vec<u8>),
+}
+
+/// Node of the binary XML-like protocol the server speaks, e.g.
+/// `<message id="..." from="..."><enc type="pkmsg" v="2">...</enc></message>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub tag: String,
+    pub attrs: Vec<(String, String)>,
+    pub content: NodeContent,
+}
+
+impl Node {
+    /// Create a node without attributes or content
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            attrs: Vec::new(),
+            content: NodeContent::None,
+        }
+    }
+
+    /// Add an attribute
+    pub fn attr(mut self, key: &str, value: &str) -> Self {
+        self.attrs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set child nodes as the content
+    pub fn children(mut self, children: Vec<Node>) -> Self {
+        self.content = NodeContent::Nodes(children);
+        self
+    }
+
+    /// Set bytes as the content
+    pub fn bytes(mut self, bytes: Vec<u8>) -> Self {
+        self.content = NodeContent::Bytes(bytes);
+        self
+    }
+
+    /// Get the value of an attribute
+    pub fn get_attr(&self, key: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    }
+
+    /// Get the child nodes, empty if the content is something else
+    pub fn get_children(&self) -> &[Node] {
+        match &self.content {
+            NodeContent::Nodes(children) => children,
+            _ => &[],
+        }
+    }
+
+    /// Get the child nodes with the given tag
+    pub fn children_by_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.get_children().iter().filter(move |child| child.tag == tag)
+    }
+
+    /// Get the bytes content, if any
+    pub fn get_bytes(&self) -> Option<&[u8]> {
+        match &self.content {
+            NodeContent::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Get the attributes as a JSON object, the way text frames carry them
+    pub fn attrs_json(&self) -> serde_json::Value {
+        self.attrs.iter()
+            .map(|(key, value)| (key.clone(), serde_json::Value::String(value.clone())))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Decode a node from an unpacked frame
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        let mut decoder = Decoder { data, pos: 0 };
+        let node = decoder.read_node()?;
+        if decoder.pos != data.len() {
+            return Err(WhatsAppError::ParsingError(format!("{} trailing bytes after node", data.len() - decoder.pos)));
+        }
+
+        Ok(node)
+    }
+
+    /// Encode the node for sending as a frame
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_node(&mut buf, self);
+        buf
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn read_bytes(&mut self, len: usize) -> WhatsAppResult<&[u8]> {
+        let end = self.pos.checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| WhatsAppError::ParsingError("Node ends unexpectedly".to_string()))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> WhatsAppResult<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_int(&mut self, len: usize) -> WhatsAppResult<usize> {
+        Ok(self.read_bytes(len)?.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    fn read_list_size(&mut self, tag: u8) -> WhatsAppResult<usize> {
+        match tag {
+            LIST_EMPTY => Ok(0),
+            LIST_8 => self.read_int(1),
+            LIST_16 => self.read_int(2),
+            tag => Err(WhatsAppError::ParsingError(format!("Expected a list, got tag {}", tag))),
+        }
+    }
+
+    fn read_node(&mut self) -> WhatsAppResult<Node> {
+        let tag = self.read_u8()?;
+        let size = self.read_list_size(tag)?;
+        if size == 0 {
+            return Err(WhatsAppError::ParsingError("Node without a tag".to_string()));
+        }
+
+        let tag = self.read_string()?;
+        let mut attrs = Vec::new();
+        for _ in 0..(size - 1) / 2 {
+            let key = self.read_string()?;
+            let value = self.read_string()?;
+            attrs.push((key, value));
+        }
+
+        let content = if size % 2 == 1 {
+            NodeContent::None
+        } else {
+            self.read_content()?
+        };
+
+        Ok(Node { tag, attrs, content })
+    }
+
+    fn read_content(&mut self) -> WhatsAppResult<NodeContent> {
+        let tag = self.read_u8()?;
+        match tag {
+            LIST_EMPTY | LIST_8 | LIST_16 => {
+                let size = self.read_list_size(tag)?;
+                let children = (0..size).map(|_| self.read_node()).collect::<WhatsAppResult<_>>()?;
+                Ok(NodeContent::Nodes(children))
+            },
+            BINARY_8 | BINARY_20 | BINARY_32 => {
+                let len = self.read_binary_len(tag)?;
+                Ok(NodeContent::Bytes(self.read_bytes(len)?.to_vec()))
+            },
+            tag => Ok(NodeContent::Bytes(self.read_string_from(tag)?.into_bytes())),
+        }
+    }
+
+    fn read_binary_len(&mut self, tag: u8) -> WhatsAppResult<usize> {
+        match tag {
+            BINARY_8 => self.read_int(1),
+            BINARY_20 => Ok(self.read_int(3)? & 0xfffff),
+            _ => self.read_int(4),
+        }
+    }
+
+    fn read_string(&mut self) -> WhatsAppResult<String> {
+        let tag = self.read_u8()?;
+        self.read_string_from(tag)
+    }
+
+    fn read_string_from(&mut self, tag: u8) -> WhatsAppResult<String> {
+        match tag {
+            LIST_EMPTY => Ok(String::new()),
+            BINARY_8 | BINARY_20 | BINARY_32 => {
+                let len = self.read_binary_len(tag)?;
+                String::from_utf8(self.read_bytes(len)?.to_vec())
+                    .map_err(|e| WhatsAppError::ParsingError(e.to_string()))
+            },
+            JID_PAIR => {
+                let user = self.read_string()?;
+                let server = self.read_string()?;
+                Ok(if user.is_empty() { server } else { format!("{}@{}", user, server) })
+            },
+            AD_JID => {
+                let agent = self.read_u8()?;
+                let device = self.read_u8()?;
+                let user = self.read_string()?;
+                let server = if agent == 1 { "lid" } else { "s.whatsapp.net" };
+                Ok(match device {
+                    0 => format!("{}@{}", user, server),
+                    device => format!("{}:{}@{}", user, device, server),
+                })
+            },
+            NIBBLE_8 | HEX_8 => self.read_packed(tag),
+            DICTIONARY_0..=DICTIONARY_3 => Err(WhatsAppError::ParsingError(
+                format!("Unsupported double byte token {}:{}", tag - DICTIONARY_0, self.read_u8()?),
+            )),
+            tag => SINGLE_BYTE_TOKENS.get(tag as usize)
+                .map(|token| token.to_string())
+                .ok_or_else(|| WhatsAppError::ParsingError(format!("Unknown token {}", tag))),
+        }
+    }
+
+    /// Read digits packed two to a byte, the high bit of the length marking an odd count
+    fn read_packed(&mut self, tag: u8) -> WhatsAppResult<String> {
+        let header = self.read_u8()?;
+        let bytes = self.read_bytes((header & 0x7f) as usize)?;
+
+        let mut nibbles: Vec<u8> = bytes.iter().flat_map(|&byte| [byte >> 4, byte & 0x0f]).collect();
+        if header & 0x80 != 0 {
+            nibbles.pop();
+        }
+
+        let mut value = String::with_capacity(nibbles.len());
+        for nibble in nibbles {
+            value.push(match (tag, nibble) {
+                (_, 0..=9) => (b'0' + nibble) as char,
+                (NIBBLE_8, 10) => '-',
+                (NIBBLE_8, 11) => '.',
+                (HEX_8, _) => (b'A' + nibble - 10) as char,
+                _ => return Err(WhatsAppError::ParsingError(format!("Invalid packed nibble {}", nibble))),
+            });
+        }
+
+        Ok(value)
+    }
+}
+
+fn write_node(buf: &mut Vec<u8>, node: &Node) {
+    let has_content = node.content != NodeContent::None;
+    write_list_size(buf, 1 + 2 * node.attrs.len() + has_content as usize);
+
+    write_string(buf, &node.tag);
+    for (key, value) in &node.attrs {
+        write_string(buf, key);
+        write_string(buf, value);
+    }
+
+    match &node.content {
+        NodeContent::None => {},
+        NodeContent::Nodes(children) => {
+            write_list_size(buf, children.len());
+            for child in children {
+                write_node(buf, child);
+            }
+        },
+        NodeContent::Bytes(bytes) => write_binary(buf, bytes),
+    }
+}
+
+fn write_list_size(buf: &mut Vec<u8>, size: usize) {
+    match size {
+        0 => buf.push(LIST_EMPTY),
+        1..=0xff => buf.extend_from_slice(&[LIST_8, size as u8]),
+        _ => {
+            buf.push(LIST_16);
+            buf.extend_from_slice(&(size as u16).to_be_bytes());
+        },
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    if let Some(token) = SINGLE_BYTE_TOKENS.iter().skip(1).position(|&token| token == value) {
+        buf.push(token as u8 + 1);
+    } else if let Some((user, server)) = value.split_once('@')
+        && SINGLE_BYTE_TOKENS.contains(&server)
+        && !user.is_empty()
+    {
+        buf.push(JID_PAIR);
+        write_string(buf, user);
+        write_string(buf, server);
+    } else {
+        write_binary(buf, value.as_bytes());
+    }
+}
+
+fn write_binary(buf: &mut Vec<u8>, bytes: &[u8]) {
+    match bytes.len() {
+        len @ 0..=0xff => buf.extend_from_slice(&[BINARY_8, len as u8]),
+        len @ 0x100..=0xfffff => {
+            buf.push(BINARY_20);
+            buf.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+        },
+        len => {
+            buf.push(BINARY_32);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        },
+    }
+    buf.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message_node() -> Node {
+        Node::new("message")
+            .attr("id", "3EB0C431C26A1916E07E")
+            .attr("to", "1234567890@s.whatsapp.net")
+            .attr("participant", "1234567890:12@s.whatsapp.net")
+            .attr("type", "text")
+            .attr("t", "")
+            .children(vec![
+                Node::new("enc").attr("v", "2").attr("type", "pkmsg").bytes((0..=255).collect()),
+                Node::new("enc").attr("type", "skmsg").bytes(vec![7; 70_000]),
+                Node::new("device-identity").bytes(Vec::new()),
+                Node::new("participants").children(Vec::new()),
+                Node::new("meta"),
+            ])
+    }
+
+    #[test]
+    fn round_trips_nodes() {
+        let node = message_node();
+        assert_eq!(Node::decode(&node.encode()).unwrap(), node);
+
+        let many = Node::new("list").children((0..300).map(|i| Node::new("item").attr("id", &i.to_string())).collect());
+        assert_eq!(Node::decode(&many.encode()).unwrap(), many);
+    }
+
+    #[test]
+    fn encodes_tokens_and_jids_compactly() {
+        let node = Node::new("iq").attr("to", "s.whatsapp.net").attr("from", "123@g.us");
+        assert_eq!(node.encode(), [LIST_8, 5, 25, 17, 3, 6, JID_PAIR, BINARY_8, 3, b'1', b'2', b'3', 28]);
+    }
+
+    #[test]
+    fn decodes_ad_jids_and_packed_strings() {
+        let data = [
+            LIST_8, 5, 25,
+            // from: agent 0, device 3, user 123
+            6, AD_JID, 0, 3, NIBBLE_8, 0x82, 0x12, 0x3f,
+            // id: 1A packed as hex
+            8, HEX_8, 0x01, 0x1a,
+        ];
+        let node = Node::decode(&data).unwrap();
+        assert_eq!(node.get_attr("from"), Some("123:3@s.whatsapp.net"));
+        assert_eq!(node.get_attr("id"), Some("1A"));
+
+        let data = [LIST_8, 3, 25, 6, AD_JID, 1, 0, NIBBLE_8, 0x02, 0x9a, 0xb0];
+        assert_eq!(Node::decode(&data).unwrap().get_attr("from"), Some("9-.0@lid"));
+    }
+
+    #[test]
+    fn rejects_truncated_nodes() {
+        let encoded = message_node().encode();
+        for len in (0..encoded.len()).step_by(97).chain(encoded.len() - 16..encoded.len()) {
+            assert!(Node::decode(&encoded[..len]).is_err(), "{} of {} bytes decoded", len, encoded.len());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_nodes() {
+        let mut trailing = Node::new("iq").encode();
+        trailing.push(0);
+        assert!(Node::decode(&trailing).is_err());
+
+        // Empty node, unknown token, double byte token and a bad packed nibble
+        for data in [&[LIST_EMPTY][..], &[LIST_8, 1, 200], &[LIST_8, 1, DICTIONARY_0, 1], &[LIST_8, 1, NIBBLE_8, 0x01, 0xcc]] {
+            assert!(Node::decode(data).is_err(), "{:?} decoded", data);
+        }
+        // Lengths far past the end of the data
+        assert!(Node::decode(&[LIST_8, 2, 25, BINARY_32, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(Node::decode(&[LIST_16, 0xff, 0xff, 25]).is_err());
+    }
+}
@@ -1,15 +1,65 @@
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::Path;
-use log::{error, info};
+use std::time::{Duration, SystemTime};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+mod albums;
+mod broadcast;
+mod communities;
+mod credentials;
+mod dedup;
+mod delete;
+mod devices;
+mod groups;
+mod identity;
+mod invites;
+mod join_requests;
+mod media;
+mod media_retry;
+mod pairing;
+mod pins;
+mod polls;
+mod presence;
+mod profile;
+mod protocol;
+mod reactions;
+mod receipts;
+mod receive;
+mod retry;
+mod schedule;
+mod send;
+mod star;
+mod status;
+mod stream;
 
 use crate::{
-    JID, Event,
+    JID, Event, EventHandler, WHATSAPP_WEB_URL,
+    adv::SignedDeviceIdentity,
+    appstate::{MutationOperation, Patch, SyncAction},
+    binary::Node,
     error::{WhatsAppError, WhatsAppResult},
-    message::Message,
-    websocket::{WebSocketHandler, WebSocketMessage},
-    crypto::{Crypto, KeyPair},
+    fetch::{HttpFetcher, MediaFetcher},
+    group::GroupInfo,
+    history::{self, HistorySyncConfig, HistorySyncProgress},
+    message::{Message, MessageKey, ReceiptStatus},
+    noise::NoiseConfig,
+    payload::{self, DeviceProps},
+    proxy::ProxyConfig,
+    qr::QrCode,
+    ratelimit::{RateLimitConfig, RateLimiter},
+    signal::{LocalIdentity, SessionCipher, group::GroupCipher},
+    store::{IdentityKeyStore, SignalStore},
+    tls::TlsPin,
+    transport::{ConnectionStats, QueueMetrics, Transport, TransportEvent},
+    websocket::{CompressionConfig, KeepaliveConfig, SendQueueConfig, WebSocketHandler, WebSocketMessage},
+    crypto::{Crypto, GCM_NONCE_SIZE, KeyPair, SecretBytes},
 };
 
 /// Logging level
@@ -21,25 +71,252 @@ pub enum LogLevel {
     Error,
 }
 
+/// How long to wait for the server to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Number of one-time prekeys generated for the Signal identity
+const PRE_KEY_COUNT: u32 = 30;
+
+/// Delay between attempts to re-establish a lost connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// State of the connection to the WhatsApp servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected and not trying to connect
+    Disconnected,
+    /// Opening the connection
+    Connecting,
+    /// Connection open, performing the protocol handshake
+    Handshaking,
+    /// Handshake done, waiting for the server to accept our login
+    Authenticating,
+    /// Connected and ready to send and receive
+    Online,
+    /// Connection lost, trying to re-establish it
+    Reconnecting,
+    /// Closed on purpose by `disconnect()` or `logout()`
+    Closed,
+}
+
+/// Message waiting in the queue to be sent at a later time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub message: Message,
+    pub send_at: SystemTime,
+}
+
+/// Media uploaded to the media servers, with what a message needs to refer to it
+#[derive(Debug, Clone)]
+pub struct UploadedMedia {
+    pub url: String,
+    pub direct_path: String,
+    pub media_key: SecretBytes,
+    /// SHA-256 of the plaintext
+    pub file_sha256: Vec<u8>,
+    /// SHA-256 of the encrypted file, MAC included
+    pub file_enc_sha256: Vec<u8>,
+    pub file_length: u64,
+}
+
+/// Progress of a media upload or download
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MediaProgress {
+    /// Bytes of the encrypted file sent or received so far
+    pub transferred: u64,
+    /// Size of the encrypted file
+    pub total: u64,
+}
+
+/// Whether someone is typing or recording a voice note in a chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatPresence {
+    Composing,
+    Recording,
+    /// Stopped typing or recording without sending
+    Paused,
+}
+
+/// Profile or group picture, to be downloaded from its URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfilePictureInfo {
+    pub id: String,
+    pub url: String,
+    pub direct_path: Option<String>,
+    /// Picture is the small preview rather than the full size
+    pub preview: bool,
+}
+
+/// What the server answered when asked for a profile or group picture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfilePictureResult {
+    Picture(ProfilePictureInfo),
+    /// They have no picture set
+    NoPicture,
+    /// Their privacy settings hide the picture from us
+    NotAllowed,
+}
+
+/// Disappearing message timer keeping messages
+pub const DISAPPEARING_TIMER_OFF: Duration = Duration::ZERO;
+
+/// Disappearing message timer deleting messages after a day
+pub const DISAPPEARING_TIMER_24H: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Disappearing message timer deleting messages after a week
+pub const DISAPPEARING_TIMER_7D: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Disappearing message timer deleting messages after 90 days
+pub const DISAPPEARING_TIMER_90D: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Disappearing message timers chats can be set to
+const DISAPPEARING_TIMERS: [Duration; 4] = [
+    DISAPPEARING_TIMER_OFF,
+    DISAPPEARING_TIMER_24H,
+    DISAPPEARING_TIMER_7D,
+    DISAPPEARING_TIMER_90D,
+];
+
+/// Pin keeping a message at the top of a chat for a day
+pub const PIN_DURATION_24H: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Pin keeping a message at the top of a chat for a week
+pub const PIN_DURATION_7D: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Pin keeping a message at the top of a chat for 30 days
+pub const PIN_DURATION_30D: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Durations messages can be pinned for
+const PIN_DURATIONS: [Duration; 3] = [PIN_DURATION_24H, PIN_DURATION_7D, PIN_DURATION_30D];
+
+/// What to do when the server reports the session was replaced by another login
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionConflictBehavior {
+    /// Stay disconnected and emit `Event::SessionConflict`
+    #[default]
+    Stop,
+    /// Reconnect until the session is taken back
+    Reconnect,
+}
+
+/// Default WebSocket endpoint of the WhatsApp servers
+pub const DEFAULT_ENDPOINT: &str = "wss://web.whatsapp.com/ws";
+
 /// Client configuration
 pub struct ClientConfig {
     pub store_path: String,
+    /// Encrypt the device store at rest with a key derived from this passphrase
+    pub store_passphrase: Option<String>,
     pub log_level: LogLevel,
+    pub history_sync: HistorySyncConfig,
+    pub session_conflict: SessionConflictBehavior,
+    /// WebSocket URL to connect to
+    pub endpoint: String,
+    /// URLs to fail over to when the endpoint can't be reached
+    pub fallback_endpoints: Vec<String>,
+    /// `Origin` header sent when opening the WebSocket
+    pub origin: String,
+    /// `User-Agent` header sent when opening the WebSocket
+    pub user_agent: Option<String>,
+    /// Extra headers sent when opening the WebSocket
+    pub headers: Vec<(String, String)>,
+    pub keepalive: Option<KeepaliveConfig>,
+    /// Reconnect when nothing was received from the server for this long
+    pub stale_timeout: Option<Duration>,
+    pub send_queue: SendQueueConfig,
+    /// Frame compression; `None` sends and expects frames without a flags byte
+    pub compression: Option<CompressionConfig>,
+    pub rate_limit: RateLimitConfig,
+    /// Runtime to run the client's tasks on; defaults to the current runtime,
+    /// or a runtime owned by the client when there is none
+    pub runtime: Option<Handle>,
+    pub proxy: Option<ProxyConfig>,
+    pub tls_pins: Vec<TlsPin>,
+    /// How this device is listed in the phone's linked devices screen
+    pub device_props: DeviceProps,
+    /// Send read receipts for incoming messages as soon as they are delivered
+    pub auto_mark_read: bool,
+    /// Where the identity keys of contacts' devices are kept; defaults to the device store
+    pub identity_store: Option<Arc<dyn IdentityKeyStore>>,
+    /// Where Signal sessions and prekeys are kept; defaults to the device store
+    pub signal_store: Option<Arc<dyn SignalStore>>,
+    /// Where `send_media_from_url` fetches files from; defaults to plain HTTP(S)
+    /// through the configured proxy
+    pub media_fetcher: Option<Arc<dyn MediaFetcher>>,
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             store_path: "whatsapp_store".to_string(),
+            store_passphrase: None,
             log_level: LogLevel::Info,
+            history_sync: HistorySyncConfig::default(),
+            session_conflict: SessionConflictBehavior::default(),
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            fallback_endpoints: Vec::new(),
+            origin: WHATSAPP_WEB_URL.to_string(),
+            user_agent: None,
+            headers: Vec::new(),
+            keepalive: Some(KeepaliveConfig::default()),
+            stale_timeout: Some(Duration::from_secs(60)),
+            send_queue: SendQueueConfig::default(),
+            compression: Some(CompressionConfig::default()),
+            rate_limit: RateLimitConfig::default(),
+            runtime: None,
+            proxy: None,
+            tls_pins: Vec::new(),
+            device_props: DeviceProps::default(),
+            auto_mark_read: false,
+            identity_store: None,
+            signal_store: None,
+            media_fetcher: None,
         }
     }
 }
 
+/// Key derivation an encrypted device store is written with, the only one it can be read with
+const STORE_KDF: &str = "pbkdf2-sha256";
+
+/// PBKDF2 iterations used when encrypting a new device store
+const STORE_KDF_ITERATIONS: u32 = 600_000;
+
+/// Fewest PBKDF2 iterations an encrypted device store is opened with, so a
+/// tampered file can't weaken the key derivation
+const STORE_MIN_KDF_ITERATIONS: u32 = 100_000;
+
+/// Size of the salt the device store key is derived with
+const STORE_SALT_SIZE: usize = 16;
+
 /// Device store for saving and loading client state
 pub struct DeviceStore {
     path: String,
     data: Mutex<HashMap<String, String>>,
+    key: Option<StoreKey>,
+}
+
+/// Key an encrypted device store is written with, derived from the passphrase
+struct StoreKey {
+    salt: Vec<u8>,
+    iterations: u32,
+    key: SecretBytes,
+}
+
+impl StoreKey {
+    fn derive(passphrase: &str, salt: Vec<u8>, iterations: u32) -> WhatsAppResult<Self> {
+        let key = Crypto::pbkdf2_sha256(passphrase.as_bytes(), &salt, iterations, 32)?;
+        Ok(Self { salt, iterations, key })
+    }
+}
+
+/// On-disk format of an encrypted device store
+#[derive(Serialize, Deserialize)]
+struct EncryptedStore {
+    kdf: String,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
 }
 
 impl DeviceStore {
@@ -47,12 +324,7 @@ impl DeviceStore {
     pub fn new(path: &str) -> Self {
         let data = if Path::new(path).exists() {
             match fs::read_to_string(path) {
-                Ok(content) => {
-                    match serde_json::from_str(&content) {
-                        Ok(data) => data,
-                        Err(_) => HashMap::new(),
-                    }
-                },
+                Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
                 Err(_) => HashMap::new(),
             }
         } else {
@@ -62,9 +334,69 @@ impl DeviceStore {
         Self {
             path: path.to_string(),
             data: Mutex::new(data),
+            key: None,
         }
     }
 
+    /// Open a device store, encrypting it at rest if a passphrase is given
+    ///
+    /// An existing plaintext store is encrypted right away when a passphrase
+    /// is given. Opening an encrypted store fails without the right passphrase.
+    pub fn open(path: &str, passphrase: Option<&str>) -> WhatsAppResult<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(WhatsAppError::IOError(e.to_string())),
+        };
+        let encrypted = content.as_deref()
+            .and_then(|content| serde_json::from_str::<EncryptedStore>(content).ok());
+
+        let store = match (encrypted, passphrase) {
+            (Some(file), Some(passphrase)) => {
+                if file.kdf != STORE_KDF {
+                    return Err(WhatsAppError::StoreError(format!("Unsupported device store key derivation {}", file.kdf)));
+                }
+                if file.iterations < STORE_MIN_KDF_ITERATIONS {
+                    return Err(WhatsAppError::StoreError(format!(
+                        "Device store key derivation uses {} iterations, at least {} are needed",
+                        file.iterations, STORE_MIN_KDF_ITERATIONS
+                    )));
+                }
+
+                let key = StoreKey::derive(passphrase, Crypto::base64_decode(&file.salt)?, file.iterations)?;
+                let plaintext = Crypto::aes_gcm_decrypt(&key.key, &Crypto::base64_decode(&file.nonce)?, &[], &Crypto::base64_decode(&file.ciphertext)?)
+                    .map_err(|_| WhatsAppError::StoreError("Wrong passphrase for the device store".to_string()))?;
+                let data = serde_json::from_slice(&SecretBytes::new(plaintext))
+                    .map_err(|e| WhatsAppError::StoreError(format!("Corrupt device store: {}", e)))?;
+
+                Self { path: path.to_string(), data: Mutex::new(data), key: Some(key) }
+            },
+            (Some(_), None) => {
+                return Err(WhatsAppError::StoreError("Device store is encrypted but no passphrase was given".to_string()));
+            },
+            (None, passphrase) => {
+                let data = match content.as_deref().map(serde_json::from_str) {
+                    Some(Ok(data)) => data,
+                    // Unreadable plaintext stores start over, as with `new`
+                    Some(Err(_)) if passphrase.is_none() => HashMap::new(),
+                    Some(Err(e)) => return Err(WhatsAppError::StoreError(format!("Corrupt device store: {}", e))),
+                    None => HashMap::new(),
+                };
+                let key = passphrase
+                    .map(|passphrase| StoreKey::derive(passphrase, Crypto::random_bytes(STORE_SALT_SIZE), STORE_KDF_ITERATIONS))
+                    .transpose()?;
+
+                let store = Self { path: path.to_string(), data: Mutex::new(data), key };
+                if store.key.is_some() && content.is_some() {
+                    store.save()?;
+                }
+                store
+            },
+        };
+
+        Ok(store)
+    }
+
     /// Get a value from the store
     pub fn get(&self, key: &str) -> Option<String> {
         self.data.lock().unwrap().get(key).cloned()
@@ -96,46 +428,443 @@ impl DeviceStore {
         let content = serde_json::to_string(&*data)
             .map_err(|e| WhatsAppError::IOError(e.to_string()))?;
 
-        fs::write(&self.path, content)
+        let content = match &self.key {
+            Some(key) => {
+                let content = SecretBytes::new(content.into_bytes());
+                let nonce = Crypto::random_bytes(GCM_NONCE_SIZE);
+                let file = EncryptedStore {
+                    kdf: STORE_KDF.to_string(),
+                    iterations: key.iterations,
+                    salt: Crypto::base64_encode(&key.salt),
+                    nonce: Crypto::base64_encode(&nonce),
+                    ciphertext: Crypto::base64_encode(&Crypto::aes_gcm_encrypt(&key.key, &nonce, &[], &content)?),
+                };
+                serde_json::to_string(&file)
+                    .map_err(|e| WhatsAppError::IOError(e.to_string()))?
+            },
+            None => content,
+        };
+
+        // Written aside and renamed, so a crash can't leave a truncated store
+        let temp_path = format!("{}.tmp", self.path);
+        fs::write(&temp_path, content)
+            .and_then(|_| fs::rename(&temp_path, &self.path))
             .map_err(|e| WhatsAppError::IOError(e.to_string()))?;
 
         Ok(())
     }
 }
 
+/// Chat list entry
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatInfo {
+    pub jid: JID,
+    pub name: Option<String>,
+    pub unread_count: u32,
+    pub marked_unread: bool,
+    pub archived: bool,
+    pub pinned: bool,
+    pub mute_end_time: u64,
+    pub last_message_timestamp: u64,
+    /// Seconds after which new messages in the chat disappear, 0 when they don't
+    #[serde(default)]
+    pub ephemeral_expiration: u32,
+}
+
+/// Chat and ID of a stored message, read without the rest of it
+#[derive(Deserialize)]
+struct StoredMessageKey {
+    id: String,
+    chat_jid: JID,
+}
+
+/// What to do with a line of the messages file when editing it
+enum LineEdit {
+    Keep,
+    Replace(String),
+    Remove,
+}
+
+/// Message store keeping message history and the chat list on disk
+pub struct MessageStore {
+    messages_path: String,
+    /// Held while writing the messages file, so appends aren't lost to a rewrite
+    messages_lock: Mutex<()>,
+    chats_path: String,
+    chats: Mutex<HashMap<String, ChatInfo>>,
+}
+
+impl MessageStore {
+    /// Create a new message store in the given directory
+    pub fn new(dir: &str) -> Self {
+        let chats_path = format!("{}/chats.json", dir);
+        let chats = fs::read_to_string(&chats_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        Self {
+            messages_path: format!("{}/messages.jsonl", dir),
+            messages_lock: Mutex::new(()),
+            chats_path,
+            chats: Mutex::new(chats),
+        }
+    }
+
+    /// Append a batch of messages to the store, skipping those it already has
+    ///
+    /// Messages are told apart by chat and ID, so importing the same history
+    /// twice doesn't duplicate it.
+    pub fn insert_messages(&self, messages: &[Message]) -> WhatsAppResult<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let _lock = self.messages_lock.lock().unwrap();
+
+        // Only the keys of the batch are kept while scanning, not the whole history
+        let mut new_keys: HashSet<(JID, String)> = messages.iter()
+            .map(|message| (message.chat_jid.clone(), message.id.clone()))
+            .collect();
+        for line in self.message_lines()? {
+            if let Ok(key) = serde_json::from_str::<StoredMessageKey>(&line?) {
+                new_keys.remove(&(key.chat_jid, key.id));
+            }
+        }
+        if new_keys.is_empty() {
+            return Ok(());
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.messages_path)
+            .map_err(|e| WhatsAppError::StoreError(e.to_string()))?;
+        let mut writer = BufWriter::new(file);
+
+        for message in messages {
+            // Taking the key out also skips repeats within the batch
+            if !new_keys.remove(&(message.chat_jid.clone(), message.id.clone())) {
+                continue;
+            }
+            serde_json::to_writer(&mut writer, message)
+                .map_err(|e| WhatsAppError::SerializationError(e.to_string()))?;
+            writer.write_all(b"\n")
+                .map_err(|e| WhatsAppError::StoreError(e.to_string()))?;
+        }
+
+        writer.flush().map_err(|e| WhatsAppError::StoreError(e.to_string()))
+    }
+
+    /// Update a stored message in place, returning whether it was found
+    pub fn update_message<F>(&self, chat: &JID, id: &str, update: F) -> WhatsAppResult<bool>
+    where
+        F: FnOnce(&mut Message),
+    {
+        let _lock = self.messages_lock.lock().unwrap();
+        let mut update = Some(update);
+        self.edit_messages(|line| {
+            if update.is_some()
+                && let Ok(mut message) = serde_json::from_str::<Message>(line)
+                && message.id == id
+                && message.chat_jid == *chat
+                && let Some(update) = update.take()
+            {
+                update(&mut message);
+                let line = serde_json::to_string(&message)
+                    .map_err(|e| WhatsAppError::SerializationError(e.to_string()))?;
+                return Ok(LineEdit::Replace(line));
+            }
+            Ok(LineEdit::Keep)
+        })?;
+
+        Ok(update.is_none())
+    }
+
+    /// Get a stored message
+    pub fn get_message(&self, chat: &JID, id: &str) -> WhatsAppResult<Option<Message>> {
+        let _lock = self.messages_lock.lock().unwrap();
+        for line in self.message_lines()? {
+            if let Ok(message) = serde_json::from_str::<Message>(&line?)
+                && message.id == id
+                && message.chat_jid == *chat
+            {
+                return Ok(Some(message));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Remove the stored messages of a chat that match, returning them
+    pub fn remove_messages<F>(&self, chat: &JID, mut matches: F) -> WhatsAppResult<Vec<Message>>
+    where
+        F: FnMut(&Message) -> bool,
+    {
+        let _lock = self.messages_lock.lock().unwrap();
+        let mut removed = Vec::new();
+        self.edit_messages(|line| Ok(match serde_json::from_str::<Message>(line) {
+            Ok(message) if message.chat_jid == *chat && matches(&message) => {
+                removed.push(message);
+                LineEdit::Remove
+            },
+            _ => LineEdit::Keep,
+        }))?;
+
+        Ok(removed)
+    }
+
+    /// Read the messages file line by line, holding `messages_lock`
+    ///
+    /// A store without messages yet reads as empty.
+    fn message_lines(&self) -> WhatsAppResult<impl Iterator<Item = WhatsAppResult<String>>> {
+        let file = match File::open(&self.messages_path) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(WhatsAppError::StoreError(e.to_string())),
+        };
+
+        Ok(file.into_iter()
+            .flat_map(|file| BufReader::new(file).lines())
+            .map(|line| line.map_err(|e| WhatsAppError::StoreError(e.to_string()))))
+    }
+
+    /// Edit the messages file line by line, holding `messages_lock`
+    ///
+    /// Lines are streamed into a file aside, which replaces the messages file
+    /// only if a line changed, so a crash can't leave a truncated history.
+    /// Returns whether anything changed.
+    fn edit_messages<F>(&self, mut edit: F) -> WhatsAppResult<bool>
+    where
+        F: FnMut(&str) -> WhatsAppResult<LineEdit>,
+    {
+        let temp_path = format!("{}.tmp", self.messages_path);
+        let mut write = || -> WhatsAppResult<bool> {
+            let file = File::create(&temp_path).map_err(|e| WhatsAppError::StoreError(e.to_string()))?;
+            let mut writer = BufWriter::new(file);
+            let mut changed = false;
+
+            for line in self.message_lines()? {
+                let line = line?;
+                let line = match edit(&line)? {
+                    LineEdit::Keep => line,
+                    LineEdit::Replace(line) => {
+                        changed = true;
+                        line
+                    },
+                    LineEdit::Remove => {
+                        changed = true;
+                        continue;
+                    },
+                };
+                writer.write_all(line.as_bytes())
+                    .and_then(|_| writer.write_all(b"\n"))
+                    .map_err(|e| WhatsAppError::StoreError(e.to_string()))?;
+            }
+
+            writer.flush().map_err(|e| WhatsAppError::StoreError(e.to_string()))?;
+            Ok(changed)
+        };
+
+        match write() {
+            Ok(true) => {
+                fs::rename(&temp_path, &self.messages_path).map_err(|e| WhatsAppError::StoreError(e.to_string()))?;
+                Ok(true)
+            },
+            result => {
+                let _ = fs::remove_file(&temp_path);
+                result
+            },
+        }
+    }
+
+    /// Get a chat from the chat list
+    pub fn get_chat(&self, jid: &JID) -> Option<ChatInfo> {
+        self.chats.lock().unwrap().get(&jid.to_string()).cloned()
+    }
+
+    /// Insert or replace a chat in the chat list without saving it
+    pub fn upsert_chat(&self, chat: ChatInfo) {
+        self.chats.lock().unwrap().insert(chat.jid.to_string(), chat);
+    }
+
+    /// Update a chat in the chat list, creating it if needed, and save the list
+    pub fn update_chat<F>(&self, jid: &JID, update: F) -> WhatsAppResult<()>
+    where
+        F: FnOnce(&mut ChatInfo),
+    {
+        {
+            let mut chats = self.chats.lock().unwrap();
+            let chat = chats.entry(jid.to_string()).or_insert_with(|| ChatInfo {
+                jid: jid.clone(),
+                ..Default::default()
+            });
+            update(chat);
+        }
+
+        self.save_chats()
+    }
+
+    /// Save the chat list to disk
+    pub fn save_chats(&self) -> WhatsAppResult<()> {
+        let chats = self.chats.lock().unwrap();
+        let content = serde_json::to_string(&*chats)
+            .map_err(|e| WhatsAppError::SerializationError(e.to_string()))?;
+
+        fs::write(&self.chats_path, content)
+            .map_err(|e| WhatsAppError::StoreError(e.to_string()))
+    }
+}
+
+/// Tokio runtime the client's tasks run on, either borrowed or owned
+struct ClientRuntime {
+    handle: Handle,
+    owned: Option<Runtime>,
+}
+
+impl ClientRuntime {
+    fn new(handle: Option<Handle>) -> Self {
+        if let Some(handle) = handle.or_else(|| Handle::try_current().ok()) {
+            return Self { handle, owned: None };
+        }
+
+        let runtime = Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("whatsandra")
+            .build()
+            .expect("Failed to create the client runtime");
+
+        Self {
+            handle: runtime.handle().clone(),
+            owned: Some(runtime),
+        }
+    }
+}
+
+impl Drop for ClientRuntime {
+    fn drop(&mut self) {
+        // The client may be dropped from within one of its own tasks
+        if let Some(runtime) = self.owned.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
 /// WhatsApp client
 #[allow(dead_code)]
 pub struct Client {
     config: ClientConfig,
     store: Arc<DeviceStore>,
-    websocket: Arc<WebSocketHandler>,
-    event_handlers: Mutex<Vec<Box<dyn Fn(Event) + Send + Sync>>>,
+    message_store: Arc<MessageStore>,
+    transport: Arc<dyn Transport>,
+    runtime: ClientRuntime,
+    rate_limiter: RateLimiter,
+    receive_task: Mutex<Option<JoinHandle<()>>>,
+    qr_task: Mutex<Option<JoinHandle<()>>>,
+    scheduler_task: Mutex<Option<JoinHandle<()>>>,
+    /// Held while the queue of scheduled messages is read and written back
+    schedule_lock: Mutex<()>,
+    pairing_code: Mutex<Option<pairing::PairingCode>>,
+    disconnect_waiter: Mutex<Option<oneshot::Sender<()>>>,
+    state: Mutex<ConnectionState>,
+    event_handlers: Mutex<Vec<EventHandler>>,
     device_id: String,
     auth_state: Mutex<Option<AuthState>>,
+    reconnect_pending: Mutex<bool>,
+    pending_responses: Mutex<HashMap<String, oneshot::Sender<serde_json::Value>>>,
+    devices: Mutex<HashMap<String, Vec<u32>>>,
+    /// Metadata of groups we asked about, dropped when they change
+    groups: Mutex<HashMap<JID, GroupInfo>>,
+    signal: Mutex<SessionCipher>,
+    group_cipher: Mutex<GroupCipher>,
+    /// Devices holding our current sender key, by group or status audience
+    sender_key_devices: Mutex<HashMap<JID, HashSet<JID>>>,
+    /// Sent messages, to re-send to devices that couldn't decrypt them
+    recent_messages: Mutex<VecDeque<Message>>,
+    /// Times we asked for each message we couldn't decrypt again
+    decrypt_retries: Mutex<HashMap<String, u32>>,
+    /// Messages handled most recently, loaded from the store on first use
+    seen_messages: Mutex<Option<VecDeque<String>>>,
+    /// Incoming albums waiting for their items, by chat and album message id
+    pending_albums: Mutex<HashMap<(JID, String), albums::PendingAlbum>>,
+    identities: Arc<dyn IdentityKeyStore>,
+    /// HTTP client for the media servers
+    http: reqwest::Client,
+    media_conn: media::MediaConnManager,
+    media_fetcher: Arc<dyn MediaFetcher>,
+    /// Media retry requests awaiting an answer, by message id, with the key to read it
+    pending_media_retries: Mutex<HashMap<String, (MessageKey, SecretBytes)>>,
 }
 
 /// Authentication state
 #[allow(dead_code)]
 struct AuthState {
-    pub jid: JID,
+    /// JID we are paired as, once paired
+    pub jid: Option<JID>,
     pub key_pair: KeyPair,
     pub session_id: String,
-    pub secret: Vec<u8>,
+    pub secret: SecretBytes,
+    /// Device identity signed by the account and us, once paired
+    pub account: Option<SignedDeviceIdentity>,
+    pub lid: Option<JID>,
+    /// Platform of the primary phone, from pairing
+    pub platform: String,
+    pub push_name: Option<String>,
+    /// Name of the business, for business accounts
+    pub business_name: Option<String>,
 }
 
 impl Client {
-    /// Create a new WhatsApp client
+    /// Create a new WhatsApp client connecting over a WebSocket
+    ///
+    /// Panics if the configuration is invalid or the client's stores can't be
+    /// set up, e.g. because the device store is encrypted and the passphrase is
+    /// wrong; use [`Client::try_new`] to handle that case.
     pub fn new(config: ClientConfig) -> Arc<Self> {
+        Self::try_new(config).expect("Failed to set up the client")
+    }
+
+    /// Create a new WhatsApp client connecting over a WebSocket, failing if its configuration is invalid or its stores can't be set up
+    pub fn try_new(config: ClientConfig) -> WhatsAppResult<Arc<Self>> {
+        let websocket = WebSocketHandler::new(&config.endpoint)
+            .with_fallback_endpoints(config.fallback_endpoints.clone())
+            .with_origin(&config.origin)
+            .with_user_agent(config.user_agent.clone())
+            .with_headers(config.headers.clone())
+            .with_keepalive(config.keepalive)
+            .with_stale_timeout(config.stale_timeout)
+            .with_send_queue(config.send_queue)
+            .with_compression(config.compression)
+            .with_proxy(config.proxy.clone())
+            .with_tls_pins(config.tls_pins.clone());
+
+        Self::try_with_transport(config, Arc::new(websocket))
+    }
+
+    /// Create a new WhatsApp client talking to the server through the given transport
+    ///
+    /// Panics if the configuration is invalid or the client's stores can't be set up, like [`Client::new`].
+    pub fn with_transport(config: ClientConfig, transport: Arc<dyn Transport>) -> Arc<Self> {
+        Self::try_with_transport(config, transport).expect("Failed to set up the client")
+    }
+
+    /// Create a new WhatsApp client talking to the server through the given transport, failing if its configuration is invalid or its stores can't be set up
+    pub fn try_with_transport(config: ClientConfig, transport: Arc<dyn Transport>) -> WhatsAppResult<Arc<Self>> {
+        let rate_limiter = RateLimiter::new(config.rate_limit)?;
+
         // Create the store directory if it doesn't exist
-        if !Path::new(&config.store_path).exists() {
-            if let Err(e) = fs::create_dir_all(&config.store_path) {
-                error!("Failed to create store directory: {}", e);
-            }
+        if !Path::new(&config.store_path).exists()
+            && let Err(e) = fs::create_dir_all(&config.store_path)
+        {
+            error!("Failed to create store directory: {}", e);
         }
 
         // Create store path
         let store_path = format!("{}/store.json", config.store_path);
-        let store = Arc::new(DeviceStore::new(&store_path));
+        let store = Arc::new(DeviceStore::open(&store_path, config.store_passphrase.as_deref())?);
+        let message_store = Arc::new(MessageStore::new(&config.store_path));
+        let identities = config.identity_store.clone().unwrap_or_else(|| store.clone());
+        let signal_store = config.signal_store.clone().unwrap_or_else(|| store.clone());
 
         // Generate device ID or use existing one
         let device_id = match store.get("device_id") {
@@ -149,23 +878,279 @@ impl Client {
             }
         };
 
+        let runtime = ClientRuntime::new(config.runtime.clone());
+
+        // A paired device keeps its identity, otherwise a new one is paired with
+        let (auth_state, identity) = match credentials::load_credentials(&store)? {
+            Some((auth_state, identity)) => (Some(auth_state), identity),
+            None => (None, LocalIdentity::generate(PRE_KEY_COUNT)?),
+        };
+        let signal = SessionCipher::new(identity).with_store(signal_store.clone())?;
+        let group_cipher = GroupCipher::new().with_store(signal_store);
+        let http = media::http_client(config.proxy.as_ref())?;
+        let media_fetcher = config.media_fetcher.clone()
+            .unwrap_or_else(|| Arc::new(HttpFetcher::new(http.clone())));
+
         // Create client
-        let client = Arc::new(Self {
+        Ok(Arc::new(Self {
             config,
+            runtime,
+            rate_limiter,
             store,
+            message_store,
+            transport,
+            receive_task: Mutex::new(None),
+            qr_task: Mutex::new(None),
+            scheduler_task: Mutex::new(None),
+            schedule_lock: Mutex::new(()),
+            pairing_code: Mutex::new(None),
+            disconnect_waiter: Mutex::new(None),
+            state: Mutex::new(ConnectionState::Disconnected),
             event_handlers: Mutex::new(Vec::new()),
             device_id,
-            auth_state: Mutex::new(None),
-            websocket: Arc::new(WebSocketHandler::new(
-                "wss://web.whatsapp.com/ws",
-                |event| {
-                    info!("WebSocket event: {:?}", event);
-                    // In a real implementation, we would dispatch to the client's handlers
+            auth_state: Mutex::new(auth_state),
+            reconnect_pending: Mutex::new(false),
+            pending_responses: Mutex::new(HashMap::new()),
+            devices: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new()),
+            signal: Mutex::new(signal),
+            group_cipher: Mutex::new(group_cipher),
+            sender_key_devices: Mutex::new(HashMap::new()),
+            recent_messages: Mutex::new(VecDeque::new()),
+            decrypt_retries: Mutex::new(HashMap::new()),
+            seen_messages: Mutex::new(None),
+            pending_albums: Mutex::new(HashMap::new()),
+            identities,
+            http,
+            media_conn: media::MediaConnManager::new(),
+            media_fetcher,
+            pending_media_retries: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Start the task handling everything the transport receives, if it isn't running yet
+    fn start_receiving(self: &Arc<Self>) {
+        let mut receive_task = self.receive_task.lock().unwrap();
+        if receive_task.is_some() {
+            return;
+        }
+
+        // The task only holds a weak reference so it doesn't keep the client alive
+        let client = Arc::downgrade(self);
+        let transport = self.transport.clone();
+        *receive_task = Some(self.runtime.handle.spawn(async move {
+            while let Some(event) = transport.recv().await {
+                let Some(client) = client.upgrade() else {
+                    break;
+                };
+                client.handle_transport_event(event);
+            }
+        }));
+    }
+
+    /// Handle a frame or connection event received by the transport
+    fn handle_transport_event(self: &Arc<Self>, event: TransportEvent) {
+        match event {
+            TransportEvent::Event(event) => self.handle_connection_event(event),
+            TransportEvent::Handshaking => self.set_state(ConnectionState::Handshaking),
+            TransportEvent::Text(text) => self.handle_text_frame(&text),
+            TransportEvent::Binary(data) => self.handle_binary_frame(&data),
+        }
+    }
+
+    /// Handle a text frame from the server, resolving pending requests
+    ///
+    /// Responses are JSON arrays like `["Ack",{"id":"...","phash":"..."}]`.
+    fn handle_text_frame(self: &Arc<Self>, text: &str) {
+        let Ok(serde_json::Value::Array(frame)) = serde_json::from_str(text) else {
+            debug!("Ignoring unrecognized frame: {}", text);
+            return;
+        };
+
+        let (Some(kind), Some(payload)) = (frame.first().and_then(|kind| kind.as_str()), frame.get(1)) else {
+            return;
+        };
+
+        self.handle_frame(kind, payload);
+    }
+
+    /// Handle a binary node from the server
+    ///
+    /// Messages carry their ciphertexts as child nodes, everything else is
+    /// handled by its attributes like the text frame of the same kind.
+    fn handle_binary_frame(self: &Arc<Self>, data: &[u8]) {
+        let node = match Node::decode(data) {
+            Ok(node) => node,
+            Err(e) => {
+                warn!("Failed to decode binary frame of {} bytes: {}", data.len(), e);
+                return;
+            },
+        };
+
+        match node.tag.as_str() {
+            "message" => self.handle_message_node(&node),
+            tag => self.handle_frame(tag, &node.attrs_json()),
+        }
+    }
+
+    /// Handle a frame from the server by its kind
+    fn handle_frame(self: &Arc<Self>, kind: &str, payload: &serde_json::Value) {
+        match kind {
+            "Ack" | "Response" | "ack" => {
+                let id = payload["id"].as_str().unwrap_or_default();
+                match self.pending_responses.lock().unwrap().remove(id) {
+                    Some(waiter) => {
+                        let _ = waiter.send(payload.clone());
+                    },
+                    None => debug!("Ignoring {} for unknown request {}", kind, id),
+                }
+            },
+            "message" => self.handle_incoming_message(payload),
+            "pair-device" => self.handle_pair_device(payload),
+            "pair-success" => self.handle_pair_success(payload),
+            "link_code_companion_reg" => self.handle_link_code_notification(payload),
+            "stream:error" => self.handle_stream_error(payload),
+            "success" => self.handle_login_success(payload),
+            "failure" => self.handle_login_failure(payload),
+            "notification" => self.handle_notification(payload),
+            "chatstate" => self.handle_chat_state(payload),
+            "receipt" => self.handle_receipt(payload),
+            "app_state" => self.handle_app_state_patch(payload),
+            _ => debug!("Ignoring {} frame", kind),
+        }
+    }
+
+    /// Handle a receipt for one of our messages
+    fn handle_receipt(self: &Arc<Self>, payload: &serde_json::Value) {
+        match payload["type"].as_str().unwrap_or_default() {
+            "retry" => self.handle_retry_receipt(payload),
+            "" => self.handle_status_receipt(payload, ReceiptStatus::Delivered),
+            "read" => self.handle_status_receipt(payload, ReceiptStatus::Read),
+            "played" => self.handle_status_receipt(payload, ReceiptStatus::Played),
+            kind => debug!("Ignoring {} receipt", kind),
+        }
+    }
+
+    /// Handle a notification from the server, e.g. about a user's devices
+    fn handle_notification(&self, payload: &serde_json::Value) {
+        match payload["type"].as_str().unwrap_or_default() {
+            "devices" => self.handle_devices_notification(payload),
+            "mediaretry" => self.handle_media_retry_notification(payload),
+            "w:gp2" => self.handle_group_notification(payload),
+            kind => debug!("Ignoring {} notification", kind),
+        }
+    }
+
+    /// Send a request and wait for the server's response to it
+    async fn query(&self, kind: &str, mut payload: serde_json::Value) -> WhatsAppResult<serde_json::Value> {
+        let id = hex::encode(Crypto::random_bytes(8));
+        payload["id"] = serde_json::Value::String(id.clone());
+
+        self.send_and_wait(&id, serde_json::json!([kind, payload])).await
+    }
+
+    /// Send a frame and wait for the response or ack carrying the given ID
+    async fn send_and_wait(&self, id: &str, frame: serde_json::Value) -> WhatsAppResult<serde_json::Value> {
+        let (waiter, response) = oneshot::channel();
+        self.pending_responses.lock().unwrap().insert(id.to_string(), waiter);
+
+        let result = match self.transport.send(WebSocketMessage::Text(frame.to_string())).await {
+            Ok(()) => match tokio::time::timeout(RESPONSE_TIMEOUT, response).await {
+                Ok(Ok(response)) => Ok(response),
+                _ => Err(WhatsAppError::ConnectionError(format!("Timed out waiting for response to {}", id))),
+            },
+            Err(e) => Err(e),
+        };
+
+        self.pending_responses.lock().unwrap().remove(id);
+        result
+    }
+
+    /// Handle a connection lifecycle event reported by the transport
+    fn handle_connection_event(self: &Arc<Self>, event: Event) {
+        info!("Connection event: {:?}", event);
+
+        match event {
+            Event::SessionConflict(ref reason) | Event::StreamReplaced(ref reason) => match self.config.session_conflict {
+                SessionConflictBehavior::Stop => {
+                    warn!("Session replaced by another login ({}), staying disconnected", reason);
+                    self.dispatch_event(event.clone());
+                },
+                SessionConflictBehavior::Reconnect => {
+                    warn!("Session replaced by another login ({}), taking it back", reason);
+                    *self.reconnect_pending.lock().unwrap() = true;
                 },
-            )),
-        });
+            },
+            Event::ConnectionStale => {
+                warn!("Connection stale, reconnecting");
+                *self.reconnect_pending.lock().unwrap() = true;
+                self.dispatch_event(Event::ConnectionStale);
+            },
+            Event::Connected => {
+                *self.reconnect_pending.lock().unwrap() = false;
+
+                // A paired device logged in during the handshake and waits for the server to accept it
+                let paired = self.paired_jid().is_some();
+                self.set_state(if paired { ConnectionState::Authenticating } else { ConnectionState::Online });
+                self.dispatch_event(Event::Connected);
+            },
+            Event::Disconnected => {
+                // Pairing refs are only valid on the connection they were sent on
+                self.stop_qr_rotation();
+
+                let reconnect = *self.reconnect_pending.lock().unwrap();
+                if self.connection_state() != ConnectionState::Closed {
+                    self.set_state(if reconnect { ConnectionState::Reconnecting } else { ConnectionState::Disconnected });
+                }
+                self.dispatch_event(Event::Disconnected);
+
+                if let Some(waiter) = self.disconnect_waiter.lock().unwrap().take() {
+                    let _ = waiter.send(());
+                }
 
-        client
+                // Keep reconnecting after a conflict or stale connection until it succeeds
+                if reconnect {
+                    let client = self.clone();
+                    self.runtime.handle.spawn(async move {
+                        loop {
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                            if !*client.reconnect_pending.lock().unwrap() {
+                                break;
+                            }
+                            // Log in if the device was paired since the last connect
+                            match client.handshake_config() {
+                                Ok(config) => client.transport.set_handshake(config),
+                                Err(e) => error!("Failed to prepare the handshake: {}", e),
+                            }
+                            match client.transport.connect().await {
+                                Ok(()) => break,
+                                Err(e) => error!("Failed to reconnect: {}", e),
+                            }
+                        }
+                    });
+                }
+            },
+            event => self.dispatch_event(event),
+        }
+    }
+
+    /// Get the runtime the client's tasks run on, e.g. to block on the client from synchronous code
+    pub fn runtime(&self) -> &Handle {
+        &self.runtime.handle
+    }
+
+    /// Get the current state of the connection
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Move the connection to a new state, emitting `Event::ConnectionStateChanged`
+    fn set_state(&self, new: ConnectionState) {
+        let old = std::mem::replace(&mut *self.state.lock().unwrap(), new);
+        if old != new {
+            debug!("Connection state {:?} -> {:?}", old, new);
+            self.dispatch_event(Event::ConnectionStateChanged(old, new));
+        }
     }
 
     /// Add an event handler
@@ -177,37 +1162,104 @@ impl Client {
         handlers.push(Box::new(handler));
     }
 
+    /// Dispatch an event to all registered handlers
+    fn dispatch_event(&self, event: Event) {
+        let handlers = self.event_handlers.lock().unwrap();
+        for handler in handlers.iter() {
+            handler(event.clone());
+        }
+    }
+
     /// Connect to WhatsApp
-    pub fn connect(&self) -> WhatsAppResult<()> {
-        self.websocket.connect()
+    pub async fn connect(self: &Arc<Self>) -> WhatsAppResult<()> {
+        self.start_receiving();
+        self.start_scheduler();
+        self.set_state(ConnectionState::Connecting);
+        self.transport.set_handshake(self.handshake_config()?);
+
+        // Connect on the client's runtime so the connection task runs there too
+        let transport = self.transport.clone();
+        let result = self.runtime.handle.spawn(async move { transport.connect().await }).await
+            .unwrap_or_else(|e| Err(WhatsAppError::ConnectionError(e.to_string())));
+        if result.is_err() {
+            self.set_state(ConnectionState::Disconnected);
+        }
+        result
     }
 
-    /// Generate QR code for pairing
-    pub fn generate_qr_code(&self) -> WhatsAppResult<String> {
-        // Generate key pair
-        let key_pair = Crypto::generate_key_pair()?;
+    /// Generate the QR code the phone scans to pair with a ref from the server, and emit it
+    ///
+    /// The code holds the ref, the Noise public key, the identity key and the
+    /// ADV secret. The keys are generated for the first ref and kept for the
+    /// following ones.
+    pub fn generate_qr_code(&self, pairing_ref: &str) -> WhatsAppResult<QrCode> {
+        let (noise_key, adv_secret) = self.pairing_keys()?;
+        let noise_key = noise_key.public;
+        let identity_key = self.signal.lock().unwrap().identity().identity.public.clone();
+
+        let payload = [
+            pairing_ref.to_string(),
+            Crypto::base64_encode(&noise_key),
+            Crypto::base64_encode(&identity_key),
+            Crypto::base64_encode(&adv_secret),
+        ].join(",");
+        let qr = QrCode::new(&payload)?;
 
-        // Generate random session ID
-        let session_id = hex::encode(Crypto::random_bytes(8));
+        self.dispatch_event(Event::QRCodeGenerated(qr.clone()));
+        Ok(qr)
+    }
 
-        // Store credentials in auth_state
-        let auth_state = AuthState {
-            jid: JID::new("placeholder", "s.whatsapp.net", None),
-            key_pair,
-            session_id: session_id.clone(),
-            secret: Crypto::random_bytes(32),
+    /// Get the Noise key pair and ADV secret to pair with, generating them on first use
+    fn pairing_keys(&self) -> WhatsAppResult<(KeyPair, SecretBytes)> {
+        let mut auth_state = self.auth_state.lock().unwrap();
+        let auth_state = match &mut *auth_state {
+            Some(auth_state) => auth_state,
+            None => auth_state.insert(AuthState {
+                jid: None,
+                key_pair: Crypto::generate_key_pair()?,
+                session_id: hex::encode(Crypto::random_bytes(8)),
+                secret: SecretBytes::new(Crypto::random_bytes(32)),
+                account: None,
+                lid: None,
+                platform: String::new(),
+                push_name: None,
+                business_name: None,
+            }),
         };
 
-        // Update auth state
-        *self.auth_state.lock().unwrap() = Some(auth_state);
+        Ok((auth_state.key_pair.clone(), auth_state.secret.clone()))
+    }
+
+    /// Get the keys and `ClientPayload` to authenticate with in the Noise handshake
+    ///
+    /// A paired device logs in with its JID, otherwise it registers itself to be paired.
+    fn handshake_config(&self) -> WhatsAppResult<NoiseConfig> {
+        let paired = self.auth_state.lock().unwrap().as_ref()
+            .filter(|auth_state| auth_state.account.is_some())
+            .and_then(|auth_state| Some((auth_state.key_pair.clone(), auth_state.jid.clone()?)));
 
-        // In a real implementation, we would generate an actual QR code
-        // For now, return a placeholder
-        Ok(format!("whatsapp://1234567890?key={}", session_id))
+        match paired {
+            Some((static_key, jid)) => Ok(NoiseConfig {
+                static_key,
+                payload: payload::login_payload(&jid, &self.config.device_props)?,
+            }),
+            None => {
+                let (static_key, _) = self.pairing_keys()?;
+                let payload = payload::registration_payload(self.signal.lock().unwrap().identity(), &self.config.device_props);
+                Ok(NoiseConfig { static_key, payload })
+            },
+        }
+    }
+
+    /// Get the JID this device logs in as, if it is paired
+    fn paired_jid(&self) -> Option<JID> {
+        self.auth_state.lock().unwrap().as_ref()
+            .filter(|auth_state| auth_state.account.is_some())
+            .and_then(|auth_state| auth_state.jid.clone())
     }
 
     /// Send a message
-    pub fn send_message(&self, message: &Message) -> WhatsAppResult<String> {
+    pub async fn send_message(&self, message: &Message) -> WhatsAppResult<String> {
         if !self.is_connected() {
             return Err(WhatsAppError::ConnectionError("Not connected".to_string()));
         }
@@ -217,36 +1269,129 @@ impl Client {
             return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
         }
 
-        // Convert message to JSON
-        let json = message.to_json()?;
+        // Messages in chats with a timer have to say so, or they'd stay on the other side
+        let expiration = self.message_store.get_chat(&message.chat_jid)
+            .map(|chat| chat.ephemeral_expiration)
+            .unwrap_or_default();
+        let ephemeral;
+        let message = if expiration > 0 && !message.is_ephemeral && message.protocol.is_none() {
+            ephemeral = message.clone().make_ephemeral(expiration);
+            &ephemeral
+        } else {
+            message
+        };
+
+        let delay = self.rate_limiter.reserve(&message.chat_jid);
+        if !delay.is_zero() {
+            debug!("Delaying message {} by {:?}", message.id, delay);
+            self.dispatch_event(Event::RateLimited(message.chat_jid.clone(), delay));
+            tokio::time::sleep(delay).await;
+        }
 
-        // Send message through WebSocket
-        self.websocket.send(WebSocketMessage::Text(json))?;
+        // Encrypt for the recipient's devices and send through WebSocket
+        self.send_to_devices(message).await?;
+        self.remember_sent(message);
 
         // Return message ID
         Ok(message.id.clone())
     }
 
+    /// Send an app state patch to sync it with the other devices
+    async fn send_app_state_patch(&self, patch: &Patch) -> WhatsAppResult<()> {
+        if !self.is_connected() {
+            return Err(WhatsAppError::ConnectionError("Not connected".to_string()));
+        }
+
+        if self.auth_state.lock().unwrap().is_none() {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        }
+
+        self.transport.send(WebSocketMessage::Text(patch.to_json()?)).await
+    }
+
+    /// Apply an app state patch another of our devices made
+    ///
+    /// Frames look like `["app_state",{"name":"regular_high","mutations":[...]}]`.
+    fn handle_app_state_patch(&self, payload: &serde_json::Value) {
+        let patch: Patch = match serde_json::from_value(payload.clone()) {
+            Ok(patch) => patch,
+            Err(e) => {
+                warn!("Ignoring invalid app state patch: {}", e);
+                return;
+            },
+        };
+
+        for mutation in patch.mutations {
+            let removed = mutation.operation == MutationOperation::Remove;
+            match &mutation.action {
+                SyncAction::MarkChatAsRead { read, .. } => {
+                    let Some(chat) = mutation.index.get(1).and_then(|chat| chat.parse::<JID>().ok()) else {
+                        continue;
+                    };
+                    if let Err(e) = self.message_store.update_chat(&chat, |info| info.marked_unread = !read) {
+                        error!("Failed to store read state of {}: {}", chat, e);
+                    }
+                },
+                SyncAction::Star { starred } => {
+                    let Some(key) = mutation.message_key() else {
+                        continue;
+                    };
+                    self.dispatch_event(Event::MessageStarred { key, starred: *starred && !removed });
+                },
+                SyncAction::DeleteMessageForMe { .. } => {
+                    if let Some(key) = mutation.message_key() {
+                        self.handle_delete_for_me(key);
+                    }
+                },
+                SyncAction::ClearChat { last_message_timestamp } => {
+                    if let Some(chat) = mutation.index.get(1).and_then(|chat| chat.parse::<JID>().ok()) {
+                        self.handle_clear_chat(chat, *last_message_timestamp);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Mark a chat as unread so it is flagged for follow-up on all devices
+    pub async fn mark_chat_unread(&self, jid: &JID) -> WhatsAppResult<()> {
+        let last_message_timestamp = self.message_store.get_chat(jid)
+            .map(|chat| chat.last_message_timestamp)
+            .unwrap_or_default();
+
+        self.send_app_state_patch(&Patch::mark_chat_as_read(jid, false, last_message_timestamp)).await?;
+
+        self.message_store.update_chat(jid, |chat| chat.marked_unread = true)
+    }
+
     /// Check if connected to WhatsApp
     pub fn is_connected(&self) -> bool {
-        self.websocket.is_connected()
+        self.transport.is_connected() && self.connection_state() != ConnectionState::Closed
+    }
+
+    /// Get the depth of the outgoing queue, to slow down before sends start failing
+    pub fn send_queue_metrics(&self) -> QueueMetrics {
+        self.transport.queue_metrics()
     }
 
-    /// Check if authenticated to WhatsApp
+    /// Get bytes and frames transferred, reconnects, keepalive RTT and uptime of the connection
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.transport.connection_stats()
+    }
+
+    /// Check if authenticated to WhatsApp, i.e. paired with a phone
     pub fn is_authenticated(&self) -> bool {
-        self.auth_state.lock().unwrap().is_some()
+        self.auth_state.lock().unwrap().as_ref().is_some_and(|auth_state| auth_state.account.is_some())
     }
 
     /// Logout from WhatsApp
-    pub fn logout(&self) -> WhatsAppResult<()> {
-        // Clear auth state
-        *self.auth_state.lock().unwrap() = None;
-
-        // Clear store
-        self.store.remove("credentials")?;
+    pub async fn logout(&self) -> WhatsAppResult<()> {
+        // Clear auth state, here and in the store
+        self.clear_credentials()?;
 
         // Disconnect
-        self.websocket.disconnect()?;
+        *self.reconnect_pending.lock().unwrap() = false;
+        self.set_state(ConnectionState::Closed);
+        self.transport.close().await?;
 
         Ok(())
     }
@@ -269,7 +1414,281 @@ impl Client {
     }
 
     /// Disconnect from WhatsApp
-    pub fn disconnect(&self) -> WhatsAppResult<()> {
-        self.websocket.disconnect()
+    pub async fn disconnect(&self) -> WhatsAppResult<()> {
+        *self.reconnect_pending.lock().unwrap() = false;
+        self.set_state(ConnectionState::Closed);
+        self.transport.close().await
+    }
+
+    /// Shut down the connection gracefully
+    ///
+    /// New sends are refused right away, messages already queued are flushed,
+    /// then the connection is closed and its tasks are stopped and joined
+    /// before `Event::ShutdownComplete` is emitted. If this takes longer than
+    /// `timeout`, the connection is dropped without flushing and tasks that
+    /// haven't stopped by then are left behind.
+    ///
+    /// Shutdown finishes even if closing the connection fails, in which case
+    /// the error is returned afterwards.
+    pub async fn shutdown(&self, timeout: Duration) -> WhatsAppResult<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        *self.reconnect_pending.lock().unwrap() = false;
+        self.set_state(ConnectionState::Closed);
+
+        let mut result = Ok(());
+        if self.transport.is_connected() {
+            let (waiter, disconnected) = oneshot::channel();
+            *self.disconnect_waiter.lock().unwrap() = Some(waiter);
+
+            // Wait for the receive task to handle the disconnect so no events are lost
+            let drained = tokio::time::timeout_at(deadline, async {
+                self.transport.close().await?;
+                let _ = disconnected.await;
+                Ok::<(), WhatsAppError>(())
+            }).await;
+
+            match drained {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => {
+                    warn!("Failed to close the connection, dropping it: {}", e);
+                    self.transport.abort();
+                    result = Err(e);
+                },
+                Err(_) => {
+                    warn!("Shutdown didn't finish within {:?}, dropping the connection", timeout);
+                    self.transport.abort();
+                },
+            }
+        }
+
+        let tasks = [&self.receive_task, &self.scheduler_task, &self.qr_task]
+            .map(|task| task.lock().unwrap().take());
+        for task in tasks.into_iter().flatten() {
+            task.abort();
+            if tokio::time::timeout_at(deadline, task).await.is_err() {
+                warn!("A client task didn't stop within {:?}", timeout);
+            }
+        }
+
+        self.dispatch_event(Event::ShutdownComplete);
+        result
+    }
+
+    /// Import a zlib-compressed history sync blob into the message store
+    ///
+    /// The blob is streamed from `reader` in bounded batches according to
+    /// `ClientConfig::history_sync`, emitting `Event::HistorySyncProgress`
+    /// after each batch is written.
+    pub fn import_history_sync<R: Read>(&self, reader: R) -> WhatsAppResult<HistorySyncProgress> {
+        history::import(reader, &self.config.history_sync, &self.message_store, |progress| {
+            self.dispatch_event(Event::HistorySyncProgress(progress.clone()));
+        })
+    }
+
+    /// Get a chat from the local chat list
+    pub fn get_chat(&self, jid: &JID) -> Option<ChatInfo> {
+        self.message_store.get_chat(jid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use async_trait::async_trait;
+    use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("whatsandra-{}-{}", name, hex::encode(Crypto::random_bytes(8))));
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    /// Connected transport that reports the disconnect on close, or fails to close
+    struct ClosingTransport {
+        fail_close: bool,
+        aborted: AtomicBool,
+        incoming: UnboundedSender<TransportEvent>,
+        receiver: tokio::sync::Mutex<UnboundedReceiver<TransportEvent>>,
+    }
+
+    impl ClosingTransport {
+        fn new(fail_close: bool) -> Arc<Self> {
+            let (incoming, receiver) = mpsc::unbounded_channel();
+            Arc::new(Self {
+                fail_close,
+                aborted: AtomicBool::new(false),
+                incoming,
+                receiver: tokio::sync::Mutex::new(receiver),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Transport for ClosingTransport {
+        async fn connect(&self) -> WhatsAppResult<()> {
+            Ok(())
+        }
+
+        async fn send(&self, _message: WebSocketMessage) -> WhatsAppResult<()> {
+            Ok(())
+        }
+
+        fn try_send(&self, _message: WebSocketMessage) -> WhatsAppResult<()> {
+            Ok(())
+        }
+
+        async fn recv(&self) -> Option<TransportEvent> {
+            self.receiver.lock().await.recv().await
+        }
+
+        async fn close(&self) -> WhatsAppResult<()> {
+            if self.fail_close {
+                return Err(WhatsAppError::ConnectionError("Close frame not sent".to_string()));
+            }
+            let _ = self.incoming.send(TransportEvent::Event(Event::Disconnected));
+            Ok(())
+        }
+
+        fn abort(&self) {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+    }
+
+    /// Shut a client down over the transport, returning the result and the events it emitted
+    async fn shut_down(transport: Arc<ClosingTransport>) -> (WhatsAppResult<()>, Vec<Event>, Arc<Client>) {
+        let dir = temp_dir("shutdown");
+        let client = Client::try_with_transport(ClientConfig { store_path: dir.clone(), ..ClientConfig::default() }, transport).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        client.add_event_handler(move |event| recorded.lock().unwrap().push(event));
+        client.start_receiving();
+
+        let result = client.shutdown(Duration::from_secs(5)).await;
+        let _ = fs::remove_dir_all(dir);
+        let events = events.lock().unwrap().clone();
+        (result, events, client)
+    }
+
+    #[tokio::test]
+    async fn shutdown_joins_tasks_after_closing() {
+        let transport = ClosingTransport::new(false);
+        let (result, events, client) = shut_down(transport.clone()).await;
+
+        assert!(result.is_ok());
+        assert!(matches!(events[..], [.., Event::Disconnected, Event::ShutdownComplete]));
+        assert!(!transport.aborted.load(Ordering::SeqCst));
+        assert!(client.receive_task.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_when_close_fails() {
+        let transport = ClosingTransport::new(true);
+        let (result, events, client) = shut_down(transport.clone()).await;
+
+        assert!(matches!(result, Err(WhatsAppError::ConnectionError(_))));
+        assert!(matches!(events.last(), Some(Event::ShutdownComplete)));
+        assert!(transport.aborted.load(Ordering::SeqCst));
+        assert!(client.receive_task.lock().unwrap().is_none());
+    }
+
+    fn read_store(path: &str) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// Write an encrypted store with the fewest iterations allowed, which derives its key much quicker
+    fn write_encrypted_store(path: &str, passphrase: &str) {
+        let key = StoreKey::derive(passphrase, Crypto::random_bytes(STORE_SALT_SIZE), STORE_MIN_KDF_ITERATIONS).unwrap();
+        let store = DeviceStore { path: path.to_string(), data: Mutex::new(HashMap::new()), key: Some(key) };
+        store.set("device_id", "rust_01234567").unwrap();
+    }
+
+    #[test]
+    fn encrypted_store_round_trips() {
+        let dir = temp_dir("store");
+        let path = format!("{}/store.json", dir);
+        write_encrypted_store(&path, "passphrase");
+
+        let file = read_store(&path);
+        assert_eq!(file["kdf"], STORE_KDF);
+        assert!(!fs::read_to_string(&path).unwrap().contains("rust_01234567"));
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        let store = DeviceStore::open(&path, Some("passphrase")).unwrap();
+        assert_eq!(store.get("device_id").as_deref(), Some("rust_01234567"));
+
+        // Saving again keeps the key, with a fresh nonce
+        store.set("push_name", "Alice").unwrap();
+        assert_eq!(read_store(&path)["salt"], file["salt"]);
+        assert_ne!(read_store(&path)["nonce"], file["nonce"]);
+        let store = DeviceStore::open(&path, Some("passphrase")).unwrap();
+        assert_eq!(store.get("push_name").as_deref(), Some("Alice"));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_passphrase() {
+        let dir = temp_dir("store");
+        let path = format!("{}/store.json", dir);
+        write_encrypted_store(&path, "passphrase");
+
+        assert!(matches!(DeviceStore::open(&path, Some("wrong")), Err(WhatsAppError::StoreError(_))));
+        assert!(matches!(DeviceStore::open(&path, None), Err(WhatsAppError::StoreError(_))));
+        // A failed open leaves the file alone
+        assert!(DeviceStore::open(&path, Some("passphrase")).is_ok());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn plaintext_store_is_encrypted_on_open() {
+        let dir = temp_dir("store");
+        let path = format!("{}/store.json", dir);
+
+        DeviceStore::open(&path, None).unwrap().set("device_id", "rust_01234567").unwrap();
+        assert_eq!(read_store(&path)["device_id"], "rust_01234567");
+
+        let store = DeviceStore::open(&path, Some("passphrase")).unwrap();
+        let file = read_store(&path);
+        assert_eq!(file["iterations"], STORE_KDF_ITERATIONS);
+        assert!(!fs::read_to_string(&path).unwrap().contains("rust_01234567"));
+
+        // Decrypted with the key it was just written with, rather than deriving it again
+        let key = &store.key.as_ref().unwrap().key;
+        let field = |name: &str| Crypto::base64_decode(file[name].as_str().unwrap()).unwrap();
+        let plaintext = Crypto::aes_gcm_decrypt(key, &field("nonce"), &[], &field("ciphertext")).unwrap();
+        let data: HashMap<String, String> = serde_json::from_slice(&plaintext).unwrap();
+        assert_eq!(data["device_id"], "rust_01234567");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_weakened_key_derivation() {
+        let dir = temp_dir("store");
+        let path = format!("{}/store.json", dir);
+        write_encrypted_store(&path, "passphrase");
+        let file = read_store(&path);
+
+        let mut weakened = file.clone();
+        weakened["iterations"] = serde_json::json!(STORE_MIN_KDF_ITERATIONS - 1);
+        fs::write(&path, weakened.to_string()).unwrap();
+        let error = DeviceStore::open(&path, Some("passphrase")).err().unwrap();
+        assert!(error.to_string().contains("iterations"), "{}", error);
+
+        let mut other_kdf = file;
+        other_kdf["kdf"] = serde_json::json!("scrypt");
+        fs::write(&path, other_kdf.to_string()).unwrap();
+        let error = DeviceStore::open(&path, Some("passphrase")).err().unwrap();
+        assert!(error.to_string().contains("scrypt"), "{}", error);
+
+        let _ = fs::remove_dir_all(dir);
     }
 }
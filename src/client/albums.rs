@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::debug;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    message::{Message, MessageType},
+};
+
+/// How long the items of an incoming album are awaited before handing over those that came
+const ALBUM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Incoming album still waiting for some of its items
+#[derive(Default)]
+pub(super) struct PendingAlbum {
+    /// Number of items announced, unknown until the album message itself arrives
+    expected: Option<usize>,
+    items: Vec<Message>,
+}
+
+impl Client {
+    /// Send images and videos to a chat as one album, returning the ids of the items
+    pub async fn send_album(&self, chat: &JID, items: Vec<Message>) -> WhatsAppResult<Vec<String>> {
+        if items.len() < 2 {
+            return Err(WhatsAppError::ProtocolError("An album needs at least two items".to_string()));
+        }
+        let count = |kind| items.iter().filter(|item| item.message_type == kind).count() as u32;
+        let (images, videos) = (count(MessageType::Image), count(MessageType::Video));
+        if (images + videos) as usize != items.len() {
+            return Err(WhatsAppError::ProtocolError("Albums hold only images and videos".to_string()));
+        }
+
+        let album = Message::new_album(chat.clone(), images, videos);
+        self.send_message(&album).await?;
+
+        let mut ids = Vec::with_capacity(items.len());
+        for mut item in items {
+            item.chat_jid = chat.clone();
+            ids.push(self.send_message(&item.in_album(&album)).await?);
+        }
+
+        Ok(ids)
+    }
+
+    /// Start waiting for the items an incoming album announces
+    pub(super) fn handle_album(self: &Arc<Self>, message: Message) {
+        let expected = message.album.as_ref()
+            .map_or(0, |album| (album.expected_images + album.expected_videos) as usize);
+        self.add_to_album(message.chat_jid, message.id, |pending| pending.expected = Some(expected));
+    }
+
+    /// Add an incoming image or video to the album it belongs to
+    pub(super) fn handle_album_item(self: &Arc<Self>, message: Message) {
+        let chat = message.chat_jid.clone();
+        let album_id = message.album_parent.as_ref().map(|parent| parent.id.clone()).unwrap_or_default();
+        self.add_to_album(chat, album_id, |pending| pending.items.push(message));
+    }
+
+    /// Update a pending album and hand it over once every item came
+    fn add_to_album(self: &Arc<Self>, chat: JID, album_id: String, update: impl FnOnce(&mut PendingAlbum)) {
+        let key = (chat, album_id);
+        let complete = {
+            let mut albums = self.pending_albums.lock().unwrap();
+            let new = !albums.contains_key(&key);
+            let pending = albums.entry(key.clone()).or_default();
+            update(pending);
+
+            if new {
+                self.expire_album(key.clone());
+            }
+            match pending.expected {
+                Some(expected) if pending.items.len() >= expected => albums.remove(&key),
+                _ => None,
+            }
+        };
+
+        if let Some(album) = complete {
+            self.dispatch_event(Event::AlbumReceived(album.items));
+        }
+    }
+
+    /// Hand over whatever items of an album came once it has been waited for long enough
+    fn expire_album(self: &Arc<Self>, key: (JID, String)) {
+        let client = Arc::downgrade(self);
+        self.runtime.handle.spawn(async move {
+            tokio::time::sleep(ALBUM_TIMEOUT).await;
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+
+            let Some(album) = client.pending_albums.lock().unwrap().remove(&key) else {
+                return;
+            };
+            debug!("Album {} in {} timed out with {} items", key.1, key.0, album.items.len());
+            if !album.items.is_empty() {
+                client.dispatch_event(Event::AlbumReceived(album.items));
+            }
+        });
+    }
+}
@@ -0,0 +1,29 @@
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+};
+
+impl Client {
+    /// Get the recipients of one of our broadcast lists
+    ///
+    /// Responses look like `{"recipients":["1234567890@s.whatsapp.net",...]}`.
+    pub async fn get_broadcast_list_recipients(&self, list: &JID) -> WhatsAppResult<Vec<JID>> {
+        if !list.is_broadcast_list() {
+            return Err(WhatsAppError::ProtocolError(format!("{} isn't a broadcast list", list)));
+        }
+
+        let response = self.query("query", json!({ "type": "broadcast_list", "jid": list.to_string() })).await?;
+        response["recipients"].as_array()
+            .ok_or_else(|| WhatsAppError::DeserializationError(format!("No recipients of {}", list)))?
+            .iter()
+            .map(|recipient| {
+                recipient.as_str()
+                    .ok_or_else(|| WhatsAppError::DeserializationError(format!("Invalid recipient of {}", list)))?
+                    .parse()
+            })
+            .collect()
+    }
+}
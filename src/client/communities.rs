@@ -0,0 +1,114 @@
+use serde_json::json;
+
+use super::{Client, send::ack_error};
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+    group::{GroupInfo, LinkedGroup, MAX_SUBJECT_LENGTH},
+};
+
+impl Client {
+    /// Create a community with us as its owner, which comes with an announcement group
+    ///
+    /// Requests look like `["group",{"id":"...","create":{"subject":"...",
+    /// "participants":[],"parent":{"description":"..."}}}]` and are answered with
+    /// the metadata of the community under `"group"`.
+    pub async fn create_community(&self, name: &str, description: Option<&str>) -> WhatsAppResult<GroupInfo> {
+        if self.paired_jid().is_none() {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        }
+        if name.trim().is_empty() {
+            return Err(WhatsAppError::GroupError("Community name can't be empty".to_string()));
+        }
+        if name.chars().count() > MAX_SUBJECT_LENGTH {
+            return Err(WhatsAppError::GroupError(format!("Community name is longer than {} characters", MAX_SUBJECT_LENGTH)));
+        }
+
+        let response = self.query("group", json!({
+            "create": {
+                "subject": name,
+                "participants": [],
+                "parent": { "description": description },
+            },
+        })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to create community {}: {}", name, error)));
+        }
+
+        let info = GroupInfo::from_json(&response["group"])?;
+        self.groups.lock().unwrap().insert(info.jid.clone(), info.clone());
+        Ok(info)
+    }
+
+    /// Link a group we administer to a community we administer
+    pub async fn link_group(&self, community: &JID, group_jid: &JID) -> WhatsAppResult<()> {
+        self.set_group_link(community, group_jid, true).await
+    }
+
+    /// Unlink a group from a community we administer
+    pub async fn unlink_group(&self, community: &JID, group_jid: &JID) -> WhatsAppResult<()> {
+        self.set_group_link(community, group_jid, false).await
+    }
+
+    /// Get the groups linked to a community, its announcement group included
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<community>","subgroups":{}}]`
+    /// and are answered with the groups under `"groups"`.
+    pub async fn get_subgroups(&self, community: &JID) -> WhatsAppResult<Vec<LinkedGroup>> {
+        if !community.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a community", community)));
+        }
+
+        let response = self.query("group", json!({
+            "to": community.to_string(),
+            "subgroups": {},
+        })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to get the groups of {}: {}", community, error)));
+        }
+
+        response["groups"].as_array().into_iter().flatten()
+            .map(LinkedGroup::from_json)
+            .collect()
+    }
+
+    /// Get the announcement group of a community, which every member is in
+    pub async fn get_announcement_group(&self, community: &JID) -> WhatsAppResult<JID> {
+        self.get_subgroups(community).await?
+            .into_iter()
+            .find(|group| group.is_default_subgroup)
+            .map(|group| group.jid)
+            .ok_or_else(|| WhatsAppError::GroupError(format!("{} has no announcement group", community)))
+    }
+
+    /// Link a group to a community or unlink it
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<community>","links":{"link":["<group>"]}}]`,
+    /// or `"unlink"`.
+    async fn set_group_link(&self, community: &JID, group_jid: &JID, link: bool) -> WhatsAppResult<()> {
+        if !community.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a community", community)));
+        }
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+
+        let kind = if link { "link" } else { "unlink" };
+        let response = self.query("group", json!({
+            "to": community.to_string(),
+            "links": { kind: [group_jid.to_string()] },
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(WhatsAppError::GroupError(format!("We aren't an admin of {} and {}", community, group_jid)));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to {} {} and {}: {}", kind, community, group_jid, error)));
+        }
+
+        // Both now list different links
+        let mut groups = self.groups.lock().unwrap();
+        groups.remove(community);
+        groups.remove(group_jid);
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use log::info;
+use serde_json::json;
+
+use super::{AuthState, Client, DeviceStore};
+use crate::{
+    JID,
+    adv::SignedDeviceIdentity,
+    crypto::{Crypto, KeyPair, SecretBytes},
+    error::{WhatsAppError, WhatsAppResult},
+    signal::LocalIdentity,
+};
+
+/// Device store key the credentials of a paired device are kept under
+const CREDENTIALS_KEY: &str = "credentials";
+
+impl Client {
+    /// Save the auth state and our Signal identity so the next start logs straight back in
+    ///
+    /// One-time prekeys aren't included, they are already in the Signal store.
+    pub(super) fn save_credentials(&self) -> WhatsAppResult<()> {
+        let auth_state = self.auth_state.lock().unwrap();
+        let Some(auth_state) = auth_state.as_ref() else {
+            return Err(WhatsAppError::AuthError("No credentials to save".to_string()));
+        };
+        let (Some(jid), Some(account)) = (&auth_state.jid, &auth_state.account) else {
+            return Err(WhatsAppError::AuthError("Credentials can only be saved once paired".to_string()));
+        };
+
+        let signal = self.signal.lock().unwrap();
+        let identity = signal.identity();
+        let credentials = json!({
+            "jid": jid.to_string(),
+            "noise_key": key_pair_to_json(&auth_state.key_pair),
+            "session_id": auth_state.session_id,
+            "adv_secret": Crypto::base64_encode(&auth_state.secret),
+            "account": Crypto::base64_encode(&account.encode(true)),
+            "lid": auth_state.lid,
+            "platform": auth_state.platform,
+            "push_name": auth_state.push_name,
+            "business_name": auth_state.business_name,
+            "registration_id": identity.registration_id,
+            "identity_key": key_pair_to_json(&identity.identity),
+            "signed_pre_key_id": identity.signed_pre_key_id,
+            "signed_pre_key": key_pair_to_json(&identity.signed_pre_key),
+            "signed_pre_key_signature": Crypto::base64_encode(&identity.signed_pre_key_signature),
+        });
+
+        self.store.set(CREDENTIALS_KEY, &credentials.to_string())
+    }
+
+    /// Forget the auth state, here and in the store, so the device has to be paired again
+    pub(super) fn clear_credentials(&self) -> WhatsAppResult<()> {
+        *self.auth_state.lock().unwrap() = None;
+        self.store.remove(CREDENTIALS_KEY)
+    }
+}
+
+/// Load the auth state and Signal identity saved when the device was paired, if it was
+pub(super) fn load_credentials(store: &DeviceStore) -> WhatsAppResult<Option<(AuthState, LocalIdentity)>> {
+    let Some(value) = store.get(CREDENTIALS_KEY) else {
+        return Ok(None);
+    };
+
+    let value: serde_json::Value = serde_json::from_str(&value)
+        .map_err(|e| WhatsAppError::StoreError(format!("Corrupt credentials: {}", e)))?;
+    let missing = |name: &str| WhatsAppError::StoreError(format!("Credentials have no {}", name));
+    let string = |name: &str| value[name].as_str().ok_or_else(|| missing(name));
+    let bytes = |name: &str| Crypto::base64_decode(string(name)?);
+    let number = |name: &str| value[name].as_u64().map(|n| n as u32).ok_or_else(|| missing(name));
+    let key_pair = |name: &str| key_pair_from_json(&value[name]).ok_or_else(|| missing(name));
+
+    let jid = match value["jid"].as_str() {
+        Some(jid) => jid.parse::<JID>()?,
+        // Kept as an object for a while, before device JIDs displayed the way they parse
+        None => serde_json::from_value(value["jid"].clone()).map_err(|_| missing("jid"))?,
+    };
+    let auth_state = AuthState {
+        jid: Some(jid.clone()),
+        key_pair: key_pair("noise_key")?,
+        session_id: string("session_id")?.to_string(),
+        secret: SecretBytes::new(bytes("adv_secret")?),
+        account: Some(SignedDeviceIdentity::decode(&bytes("account")?)?),
+        // Missing from credentials saved by older versions
+        lid: serde_json::from_value(value["lid"].clone()).unwrap_or_default(),
+        platform: value["platform"].as_str().unwrap_or_default().to_string(),
+        push_name: value["push_name"].as_str().map(str::to_string),
+        business_name: value["business_name"].as_str().map(str::to_string),
+    };
+    let identity = LocalIdentity {
+        registration_id: number("registration_id")?,
+        identity: key_pair("identity_key")?,
+        signed_pre_key_id: number("signed_pre_key_id")?,
+        signed_pre_key: key_pair("signed_pre_key")?,
+        signed_pre_key_signature: bytes("signed_pre_key_signature")?,
+        // Loaded from the Signal store when a session needs them
+        pre_keys: Default::default(),
+    };
+
+    info!("Restored credentials of {}", jid);
+    Ok(Some((auth_state, identity)))
+}
+
+fn key_pair_to_json(key_pair: &KeyPair) -> serde_json::Value {
+    json!({
+        "private": Crypto::base64_encode(&key_pair.private),
+        "public": Crypto::base64_encode(&key_pair.public),
+    })
+}
+
+fn key_pair_from_json(value: &serde_json::Value) -> Option<KeyPair> {
+    let field = |name: &str| value[name].as_str().and_then(|field| Crypto::base64_decode(field).ok());
+
+    Some(KeyPair {
+        private: SecretBytes::new(field("private")?),
+        public: field("public")?,
+    })
+}
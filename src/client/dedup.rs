@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+use log::{debug, warn};
+
+use super::Client;
+use crate::error::WhatsAppError;
+
+/// Device store key of the messages handled most recently
+const SEEN_MESSAGES_KEY: &str = "seen_messages";
+
+/// Number of handled messages remembered to recognize redeliveries
+const SEEN_MESSAGES: usize = 1000;
+
+impl Client {
+    /// Whether a message relayed by the server was handled before, e.g. redelivered after a reconnect
+    pub(super) fn is_duplicate(&self, payload: &serde_json::Value) -> bool {
+        let Some(key) = seen_key(payload) else {
+            return false;
+        };
+
+        let mut seen = self.seen_messages.lock().unwrap();
+        let duplicate = self.load_seen(&mut seen).contains(&key);
+        if duplicate {
+            debug!("Ignoring redelivered message {}", key);
+        }
+        duplicate
+    }
+
+    /// Remember a message was handled, so a redelivery of it is ignored
+    pub(super) fn mark_seen(&self, payload: &serde_json::Value) {
+        let Some(key) = seen_key(payload) else {
+            return;
+        };
+
+        let mut seen = self.seen_messages.lock().unwrap();
+        let queue = self.load_seen(&mut seen);
+        if queue.len() >= SEEN_MESSAGES {
+            queue.pop_front();
+        }
+        queue.push_back(key);
+
+        let saved = serde_json::to_string(queue)
+            .map_err(|e| WhatsAppError::SerializationError(e.to_string()))
+            .and_then(|value| self.store.set(SEEN_MESSAGES_KEY, &value));
+        if let Err(e) = saved {
+            warn!("Failed to store handled messages: {}", e);
+        }
+    }
+
+    /// Get the handled messages, loading them from the store on first use
+    fn load_seen<'a>(&self, seen: &'a mut Option<VecDeque<String>>) -> &'a mut VecDeque<String> {
+        seen.get_or_insert_with(|| {
+            self.store.get(SEEN_MESSAGES_KEY)
+                .and_then(|value| serde_json::from_str(&value).ok())
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// Identify a message by its chat, sender device and id, as ids are only unique per sender
+fn seen_key(payload: &serde_json::Value) -> Option<String> {
+    let id = payload["id"].as_str()?;
+    let from = payload["from"].as_str()?;
+    let participant = payload["participant"].as_str().unwrap_or_default();
+    Some(format!("{}/{}/{}", from, participant, id))
+}
@@ -0,0 +1,59 @@
+use log::error;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    appstate::Patch,
+    error::WhatsAppResult,
+    message::MessageKey,
+};
+
+impl Client {
+    /// Delete a message from our devices only, leaving it for everyone else in the chat
+    pub async fn delete_message_for_me(&self, chat: &JID, message_id: &str) -> WhatsAppResult<()> {
+        // The stored message tells who wrote it, which the other devices need to find it
+        let (key, timestamp) = match self.message_store.get_message(chat, message_id)? {
+            Some(message) => (message.key(), message.timestamp),
+            None => (MessageKey {
+                chat_jid: chat.clone(),
+                id: message_id.to_string(),
+                from_me: false,
+                sender_jid: None,
+            }, 0),
+        };
+
+        self.send_app_state_patch(&Patch::delete_message_for_me(&key, timestamp)).await?;
+        self.message_store.remove_messages(chat, |message| message.id == message_id)?;
+        Ok(())
+    }
+
+    /// Delete every message of a chat from our devices, keeping the chat in the list
+    pub async fn clear_chat(&self, chat: &JID) -> WhatsAppResult<()> {
+        let last_message_timestamp = self.message_store.get_chat(chat)
+            .map(|info| info.last_message_timestamp)
+            .unwrap_or_default();
+
+        self.send_app_state_patch(&Patch::clear_chat(chat, last_message_timestamp)).await?;
+        self.message_store.remove_messages(chat, |_| true)?;
+        Ok(())
+    }
+
+    /// Drop a message another of our devices deleted and tell the application
+    pub(super) fn handle_delete_for_me(&self, key: MessageKey) {
+        if let Err(e) = self.message_store.remove_messages(&key.chat_jid, |message| message.id == key.id) {
+            error!("Failed to delete message {}: {}", key.id, e);
+        }
+        self.dispatch_event(Event::MessageDeletedForMe { key });
+    }
+
+    /// Drop the messages of a chat another of our devices cleared and tell the application
+    pub(super) fn handle_clear_chat(&self, chat: JID, last_message_timestamp: u64) {
+        let removed = self.message_store.remove_messages(&chat, |message| {
+            last_message_timestamp == 0 || message.timestamp <= last_message_timestamp
+        });
+        if let Err(e) = removed {
+            error!("Failed to clear chat {}: {}", chat, e);
+        }
+        self.dispatch_event(Event::ChatCleared { chat });
+    }
+}
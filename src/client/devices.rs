@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use log::{debug, warn};
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+};
+
+impl Client {
+    /// Get the devices of a user, from the cache or by asking the server
+    ///
+    /// The primary device has no device number in its JID.
+    pub async fn get_user_devices(&self, jid: &JID) -> WhatsAppResult<Vec<JID>> {
+        self.get_devices(&[JID::new(&jid.user, &jid.server, None)]).await
+    }
+
+    /// Get the devices of the given users, fetching any that aren't cached
+    pub(super) async fn get_devices(&self, users: &[JID]) -> WhatsAppResult<Vec<JID>> {
+        let missing: Vec<JID> = {
+            let cache = self.devices.lock().unwrap();
+            users.iter().filter(|user| !cache.contains_key(&user.to_string())).cloned().collect()
+        };
+
+        if !missing.is_empty() {
+            self.fetch_devices(&missing).await?;
+        }
+
+        let cache = self.devices.lock().unwrap();
+        Ok(users.iter()
+            .flat_map(|user| {
+                cache.get(&user.to_string())
+                    .into_iter()
+                    .flatten()
+                    .map(|&device| Self::device_jid(user, device))
+            })
+            .collect())
+    }
+
+    /// Fetch the current device lists of the given users with a USync query and update the cache
+    pub(super) async fn fetch_devices(&self, users: &[JID]) -> WhatsAppResult<Vec<JID>> {
+        let jids: Vec<String> = users.iter().map(|user| user.to_string()).collect();
+        let response = self.query("query", json!({ "type": "devices", "jids": jids })).await?;
+
+        let lists: HashMap<String, Vec<u32>> = serde_json::from_value(response["devices"].clone())
+            .map_err(|e| WhatsAppError::DeserializationError(e.to_string()))?;
+
+        let mut cache = self.devices.lock().unwrap();
+        let mut devices = Vec::new();
+        for user in users {
+            let list = lists.get(&user.to_string()).cloned().unwrap_or_else(|| vec![0]);
+            devices.extend(list.iter().map(|&device| Self::device_jid(user, device)));
+            cache.insert(user.to_string(), list);
+        }
+
+        Ok(devices)
+    }
+
+    /// Update the cached device list of a user when one of their devices is linked or unlinked
+    ///
+    /// Notifications look like `["notification",{"type":"devices","from":"...",
+    /// "add":["1234567890:3@s.whatsapp.net"],"remove":[...]}]`.
+    pub(super) fn handle_devices_notification(&self, payload: &serde_json::Value) {
+        let Some(from) = payload["from"].as_str().and_then(|from| from.parse::<JID>().ok()) else {
+            warn!("Devices notification without a sender: {}", payload);
+            return;
+        };
+        let user = JID::new(&from.user, &from.server, None);
+
+        let devices = |name: &str| -> Vec<u32> {
+            payload[name].as_array().into_iter().flatten()
+                .filter_map(|device| device.as_str()?.parse::<JID>().ok())
+                .filter(|device| device.user == user.user)
+                .map(|device| device.device.unwrap_or(0))
+                .collect()
+        };
+        let (added, removed) = (devices("add"), devices("remove"));
+        debug!("Devices of {} changed, added {:?}, removed {:?}", user, added, removed);
+
+        // Users not cached yet get their whole list on the next lookup
+        let mut cache = self.devices.lock().unwrap();
+        let Some(list) = cache.get_mut(&user.to_string()) else {
+            return;
+        };
+        list.retain(|device| !removed.contains(device));
+        for device in added {
+            if !list.contains(&device) {
+                list.push(device);
+            }
+        }
+    }
+
+    /// Build the JID of one of a user's devices, the primary device being 0
+    fn device_jid(user: &JID, device: u32) -> JID {
+        JID::new(&user.user, &user.server, if device == 0 { None } else { Some(device) })
+    }
+}
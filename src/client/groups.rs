@@ -0,0 +1,500 @@
+use std::time::Duration;
+use log::{debug, warn};
+use serde_json::json;
+
+use super::{Client, DISAPPEARING_TIMERS, send::ack_error};
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    group::{CreateGroupRequest, GroupInfo, GroupParticipant, GroupSetting, MAX_SUBJECT_LENGTH, ParticipantAction, ParticipantRole, PastParticipant},
+};
+
+impl Client {
+    /// Create a group with us as its owner and try to add the given participants
+    pub async fn create_group(&self, subject: &str, participants: &[JID]) -> WhatsAppResult<GroupInfo> {
+        self.create_group_with(CreateGroupRequest::new(subject, participants)).await
+    }
+
+    /// Create a group as requested, linking it to a community and greeting it if asked to
+    ///
+    /// Requests look like `["group",{"id":"...","create":{"subject":"...",
+    /// "participants":["..."],"key":"<create key>","linked_parent":"<community>"}}]`
+    /// and are answered with the metadata of the new group under `"group"`.
+    /// Participants that couldn't be added are listed with an error code, and
+    /// with an invite to send them when their privacy settings forbid adding them.
+    ///
+    /// When the first message can't be sent the group stays created, and the
+    /// error names it.
+    pub async fn create_group_with(&self, request: CreateGroupRequest) -> WhatsAppResult<GroupInfo> {
+        let subject = request.subject.as_str();
+        if self.paired_jid().is_none() {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        }
+        if subject.trim().is_empty() {
+            return Err(WhatsAppError::GroupError("Group subject can't be empty".to_string()));
+        }
+        if subject.chars().count() > MAX_SUBJECT_LENGTH {
+            return Err(WhatsAppError::GroupError(format!("Group subject is longer than {} characters", MAX_SUBJECT_LENGTH)));
+        }
+        if let Some(jid) = request.participants.iter().find(|jid| !jid.is_user()) {
+            return Err(WhatsAppError::GroupError(format!("{} can't be a group participant", jid)));
+        }
+        if let Some(parent) = request.linked_parent.as_ref().filter(|parent| !parent.is_group()) {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a community", parent)));
+        }
+
+        let participants: Vec<String> = request.participants.iter()
+            .map(|jid| JID::new(&jid.user, &jid.server, None).to_string())
+            .collect();
+        let mut create = json!({ "subject": subject, "participants": participants, "key": request.create_key });
+        if let Some(parent) = &request.linked_parent {
+            create["linked_parent"] = json!(parent.to_string());
+        }
+        let response = self.query("group", json!({ "create": create })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to create group {}: {}", subject, error)));
+        }
+
+        let info = GroupInfo::from_json(&response["group"])?;
+        {
+            let mut groups = self.groups.lock().unwrap();
+            groups.insert(info.jid.clone(), info.clone());
+            // The community lists one more group now
+            if let Some(parent) = &request.linked_parent {
+                groups.remove(parent);
+            }
+        }
+
+        if let Some(mut message) = request.first_message {
+            message.chat_jid = info.jid.clone();
+            self.send_message(&message).await.map_err(|e| {
+                WhatsAppError::GroupError(format!("Created {} but failed to send its first message: {}", info.jid, e))
+            })?;
+        }
+
+        Ok(info)
+    }
+
+    /// Get the metadata of a group we're in, from the cache or by asking the server
+    pub async fn get_group_info(&self, group_jid: &JID) -> WhatsAppResult<GroupInfo> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        if let Some(info) = self.groups.lock().unwrap().get(group_jid) {
+            return Ok(info.clone());
+        }
+
+        let info = self.fetch_group_info(group_jid).await?;
+        self.groups.lock().unwrap().insert(group_jid.clone(), info.clone());
+        Ok(info)
+    }
+
+    /// Ask the server for the metadata of a group
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","metadata":{}}]`
+    /// and are answered with the metadata under `"group"`.
+    async fn fetch_group_info(&self, group_jid: &JID) -> WhatsAppResult<GroupInfo> {
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "metadata": {},
+        })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to get the metadata of {}: {}", group_jid, error)));
+        }
+
+        GroupInfo::from_json(&response["group"])
+    }
+
+    /// Get who used to be in a group, and remember them in its cached metadata
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","past_participants":{}}]`
+    /// and are answered with the former participants under `"past_participants"`.
+    pub async fn get_past_participants(&self, group_jid: &JID) -> WhatsAppResult<Vec<PastParticipant>> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "past_participants": {},
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(not_admin(group_jid));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to get the past participants of {}: {}", group_jid, error)));
+        }
+
+        let past_participants = response["past_participants"].as_array().into_iter().flatten()
+            .map(PastParticipant::from_json)
+            .collect::<WhatsAppResult<Vec<_>>>()?;
+        if let Some(info) = self.groups.lock().unwrap().get_mut(group_jid) {
+            info.past_participants = past_participants.clone();
+        }
+        Ok(past_participants)
+    }
+
+    /// Add people to a group we administer and remove others, returning how it went for each
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","participants":
+    /// {"add":["..."],"remove":["..."]}}]` and are answered with a participant
+    /// for each JID under `"participants"`, with an error code for those that
+    /// couldn't be changed. People whose privacy settings forbid adding them come
+    /// with an invite to send them instead.
+    pub async fn update_group_participants(
+        &self,
+        group_jid: &JID,
+        adds: &[JID],
+        removes: &[JID],
+    ) -> WhatsAppResult<Vec<GroupParticipant>> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        if let Some(jid) = adds.iter().chain(removes).find(|jid| !jid.is_user()) {
+            return Err(WhatsAppError::GroupError(format!("{} can't be a group participant", jid)));
+        }
+        if adds.is_empty() && removes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let users = |jids: &[JID]| -> Vec<JID> { jids.iter().map(|jid| JID::new(&jid.user, &jid.server, None)).collect() };
+        let (adds, removes) = (users(adds), users(removes));
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "participants": {
+                "add": adds.iter().map(JID::to_string).collect::<Vec<_>>(),
+                "remove": removes.iter().map(JID::to_string).collect::<Vec<_>>(),
+            },
+        })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to change the participants of {}: {}", group_jid, error)));
+        }
+
+        let results = response["participants"].as_array().into_iter().flatten()
+            .map(GroupParticipant::from_json)
+            .collect::<WhatsAppResult<Vec<_>>>()?;
+        let changed = |jids: &[JID]| -> Vec<JID> {
+            results.iter()
+                .filter(|result| result.error.is_none() && jids.contains(&result.jid))
+                .map(|result| result.jid.clone())
+                .collect()
+        };
+        let (added, removed) = (changed(&adds), changed(&removes));
+
+        if let Some(info) = self.groups.lock().unwrap().get_mut(group_jid) {
+            info.participants.retain(|participant| !removed.contains(&participant.jid));
+            for jid in &added {
+                if info.participant(jid).is_none() {
+                    info.participants.push(GroupParticipant {
+                        jid: jid.clone(),
+                        role: ParticipantRole::Member,
+                        error: None,
+                        add_request: None,
+                    });
+                }
+            }
+        }
+
+        if !removed.is_empty() {
+            self.rotate_sender_key(group_jid)?;
+        }
+
+        let actor = self.paired_jid().map(|jid| JID::new(&jid.user, &jid.server, None));
+        for (action, participants) in [(ParticipantAction::Add, added), (ParticipantAction::Remove, removed)] {
+            if !participants.is_empty() {
+                self.dispatch_event(Event::GroupParticipantsChanged {
+                    group: group_jid.clone(),
+                    action,
+                    actor: actor.clone(),
+                    participants,
+                });
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Make participants of a group we administer admins too
+    pub async fn promote(&self, group_jid: &JID, jids: &[JID]) -> WhatsAppResult<()> {
+        self.set_admins(group_jid, jids, true).await
+    }
+
+    /// Take admin rights away from participants of a group we administer
+    pub async fn demote(&self, group_jid: &JID, jids: &[JID]) -> WhatsAppResult<()> {
+        self.set_admins(group_jid, jids, false).await
+    }
+
+    /// Promote or demote participants of a group
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","promote":["..."]}]`,
+    /// or `"demote"`, and are answered with a participant for each JID under
+    /// `"participants"`, with an error code for those that couldn't be changed.
+    /// Those that could are changed even when others fail.
+    async fn set_admins(&self, group_jid: &JID, jids: &[JID], admin: bool) -> WhatsAppResult<()> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        let Some(own_jid) = self.paired_jid().map(|jid| JID::new(&jid.user, &jid.server, None)) else {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        };
+        if jids.is_empty() {
+            return Ok(());
+        }
+
+        // Fail early when the cached metadata already tells it won't work
+        if let Some(info) = self.groups.lock().unwrap().get(group_jid) {
+            if !info.participant(&own_jid).is_some_and(GroupParticipant::is_admin) {
+                return Err(not_admin(group_jid));
+            }
+            if let Some(jid) = jids.iter().find(|jid| info.participant(jid).is_none()) {
+                return Err(not_participant(group_jid, jid));
+            }
+        }
+
+        let jids: Vec<JID> = jids.iter().map(|jid| JID::new(&jid.user, &jid.server, None)).collect();
+        let kind = if admin { "promote" } else { "demote" };
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            kind: jids.iter().map(JID::to_string).collect::<Vec<_>>(),
+        })).await?;
+        match response["error"].as_u64() {
+            Some(401 | 403) => return Err(not_admin(group_jid)),
+            Some(404) => return Err(WhatsAppError::GroupError(format!("{} doesn't exist or we aren't in it", group_jid))),
+            _ => {}
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to {} participants of {}: {}", kind, group_jid, error)));
+        }
+
+        let results = response["participants"].as_array().into_iter().flatten()
+            .map(GroupParticipant::from_json)
+            .collect::<WhatsAppResult<Vec<_>>>()?;
+        let failed = results.iter().find(|result| result.error.is_some());
+        let changed: Vec<JID> = jids.iter()
+            .filter(|jid| !results.iter().any(|result| &result.jid == *jid && result.error.is_some()))
+            .cloned()
+            .collect();
+
+        if !changed.is_empty() {
+            if let Some(info) = self.groups.lock().unwrap().get_mut(group_jid) {
+                for participant in &mut info.participants {
+                    // The creator stays a super admin
+                    if changed.contains(&participant.jid) && participant.role != ParticipantRole::SuperAdmin {
+                        participant.role = if admin { ParticipantRole::Admin } else { ParticipantRole::Member };
+                    }
+                }
+            }
+            if changed.contains(&own_jid) {
+                self.dispatch_event(Event::GroupAdminStatusChanged {
+                    group: group_jid.clone(),
+                    admin,
+                    actor: Some(own_jid.clone()),
+                });
+            }
+            self.dispatch_event(Event::GroupParticipantsChanged {
+                group: group_jid.clone(),
+                action: if admin { ParticipantAction::Promote } else { ParticipantAction::Demote },
+                actor: Some(own_jid),
+                participants: changed,
+            });
+        }
+
+        match failed {
+            Some(GroupParticipant { jid, error: Some(404), .. }) => Err(not_participant(group_jid, jid)),
+            Some(GroupParticipant { jid, error: Some(code), .. }) => {
+                Err(WhatsAppError::GroupError(format!("Failed to {} {} in {}: server error {}", kind, jid, group_jid, code)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Let only admins send messages to a group, or everyone again
+    pub async fn set_group_announce(&self, group_jid: &JID, announce: bool) -> WhatsAppResult<()> {
+        self.set_group_setting(group_jid, GroupSetting::Announce, announce).await
+    }
+
+    /// Let only admins change the subject, description and photo of a group, or everyone again
+    pub async fn set_group_locked(&self, group_jid: &JID, locked: bool) -> WhatsAppResult<()> {
+        self.set_group_setting(group_jid, GroupSetting::Locked, locked).await
+    }
+
+    /// Make admins approve who joins a group, or let anyone with an invite in again
+    pub async fn set_group_join_approval(&self, group_jid: &JID, join_approval: bool) -> WhatsAppResult<()> {
+        self.set_group_setting(group_jid, GroupSetting::JoinApproval, join_approval).await
+    }
+
+    /// Turn a setting of a group we administer on or off
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","settings":{"announce":true}}]`.
+    async fn set_group_setting(&self, group_jid: &JID, setting: GroupSetting, enabled: bool) -> WhatsAppResult<()> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "settings": { setting.as_str(): enabled },
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(not_admin(group_jid));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to change {} of {}: {}", setting.as_str(), group_jid, error)));
+        }
+
+        if let Some(info) = self.groups.lock().unwrap().get_mut(group_jid) {
+            info.set_setting(setting, enabled);
+        }
+        self.dispatch_event(Event::GroupSettingsChanged {
+            group: group_jid.clone(),
+            setting,
+            enabled,
+            actor: self.paired_jid().map(|jid| JID::new(&jid.user, &jid.server, None)),
+        });
+        Ok(())
+    }
+
+    /// Set how long until new messages in a group disappear, `DISAPPEARING_TIMER_OFF` keeping them
+    ///
+    /// Groups keep the timer on the server, which tells the participants.
+    /// Requests look like `["group",{"id":"...","to":"<group>","ephemeral":86400}]`.
+    pub async fn set_group_ephemeral(&self, group_jid: &JID, duration: Duration) -> WhatsAppResult<()> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        if !DISAPPEARING_TIMERS.contains(&duration) {
+            return Err(WhatsAppError::ProtocolError(format!("Unsupported disappearing message timer {:?}", duration)));
+        }
+        let expiration = duration.as_secs() as u32;
+
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "ephemeral": expiration,
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(not_admin(group_jid));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to set the disappearing timer of {}: {}", group_jid, error)));
+        }
+
+        if let Some(info) = self.groups.lock().unwrap().get_mut(group_jid) {
+            info.ephemeral_expiration = expiration;
+        }
+        self.message_store.update_chat(group_jid, |info| info.ephemeral_expiration = expiration)
+    }
+
+    /// Leave a group, forgetting its metadata and sender keys
+    ///
+    /// Requests look like `["group",{"id":"...","leave":{"jids":["<group>"]}}]`.
+    pub async fn leave_group(&self, group_jid: &JID) -> WhatsAppResult<()> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        let Some(own_jid) = self.paired_jid() else {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        };
+
+        let response = self.query("group", json!({
+            "leave": { "jids": [group_jid.to_string()] },
+        })).await?;
+        if let Some(404) = response["error"].as_u64() {
+            return Err(WhatsAppError::GroupError(format!("{} doesn't exist or we aren't in it", group_jid)));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to leave {}: {}", group_jid, error)));
+        }
+
+        self.groups.lock().unwrap().remove(group_jid);
+        self.sender_key_devices.lock().unwrap().remove(group_jid);
+        self.group_cipher.lock().unwrap().forget_group(group_jid, &own_jid)?;
+        self.dispatch_event(Event::GroupLeft(group_jid.clone()));
+        Ok(())
+    }
+
+    /// Forget the cached metadata of a group that changed and tell the application how
+    ///
+    /// Notifications look like `["notification",{"type":"w:gp2","from":"<group>",
+    /// "participant":"<who changed it>","action":"subject","subject":"..."}]`.
+    /// Participant changes list who they apply to, e.g. `"action":"add",
+    /// "participants":["..."]`, and settings say how they were set, e.g.
+    /// `"action":"announce","enabled":true`. Join requests name who asked, e.g.
+    /// `"action":"join_request","requester":"..."`, and timers say how long they
+    /// are, e.g. `"action":"ephemeral","expiration":86400`.
+    pub(super) fn handle_group_notification(&self, payload: &serde_json::Value) {
+        let Some(group) = payload["from"].as_str().and_then(|from| from.parse::<JID>().ok()) else {
+            warn!("Group notification without a group: {}", payload);
+            return;
+        };
+        let action = payload["action"].as_str().unwrap_or_default();
+        let actor: Option<JID> = payload["participant"].as_str().and_then(|actor| actor.parse().ok());
+        debug!("Group {} changed: {}", group, action);
+
+        let cached = self.groups.lock().unwrap().remove(&group);
+
+        if let Some(participant_action) = ParticipantAction::from_name(action) {
+            let participants: Vec<JID> = payload["participants"].as_array().into_iter().flatten()
+                .filter_map(|jid| jid.as_str()?.parse().ok())
+                .collect();
+
+            let own_jid = self.paired_jid();
+            let us = own_jid.is_some_and(|own_jid| {
+                participants.iter().any(|jid| jid.user == own_jid.user && jid.server == own_jid.server)
+            });
+            if us && matches!(participant_action, ParticipantAction::Promote | ParticipantAction::Demote) {
+                self.dispatch_event(Event::GroupAdminStatusChanged {
+                    group: group.clone(),
+                    admin: participant_action == ParticipantAction::Promote,
+                    actor: actor.clone(),
+                });
+            }
+
+            // Those who left mustn't read what is sent after
+            if participant_action == ParticipantAction::Remove
+                && let Err(e) = self.rotate_sender_key(&group)
+            {
+                warn!("Failed to rotate sender key in {}: {}", group, e);
+            }
+
+            self.dispatch_event(Event::GroupParticipantsChanged { group, action: participant_action, actor, participants });
+        } else if let Some(setting) = GroupSetting::from_name(action) {
+            self.dispatch_event(Event::GroupSettingsChanged {
+                group,
+                setting,
+                enabled: payload["enabled"].as_bool().unwrap_or_default(),
+                actor,
+            });
+        } else if action == "join_request"
+            && let Some(requester) = payload["requester"].as_str().and_then(|requester| requester.parse().ok())
+        {
+            self.dispatch_event(Event::GroupJoinRequest { group, requester });
+        } else if action == "ephemeral" {
+            // Only the timer changed, so the rest of the metadata still holds
+            let expiration = payload["expiration"].as_u64().unwrap_or_default() as u32;
+            if let Some(mut info) = cached {
+                info.ephemeral_expiration = expiration;
+                self.groups.lock().unwrap().insert(group.clone(), info);
+            }
+            match actor {
+                Some(sender) => self.handle_ephemeral_setting(group, sender, expiration),
+                None => warn!("Disappearing timer of {} changed by no one: {}", group, payload),
+            }
+        } else if action == "subject" {
+            self.dispatch_event(Event::GroupSubjectChanged {
+                group,
+                subject: payload["subject"].as_str().unwrap_or_default().to_string(),
+                actor,
+            });
+        } else {
+            debug!("Ignoring {} notification of {}", action, group);
+        }
+    }
+}
+
+pub(super) fn not_admin(group_jid: &JID) -> WhatsAppError {
+    WhatsAppError::GroupError(format!("We aren't an admin of {}", group_jid))
+}
+
+fn not_participant(group_jid: &JID, jid: &JID) -> WhatsAppError {
+    WhatsAppError::GroupError(format!("{} isn't a participant of {}", jid, group_jid))
+}
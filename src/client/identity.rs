@@ -0,0 +1,47 @@
+use log::warn;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    signal::fingerprint::SafetyNumber,
+};
+
+impl Client {
+    /// Compute the safety number to verify end-to-end encryption with a contact
+    ///
+    /// The identity key of the contact's primary device must be known, so a
+    /// session with it must have been set up.
+    pub fn get_safety_number(&self, jid: &JID) -> WhatsAppResult<SafetyNumber> {
+        let own_id = self.auth_state.lock().unwrap()
+            .as_ref()
+            .and_then(|auth| auth.jid.as_ref().map(|jid| jid.user.clone()))
+            .ok_or_else(|| WhatsAppError::AuthError("Not logged in".to_string()))?;
+
+        let primary = JID::new(&jid.user, &jid.server, None);
+        let remote_key = self.identities.get_identity(&primary)?
+            .ok_or_else(|| WhatsAppError::CryptoError(format!("Identity key of {} is not known", primary)))?;
+        let local_key = self.signal.lock().unwrap().identity().identity.public.clone();
+
+        Ok(SafetyNumber::new(&own_id, &local_key, &jid.user, &remote_key))
+    }
+
+    /// Make sure the identity key may be used with the device before a session relies on it
+    pub(super) fn check_identity(&self, device: &JID, identity_key: &[u8]) -> WhatsAppResult<()> {
+        if self.identities.is_trusted_identity(device, identity_key)? {
+            Ok(())
+        } else {
+            Err(WhatsAppError::CryptoError(format!("Untrusted identity key for {}", device)))
+        }
+    }
+
+    /// Remember the identity key of a device once a session uses it, telling handlers if it changed
+    pub(super) fn save_identity(&self, device: &JID, identity_key: &[u8]) -> WhatsAppResult<()> {
+        if self.identities.save_identity(device, identity_key)? {
+            warn!("Identity key of {} changed", device);
+            self.dispatch_event(Event::IdentityChanged(device.clone()));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::json;
+
+use super::{Client, send::ack_error};
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+    group::{self, GroupInfo, INVITE_LINK_PREFIX},
+    message::{GroupInvite, Message},
+};
+
+impl Client {
+    /// Invite someone to join a group we administer with one of its invite codes
+    pub async fn send_group_invite(
+        &self,
+        to: &JID,
+        group_jid: &JID,
+        invite_code: &str,
+        expiration: SystemTime,
+        group_name: &str,
+    ) -> WhatsAppResult<String> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+
+        let expiration = expiration.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let invite = Message::new_group_invite(to.clone(), group_jid.clone(), invite_code, expiration, group_name);
+        self.send_message(&invite).await
+    }
+
+    /// Join a group with an invite received from one of its admins, returning the group
+    pub async fn accept_group_invite(&self, invite: &GroupInvite, inviter: &JID) -> WhatsAppResult<JID> {
+        if invite.is_expired() {
+            return Err(WhatsAppError::ProtocolError(format!("Invite to {} has expired", invite.group_name)));
+        }
+
+        let response = self.query("group", json!({
+            "to": invite.group_jid.to_string(),
+            "accept": {
+                "code": invite.invite_code,
+                "expiration": invite.expiration,
+                "admin": inviter.to_string(),
+            },
+        })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::ProtocolError(format!("Failed to join {}: {}", invite.group_jid, error)));
+        }
+
+        match response["jid"].as_str() {
+            Some(jid) => jid.parse(),
+            None => Ok(invite.group_jid.clone()),
+        }
+    }
+
+    /// Get the invite link of a group we administer, revoking the old one for a new one when resetting
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","invite":{"reset":false}}]`
+    /// and are answered with the invite code under `"code"`.
+    pub async fn get_invite_link(&self, group_jid: &JID, reset: bool) -> WhatsAppResult<String> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "invite": { "reset": reset },
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(WhatsAppError::GroupError(format!("We aren't an admin of {}", group_jid)));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to get the invite link of {}: {}", group_jid, error)));
+        }
+
+        match response["code"].as_str() {
+            Some(code) => Ok(format!("{}{}", INVITE_LINK_PREFIX, code)),
+            None => Err(WhatsAppError::ParsingError(format!("No invite code for {}: {}", group_jid, response))),
+        }
+    }
+
+    /// Join a group with an invite link or its code, returning the group
+    ///
+    /// Requests look like `["group",{"id":"...","join":{"code":"..."}}]` and are
+    /// answered with the group under `"jid"`.
+    pub async fn join_group_with_link(&self, link: &str) -> WhatsAppResult<JID> {
+        let code = group::invite_code(link);
+        let response = self.query("group", json!({ "join": { "code": code } })).await?;
+        if let Some(error) = invite_error(&response, code) {
+            return Err(error);
+        }
+
+        response["jid"].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("No group for invite {}: {}", code, response)))?
+            .parse()
+    }
+
+    /// Preview the group an invite link or code leads to without joining it
+    ///
+    /// Requests look like `["group",{"id":"...","invite_info":{"code":"..."}}]`
+    /// and are answered with the metadata under `"group"`, which may list only
+    /// some of the participants.
+    pub async fn get_invite_info(&self, link: &str) -> WhatsAppResult<GroupInfo> {
+        let code = group::invite_code(link);
+        let response = self.query("group", json!({ "invite_info": { "code": code } })).await?;
+        if let Some(error) = invite_error(&response, code) {
+            return Err(error);
+        }
+
+        GroupInfo::from_json(&response["group"])
+    }
+}
+
+/// Explain why the server turned down an invite code
+fn invite_error(response: &serde_json::Value, code: &str) -> Option<WhatsAppError> {
+    let error = match response["error"].as_u64() {
+        Some(404 | 406) => format!("Invite {} isn't valid", code),
+        Some(410) => format!("Invite {} was revoked", code),
+        Some(409) => "We're already in the group".to_string(),
+        _ => format!("Failed to use invite {}: {}", code, ack_error(response)?),
+    };
+    Some(WhatsAppError::GroupError(error))
+}
@@ -0,0 +1,82 @@
+use serde_json::json;
+
+use super::{Client, groups::not_admin, send::ack_error};
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+    group::{GroupParticipant, JoinRequest},
+};
+
+impl Client {
+    /// Get who is waiting to join a group we administer
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","join_requests":{}}]`
+    /// and are answered with the requests under `"requests"`.
+    pub async fn get_join_requests(&self, group_jid: &JID) -> WhatsAppResult<Vec<JoinRequest>> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "join_requests": {},
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(not_admin(group_jid));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to get the join requests of {}: {}", group_jid, error)));
+        }
+
+        response["requests"].as_array().into_iter().flatten()
+            .map(JoinRequest::from_json)
+            .collect()
+    }
+
+    /// Let people who asked to join a group in, returning how it went for each
+    pub async fn approve_join_requests(&self, group_jid: &JID, jids: &[JID]) -> WhatsAppResult<Vec<GroupParticipant>> {
+        self.answer_join_requests(group_jid, jids, true).await
+    }
+
+    /// Turn down people who asked to join a group, returning how it went for each
+    pub async fn reject_join_requests(&self, group_jid: &JID, jids: &[JID]) -> WhatsAppResult<Vec<GroupParticipant>> {
+        self.answer_join_requests(group_jid, jids, false).await
+    }
+
+    /// Approve or reject join requests
+    ///
+    /// Requests look like `["group",{"id":"...","to":"<group>","join_requests":
+    /// {"approve":["..."]}}]`, or `"reject"`, and are answered with a participant
+    /// for each JID under `"participants"`, with an error code for requests that
+    /// couldn't be answered, e.g. because they were withdrawn.
+    async fn answer_join_requests(&self, group_jid: &JID, jids: &[JID], approve: bool) -> WhatsAppResult<Vec<GroupParticipant>> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        if jids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let kind = if approve { "approve" } else { "reject" };
+        let jids: Vec<String> = jids.iter().map(|jid| JID::new(&jid.user, &jid.server, None).to_string()).collect();
+        let response = self.query("group", json!({
+            "to": group_jid.to_string(),
+            "join_requests": { kind: jids },
+        })).await?;
+        if let Some(401 | 403) = response["error"].as_u64() {
+            return Err(not_admin(group_jid));
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::GroupError(format!("Failed to {} join requests of {}: {}", kind, group_jid, error)));
+        }
+
+        // Approved people are members now
+        if approve {
+            self.groups.lock().unwrap().remove(group_jid);
+        }
+
+        response["participants"].as_array().into_iter().flatten()
+            .map(GroupParticipant::from_json)
+            .collect()
+    }
+}
@@ -0,0 +1,479 @@
+use std::future::Future;
+use std::io::{Cursor, SeekFrom};
+use std::time::{Duration, Instant};
+use futures::{SinkExt, StreamExt, channel::mpsc};
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{Client, MediaProgress, UploadedMedia};
+use crate::{
+    JID,
+    crypto::{Crypto, media::{self, MediaDecryptor, MediaDigest, MediaEncryptor, MediaKeys, MediaType}},
+    error::{WhatsAppError, WhatsAppResult},
+    message::{Message, MessageType, validation},
+    proxy::ProxyConfig,
+};
+
+/// Hosts and credentials for talking to the media servers
+///
+/// Responses look like `{"media_conn":{"auth":"...","ttl":300,"hosts":[{"hostname":"mmg.whatsapp.net"}]}}`.
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct MediaConn {
+    pub auth: String,
+    /// Seconds the auth token is valid for
+    pub ttl: u64,
+    pub hosts: Vec<MediaHost>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct MediaHost {
+    pub hostname: String,
+}
+
+/// Size of the chunks media is read, encrypted and sent in
+const MEDIA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of encrypted chunks waiting for the upload to send them
+const UPLOAD_QUEUE_CHUNKS: usize = 4;
+
+/// How long before its auth token expires a media connection is replaced
+const MEDIA_CONN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// Media connection shared by uploads and downloads, fetched again once it expires
+pub(super) struct MediaConnManager {
+    /// Held while fetching, so concurrent transfers wait for one request instead of each sending theirs
+    cached: tokio::sync::Mutex<Option<(MediaConn, Instant)>>,
+}
+
+impl MediaConnManager {
+    pub fn new() -> Self {
+        Self { cached: tokio::sync::Mutex::new(None) }
+    }
+
+    /// Get the cached media connection, fetching a new one when there is none or it expired
+    pub async fn get<F, Fut>(&self, fetch: F) -> WhatsAppResult<MediaConn>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = WhatsAppResult<MediaConn>>,
+    {
+        let mut cached = self.cached.lock().await;
+        if let Some((conn, expires_at)) = &*cached
+            && Instant::now() < *expires_at
+        {
+            return Ok(conn.clone());
+        }
+
+        let conn = fetch().await?;
+        let ttl = Duration::from_secs(conn.ttl).saturating_sub(MEDIA_CONN_REFRESH_MARGIN);
+        debug!("Fetched media connection with {} hosts, valid for {:?}", conn.hosts.len(), ttl);
+        *cached = Some((conn.clone(), Instant::now() + ttl));
+        Ok(conn)
+    }
+
+    /// Forget the cached media connection, e.g. after a media server rejected its token
+    pub async fn invalidate(&self) {
+        *self.cached.lock().await = None;
+    }
+}
+
+/// Response of a media server to an upload
+#[derive(Deserialize)]
+struct UploadResponse {
+    url: String,
+    direct_path: String,
+}
+
+impl Client {
+    /// Encrypt media and upload it to the media servers
+    pub async fn upload_media(&self, data: &[u8], media_type: MediaType) -> WhatsAppResult<UploadedMedia> {
+        self.upload_media_stream(&mut Cursor::new(data), media_type, |_| {}).await
+    }
+
+    /// Encrypt media read from a file or other seekable source and upload it chunk by chunk
+    ///
+    /// The source is read twice: once to hash the encrypted file, which names
+    /// the upload, and once more for every host the upload is sent to.
+    pub async fn upload_media_stream<R, F>(
+        &self,
+        source: &mut R,
+        media_type: MediaType,
+        mut on_progress: F,
+    ) -> WhatsAppResult<UploadedMedia>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+        F: FnMut(&MediaProgress) + Send,
+    {
+        let mut encryptor = MediaEncryptor::new(media_type)?;
+        let mut buffer = vec![0u8; MEDIA_CHUNK_SIZE];
+        loop {
+            let read = source.read(&mut buffer).await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+            if read == 0 {
+                break;
+            }
+            encryptor.update(&buffer[..read])?;
+        }
+        let (_, digest) = encryptor.finalize()?;
+        let total = media::encrypted_length(digest.file_length);
+
+        // The hash of the upload names it, so retrying on another host can't duplicate it
+        let token = Crypto::base64_url_encode(&digest.file_enc_sha256);
+        let mut last_error = WhatsAppError::MediaError("No media hosts to upload to".to_string());
+        // A rejected token is fetched again once, in case it was revoked before it expired
+        for _ in 0..2 {
+            let conn = self.media_conn().await?;
+            for host in &conn.hosts {
+                let url = format!(
+                    "https://{}/mms/{}/{}?auth={}&token={}",
+                    host.hostname, upload_path(media_type), token, urlencode(&conn.auth), token,
+                );
+                debug!("Uploading {} bytes of {:?} to {}", total, media_type, host.hostname);
+
+                source.seek(SeekFrom::Start(0)).await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+                let (sender, receiver) = mpsc::channel(UPLOAD_QUEUE_CHUNKS);
+                let (encrypted, response) = tokio::join!(
+                    encrypt_to(source, media_type, &digest, &mut on_progress, sender),
+                    self.post_media(&url, total, reqwest::Body::wrap_stream(receiver)),
+                );
+
+                match encrypted.and(response) {
+                    Ok(response) => {
+                        return Ok(UploadedMedia {
+                            url: response.url,
+                            direct_path: response.direct_path,
+                            media_key: digest.media_key,
+                            file_sha256: digest.file_sha256,
+                            file_enc_sha256: digest.file_enc_sha256,
+                            file_length: digest.file_length,
+                        });
+                    }
+                    Err(e @ WhatsAppError::AuthError(_)) => {
+                        self.media_conn.invalidate().await;
+                        last_error = e;
+                        break;
+                    }
+                    // The source can't be read, so other hosts won't do better
+                    Err(e @ WhatsAppError::IOError(_)) => return Err(e),
+                    Err(e) => {
+                        warn!("Failed to upload media to {}: {}", host.hostname, e);
+                        last_error = e;
+                    }
+                }
+            }
+            if !matches!(last_error, WhatsAppError::AuthError(_)) {
+                break;
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Download and decrypt the media of a message
+    pub async fn download_media(&self, message: &Message) -> WhatsAppResult<Vec<u8>> {
+        let mut data = Vec::new();
+        self.download_media_stream(message, &mut data, |_| {}).await?;
+        Ok(data)
+    }
+
+    /// Download the media of a message chunk by chunk, writing it decrypted to a file or other writer
+    ///
+    /// Plaintext is written before the MAC at the end of the file can be
+    /// checked, so whatever was written must be discarded when this fails.
+    /// Returns the number of bytes written.
+    pub async fn download_media_stream<W, F>(
+        &self,
+        message: &Message,
+        writer: &mut W,
+        mut on_progress: F,
+    ) -> WhatsAppResult<u64>
+    where
+        W: AsyncWrite + Unpin + Send,
+        F: FnMut(&MediaProgress) + Send,
+    {
+        let media_type = media_type(&message.message_type)?;
+        let info = message.media.as_ref()
+            .ok_or_else(|| WhatsAppError::MediaError(format!("Message {} has no media", message.id)))?;
+        let media_key = info.media_key.clone()
+            .ok_or_else(|| WhatsAppError::MediaError(format!("Message {} has no media key", message.id)))?;
+        let keys = MediaKeys {
+            media_key: media_key.into(),
+            file_sha256: Some(info.sha256.clone()).filter(|sha256| !sha256.is_empty()),
+            file_enc_sha256: info.file_enc_sha256.clone(),
+        };
+
+        // The direct path is valid on any media host, the URL only on the one it names
+        let mut urls = Vec::new();
+        if let Some(direct_path) = &info.direct_path {
+            let conn = self.media_conn().await?;
+            urls.extend(conn.hosts.iter().map(|host| format!("https://{}{}", host.hostname, direct_path)));
+        }
+        urls.extend(info.url.clone());
+
+        // Hosts are only tried until one answers, as nothing is written before that
+        let mut last_error = WhatsAppError::MediaError(format!("Message {} has no media URL", message.id));
+        let mut response = None;
+        for url in urls {
+            match self.get_media(&url).await {
+                Ok(found) => {
+                    response = Some(found);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to download media from {}: {}", url, e);
+                    last_error = e;
+                }
+            }
+        }
+        let Some(response) = response else {
+            return Err(last_error);
+        };
+
+        let mut progress = MediaProgress {
+            transferred: 0,
+            total: response.content_length().unwrap_or(media::encrypted_length(info.file_length)),
+        };
+        let mut decryptor = MediaDecryptor::new(media_type, &keys)?;
+        let mut written = 0;
+        let mut chunks = response.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|e| WhatsAppError::MediaError(e.to_string()))?;
+            let plaintext = decryptor.update(&chunk)?;
+            writer.write_all(&plaintext).await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+            written += plaintext.len() as u64;
+
+            progress.transferred += chunk.len() as u64;
+            on_progress(&progress);
+        }
+
+        let plaintext = decryptor.finalize()?;
+        writer.write_all(&plaintext).await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+        writer.flush().await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+        Ok(written + plaintext.len() as u64)
+    }
+
+    /// Check the content of a media message against WhatsApp's limits, upload it and send it, returning the message id
+    pub async fn send_media_message(&self, message: &Message, data: &[u8]) -> WhatsAppResult<String> {
+        let media_type = media_type(&message.message_type)?;
+        if message.media.is_none() {
+            return Err(WhatsAppError::MediaError(format!("Message {} has no media info", message.id)));
+        }
+        // Rejected media is cheaper to catch before it is encrypted and uploaded
+        validation::validate_media(message, data)?;
+
+        let uploaded = self.upload_media(data, media_type).await?;
+        let mut message = message.clone();
+        if let Some(media) = &mut message.media {
+            media.url = Some(uploaded.url);
+            media.direct_path = Some(uploaded.direct_path);
+            media.media_key = Some(uploaded.media_key.to_vec());
+            media.sha256 = uploaded.file_sha256;
+            media.file_enc_sha256 = Some(uploaded.file_enc_sha256);
+            media.file_length = uploaded.file_length;
+        }
+
+        self.send_message(&message).await
+    }
+
+    /// Fetch a remote file and send it as the kind of media it turns out to be, returning the message id
+    ///
+    /// JPEG and PNG images, MP4 videos, audio and 512x512 WebP stickers within
+    /// WhatsApp's limits are sent as such, anything else as a document. Audio
+    /// and stickers are shown without the caption.
+    pub async fn send_media_from_url(&self, to: &JID, url: &str, caption: Option<&str>) -> WhatsAppResult<String> {
+        let fetched = self.media_fetcher.fetch(url).await?;
+        let data = fetched.data;
+
+        // The bytes say more than the server, whose claim is only used when they say nothing
+        let probe = validation::probe_media(&data);
+        let mime_type = match probe.mime_type {
+            "application/octet-stream" => fetched.content_type.as_deref().unwrap_or(probe.mime_type),
+            mime_type => mime_type,
+        };
+        debug!("Fetched {} bytes of {} from {}", data.len(), mime_type, url);
+
+        let chat = to.clone();
+        let media = match mime_type {
+            "image/webp" => Some(Message::new_sticker(chat.clone(), &data)),
+            mime_type if mime_type.starts_with("image/") => Some(Message::new_image(chat.clone(), mime_type, &data, caption)),
+            mime_type if mime_type.starts_with("video/") => Some(Message::new_video(chat.clone(), mime_type, &data, caption)),
+            mime_type if mime_type.starts_with("audio/") => Some(Message::new_audio(chat.clone(), mime_type, &data)),
+            _ => None,
+        };
+        let message = media
+            .filter(|message| validation::validate_media(message, &data).is_ok())
+            .unwrap_or_else(|| {
+                let file_name = fetched.file_name.as_deref().unwrap_or("file");
+                let mut document = Message::new_document(chat, file_name, &data, caption);
+                if let Some(media) = &mut document.media
+                    && media.mime_type == "application/octet-stream"
+                {
+                    media.mime_type = mime_type.to_string();
+                }
+                document
+            });
+
+        self.send_media_message(&message, &data).await
+    }
+
+    /// Get the hosts and auth token of the media servers, from the cache or by asking the server
+    async fn media_conn(&self) -> WhatsAppResult<MediaConn> {
+        self.media_conn.get(|| self.fetch_media_conn()).await
+    }
+
+    /// Ask the server for the hosts and auth token of the media servers
+    async fn fetch_media_conn(&self) -> WhatsAppResult<MediaConn> {
+        let response = self.query("query", json!({ "type": "media_conn" })).await?;
+        serde_json::from_value(response["media_conn"].clone())
+            .map_err(|e| WhatsAppError::DeserializationError(format!("Invalid media connection: {}", e)))
+    }
+
+    /// POST an encrypted file to a media server
+    async fn post_media(&self, url: &str, length: u64, body: reqwest::Body) -> WhatsAppResult<UploadResponse> {
+        let response = self.http.post(url)
+            .header(reqwest::header::ORIGIN, self.config.origin.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(reqwest::header::CONTENT_LENGTH, length)
+            .body(body)
+            .send().await
+            .map_err(|e| WhatsAppError::MediaError(e.to_string()))?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            return Err(WhatsAppError::AuthError(format!("Media server rejected the upload token: {}", status)));
+        }
+        if !status.is_success() {
+            return Err(WhatsAppError::MediaError(format!("Media server answered {}", status)));
+        }
+
+        response.json().await
+            .map_err(|e| WhatsAppError::DeserializationError(format!("Invalid upload response: {}", e)))
+    }
+
+    /// Start downloading an encrypted file from a media server
+    async fn get_media(&self, url: &str) -> WhatsAppResult<reqwest::Response> {
+        let response = self.http.get(url)
+            .header(reqwest::header::ORIGIN, self.config.origin.as_str())
+            .send().await
+            .map_err(|e| WhatsAppError::MediaError(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(WhatsAppError::MediaError(format!("Media server answered {}", status)));
+        }
+
+        Ok(response)
+    }
+}
+
+/// Encrypt the source again with the key it was hashed with, feeding the upload body
+///
+/// Failures are passed on to the body too, so the request is aborted instead
+/// of sending a truncated file.
+async fn encrypt_to<R, F>(
+    source: &mut R,
+    media_type: MediaType,
+    digest: &MediaDigest,
+    on_progress: &mut F,
+    mut sender: mpsc::Sender<std::io::Result<Vec<u8>>>,
+) -> WhatsAppResult<()>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(&MediaProgress),
+{
+    let mut progress = MediaProgress { transferred: 0, total: media::encrypted_length(digest.file_length) };
+    let result: WhatsAppResult<()> = async {
+        let mut encryptor = MediaEncryptor::with_key(media_type, digest.media_key.clone())?;
+        let mut buffer = vec![0u8; MEDIA_CHUNK_SIZE];
+        loop {
+            let read = source.read(&mut buffer).await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+            if read == 0 {
+                break;
+            }
+            let chunk = encryptor.update(&buffer[..read])?;
+            // The request ended early and reports why
+            if !send_chunk(&mut sender, chunk, &mut progress, on_progress).await {
+                return Ok(());
+            }
+        }
+
+        let (chunk, _) = encryptor.finalize()?;
+        send_chunk(&mut sender, chunk, &mut progress, on_progress).await;
+        Ok(())
+    }.await;
+
+    if let Err(e) = &result {
+        let _ = sender.send(Err(std::io::Error::other(e.to_string()))).await;
+    }
+    result
+}
+
+/// Hand a chunk to the upload body and report it, returning whether the body still takes chunks
+async fn send_chunk<F>(
+    sender: &mut mpsc::Sender<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    progress: &mut MediaProgress,
+    on_progress: &mut F,
+) -> bool
+where
+    F: FnMut(&MediaProgress),
+{
+    progress.transferred += chunk.len() as u64;
+    if sender.send(Ok(chunk)).await.is_err() {
+        return false;
+    }
+    on_progress(progress);
+    true
+}
+
+/// Kind of encrypted media a message of the given type carries
+fn media_type(message_type: &MessageType) -> WhatsAppResult<MediaType> {
+    match message_type {
+        MessageType::Image => Ok(MediaType::Image),
+        MessageType::Video => Ok(MediaType::Video),
+        MessageType::Audio => Ok(MediaType::Audio),
+        MessageType::Document => Ok(MediaType::Document),
+        MessageType::Sticker => Ok(MediaType::Sticker),
+        kind => Err(WhatsAppError::MediaError(format!("{:?} messages carry no media", kind))),
+    }
+}
+
+/// Build the HTTP client media is transferred with, through the configured proxy
+pub(super) fn http_client(proxy: Option<&ProxyConfig>) -> WhatsAppResult<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    match proxy {
+        Some(ProxyConfig::Http { address, auth }) => {
+            let mut proxy = reqwest::Proxy::all(format!("http://{}", address))
+                .map_err(|e| WhatsAppError::ConnectionError(format!("Invalid proxy {}: {}", address, e)))?;
+            if let Some(auth) = auth {
+                proxy = proxy.basic_auth(&auth.username, &auth.password);
+            }
+            builder = builder.proxy(proxy);
+        }
+        // Without SOCKS support in the HTTP client media goes out directly
+        Some(ProxyConfig::Socks5 { address, .. }) => {
+            warn!("Media transfers don't go through SOCKS5 proxy {}", address);
+        }
+        None => {}
+    }
+
+    builder.build().map_err(|e| WhatsAppError::ConnectionError(e.to_string()))
+}
+
+/// Path on the media servers files of a type are uploaded to
+fn upload_path(media_type: MediaType) -> &'static str {
+    match media_type {
+        MediaType::Image | MediaType::Sticker => "image",
+        MediaType::Video => "video",
+        MediaType::Audio => "audio",
+        MediaType::Document => "document",
+        MediaType::History => "md-msg-hist",
+        MediaType::AppState => "md-app-state",
+        MediaType::LinkThumbnail => "thumbnail-link",
+    }
+}
+
+/// Percent-encode a query parameter
+fn urlencode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
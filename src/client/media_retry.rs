@@ -0,0 +1,136 @@
+use log::{debug, warn};
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    Event,
+    crypto::{Crypto, SecretBytes, media},
+    error::{WhatsAppError, WhatsAppResult},
+    message::{MediaRetryResult, Message},
+    proto::{self, Encoder},
+    websocket::WebSocketMessage,
+};
+
+/// `MediaRetryNotification.ResultType` of media that was uploaded again
+const RETRY_RESULT_SUCCESS: u64 = 1;
+
+/// `MediaRetryNotification.ResultType` of media the phone no longer has
+const RETRY_RESULT_NOT_FOUND: u64 = 2;
+
+/// `MediaRetryNotification.ResultType` of a request the phone couldn't decrypt
+const RETRY_RESULT_DECRYPTION_ERROR: u64 = 3;
+
+impl Client {
+    /// Ask the sender's phone to upload the media of a message again, e.g. once its URL expired
+    ///
+    /// The answer arrives as `Event::MediaRetry`, and a stored copy of the
+    /// message gets the new direct path. Frames look like `["receipt",{"id":"...",
+    /// "to":"<our JID>","type":"server-error","encrypt":{"enc_p":"...","enc_iv":"..."},
+    /// "rmr":{"jid":"<chat>","from_me":false,"participant":"..."}}]`.
+    pub async fn request_media_retry(&self, message: &Message) -> WhatsAppResult<()> {
+        let media_key = message.media.as_ref()
+            .and_then(|media| media.media_key.clone())
+            .ok_or_else(|| WhatsAppError::MediaError(format!("Message {} has no media key", message.id)))?;
+        let Some(own_jid) = self.paired_jid() else {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        };
+        if !self.is_connected() {
+            return Err(WhatsAppError::ConnectionError("Not connected".to_string()));
+        }
+
+        // ServerErrorReceipt { stanzaId = 1 }
+        let request = Encoder::new().bytes(1, message.id.as_bytes()).finish();
+        let (ciphertext, nonce) = media::encrypt_media_retry(&media_key, &message.id, &request)?;
+
+        let mut rmr = json!({ "jid": message.chat_jid.to_string(), "from_me": message.from_me });
+        if message.chat_jid.is_group()
+            && let Some(sender) = &message.sender_jid
+        {
+            rmr["participant"] = json!(sender.to_string());
+        }
+        let receipt = json!({
+            "id": message.id,
+            "to": own_jid.to_string(),
+            "type": "server-error",
+            "encrypt": {
+                "enc_p": Crypto::base64_encode(&ciphertext),
+                "enc_iv": Crypto::base64_encode(&nonce),
+            },
+            "rmr": rmr,
+        });
+
+        self.pending_media_retries.lock().unwrap()
+            .insert(message.id.clone(), (message.key(), SecretBytes::new(media_key)));
+        self.transport.send(WebSocketMessage::Text(json!(["receipt", receipt]).to_string())).await
+    }
+
+    /// Tell the application how the sender's phone answered a media retry request
+    ///
+    /// Notifications look like `["notification",{"type":"mediaretry","id":"...",
+    /// "encrypt":{"enc_p":"...","enc_iv":"..."}}]`, or carry `"error":{"code":2}`
+    /// instead when the server couldn't reach the phone or it lost the media.
+    pub(super) fn handle_media_retry_notification(&self, payload: &serde_json::Value) {
+        let id = payload["id"].as_str().unwrap_or_default();
+        let Some((key, media_key)) = self.pending_media_retries.lock().unwrap().remove(id) else {
+            debug!("Ignoring media retry notification for unknown message {}", id);
+            return;
+        };
+
+        let (result, direct_path) = decode_media_retry(payload, id, &media_key).unwrap_or_else(|e| {
+            warn!("Invalid media retry notification for {}: {}", id, e);
+            (MediaRetryResult::GeneralError, None)
+        });
+
+        if let Some(direct_path) = &direct_path {
+            let stored = self.message_store.update_message(&key.chat_jid, &key.id, |message| {
+                if let Some(media) = &mut message.media {
+                    media.direct_path = Some(direct_path.clone());
+                }
+            });
+            if let Err(e) = stored {
+                warn!("Failed to store new direct path of {}: {}", id, e);
+            }
+        }
+
+        self.dispatch_event(Event::MediaRetry { key, result, direct_path });
+    }
+}
+
+/// Decrypt and decode the `MediaRetryNotification` in a notification
+fn decode_media_retry(
+    payload: &serde_json::Value,
+    message_id: &str,
+    media_key: &[u8],
+) -> WhatsAppResult<(MediaRetryResult, Option<String>)> {
+    if let Some(code) = payload["error"]["code"].as_u64() {
+        let result = match code {
+            RETRY_RESULT_NOT_FOUND => MediaRetryResult::NotFound,
+            _ => MediaRetryResult::GeneralError,
+        };
+        return Ok((result, None));
+    }
+
+    let field = |name: &str| -> WhatsAppResult<Vec<u8>> {
+        let value = payload["encrypt"][name].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Media retry notification has no {}", name)))?;
+        Crypto::base64_decode(value)
+    };
+    let plaintext = media::decrypt_media_retry(media_key, message_id, &field("enc_p")?, &field("enc_iv")?)?;
+
+    let mut result = MediaRetryResult::GeneralError;
+    let mut direct_path = None;
+    for (field, value) in proto::decode_fields(&plaintext)? {
+        match field {
+            2 => direct_path = Some(proto::field_string(&value)?),
+            3 => result = match proto::field_u64(&value)? {
+                RETRY_RESULT_SUCCESS => MediaRetryResult::Success,
+                RETRY_RESULT_NOT_FOUND => MediaRetryResult::NotFound,
+                RETRY_RESULT_DECRYPTION_ERROR => MediaRetryResult::DecryptionError,
+                _ => MediaRetryResult::GeneralError,
+            },
+            _ => {}
+        }
+    }
+
+    Ok((result, direct_path.filter(|_| result == MediaRetryResult::Success)))
+}
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, info, warn};
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    adv::{self, SignedDeviceIdentity},
+    crypto::{Crypto, GCM_NONCE_SIZE, KeyPair, SecretBytes},
+    error::{WhatsAppError, WhatsAppResult},
+    websocket::WebSocketMessage,
+};
+
+/// How long the first QR code is shown before moving to the next ref
+const FIRST_QR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long each following QR code is shown
+const QR_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Characters of pairing codes, leaving out ones easily mistaken for others
+const PAIRING_CODE_ALPHABET: &[u8; 32] = b"123456789ABCDEFGHJKLMNPQRSTVWXYZ";
+
+/// PBKDF2 iterations deriving the key that wraps ephemeral keys from a pairing code
+const PAIRING_CODE_ITERATIONS: u32 = 2 << 16;
+
+/// Salt, IV and encrypted public key of a wrapped ephemeral key
+const WRAPPED_KEY_SIZE: usize = 32 + 16 + 32;
+
+/// Pairing code waiting to be entered on the phone
+pub(super) struct PairingCode {
+    code: String,
+    ephemeral: KeyPair,
+    pairing_ref: String,
+}
+
+impl Client {
+    /// Start showing QR codes for the pairing refs the server sent
+    ///
+    /// Frames look like `["pair-device",{"refs":["...","..."]}]`.
+    pub(super) fn handle_pair_device(self: &Arc<Self>, payload: &serde_json::Value) {
+        let refs: Vec<String> = payload["refs"].as_array()
+            .map(|refs| refs.iter().filter_map(|r| r.as_str().map(str::to_string)).collect())
+            .unwrap_or_default();
+
+        if refs.is_empty() {
+            warn!("Server asked to pair without any refs");
+            self.dispatch_event(Event::Error(WhatsAppError::ProtocolError("pair-device has no refs".to_string())));
+            return;
+        }
+
+        self.start_qr_rotation(refs);
+    }
+
+    /// Emit a QR code for each ref in turn, then `Event::QRTimeout` once they have all expired
+    ///
+    /// Replaces any rotation already running.
+    fn start_qr_rotation(self: &Arc<Self>, refs: Vec<String>) {
+        // The task only holds a weak reference so it doesn't keep the client alive
+        let client = Arc::downgrade(self);
+        let task = self.runtime.handle.spawn(async move {
+            for (i, pairing_ref) in refs.iter().enumerate() {
+                let Some(strong) = client.upgrade() else {
+                    return;
+                };
+                if let Err(e) = strong.generate_qr_code(pairing_ref) {
+                    warn!("Failed to generate QR code: {}", e);
+                    strong.dispatch_event(Event::Error(e));
+                    return;
+                }
+                drop(strong);
+
+                tokio::time::sleep(if i == 0 { FIRST_QR_TIMEOUT } else { QR_TIMEOUT }).await;
+            }
+
+            if let Some(client) = client.upgrade() {
+                info!("All {} pairing refs expired", refs.len());
+                client.dispatch_event(Event::QRTimeout);
+            }
+        });
+
+        if let Some(previous) = self.qr_task.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    /// Ask the server to link this device to a phone number with a pairing code
+    ///
+    /// The returned code, formatted like `ABCD-EFGH`, is entered on the phone
+    /// under Linked devices instead of scanning a QR code. The phone then
+    /// sends its keys back and the exchange finishes in the background.
+    pub async fn request_pairing_code(&self, phone_number: &str) -> WhatsAppResult<String> {
+        let phone: String = phone_number.chars()
+            .filter(|c| !matches!(c, '+' | ' ' | '-' | '(' | ')'))
+            .collect();
+        if phone.is_empty() || !phone.chars().all(|c| c.is_ascii_digit()) {
+            return Err(WhatsAppError::AuthError(format!("Invalid phone number {}", phone_number)));
+        }
+
+        let (noise_key, _) = self.pairing_keys()?;
+        let props = &self.config.device_props;
+        let code = encode_pairing_code(&Crypto::random_bytes(5));
+        let ephemeral = Crypto::generate_key_pair()?;
+        let wrapped = wrap_ephemeral_key(&code, &ephemeral.public)?;
+
+        let response = self.query("query", json!({
+            "type": "link_code_companion_reg",
+            "stage": "companion_hello",
+            "jid": JID::new(&phone, "s.whatsapp.net", None).to_string(),
+            "should_show_push_notification": true,
+            "wrapped_companion_ephemeral_pub": Crypto::base64_encode(&wrapped),
+            "companion_server_auth_key_pub": Crypto::base64_encode(&noise_key.public),
+            "companion_platform_id": props.platform_type.id().to_string(),
+            "companion_platform_display": props.display_name(),
+        })).await?;
+        let pairing_ref = response["ref"].as_str()
+            .ok_or_else(|| WhatsAppError::ProtocolError("Pairing code response has no ref".to_string()))?;
+
+        // The code replaces scanning, so stop showing QR codes
+        self.stop_qr_rotation();
+        *self.pairing_code.lock().unwrap() = Some(PairingCode {
+            code: code.clone(),
+            ephemeral,
+            pairing_ref: pairing_ref.to_string(),
+        });
+
+        Ok(format!("{}-{}", &code[..4], &code[4..]))
+    }
+
+    /// Handle the phone's reply once the pairing code was entered on it
+    ///
+    /// Frames look like `["link_code_companion_reg",{"stage":"primary_hello","ref":"...",
+    /// "wrapped_primary_ephemeral_pub":"...","primary_identity_pub":"..."}]`.
+    pub(super) fn handle_link_code_notification(self: &Arc<Self>, payload: &serde_json::Value) {
+        let stage = payload["stage"].as_str().unwrap_or_default();
+        if stage != "primary_hello" {
+            debug!("Ignoring link code stage {}", stage);
+            return;
+        }
+
+        let client = self.clone();
+        let payload = payload.clone();
+        self.runtime.handle.spawn(async move {
+            if let Err(e) = client.finish_code_pairing(&payload).await {
+                warn!("Failed to finish pairing with a code: {}", e);
+                client.dispatch_event(Event::Error(e));
+            }
+        });
+    }
+
+    /// Send our identity and a new ADV secret to the phone, encrypted with the agreed ephemeral keys
+    async fn finish_code_pairing(&self, payload: &serde_json::Value) -> WhatsAppResult<()> {
+        let field = |name: &str| match payload[name].as_str() {
+            Some(value) => Crypto::base64_decode(value),
+            None => Err(WhatsAppError::ProtocolError(format!("primary_hello has no {}", name))),
+        };
+
+        let pairing_ref = payload["ref"].as_str().unwrap_or_default();
+        let pairing = self.pairing_code.lock().unwrap()
+            .take_if(|pairing| pairing.pairing_ref == pairing_ref)
+            .ok_or_else(|| WhatsAppError::ProtocolError(format!("No pairing code for ref {}", pairing_ref)))?;
+
+        let primary_ephemeral = unwrap_ephemeral_key(&pairing.code, &field("wrapped_primary_ephemeral_pub")?)?;
+        let primary_identity = field("primary_identity_pub")?;
+        let identity = self.signal.lock().unwrap().identity().identity.clone();
+
+        let ephemeral_secret = pairing.ephemeral.dh(&primary_ephemeral)?;
+        let adv_random = SecretBytes::new(Crypto::random_bytes(32));
+
+        let bundle_salt = Crypto::random_bytes(32);
+        let bundle_nonce = Crypto::random_bytes(GCM_NONCE_SIZE);
+        let bundle_key = Crypto::hkdf_with_salt(Some(&bundle_salt), &ephemeral_secret, b"link_code_pairing_key_bundle_encryption_key", 32)?;
+        let bundle = SecretBytes::concat(&[&identity.public, &primary_identity, &adv_random]);
+        let wrapped_bundle = [bundle_salt, bundle_nonce.clone(), Crypto::aes_gcm_encrypt(&bundle_key, &bundle_nonce, &[], &bundle)?].concat();
+
+        // Both sides derive the ADV secret pair-success is checked with from the two agreements
+        let identity_secret = identity.dh(&primary_identity)?;
+        let adv_secret = Crypto::hkdf(&SecretBytes::concat(&[&ephemeral_secret, &identity_secret, &adv_random]), b"adv_secret", 32)?;
+        if let Some(auth_state) = self.auth_state.lock().unwrap().as_mut() {
+            auth_state.secret = adv_secret;
+        }
+
+        self.query("query", json!({
+            "type": "link_code_companion_reg",
+            "stage": "companion_finish",
+            "ref": pairing.pairing_ref,
+            "wrapped_key_bundle": Crypto::base64_encode(&wrapped_bundle),
+            "companion_identity_public": Crypto::base64_encode(&identity.public),
+        })).await?;
+
+        Ok(())
+    }
+
+    /// Finish pairing once the phone accepted our QR code or pairing code
+    ///
+    /// Frames look like `["pair-success",{"id":"...","jid":"...","lid":"...","platform":"...",
+    /// "business_name":"...","device_identity":"..."}]`. The countersigned
+    /// device identity goes back to the server in a `pair-device-sign` frame.
+    pub(super) fn handle_pair_success(self: &Arc<Self>, payload: &serde_json::Value) {
+        // Whichever way pairing was started, it is over now
+        self.stop_qr_rotation();
+        self.pairing_code.lock().unwrap().take();
+
+        let client = self.clone();
+        let payload = payload.clone();
+        self.runtime.handle.spawn(async move {
+            let id = payload["id"].as_str().unwrap_or_default();
+            let (frame, event) = match client.complete_pairing(&payload) {
+                Ok((jid, signed)) => {
+                    let key_index = signed.device_identity().map(|details| details.key_index).unwrap_or_default();
+                    let frame = json!(["pair-device-sign", {
+                        "id": id,
+                        "device_identity": Crypto::base64_encode(&signed.encode(false)),
+                        "key_index": key_index,
+                    }]);
+                    let event = Event::PairSuccess {
+                        jid,
+                        platform: payload["platform"].as_str().unwrap_or_default().to_string(),
+                        business_name: payload["business_name"].as_str().filter(|name| !name.is_empty()).map(str::to_string),
+                    };
+                    (frame, event)
+                },
+                Err(e) => {
+                    warn!("Rejecting pairing: {}", e);
+                    (json!(["pair-error", {"id": id, "code": 401, "text": "not-authorized"}]), Event::Error(e))
+                },
+            };
+
+            if let Err(e) = client.transport.send(WebSocketMessage::Text(frame.to_string())).await {
+                warn!("Failed to answer pair-success: {}", e);
+            }
+            client.dispatch_event(event);
+        });
+    }
+
+    /// Check the device identity of a pair-success and store the JID and identity it assigns us
+    fn complete_pairing(&self, payload: &serde_json::Value) -> WhatsAppResult<(JID, SignedDeviceIdentity)> {
+        let field = |name: &str| payload[name].as_str()
+            .ok_or_else(|| WhatsAppError::ProtocolError(format!("pair-success has no {}", name)));
+
+        let jid: JID = field("jid")?.parse()?;
+        let container = Crypto::base64_decode(field("device_identity")?)?;
+        let identity = self.signal.lock().unwrap().identity().identity.clone();
+
+        let signed = {
+            let mut auth_state = self.auth_state.lock().unwrap();
+            let auth_state = auth_state.as_mut()
+                .ok_or_else(|| WhatsAppError::AuthError("pair-success before any pairing keys were generated".to_string()))?;
+
+            let signed = adv::validate_pairing(&container, &auth_state.secret, &identity)?;
+            auth_state.jid = Some(jid.clone());
+            auth_state.account = Some(signed.clone());
+            auth_state.lid = payload["lid"].as_str().and_then(|lid| lid.parse().ok());
+            auth_state.platform = payload["platform"].as_str().unwrap_or_default().to_string();
+            auth_state.business_name = payload["business_name"].as_str().filter(|name| !name.is_empty()).map(str::to_string);
+            signed
+        };
+        info!("Paired as {}", jid);
+
+        self.save_credentials()?;
+        Ok((jid, signed))
+    }
+
+    /// Stop rotating QR codes, e.g. because the refs died with the connection
+    pub(super) fn stop_qr_rotation(&self) {
+        if let Some(task) = self.qr_task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+/// Encode five random bytes as an 8-character pairing code, five bits per character
+fn encode_pairing_code(bytes: &[u8]) -> String {
+    let bits = bytes.iter().fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+    (0..8).rev()
+        .map(|i| PAIRING_CODE_ALPHABET[(bits >> (i * 5)) as usize & 31] as char)
+        .collect()
+}
+
+/// Encrypt an ephemeral public key with a key derived from the pairing code, as salt, IV and ciphertext
+fn wrap_ephemeral_key(code: &str, public_key: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let salt = Crypto::random_bytes(32);
+    let iv = Crypto::random_bytes(16);
+    let key = Crypto::pbkdf2_sha256(code.as_bytes(), &salt, PAIRING_CODE_ITERATIONS, 32)?;
+
+    let encrypted = Crypto::aes_ctr(&key, &iv, public_key)?;
+    Ok([salt, iv, encrypted].concat())
+}
+
+/// Decrypt an ephemeral public key wrapped by the other side with the same pairing code
+fn unwrap_ephemeral_key(code: &str, wrapped: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    if wrapped.len() != WRAPPED_KEY_SIZE {
+        return Err(WhatsAppError::ProtocolError(format!("Wrapped ephemeral key must be {} bytes, got {}", WRAPPED_KEY_SIZE, wrapped.len())));
+    }
+
+    let (salt, rest) = wrapped.split_at(32);
+    let (iv, encrypted) = rest.split_at(16);
+    let key = Crypto::pbkdf2_sha256(code.as_bytes(), salt, PAIRING_CODE_ITERATIONS, 32)?;
+    Crypto::aes_ctr(&key, iv, encrypted)
+}
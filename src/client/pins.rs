@@ -0,0 +1,45 @@
+use std::time::Duration;
+use log::warn;
+
+use super::{Client, PIN_DURATIONS};
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    message::{Message, PinInChat},
+};
+
+impl Client {
+    /// Pin a message at the top of the chat for everyone in it, for 24 hours, 7 days or 30 days
+    ///
+    /// The sender is who wrote the pinned message, which may be us.
+    pub async fn pin_message(&self, chat: &JID, message_id: &str, sender: &JID, duration: Duration) -> WhatsAppResult<String> {
+        if !PIN_DURATIONS.contains(&duration) {
+            return Err(WhatsAppError::ProtocolError(format!("Unsupported pin duration {:?}", duration)));
+        }
+
+        let key = self.key_for_sender(chat, message_id, sender);
+        let pin = Message::new_pin(chat.clone(), key, true, Some(duration.as_secs() as u32));
+        self.send_message(&pin).await
+    }
+
+    /// Unpin a message for everyone in the chat
+    pub async fn unpin_message(&self, chat: &JID, message_id: &str, sender: &JID) -> WhatsAppResult<String> {
+        let key = self.key_for_sender(chat, message_id, sender);
+        self.send_message(&Message::new_pin(chat.clone(), key, false, None)).await
+    }
+
+    /// Tell the application someone pinned or unpinned a message in a chat
+    pub(super) fn handle_pin(&self, pin: PinInChat, sender: JID) {
+        if pin.key.id.is_empty() {
+            warn!("Ignoring pin by {} without a message", sender);
+            return;
+        }
+
+        self.dispatch_event(Event::MessagePinned {
+            key: self.key_from_our_side(pin.key, &sender),
+            pinned: pin.pinned,
+            duration: pin.duration.map(|seconds| Duration::from_secs(seconds as u64)),
+            sender,
+        });
+    }
+}
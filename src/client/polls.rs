@@ -0,0 +1,97 @@
+use log::{error, warn};
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+    message::{Message, Poll, PollUpdate},
+    proto::{decode_fields, field_bytes},
+};
+
+/// HKDF info suffix of the key poll votes are encrypted with
+const POLL_VOTE_INFO: &[u8] = b"Poll Vote";
+
+impl Client {
+    /// Send a poll, letting voters select any number of options or only one
+    pub async fn send_poll(&self, chat: &JID, question: &str, options: &[&str], multi_select: bool) -> WhatsAppResult<String> {
+        let message = Message::new_poll(chat.clone(), question, options, multi_select);
+        if let Some(poll) = &message.poll {
+            self.remember_poll(&message.id, poll)?;
+        }
+
+        self.send_message(&message).await
+    }
+
+    /// Keep the secret and options of a poll, to decrypt the votes on it later
+    pub(super) fn remember_poll(&self, poll_id: &str, poll: &Poll) -> WhatsAppResult<()> {
+        let value = json!({
+            "secret": Crypto::base64_encode(&poll.secret),
+            "options": poll.options,
+        });
+
+        self.store.set(&poll_store_key(poll_id), &value.to_string())
+    }
+
+    /// Decrypt a vote on a poll and dispatch the options the voter selected
+    pub(super) fn handle_poll_update(&self, update: PollUpdate, voter: JID) {
+        let poll_id = update.poll_key.id.clone();
+
+        match self.decrypt_poll_vote(update, &voter) {
+            Ok(selected_options) => {
+                self.dispatch_event(Event::PollVote { poll_id, voter, selected_options });
+            },
+            Err(e) => {
+                error!("Failed to decrypt vote of {} on poll {}: {}", voter, poll_id, e);
+                self.dispatch_event(Event::Error(e));
+            },
+        }
+    }
+
+    fn decrypt_poll_vote(&self, update: PollUpdate, voter: &JID) -> WhatsAppResult<Vec<String>> {
+        let poll_id = update.poll_key.id.clone();
+        let stored = self.store.get(&poll_store_key(&poll_id))
+            .ok_or_else(|| WhatsAppError::CryptoError(format!("Unknown poll {}", poll_id)))?;
+        let stored: serde_json::Value = serde_json::from_str(&stored)
+            .map_err(|e| WhatsAppError::StoreError(format!("Corrupt poll {}: {}", poll_id, e)))?;
+        let secret = Crypto::base64_decode(stored["secret"].as_str().unwrap_or_default())?;
+        let options: Vec<String> = serde_json::from_value(stored["options"].clone()).unwrap_or_default();
+
+        // The key is the poll's as the voter sees it
+        let key = self.key_from_our_side(update.poll_key, voter);
+        let creator = match (key.from_me, key.sender_jid) {
+            (true, _) => self.paired_jid()
+                .ok_or_else(|| WhatsAppError::AuthError("Not paired".to_string()))?,
+            (false, Some(sender)) => sender,
+            (false, None) => key.chat_jid,
+        };
+        let creator = JID::new(&creator.user, &creator.server, None);
+        let voter = JID::new(&voter.user, &voter.server, None);
+
+        let info = [poll_id.as_bytes(), creator.to_string().as_bytes(), voter.to_string().as_bytes(), POLL_VOTE_INFO].concat();
+        let vote_key = Crypto::hkdf(&secret, &info, 32)?;
+        let aad = format!("{}\0{}", poll_id, voter);
+        let plaintext = Crypto::aes_gcm_decrypt(&vote_key, &update.enc_iv, aad.as_bytes(), &update.enc_payload)?;
+
+        // A `PollVoteMessage` has the SHA-256 of each selected option's name
+        let mut selected_options = Vec::new();
+        for (field, value) in decode_fields(&plaintext)? {
+            if field != 1 {
+                continue;
+            }
+            let hash = field_bytes(&value)?;
+            match options.iter().find(|option| Crypto::sha256(option.as_bytes()) == hash) {
+                Some(option) => selected_options.push(option.clone()),
+                None => warn!("Vote on poll {} for an unknown option", poll_id),
+            }
+        }
+
+        Ok(selected_options)
+    }
+}
+
+/// Device store key a poll's secret and options are kept under
+fn poll_store_key(poll_id: &str) -> String {
+    format!("poll:{}", poll_id)
+}
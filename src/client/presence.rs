@@ -0,0 +1,63 @@
+use log::{debug, warn};
+use serde_json::json;
+
+use super::{ChatPresence, Client};
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    websocket::WebSocketMessage,
+};
+
+impl Client {
+    /// Tell a chat we are typing or recording a voice note, or stopped doing so
+    ///
+    /// Frames look like `["chatstate",{"to":"...","state":"composing","media":"audio"}]`.
+    pub async fn send_chat_presence(&self, chat: &JID, presence: ChatPresence) -> WhatsAppResult<()> {
+        if !self.is_connected() {
+            return Err(WhatsAppError::ConnectionError("Not connected".to_string()));
+        }
+
+        let mut state = json!({ "to": chat.to_string(), "state": presence.state() });
+        if presence == ChatPresence::Recording {
+            state["media"] = json!("audio");
+        }
+
+        self.transport.send(WebSocketMessage::Text(json!(["chatstate", state]).to_string())).await
+    }
+
+    /// Handle a contact starting or stopping typing or recording in a chat
+    ///
+    /// Frames look like `["chatstate",{"from":"...","participant":"...","state":"composing","media":"audio"}]`,
+    /// the participant only being set in groups.
+    pub(super) fn handle_chat_state(&self, payload: &serde_json::Value) {
+        let Some(chat) = payload["from"].as_str().and_then(|from| from.parse::<JID>().ok()) else {
+            warn!("Chat state without a chat: {}", payload);
+            return;
+        };
+        let sender = payload["participant"].as_str()
+            .and_then(|participant| participant.parse().ok())
+            .unwrap_or_else(|| chat.clone());
+
+        let state = match (payload["state"].as_str(), payload["media"].as_str()) {
+            (Some("composing"), Some("audio")) => ChatPresence::Recording,
+            (Some("composing"), _) => ChatPresence::Composing,
+            (Some("paused"), _) => ChatPresence::Paused,
+            (state, _) => {
+                debug!("Ignoring chat state {:?} from {}", state, sender);
+                return;
+            },
+        };
+
+        self.dispatch_event(Event::ChatPresence { chat, sender, state });
+    }
+}
+
+impl ChatPresence {
+    /// Chat state sent for the presence
+    fn state(self) -> &'static str {
+        match self {
+            ChatPresence::Composing | ChatPresence::Recording => "composing",
+            ChatPresence::Paused => "paused",
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use serde_json::json;
+
+use super::{Client, ProfilePictureInfo, ProfilePictureResult, send::ack_error};
+use crate::{
+    JID,
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+    message::thumbnail,
+};
+
+/// Longest about text WhatsApp accepts, in characters
+const MAX_STATUS_MESSAGE_LENGTH: usize = 139;
+
+impl Client {
+    /// Get the profile picture of a user or the photo of a group, the small preview or the full size
+    ///
+    /// Requests look like `["picture",{"id":"...","type":"get","target":"<JID>",
+    /// "preview":true}]` and are answered with `"picture_id"`, `"url"` and
+    /// `"direct_path"`, or error 404 when there is no picture and 401 when
+    /// we aren't allowed to see it.
+    pub async fn get_profile_picture(&self, jid: &JID, preview: bool) -> WhatsAppResult<ProfilePictureResult> {
+        let target = JID::new(&jid.user, &jid.server, None);
+        let response = self.query("picture", json!({
+            "type": "get",
+            "target": target.to_string(),
+            "preview": preview,
+        })).await?;
+        match response["error"].as_u64() {
+            Some(404) => return Ok(ProfilePictureResult::NoPicture),
+            Some(401 | 403) => return Ok(ProfilePictureResult::NotAllowed),
+            _ => {}
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::ProtocolError(format!("Failed to get the picture of {}: {}", target, error)));
+        }
+
+        let Some(url) = response["url"].as_str() else {
+            return Ok(ProfilePictureResult::NoPicture);
+        };
+        Ok(ProfilePictureResult::Picture(ProfilePictureInfo {
+            id: picture_id(&response),
+            url: url.to_string(),
+            direct_path: response["direct_path"].as_str().map(str::to_string),
+            preview,
+        }))
+    }
+
+    /// Download a profile or group picture, which unlike other media isn't encrypted
+    pub async fn download_profile_picture(&self, picture: &ProfilePictureInfo) -> WhatsAppResult<Vec<u8>> {
+        let response = self.http.get(&picture.url)
+            .header(reqwest::header::ORIGIN, self.config.origin.as_str())
+            .send().await
+            .map_err(|e| WhatsAppError::MediaError(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(WhatsAppError::MediaError(format!("Media server answered {} for picture {}", status, picture.id)));
+        }
+
+        let bytes = response.bytes().await
+            .map_err(|e| WhatsAppError::MediaError(e.to_string()))?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Set our own profile picture from an image, returning the id of the new picture
+    ///
+    /// The image is cropped to a centered square and scaled to what WhatsApp expects.
+    pub async fn set_profile_picture(&self, image: &[u8]) -> WhatsAppResult<String> {
+        let Some(own_jid) = self.paired_jid() else {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        };
+        self.set_picture(&JID::new(&own_jid.user, &own_jid.server, None), image).await
+    }
+
+    /// Remove our own profile picture
+    ///
+    /// Requests look like `["picture",{"id":"...","type":"delete","target":"<our JID>"}]`.
+    pub async fn remove_profile_picture(&self) -> WhatsAppResult<()> {
+        let Some(own_jid) = self.paired_jid() else {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        };
+        let target = JID::new(&own_jid.user, &own_jid.server, None);
+
+        let response = self.query("picture", json!({
+            "type": "delete",
+            "target": target.to_string(),
+        })).await?;
+        // Nothing to remove is as good as removed
+        if let Some(404) = response["error"].as_u64() {
+            return Ok(());
+        }
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::ProtocolError(format!("Failed to remove our profile picture: {}", error)));
+        }
+        Ok(())
+    }
+
+    /// Set the about text shown on our profile
+    ///
+    /// Requests look like `["status",{"id":"...","text":"..."}]`.
+    pub async fn set_status_message(&self, text: &str) -> WhatsAppResult<()> {
+        if self.paired_jid().is_none() {
+            return Err(WhatsAppError::AuthError("Not authenticated".to_string()));
+        }
+        if text.chars().count() > MAX_STATUS_MESSAGE_LENGTH {
+            return Err(WhatsAppError::ProtocolError(format!("About text is longer than {} characters", MAX_STATUS_MESSAGE_LENGTH)));
+        }
+
+        let response = self.query("status", json!({ "text": text })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::ProtocolError(format!("Failed to set our about text: {}", error)));
+        }
+        Ok(())
+    }
+
+    /// Set the photo of a group we administer from an image, returning the id of the new picture
+    pub async fn set_group_photo(&self, group_jid: &JID, image: &[u8]) -> WhatsAppResult<String> {
+        if !group_jid.is_group() {
+            return Err(WhatsAppError::ProtocolError(format!("{} is not a group", group_jid)));
+        }
+        self.set_picture(group_jid, image).await
+    }
+
+    /// Upload a picture for a user or group
+    ///
+    /// Requests look like `["picture",{"id":"...","type":"set","target":"<JID>",
+    /// "image":"<base64 JPEG>","preview":"<base64 JPEG>"}]` and are answered
+    /// with the id of the picture.
+    async fn set_picture(&self, target: &JID, image: &[u8]) -> WhatsAppResult<String> {
+        let picture = thumbnail::prepare_profile_picture(image)?;
+        let response = self.query("picture", json!({
+            "type": "set",
+            "target": target.to_string(),
+            "image": Crypto::base64_encode(&picture.jpeg),
+            "preview": Crypto::base64_encode(&picture.preview),
+        })).await?;
+        if let Some(error) = ack_error(&response) {
+            return Err(WhatsAppError::ProtocolError(format!("Failed to set the picture of {}: {}", target, error)));
+        }
+
+        Ok(picture_id(&response))
+    }
+}
+
+/// Get the id of a picture from a response, given as a string or a number
+fn picture_id(response: &serde_json::Value) -> String {
+    response["picture_id"].as_str()
+        .map(str::to_string)
+        .or_else(|| response["picture_id"].as_u64().map(|id| id.to_string()))
+        .unwrap_or_default()
+}
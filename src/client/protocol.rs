@@ -0,0 +1,131 @@
+use std::time::Duration;
+use log::{error, warn};
+
+use super::{Client, DISAPPEARING_TIMERS};
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    message::{Message, MessageKey, ProtocolMessage, ProtocolMessageType},
+};
+
+impl Client {
+    /// Replace the text of one of our messages on every device in the chat
+    pub async fn edit_message(&self, chat: &JID, message_id: &str, new_text: &str) -> WhatsAppResult<String> {
+        let key = MessageKey {
+            chat_jid: chat.clone(),
+            id: message_id.to_string(),
+            from_me: true,
+            sender_jid: None,
+        };
+
+        let id = self.send_message(&Message::new_edit(chat.clone(), key, new_text)).await?;
+        if let Err(e) = self.message_store.update_message(chat, message_id, |message| message.text = Some(new_text.to_string())) {
+            error!("Failed to store edit of message {}: {}", message_id, e);
+        }
+
+        Ok(id)
+    }
+
+    /// Delete one of our messages for everyone in the chat
+    pub async fn revoke_message(&self, chat: &JID, message_id: &str) -> WhatsAppResult<String> {
+        let key = MessageKey {
+            chat_jid: chat.clone(),
+            id: message_id.to_string(),
+            from_me: true,
+            sender_jid: None,
+        };
+
+        let id = self.send_message(&Message::new_revoke(chat.clone(), key)).await?;
+        self.forget_revoked(chat, message_id);
+
+        Ok(id)
+    }
+
+    /// Set how long until new messages in a chat disappear, `DISAPPEARING_TIMER_OFF` keeping them
+    ///
+    /// Only the protocol's timers are accepted: off, 24 hours, 7 days and 90 days.
+    pub async fn set_disappearing_timer(&self, chat: &JID, duration: Duration) -> WhatsAppResult<()> {
+        if !DISAPPEARING_TIMERS.contains(&duration) {
+            return Err(WhatsAppError::ProtocolError(format!("Unsupported disappearing message timer {:?}", duration)));
+        }
+        let expiration = duration.as_secs() as u32;
+
+        if chat.is_group() {
+            return self.set_group_ephemeral(chat, duration).await;
+        }
+        self.send_message(&Message::new_ephemeral_setting(chat.clone(), expiration)).await?;
+
+        self.message_store.update_chat(chat, |info| info.ephemeral_expiration = expiration)
+    }
+
+    /// Apply a protocol message received from the sender to the message it refers to
+    pub(super) fn handle_protocol_message(&self, protocol: ProtocolMessage, sender: JID) {
+        // The key says from the sender's side whether they wrote the message
+        let by_author = protocol.key.from_me;
+        // Group admins may revoke messages of other participants
+        let admin = !by_author
+            && protocol.kind == ProtocolMessageType::Revoke
+            && protocol.key.chat_jid.is_group()
+            && protocol.key.sender_jid.is_some();
+        // Anyone in the chat may change its timer
+        if !by_author && !admin && protocol.kind != ProtocolMessageType::EphemeralSetting {
+            warn!("Ignoring {:?} of message {} not sent by {}", protocol.kind, protocol.key.id, sender);
+            return;
+        }
+        let key = self.key_from_our_side(protocol.key, &sender);
+
+        match protocol.kind {
+            ProtocolMessageType::MessageEdit => {
+                let Some(message) = protocol.edited_message else {
+                    warn!("Edit of message {} has no new content", key.id);
+                    return;
+                };
+
+                let stored = self.message_store.update_message(&key.chat_jid, &key.id, |stored| {
+                    stored.text = message.text.clone();
+                    if message.media.is_some() {
+                        stored.media = message.media.clone();
+                    }
+                });
+                if let Err(e) = stored {
+                    error!("Failed to store edit of message {}: {}", key.id, e);
+                }
+
+                self.dispatch_event(Event::MessageEdited { key, message: *message, sender });
+            },
+            ProtocolMessageType::EphemeralSetting => {
+                let expiration = protocol.ephemeral_expiration.unwrap_or_default();
+                self.handle_ephemeral_setting(key.chat_jid, sender, expiration);
+            },
+            ProtocolMessageType::Revoke => {
+                self.forget_revoked(&key.chat_jid, &key.id);
+                self.dispatch_event(Event::MessageRevoked { key, revoked_by: sender, admin });
+            },
+        }
+    }
+
+    /// Remember a chat's new disappearing message timer and tell the application who changed it
+    pub(super) fn handle_ephemeral_setting(&self, chat: JID, sender: JID, expiration: u32) {
+        if let Err(e) = self.message_store.update_chat(&chat, |info| info.ephemeral_expiration = expiration) {
+            error!("Failed to store disappearing timer of {}: {}", chat, e);
+        }
+
+        self.dispatch_event(Event::DisappearingTimerChanged {
+            chat,
+            sender,
+            timer: Duration::from_secs(expiration as u64),
+        });
+    }
+
+    /// Drop the content of a revoked message from the store
+    fn forget_revoked(&self, chat: &JID, message_id: &str) {
+        let stored = self.message_store.update_message(chat, message_id, |message| {
+            message.text = None;
+            message.media = None;
+            message.context_info.quoted = None;
+        });
+        if let Err(e) = stored {
+            error!("Failed to store revoke of message {}: {}", message_id, e);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use super::Client;
+use crate::{
+    JID,
+    error::WhatsAppResult,
+    message::{Message, MessageKey},
+};
+
+impl Client {
+    /// React to a message with an emoji, or remove our reaction with an empty one
+    ///
+    /// The sender is who wrote the reacted message, which may be us.
+    pub async fn send_reaction(&self, chat: &JID, message_id: &str, sender: &JID, emoji: &str) -> WhatsAppResult<String> {
+        let key = self.key_for_sender(chat, message_id, sender);
+        self.send_message(&Message::new_reaction(chat.clone(), key, emoji)).await
+    }
+
+    /// Build the key of a message written by the sender, which may be us
+    pub(super) fn key_for_sender(&self, chat: &JID, message_id: &str, sender: &JID) -> MessageKey {
+        let from_me = self.paired_jid().is_some_and(|jid| jid.user == sender.user);
+        MessageKey {
+            chat_jid: chat.clone(),
+            id: message_id.to_string(),
+            from_me,
+            // Groups need the author to find the message, other chats have only two
+            sender_jid: (chat.is_group() && !from_me).then(|| sender.clone()),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::warn;
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    message::{MessageReceipt, ReceiptStatus},
+    websocket::WebSocketMessage,
+};
+
+impl Client {
+    /// Mark messages of a sender as read, so they see blue ticks and our other devices clear the chat
+    ///
+    /// The sender is only needed to address the receipt in group chats.
+    pub async fn mark_read(&self, chat: &JID, message_ids: &[String], sender: &JID) -> WhatsAppResult<()> {
+        let participant = chat.is_group().then(|| sender.to_string());
+        self.send_receipt(&chat.to_string(), participant.as_deref(), message_ids, Some("read")).await
+    }
+
+    /// Mark a voice note or video message as played, so the sender sees it was listened to
+    ///
+    /// The sender is only needed to address the receipt in group chats.
+    pub async fn mark_played(&self, chat: &JID, message_id: &str, sender: &JID) -> WhatsAppResult<()> {
+        let participant = chat.is_group().then(|| sender.to_string());
+        self.send_receipt(&chat.to_string(), participant.as_deref(), &[message_id.to_string()], Some("played")).await
+    }
+
+    /// Tell the application a recipient received, read or played our messages
+    ///
+    /// Receipts look like `["receipt",{"id":"...","from":"...","participant":"...",
+    /// "type":"played","t":1700000000,"list":["..."]}]`, delivery receipts having no type.
+    pub(super) fn handle_status_receipt(&self, payload: &serde_json::Value, status: ReceiptStatus) {
+        let Some(recipient) = payload["participant"].as_str()
+            .or_else(|| payload["from"].as_str())
+            .and_then(|recipient| recipient.parse::<JID>().ok())
+        else {
+            warn!("Ignoring receipt without a sender: {}", payload);
+            return;
+        };
+        // Binary receipts carry the timestamp as a string
+        let timestamp = payload["t"].as_u64()
+            .or_else(|| payload["t"].as_str().and_then(|t| t.parse().ok()))
+            .unwrap_or_default();
+
+        let ids = payload["id"].as_str().into_iter()
+            .chain(payload["list"].as_array().into_iter().flatten().filter_map(|id| id.as_str()));
+        for id in ids {
+            self.dispatch_event(Event::MessageStatus(MessageReceipt {
+                message_id: id.to_string(),
+                status: status.clone(),
+                timestamp,
+                recipient: recipient.clone(),
+            }));
+        }
+    }
+
+    /// Acknowledge a message relayed by the server, which redelivers it until we do
+    ///
+    /// Read receipts follow right away when `auto_mark_read` is on.
+    pub(super) fn acknowledge_message(self: &Arc<Self>, payload: &serde_json::Value) {
+        let (Some(id), Some(from)) = (payload["id"].as_str(), payload["from"].as_str()) else {
+            warn!("Can't acknowledge message without an id and sender: {}", payload);
+            return;
+        };
+        let ids = vec![id.to_string()];
+        let from = from.to_string();
+        let participant = payload["participant"].as_str().map(str::to_string);
+
+        let client = self.clone();
+        self.runtime.handle.spawn(async move {
+            if let Err(e) = client.send_receipt(&from, participant.as_deref(), &ids, None).await {
+                warn!("Failed to send delivery receipt for {}: {}", ids[0], e);
+            }
+            if client.config.auto_mark_read
+                && let Err(e) = client.send_receipt(&from, participant.as_deref(), &ids, Some("read")).await
+            {
+                warn!("Failed to send read receipt for {}: {}", ids[0], e);
+            }
+        });
+    }
+
+    /// Send a receipt for messages in a chat, a delivery receipt when no type is given
+    ///
+    /// Frames look like `["receipt",{"id":"...","to":"...","participant":"...",
+    /// "type":"read","t":1700000000,"list":["..."]}]`, the list carrying any ids after the first.
+    pub(super) async fn send_receipt(&self, to: &str, participant: Option<&str>, message_ids: &[String], kind: Option<&str>) -> WhatsAppResult<()> {
+        let Some((id, rest)) = message_ids.split_first() else {
+            return Ok(());
+        };
+        if !self.is_connected() {
+            return Err(WhatsAppError::ConnectionError("Not connected".to_string()));
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut receipt = json!({ "id": id, "to": to, "t": timestamp });
+        if let Some(participant) = participant {
+            receipt["participant"] = json!(participant);
+        }
+        if let Some(kind) = kind {
+            receipt["type"] = json!(kind);
+        }
+        if !rest.is_empty() {
+            receipt["list"] = json!(rest);
+        }
+
+        self.transport.send(WebSocketMessage::Text(json!(["receipt", receipt]).to_string())).await
+    }
+}
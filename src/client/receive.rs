@@ -0,0 +1,269 @@
+use std::sync::Arc;
+use log::warn;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    binary::Node,
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+    message::{Message, MessageKey, MessageParser, SenderKeyDistribution, interactive::Interactive},
+    proto,
+    signal::{PreKeySignalMessage, SignalMessage, group::{SenderKeyDistributionMessage, SenderKeyMessage}},
+};
+
+impl Client {
+    /// Decrypt a message relayed by the server and dispatch it
+    ///
+    /// Messages look like `["message",{"id":"...","from":"...","type":"pkmsg","ciphertext":"..."}]`.
+    pub(super) fn handle_incoming_message(self: &Arc<Self>, payload: &serde_json::Value) {
+        let id = payload["id"].as_str().unwrap_or_default();
+        // The server redelivers messages it saw no receipt for, which can't be decrypted twice
+        if self.is_duplicate(payload) {
+            self.acknowledge_message(payload);
+            return;
+        }
+
+        let decrypted = match self.decrypt_incoming(payload) {
+            Ok(message) => {
+                self.clear_retries(id);
+                self.mark_seen(payload);
+                self.acknowledge_message(payload);
+                message
+            },
+            Err(e) => {
+                warn!("Failed to decrypt message {}: {}", id, e);
+                self.request_retry(payload, e);
+                return;
+            },
+        };
+
+        self.dispatch_message(decrypted);
+    }
+
+    /// Decrypt a message node relayed by the server and dispatch it
+    ///
+    /// Nodes look like `<message id="..." from="..." participant="..." t="...">
+    /// <enc type="skmsg" v="2">...</enc></message>`, a group message carrying a
+    /// `pkmsg` with the sender key next to the `skmsg` encrypted with it.
+    pub(super) fn handle_message_node(self: &Arc<Self>, node: &Node) {
+        let payload = node.attrs_json();
+        let id = node.get_attr("id").unwrap_or_default();
+        if self.is_duplicate(&payload) {
+            self.acknowledge_message(&payload);
+            return;
+        }
+
+        match self.decrypt_node(node) {
+            Ok(messages) => {
+                self.clear_retries(id);
+                self.mark_seen(&payload);
+                self.acknowledge_message(&payload);
+                for message in messages {
+                    self.dispatch_message(message);
+                }
+            },
+            Err(e) => {
+                warn!("Failed to decrypt message {}: {}", id, e);
+                self.request_retry(&payload, e);
+            },
+        }
+    }
+
+    /// Tell the application about a decrypted message, or apply it if it acts on another
+    fn dispatch_message(self: &Arc<Self>, message: Message) {
+        match message {
+            Message { reaction: Some(reaction), sender_jid: Some(sender), .. } => {
+                self.dispatch_event(Event::ReactionReceived {
+                    remover: reaction.emoji.is_empty(),
+                    key: self.key_from_our_side(reaction.key, &sender),
+                    emoji: reaction.emoji,
+                    sender,
+                });
+            },
+            Message { protocol: Some(protocol), sender_jid: Some(sender), .. } => {
+                self.handle_protocol_message(protocol, sender);
+            },
+            Message { interactive: Some(Interactive::ButtonsResponse(response)), chat_jid, sender_jid: Some(sender), .. } => {
+                self.dispatch_event(Event::ButtonResponse { chat: chat_jid, sender, response });
+            },
+            Message { interactive: Some(Interactive::ListResponse(response)), chat_jid, sender_jid: Some(sender), .. } => {
+                self.dispatch_event(Event::ListResponse { chat: chat_jid, sender, response });
+            },
+            Message { sender_key_distribution: Some(distribution), sender_jid: Some(sender), .. } => {
+                self.handle_sender_key_distribution(distribution, &sender);
+            },
+            message if message.chat_jid.is_status_broadcast() => {
+                let Some(sender) = message.sender_jid.clone() else {
+                    return;
+                };
+                self.dispatch_event(Event::StatusPosted { sender, message });
+            },
+            Message { poll_update: Some(update), sender_jid: Some(sender), .. } => {
+                self.handle_poll_update(update, sender);
+            },
+            Message { pin_in_chat: Some(pin), sender_jid: Some(sender), .. } => {
+                self.handle_pin(pin, sender);
+            },
+            message if message.album.is_some() => self.handle_album(message),
+            message if message.album_parent.is_some() => self.handle_album_item(message),
+            message => {
+                if let Some(poll) = &message.poll
+                    && let Err(e) = self.remember_poll(&message.id, poll)
+                {
+                    warn!("Failed to store poll {}: {}", message.id, e);
+                }
+                self.dispatch_event(Event::MessageReceived(message));
+            },
+        }
+    }
+
+    /// Turn a message key as the sender sees it into how we see it
+    pub(super) fn key_from_our_side(&self, mut key: MessageKey, sender: &JID) -> MessageKey {
+        // To the sender a direct chat is with us
+        if !key.chat_jid.is_group() {
+            key.chat_jid = JID::new(&sender.user, &sender.server, None);
+        }
+
+        if key.from_me {
+            key.from_me = false;
+            key.sender_jid.get_or_insert_with(|| JID::new(&sender.user, &sender.server, None));
+        } else {
+            let own = self.paired_jid();
+            key.from_me = match &key.sender_jid {
+                Some(author) => own.is_some_and(|own| own.user == author.user),
+                None => !key.chat_jid.is_group(),
+            };
+            if key.from_me {
+                key.sender_jid = None;
+            }
+        }
+        key
+    }
+
+    /// Keep the sender key another member of a group or status broadcast handed us
+    fn handle_sender_key_distribution(&self, distribution: SenderKeyDistribution, sender: &JID) {
+        let processed = SenderKeyDistributionMessage::decode(&distribution.message)
+            .and_then(|message| {
+                self.group_cipher.lock().unwrap().process_distribution(&distribution.group_id, sender, &message)
+            });
+
+        if let Err(e) = processed {
+            warn!("Failed to process sender key of {} in {}: {}", sender, distribution.group_id, e);
+        }
+    }
+
+    fn decrypt_incoming(&self, payload: &serde_json::Value) -> WhatsAppResult<Message> {
+        let sender: JID = payload["participant"].as_str()
+            .or_else(|| payload["from"].as_str())
+            .ok_or_else(|| WhatsAppError::ProtocolError("Message has no sender".to_string()))?
+            .parse()?;
+        let ciphertext = Crypto::base64_decode(payload["ciphertext"].as_str().unwrap_or_default())?;
+        let kind = payload["type"].as_str().unwrap_or_default();
+        let chat = match kind {
+            // Encrypted once for a group or status broadcast with the sender's sender key
+            "skmsg" => payload["from"].as_str()
+                .ok_or_else(|| WhatsAppError::ProtocolError("Group message has no chat".to_string()))?
+                .parse()?,
+            _ => JID::new(&sender.user, &sender.server, None),
+        };
+
+        let plaintext = self.decrypt_ciphertext(&sender, &chat, kind, &ciphertext)?;
+        let text = String::from_utf8(plaintext)
+            .map_err(|e| WhatsAppError::ParsingError(e.to_string()))?;
+        let mut message = MessageParser::parse_json(&text)?;
+        message.from_me = false;
+        message.chat_jid = chat;
+        message.sender_jid = Some(sender);
+        Ok(message)
+    }
+
+    /// Decrypt every `enc` child of a message node into the messages it carries
+    fn decrypt_node(&self, node: &Node) -> WhatsAppResult<Vec<Message>> {
+        let from: JID = node.get_attr("from")
+            .ok_or_else(|| WhatsAppError::ProtocolError("Message has no sender".to_string()))?
+            .parse()?;
+        let sender: JID = match node.get_attr("participant") {
+            Some(participant) => participant.parse()?,
+            None => from.clone(),
+        };
+        let chat = JID::new(&from.user, &from.server, None);
+        let timestamp = node.get_attr("t").and_then(|t| t.parse().ok()).unwrap_or_default();
+
+        // The sender key has to be processed before the message encrypted with it
+        let mut encs: Vec<&Node> = node.children_by_tag("enc").collect();
+        encs.sort_by_key(|enc| enc.get_attr("type") == Some("skmsg"));
+        if encs.is_empty() {
+            return Err(WhatsAppError::ProtocolError("Message has no encrypted content".to_string()));
+        }
+
+        let mut messages = Vec::new();
+        for enc in encs {
+            let kind = enc.get_attr("type").unwrap_or_default();
+            let ciphertext = enc.get_bytes().unwrap_or_default();
+            let plaintext = self.decrypt_ciphertext(&sender, &chat, kind, ciphertext)?;
+
+            let mut message = proto::decode_message(unpad(&plaintext)?)?;
+            message.id = node.get_attr("id").unwrap_or_default().to_string();
+            message.timestamp = timestamp;
+            message.from_me = false;
+            message.chat_jid = chat.clone();
+            message.sender_jid = Some(sender.clone());
+
+            // Sender keys are handled right away so the `skmsg` can use them
+            if let Some(distribution) = message.sender_key_distribution.take() {
+                self.handle_sender_key_distribution(distribution, &sender);
+                if is_empty(&message) {
+                    continue;
+                }
+            }
+            messages.push(message);
+        }
+
+        Ok(messages)
+    }
+
+    /// Decrypt a Signal or sender key ciphertext from a device
+    fn decrypt_ciphertext(&self, sender: &JID, chat: &JID, kind: &str, ciphertext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        let plaintext = match kind {
+            "pkmsg" => {
+                let message = PreKeySignalMessage::decode(ciphertext)?;
+                self.check_identity(sender, &message.identity_key)?;
+                let plaintext = self.signal.lock().unwrap().decrypt_pre_key_message(sender, &message)?;
+                self.save_identity(sender, &message.identity_key)?;
+                plaintext
+            },
+            "msg" => {
+                let message = SignalMessage::decode(ciphertext)?;
+                self.signal.lock().unwrap().decrypt(sender, &message)?
+            },
+            "skmsg" => {
+                let message = SenderKeyMessage::decode(ciphertext)?;
+                self.group_cipher.lock().unwrap().decrypt(chat, sender, &message)?
+            },
+            kind => return Err(WhatsAppError::ProtocolError(format!("Unknown message type {}", kind))),
+        };
+
+        Ok(plaintext)
+    }
+}
+
+/// Strip the random padding of a decrypted message, its length repeated in every byte
+fn unpad(plaintext: &[u8]) -> WhatsAppResult<&[u8]> {
+    match plaintext.last() {
+        Some(&len) if len > 0 && (len as usize) <= plaintext.len() => Ok(&plaintext[..plaintext.len() - len as usize]),
+        _ => Err(WhatsAppError::ParsingError("Invalid message padding".to_string())),
+    }
+}
+
+/// Whether a decoded message carries nothing but its addressing, like a bare sender key carrier
+fn is_empty(message: &Message) -> bool {
+    message.text.is_none()
+        && message.media.is_none()
+        && message.contacts.is_empty()
+        && message.reaction.is_none()
+        && message.protocol.is_none()
+        && message.poll.is_none()
+        && message.poll_update.is_none()
+        && message.interactive.is_none()
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID, Event,
+    error::{WhatsAppError, WhatsAppResult},
+    message::Message,
+    websocket::WebSocketMessage,
+};
+
+/// Number of times a message is re-sent, or asked to be re-sent, before giving up
+const MAX_RETRIES: u64 = 5;
+
+/// Number of sent messages kept to re-send when a recipient can't decrypt them
+const RECENT_MESSAGES: usize = 256;
+
+impl Client {
+    /// Keep a sent message around in case a recipient asks for it again
+    pub(super) fn remember_sent(&self, message: &Message) {
+        let mut recent = self.recent_messages.lock().unwrap();
+        if recent.len() >= RECENT_MESSAGES {
+            recent.pop_front();
+        }
+        recent.push_back(message.clone());
+    }
+
+    /// Re-send a message to a device that couldn't decrypt it, over a fresh session
+    ///
+    /// Receipts look like `["receipt",{"type":"retry","id":"...","from":"...",
+    /// "participant":"...","count":1}]`, the participant only being set in groups.
+    pub(super) fn handle_retry_receipt(self: &Arc<Self>, payload: &serde_json::Value) {
+        let id = payload["id"].as_str().unwrap_or_default().to_string();
+        let count = payload["count"].as_u64().unwrap_or(1);
+        let Some(device) = payload["participant"].as_str()
+            .or_else(|| payload["from"].as_str())
+            .and_then(|device| device.parse::<JID>().ok())
+        else {
+            warn!("Retry receipt without a sender: {}", payload);
+            return;
+        };
+
+        if count > MAX_RETRIES {
+            warn!("Giving up on re-sending {} to {} after {} retries", id, device, MAX_RETRIES);
+            return;
+        }
+        let Some(message) = self.recent_messages.lock().unwrap().iter().find(|message| message.id == id).cloned() else {
+            warn!("{} asked for message {} again, which is no longer kept", device, id);
+            return;
+        };
+
+        let client = self.clone();
+        self.runtime.handle.spawn(async move {
+            info!("Re-sending {} to {}, retry {}", id, device, count);
+            if let Err(e) = client.resend_to_device(&message, &device).await {
+                warn!("Failed to re-send {} to {}: {}", id, device, e);
+            }
+        });
+    }
+
+    /// Ask the sender of a message we couldn't decrypt to send it again, or give up on it
+    ///
+    /// Frames look like `["receipt",{"type":"retry","id":"...","to":"...","count":1,"registration_id":123}]`.
+    pub(super) fn request_retry(self: &Arc<Self>, payload: &serde_json::Value, error: WhatsAppError) {
+        let (Some(id), Some(from)) = (payload["id"].as_str(), payload["from"].as_str()) else {
+            return;
+        };
+        let participant = payload["participant"].as_str();
+        let sender = participant.unwrap_or(from).parse::<JID>().ok();
+
+        let count = {
+            let mut retries = self.decrypt_retries.lock().unwrap();
+            let count = retries.entry(id.to_string()).or_insert(0);
+            *count += 1;
+            *count as u64
+        };
+
+        if count > MAX_RETRIES {
+            warn!("Message {} from {} stays undecryptable: {}", id, from, error);
+            self.decrypt_retries.lock().unwrap().remove(id);
+            // Acknowledged anyway, or the server keeps delivering it
+            self.acknowledge_message(payload);
+            if let (Some(sender), Ok(chat)) = (sender, from.parse::<JID>()) {
+                self.dispatch_event(Event::UndecryptableMessage { id: id.to_string(), chat, sender, error });
+            }
+            return;
+        }
+
+        debug!("Asking for message {} from {} again, retry {}: {}", id, from, count, error);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut receipt = json!({
+            "id": id,
+            "to": from,
+            "type": "retry",
+            "count": count,
+            "registration_id": self.signal.lock().unwrap().identity().registration_id,
+            "t": timestamp,
+        });
+        if let Some(participant) = participant {
+            receipt["participant"] = json!(participant);
+        }
+
+        let client = self.clone();
+        let frame = json!(["receipt", receipt]).to_string();
+        self.runtime.handle.spawn(async move {
+            if let Err(e) = client.transport.send(WebSocketMessage::Text(frame)).await {
+                warn!("Failed to send retry receipt: {}", e);
+            }
+        });
+    }
+
+    /// Forget a message we finally decrypted after asking for it again
+    pub(super) fn clear_retries(&self, id: &str) {
+        self.decrypt_retries.lock().unwrap().remove(id);
+    }
+
+    /// Encrypt a message again for a single device, starting over with a new session
+    async fn resend_to_device(&self, message: &Message, device: &JID) -> WhatsAppResult<()> {
+        // Its side of the old session is gone or broken, so ours is useless too
+        self.signal.lock().unwrap().delete_session(device)?;
+        // And it may have lost our sender key along with it
+        if let Some(devices) = self.sender_key_devices.lock().unwrap().get_mut(&message.chat_jid) {
+            devices.remove(device);
+        }
+        let devices = [device.clone()];
+        if !self.establish_sessions(&devices).await?.is_empty() {
+            return Err(WhatsAppError::MessageSendError(format!("No prekey bundle for {}", device)));
+        }
+
+        let plaintext = message.to_json()?.into_bytes();
+        let envelopes = self.encrypt_for_devices(&plaintext, &devices)?;
+        let ack = self.send_envelopes(message, &Self::participant_hash(&devices), &envelopes).await?;
+
+        match ack.error {
+            Some(error) => Err(WhatsAppError::MessageSendError(error)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+
+use super::{Client, ScheduledMessage};
+use crate::{
+    Event,
+    error::{WhatsAppError, WhatsAppResult},
+    message::Message,
+};
+
+/// Device store key of the queue of scheduled messages
+const SCHEDULED_MESSAGES_KEY: &str = "scheduled_messages";
+
+/// How often the queue is checked for messages that are due
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Client {
+    /// Queue a message to be sent at the given time, returning its id
+    ///
+    /// The queue is kept in the device store, so messages still go out after a
+    /// restart. They are sent once they are due and the client is logged in.
+    pub fn schedule_message(&self, message: Message, send_at: SystemTime) -> WhatsAppResult<String> {
+        let id = message.id.clone();
+        self.update_schedule(|queue| {
+            queue.push(ScheduledMessage { message, send_at });
+            queue.sort_by_key(|scheduled| scheduled.send_at);
+        })?;
+
+        Ok(id)
+    }
+
+    /// Remove a message from the queue before it is sent, returning whether it was queued
+    pub fn cancel_scheduled_message(&self, message_id: &str) -> WhatsAppResult<bool> {
+        self.update_schedule(|queue| {
+            let queued = queue.len();
+            queue.retain(|scheduled| scheduled.message.id != message_id);
+            queue.len() != queued
+        })
+    }
+
+    /// Get the messages waiting to be sent, the earliest first
+    pub fn scheduled_messages(&self) -> WhatsAppResult<Vec<ScheduledMessage>> {
+        let _guard = self.schedule_lock.lock().unwrap();
+        self.load_schedule()
+    }
+
+    /// Start the task sending scheduled messages when they are due, if it isn't running yet
+    pub(super) fn start_scheduler(self: &Arc<Self>) {
+        let mut scheduler_task = self.scheduler_task.lock().unwrap();
+        if scheduler_task.is_some() {
+            return;
+        }
+
+        // The task only holds a weak reference so it doesn't keep the client alive
+        let client = Arc::downgrade(self);
+        *scheduler_task = Some(self.runtime.handle.spawn(async move {
+            loop {
+                tokio::time::sleep(SCHEDULE_CHECK_INTERVAL).await;
+                let Some(client) = client.upgrade() else {
+                    break;
+                };
+                if client.is_connected() && client.is_authenticated() {
+                    client.send_due_messages().await;
+                }
+            }
+        }));
+    }
+
+    /// Send the scheduled messages that are due
+    ///
+    /// Messages failing for lack of a connection stay queued for the next
+    /// attempt, those failing otherwise are dropped and reported.
+    async fn send_due_messages(&self) {
+        let now = SystemTime::now();
+        let due: Vec<ScheduledMessage> = match self.scheduled_messages() {
+            Ok(queue) => queue.into_iter().take_while(|scheduled| scheduled.send_at <= now).collect(),
+            Err(e) => {
+                warn!("Failed to load scheduled messages: {}", e);
+                return;
+            },
+        };
+
+        for ScheduledMessage { mut message, .. } in due {
+            // Sent messages are timestamped when they go out, not when they were queued
+            message.timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+            let result = self.send_message(&message).await;
+            if let Err(WhatsAppError::ConnectionError(e)) = &result {
+                warn!("Scheduled message {} not sent yet: {}", message.id, e);
+                return;
+            }
+            if let Err(e) = self.cancel_scheduled_message(&message.id) {
+                warn!("Failed to remove scheduled message {} from the queue: {}", message.id, e);
+            }
+
+            match result {
+                Ok(id) => {
+                    info!("Sent scheduled message {}", id);
+                    self.dispatch_event(Event::ScheduledMessageSent { id, chat: message.chat_jid });
+                },
+                Err(e) => {
+                    warn!("Dropping scheduled message {}: {}", message.id, e);
+                    self.dispatch_event(Event::Error(e));
+                },
+            }
+        }
+    }
+
+    /// Change the queue and write it back to the store
+    fn update_schedule<T>(&self, update: impl FnOnce(&mut Vec<ScheduledMessage>) -> T) -> WhatsAppResult<T> {
+        let _guard = self.schedule_lock.lock().unwrap();
+        let mut queue = self.load_schedule()?;
+        let result = update(&mut queue);
+
+        let value = serde_json::to_string(&queue)
+            .map_err(|e| WhatsAppError::SerializationError(e.to_string()))?;
+        self.store.set(SCHEDULED_MESSAGES_KEY, &value)?;
+        Ok(result)
+    }
+
+    fn load_schedule(&self) -> WhatsAppResult<Vec<ScheduledMessage>> {
+        match self.store.get(SCHEDULED_MESSAGES_KEY) {
+            Some(value) => serde_json::from_str(&value)
+                .map_err(|e| WhatsAppError::StoreError(format!("Corrupt scheduled messages: {}", e))),
+            None => Ok(Vec::new()),
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use log::{debug, warn};
+use serde_json::json;
+
+use super::Client;
+use crate::{
+    JID,
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+    message::Message,
+    signal::{self, PreKeyBundle},
+};
+
+/// Maximum number of times a message is re-sent to newly discovered devices
+const MAX_DEVICE_RESENDS: usize = 3;
+
+/// Message ciphertext addressed to a single recipient device
+pub(super) struct DeviceEnvelope {
+    jid: JID,
+    /// Signal message type, `pkmsg` or `msg`
+    kind: &'static str,
+    ciphertext: Vec<u8>,
+}
+
+/// Server acknowledgement of a sent message
+pub(super) struct MessageAck {
+    pub(super) phash: Option<String>,
+    pub(super) error: Option<String>,
+}
+
+impl Client {
+    /// Encrypt a message for every device of the recipient and send it
+    ///
+    /// Messages to a broadcast list are encrypted for every device of each of
+    /// its recipients instead.
+    ///
+    /// If the server reports a different device list than the one the message
+    /// was encrypted for, the device list is refreshed and the message is
+    /// re-encrypted and re-sent for the new devices only, so the caller just
+    /// sees the final result.
+    pub(super) async fn send_to_devices(&self, message: &Message) -> WhatsAppResult<()> {
+        // Group messages are encrypted once with our sender key instead
+        if message.chat_jid.is_group() {
+            let info = self.get_group_info(&message.chat_jid).await?;
+            let members: Vec<JID> = info.participants.into_iter().map(|participant| participant.jid).collect();
+            return self.send_with_sender_key(message, &members).await;
+        }
+
+        let users = if message.chat_jid.is_broadcast_list() {
+            let recipients = self.get_broadcast_list_recipients(&message.chat_jid).await?;
+            Self::users_of(&recipients)
+        } else {
+            vec![JID::new(&message.chat_jid.user, &message.chat_jid.server, None)]
+        };
+        let plaintext = message.to_json()?.into_bytes();
+
+        // Devices without a prekey bundle can't be sent to, and don't count for the hash either
+        let mut devices = self.get_devices(&users).await?;
+        let mut skipped = self.establish_sessions(&devices).await?;
+        devices.retain(|device| !skipped.contains(device));
+        if devices.is_empty() {
+            return Err(WhatsAppError::MessageSendError(format!("No device of {} can receive {}", message.chat_jid, message.id)));
+        }
+        let mut envelopes = self.encrypt_for_devices(&plaintext, &devices)?;
+
+        for _ in 0..=MAX_DEVICE_RESENDS {
+            let phash = Self::participant_hash(&devices);
+            let ack = self.send_envelopes(message, &phash, &envelopes).await?;
+
+            if ack.error.is_none() && ack.phash.as_deref().is_none_or(|server| server == phash) {
+                return Ok(());
+            }
+
+            debug!("Device list for {} changed while sending {}", message.chat_jid, message.id);
+
+            let refreshed = self.fetch_devices(&users).await?;
+            let mut new_devices: Vec<JID> = refreshed.iter()
+                .filter(|device| !devices.contains(device) && !skipped.contains(device))
+                .cloned()
+                .collect();
+            let unreachable = self.establish_sessions(&new_devices).await?;
+            new_devices.retain(|device| !unreachable.contains(device));
+            skipped.extend(unreachable);
+
+            if new_devices.is_empty() {
+                // Only devices we already encrypted for, removed ones or unreachable ones remain
+                return match ack.error {
+                    Some(error) => Err(WhatsAppError::MessageSendError(error)),
+                    None => Ok(()),
+                };
+            }
+
+            envelopes = self.encrypt_for_devices(&plaintext, &new_devices)?;
+            devices = refreshed.into_iter().filter(|device| !skipped.contains(device)).collect();
+        }
+
+        Err(WhatsAppError::MessageSendError(format!(
+            "Device list for {} kept changing while sending {}",
+            message.chat_jid, message.id
+        )))
+    }
+
+    /// Encrypt a message once with our sender key in its chat and send it to the recipients
+    ///
+    /// Devices of the recipients that don't have our sender key yet get it over
+    /// their pairwise session along with the message, so they can decrypt it
+    /// and later ones. Which devices have it is only remembered until restart,
+    /// after which every device gets it once more.
+    pub(super) async fn send_with_sender_key(&self, message: &Message, recipients: &[JID]) -> WhatsAppResult<()> {
+        let own = self.paired_jid()
+            .ok_or_else(|| WhatsAppError::AuthError("Not paired".to_string()))?;
+        let users = Self::users_of(recipients);
+
+        let devices = self.get_devices(&users).await?;
+        let mut missing: Vec<JID> = {
+            let distributed = self.sender_key_devices.lock().unwrap();
+            let distributed = distributed.get(&message.chat_jid);
+            devices.into_iter()
+                .filter(|device| *device != own && !distributed.is_some_and(|devices| devices.contains(device)))
+                .collect()
+        };
+        let skipped = self.establish_sessions(&missing).await?;
+        missing.retain(|device| !skipped.contains(device));
+
+        let (distribution, ciphertext) = {
+            let mut group_cipher = self.group_cipher.lock().unwrap();
+            let distribution = group_cipher.distribution_message(&message.chat_jid, &own)?;
+            let ciphertext = group_cipher.encrypt(&message.chat_jid, &own, message.to_json()?.as_bytes())?;
+            (distribution, ciphertext)
+        };
+        let carrier = Message::new_sender_key_distribution(message.chat_jid.clone(), distribution.serialize());
+        let envelopes = self.encrypt_for_devices(carrier.to_json()?.as_bytes(), &missing)?;
+
+        let participants: Vec<_> = envelopes.iter()
+            .map(|envelope| json!({
+                "jid": envelope.jid.to_string(),
+                "type": envelope.kind,
+                "ciphertext": Crypto::base64_encode(&envelope.ciphertext),
+            }))
+            .collect();
+        let frame = json!(["message", {
+            "id": message.id,
+            "to": message.chat_jid.to_string(),
+            "type": "skmsg",
+            "ciphertext": Crypto::base64_encode(ciphertext.serialize()),
+            "participants": participants,
+        }]);
+
+        let ack = self.send_and_wait(&message.id, frame).await?;
+        match ack_error(&ack) {
+            Some(error) => {
+                warn!("Message {} rejected: {}", message.id, error);
+                Err(WhatsAppError::MessageSendError(error))
+            },
+            None => {
+                self.sender_key_devices.lock().unwrap()
+                    .entry(message.chat_jid.clone())
+                    .or_default()
+                    .extend(missing);
+                Ok(())
+            },
+        }
+    }
+
+    /// Replace our sender key in a group with a new one, e.g. after someone left,
+    /// so they can't read what is sent after
+    pub(super) fn rotate_sender_key(&self, group_jid: &JID) -> WhatsAppResult<()> {
+        self.sender_key_devices.lock().unwrap().remove(group_jid);
+        match self.paired_jid() {
+            Some(own) => self.group_cipher.lock().unwrap().rotate(group_jid, &own),
+            None => Ok(()),
+        }
+    }
+
+    /// Make sure there is a Signal session with each of the devices
+    ///
+    /// Prekey bundles are fetched for devices without a session, which are
+    /// then started with X3DH. Devices the server has no bundle for are
+    /// returned, as nothing can be encrypted for them.
+    pub(super) async fn establish_sessions(&self, devices: &[JID]) -> WhatsAppResult<Vec<JID>> {
+        let missing: Vec<&JID> = {
+            let mut signal = self.signal.lock().unwrap();
+            let mut missing = Vec::new();
+            for device in devices {
+                if !signal.has_session(device)? {
+                    missing.push(device);
+                }
+            }
+            missing
+        };
+        if missing.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let jids: Vec<String> = missing.iter().map(|device| device.to_string()).collect();
+        let response = self.query("query", json!({ "type": "prekeys", "jids": jids })).await?;
+
+        let mut skipped = Vec::new();
+        for (device, address) in missing.into_iter().zip(jids) {
+            let Some(bundle) = response["bundles"].get(&address) else {
+                warn!("No prekey bundle for {}, skipping it", device);
+                skipped.push(device.clone());
+                continue;
+            };
+
+            debug!("Establishing session with {}", device);
+            let bundle = Self::parse_pre_key_bundle(bundle)?;
+            let identity_key = signal::public_key(&bundle.identity_key)?;
+
+            self.check_identity(device, &identity_key)?;
+            self.signal.lock().unwrap().process_bundle(device, &bundle)?;
+            self.save_identity(device, &identity_key)?;
+        }
+
+        Ok(skipped)
+    }
+
+    /// Parse a prekey bundle from a query response, keys being base64-encoded
+    fn parse_pre_key_bundle(bundle: &serde_json::Value) -> WhatsAppResult<PreKeyBundle> {
+        let number = |name: &str| {
+            bundle[name].as_u64()
+                .map(|value| value as u32)
+                .ok_or_else(|| WhatsAppError::DeserializationError(format!("Prekey bundle has no {}", name)))
+        };
+        let key = |name: &str| match bundle[name].as_str() {
+            Some(key) => Crypto::base64_decode(key),
+            None => Err(WhatsAppError::DeserializationError(format!("Prekey bundle has no {}", name))),
+        };
+
+        Ok(PreKeyBundle {
+            registration_id: number("registration_id")?,
+            device_id: number("device_id").unwrap_or(0),
+            identity_key: key("identity_key")?,
+            signed_pre_key_id: number("signed_pre_key_id")?,
+            signed_pre_key: key("signed_pre_key")?,
+            signed_pre_key_signature: key("signed_pre_key_signature")?,
+            pre_key_id: number("pre_key_id").ok(),
+            pre_key: key("pre_key").ok(),
+        })
+    }
+
+    /// Encrypt the plaintext separately for each device
+    pub(super) fn encrypt_for_devices(&self, plaintext: &[u8], devices: &[JID]) -> WhatsAppResult<Vec<DeviceEnvelope>> {
+        let mut signal = self.signal.lock().unwrap();
+
+        devices.iter()
+            .map(|device| {
+                let message = signal.encrypt(device, plaintext)?;
+
+                Ok(DeviceEnvelope {
+                    jid: device.clone(),
+                    kind: message.kind(),
+                    ciphertext: message.serialize().to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    /// Send the envelopes of a message and wait for the server ack
+    pub(super) async fn send_envelopes(&self, message: &Message, phash: &str, envelopes: &[DeviceEnvelope]) -> WhatsAppResult<MessageAck> {
+        let participants: Vec<_> = envelopes.iter()
+            .map(|envelope| json!({
+                "jid": envelope.jid.to_string(),
+                "type": envelope.kind,
+                "ciphertext": Crypto::base64_encode(&envelope.ciphertext),
+            }))
+            .collect();
+
+        let frame = json!(["message", {
+            "id": message.id,
+            "to": message.chat_jid.to_string(),
+            "phash": phash,
+            "participants": participants,
+        }]);
+
+        let ack = self.send_and_wait(&message.id, frame).await?;
+        let error = ack_error(&ack);
+
+        if let Some(error) = &error {
+            warn!("Message {} rejected: {}", message.id, error);
+        }
+
+        Ok(MessageAck {
+            phash: ack["phash"].as_str().map(|phash| phash.to_string()),
+            error,
+        })
+    }
+
+    /// Get the users of the given JIDs without their devices, each once
+    fn users_of(jids: &[JID]) -> Vec<JID> {
+        let mut users = Vec::new();
+        for jid in jids {
+            let user = JID::new(&jid.user, &jid.server, None);
+            if !users.contains(&user) {
+                users.push(user);
+            }
+        }
+        users
+    }
+
+    /// Compute the participant list hash the server uses to detect stale device lists
+    pub(super) fn participant_hash(devices: &[JID]) -> String {
+        let mut jids: Vec<String> = devices.iter().map(|device| device.to_string()).collect();
+        jids.sort();
+
+        let hash = Crypto::sha256(jids.concat().as_bytes());
+        format!("2:{}", &Crypto::base64_encode(&hash)[..6])
+    }
+}
+
+/// Get the error of a server ack, given as a message or a code
+pub(super) fn ack_error(ack: &serde_json::Value) -> Option<String> {
+    ack["error"].as_str().map(|error| error.to_string())
+        .or_else(|| ack["error"].as_u64().map(|code| format!("Server error {}", code)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+    use crate::{
+        Event,
+        client::ClientConfig,
+        ratelimit::{RateLimit, RateLimitConfig},
+        signal::{LocalIdentity, PreKeySignalMessage, SessionState},
+        transport::{Transport, TransportEvent},
+        websocket::WebSocketMessage,
+    };
+
+    /// In-memory server answering device, prekey and message requests for one user
+    struct MockServer {
+        /// Devices of the user the server knows of
+        devices: Vec<u32>,
+        /// Identities of the devices that have a prekey bundle
+        identities: HashMap<u32, LocalIdentity>,
+        /// Message frames received, in order
+        messages: Mutex<Vec<serde_json::Value>>,
+        incoming: UnboundedSender<TransportEvent>,
+        receiver: tokio::sync::Mutex<UnboundedReceiver<TransportEvent>>,
+    }
+
+    impl MockServer {
+        fn new(devices: &[u32], with_bundles: &[u32]) -> Arc<Self> {
+            let (incoming, receiver) = mpsc::unbounded_channel();
+            Arc::new(Self {
+                devices: devices.to_vec(),
+                identities: with_bundles.iter().map(|&device| (device, LocalIdentity::generate(1).unwrap())).collect(),
+                messages: Mutex::new(Vec::new()),
+                incoming,
+                receiver: tokio::sync::Mutex::new(receiver),
+            })
+        }
+
+        fn device_jid(device: u32) -> JID {
+            JID::new("1111", "s.whatsapp.net", if device == 0 { None } else { Some(device) })
+        }
+
+        fn bundle(identity: &LocalIdentity, device: u32) -> serde_json::Value {
+            json!({
+                "registration_id": identity.registration_id,
+                "device_id": device,
+                "identity_key": Crypto::base64_encode(&identity.identity.public),
+                "signed_pre_key_id": identity.signed_pre_key_id,
+                "signed_pre_key": Crypto::base64_encode(&identity.signed_pre_key.public),
+                "signed_pre_key_signature": Crypto::base64_encode(&identity.signed_pre_key_signature),
+                "pre_key_id": 1,
+                "pre_key": Crypto::base64_encode(&identity.pre_keys[&1].public),
+            })
+        }
+
+        fn answer(&self, kind: &str, payload: &serde_json::Value) -> serde_json::Value {
+            let id = payload["id"].clone();
+            match (kind, payload["type"].as_str()) {
+                ("query", Some("devices")) => json!(["Response", { "id": id, "devices": { "1111@s.whatsapp.net": self.devices } }]),
+                ("query", Some("prekeys")) => {
+                    let bundles: serde_json::Map<_, _> = self.identities.iter()
+                        .map(|(&device, identity)| (Self::device_jid(device).to_string(), Self::bundle(identity, device)))
+                        .filter(|(jid, _)| payload["jids"].as_array().unwrap().iter().any(|requested| requested == jid))
+                        .collect();
+                    json!(["Response", { "id": id, "bundles": bundles }])
+                },
+                ("message", _) => {
+                    self.messages.lock().unwrap().push(payload.clone());
+                    let devices: Vec<JID> = self.devices.iter().map(|&device| Self::device_jid(device)).collect();
+                    json!(["Ack", { "id": id, "phash": Client::participant_hash(&devices) }])
+                },
+                _ => panic!("Unexpected {} frame: {}", kind, payload),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Transport for MockServer {
+        async fn connect(&self) -> WhatsAppResult<()> {
+            Ok(())
+        }
+
+        async fn send(&self, message: WebSocketMessage) -> WhatsAppResult<()> {
+            self.try_send(message)
+        }
+
+        fn try_send(&self, message: WebSocketMessage) -> WhatsAppResult<()> {
+            let WebSocketMessage::Text(text) = message else {
+                panic!("Unexpected frame");
+            };
+            let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+            let answer = self.answer(frame[0].as_str().unwrap(), &frame[1]);
+            let _ = self.incoming.send(TransportEvent::Text(answer.to_string()));
+            Ok(())
+        }
+
+        async fn recv(&self) -> Option<TransportEvent> {
+            self.receiver.lock().await.recv().await
+        }
+
+        async fn close(&self) -> WhatsAppResult<()> {
+            Ok(())
+        }
+
+        fn abort(&self) {}
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+    }
+
+    fn client(server: &Arc<MockServer>) -> (Arc<Client>, String) {
+        client_with_config(server, ClientConfig::default())
+    }
+
+    fn client_with_config(server: &Arc<MockServer>, config: ClientConfig) -> (Arc<Client>, String) {
+        let store_path = std::env::temp_dir()
+            .join(format!("whatsandra-send-{}", hex::encode(Crypto::random_bytes(8))))
+            .to_string_lossy()
+            .into_owned();
+        let config = ClientConfig { store_path: store_path.clone(), ..config };
+
+        let client = Client::try_with_transport(config, server.clone()).unwrap();
+        client.start_receiving();
+        (client, store_path)
+    }
+
+    /// Participants of a message frame as (jid, type) pairs
+    fn participants(frame: &serde_json::Value) -> Vec<(String, String)> {
+        frame["participants"].as_array().unwrap().iter()
+            .map(|participant| (participant["jid"].as_str().unwrap().to_string(), participant["type"].as_str().unwrap().to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resends_only_to_new_devices_on_phash_mismatch() {
+        let server = MockServer::new(&[0, 2], &[0, 2]);
+        let (client, store_path) = client(&server);
+        // The cached device list predates device 2
+        client.devices.lock().unwrap().insert("1111@s.whatsapp.net".to_string(), vec![0]);
+
+        let message = Message::new_text(JID::new("1111", "s.whatsapp.net", None), "hello");
+        client.send_to_devices(&message).await.unwrap();
+
+        let messages = server.messages.lock().unwrap().clone();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(participants(&messages[0]), [("1111@s.whatsapp.net".to_string(), "pkmsg".to_string())]);
+        assert_eq!(messages[0]["phash"], Client::participant_hash(&[MockServer::device_jid(0)]));
+        assert_eq!(participants(&messages[1]), [("1111:2@s.whatsapp.net".to_string(), "pkmsg".to_string())]);
+        assert_eq!(messages[1]["phash"], Client::participant_hash(&[MockServer::device_jid(0), MockServer::device_jid(2)]));
+
+        // The new device can read what it got
+        let owner = &server.identities[&2];
+        let mut identity = LocalIdentity {
+            registration_id: owner.registration_id,
+            identity: owner.identity.clone(),
+            signed_pre_key_id: owner.signed_pre_key_id,
+            signed_pre_key: owner.signed_pre_key.clone(),
+            signed_pre_key_signature: owner.signed_pre_key_signature.clone(),
+            pre_keys: owner.pre_keys.clone(),
+        };
+        let ciphertext = Crypto::base64_decode(messages[1]["participants"][0]["ciphertext"].as_str().unwrap()).unwrap();
+        let (_, plaintext) = SessionState::accept(&mut identity, &PreKeySignalMessage::decode(&ciphertext).unwrap()).unwrap();
+        assert_eq!(plaintext, message.to_json().unwrap().into_bytes());
+
+        let _ = std::fs::remove_dir_all(store_path);
+    }
+
+    #[tokio::test]
+    async fn skips_devices_without_a_bundle() {
+        let server = MockServer::new(&[0, 3], &[0]);
+        let (client, store_path) = client(&server);
+
+        let message = Message::new_text(JID::new("1111", "s.whatsapp.net", None), "hello");
+        client.send_to_devices(&message).await.unwrap();
+
+        // Device 3 is left out of the envelopes and the hash, and isn't retried
+        let messages = server.messages.lock().unwrap().clone();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(participants(&messages[0]), [("1111@s.whatsapp.net".to_string(), "pkmsg".to_string())]);
+        assert_eq!(messages[0]["phash"], Client::participant_hash(&[MockServer::device_jid(0)]));
+
+        let _ = std::fs::remove_dir_all(store_path);
+    }
+
+    #[tokio::test]
+    async fn fails_without_any_reachable_device() {
+        let server = MockServer::new(&[0, 3], &[]);
+        let (client, store_path) = client(&server);
+
+        let message = Message::new_text(JID::new("1111", "s.whatsapp.net", None), "hello");
+        assert!(matches!(client.send_to_devices(&message).await, Err(WhatsAppError::MessageSendError(_))));
+        assert!(server.messages.lock().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(store_path);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_rate_limited_messages() {
+        let server = MockServer::new(&[0], &[0]);
+        let rate_limit = RateLimitConfig { global: None, per_chat: Some(RateLimit { per_second: 1.0, burst: 1 }) };
+        let (client, store_path) = client_with_config(&server, ClientConfig { rate_limit, ..ClientConfig::default() });
+        client.pairing_keys().unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let handled = events.clone();
+        client.add_event_handler(move |event| {
+            if let Event::RateLimited(chat, delay) = event {
+                handled.lock().unwrap().push((chat, delay));
+            }
+        });
+
+        let chat = JID::new("1111", "s.whatsapp.net", None);
+        let started = tokio::time::Instant::now();
+        client.send_message(&Message::new_text(chat.clone(), "first")).await.unwrap();
+        assert!(events.lock().unwrap().is_empty());
+
+        // The second message waits for the chat's bucket to refill before it goes out
+        client.send_message(&Message::new_text(chat.clone(), "second")).await.unwrap();
+        assert_eq!(*events.lock().unwrap(), [(chat, Duration::from_secs(1))]);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(server.messages.lock().unwrap().len(), 2);
+
+        let _ = std::fs::remove_dir_all(store_path);
+    }
+}
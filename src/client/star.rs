@@ -0,0 +1,14 @@
+use super::Client;
+use crate::{
+    JID,
+    appstate::Patch,
+    error::WhatsAppResult,
+};
+
+impl Client {
+    /// Star or unstar a message on all our devices
+    pub async fn star_message(&self, chat: &JID, message_id: &str, sender: &JID, starred: bool) -> WhatsAppResult<()> {
+        let key = self.key_for_sender(chat, message_id, sender);
+        self.send_app_state_patch(&Patch::star(&key, starred)).await
+    }
+}
@@ -0,0 +1,25 @@
+use super::Client;
+use crate::{
+    JID,
+    error::WhatsAppResult,
+    message::Message,
+};
+
+impl Client {
+    /// Post a text, image or video message as a status, shown to the given contacts
+    ///
+    /// The message is encrypted once with our status sender key, which each
+    /// of the contacts' devices gets over its own session.
+    pub async fn post_status(&self, mut message: Message, recipients: &[JID]) -> WhatsAppResult<String> {
+        message.chat_jid = JID::status_broadcast();
+        self.send_with_sender_key(&message, recipients).await?;
+
+        Ok(message.id)
+    }
+
+    /// Tell a contact we viewed their statuses
+    pub async fn mark_status_viewed(&self, sender: &JID, status_ids: &[String]) -> WhatsAppResult<()> {
+        let sender = sender.to_string();
+        self.send_receipt(&JID::status_broadcast().to_string(), Some(&sender), status_ids, Some("read")).await
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info, warn};
+
+use super::{Client, ConnectionState};
+use crate::{Event, error::WhatsAppError};
+
+impl Client {
+    /// Handle a stream error the server sends before closing the connection
+    ///
+    /// Frames look like `["stream:error",{"code":401,"conflict":"device_removed"}]`.
+    pub(super) fn handle_stream_error(self: &Arc<Self>, payload: &serde_json::Value) {
+        let code = payload["code"].as_u64().unwrap_or_default();
+        let conflict = payload["conflict"].as_str();
+
+        match (code, conflict) {
+            (401, _) | (_, Some("device_removed")) => self.handle_remote_logout(conflict.unwrap_or("unauthorized")),
+            (409, _) | (_, Some("replaced")) => {
+                self.handle_connection_event(Event::StreamReplaced(conflict.unwrap_or("replaced").to_string()));
+            },
+            (503 | 515, _) => {
+                info!("Server asked to reconnect with stream error {}", code);
+                *self.reconnect_pending.lock().unwrap() = true;
+            },
+            _ => warn!("Unhandled stream error: {}", payload),
+        }
+    }
+
+    /// Handle the server accepting the login of a paired device
+    ///
+    /// Frames look like `["success",{"lid":"...@lid","pushname":"..."}]`, both
+    /// being optional. They update what was learned while pairing.
+    pub(super) fn handle_login_success(&self, payload: &serde_json::Value) {
+        let event = {
+            let mut auth_state = self.auth_state.lock().unwrap();
+            let Some(auth_state) = auth_state.as_mut().filter(|auth_state| auth_state.account.is_some()) else {
+                warn!("Login success without being paired");
+                return;
+            };
+            let Some(jid) = auth_state.jid.clone() else {
+                warn!("Login success without a paired JID");
+                return;
+            };
+
+            if let Some(lid) = payload["lid"].as_str().and_then(|lid| lid.parse().ok()) {
+                auth_state.lid = Some(lid);
+            }
+            if let Some(push_name) = payload["pushname"].as_str().filter(|name| !name.is_empty()) {
+                auth_state.push_name = Some(push_name.to_string());
+            }
+
+            Event::LoggedIn {
+                jid,
+                lid: auth_state.lid.clone(),
+                platform: auth_state.platform.clone(),
+                push_name: auth_state.push_name.clone(),
+                business: auth_state.business_name.is_some(),
+            }
+        };
+        if let Err(e) = self.save_credentials() {
+            error!("Failed to save credentials: {}", e);
+        }
+
+        info!("Logged in: {:?}", event);
+        self.set_state(ConnectionState::Online);
+        self.dispatch_event(event);
+    }
+
+    /// Handle the server refusing our login
+    ///
+    /// Frames look like `["failure",{"reason":402,"code":101,"expire":86400}]`.
+    pub(super) fn handle_login_failure(&self, payload: &serde_json::Value) {
+        let reason = payload["reason"].as_u64().unwrap_or_default();
+
+        match reason {
+            401 | 403 | 406 => self.handle_remote_logout(&format!("login failure {}", reason)),
+            402 => {
+                let code = payload["code"].as_u64().unwrap_or_default();
+                let expires_in = payload["expire"].as_u64().map(Duration::from_secs);
+                warn!("Account temporarily banned ({}), expires in {:?}", code, expires_in);
+
+                *self.reconnect_pending.lock().unwrap() = false;
+                self.dispatch_event(Event::TemporaryBan {
+                    reason: temporary_ban_reason(code),
+                    expires_in,
+                });
+            },
+            _ => {
+                warn!("Login failed: {}", payload);
+                self.dispatch_event(Event::Error(WhatsAppError::AuthError(format!("Login failed with reason {}", reason))));
+            },
+        }
+    }
+
+    /// Forget the credentials after the phone unlinked this device or the server rejected them
+    fn handle_remote_logout(&self, reason: &str) {
+        warn!("Logged out by the server: {}", reason);
+
+        // Reconnecting would only be rejected again
+        *self.reconnect_pending.lock().unwrap() = false;
+        if let Err(e) = self.clear_credentials() {
+            error!("Failed to clear credentials: {}", e);
+        }
+
+        self.dispatch_event(Event::LoggedOut(reason.to_string()));
+    }
+}
+
+/// Describe the code of a temporary ban
+fn temporary_ban_reason(code: u64) -> String {
+    match code {
+        101 => "sent to too many people".to_string(),
+        102 => "blocked by too many users".to_string(),
+        103 => "created too many groups".to_string(),
+        104 => "sent too many of the same message".to_string(),
+        106 => "sent too many messages to a broadcast list".to_string(),
+        code => format!("unknown reason {}", code),
+    }
+}
@@ -1,16 +1,151 @@
 use hmac::{Hmac, Mac};
-use sha2::{Sha256, Digest};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512, Digest};
 use rand::{thread_rng, Rng};
 use base64::{Engine as _, engine::general_purpose};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{self, Ordering};
 
 use crate::error::WhatsAppError;
+use backend::{Backend, Selected};
+
+mod backend;
+mod curve25519;
+pub mod media;
+
+/// AES block and IV size
+const AES_BLOCK_SIZE: usize = 16;
+
+/// Longest HKDF-SHA256 output, 255 hash blocks
+const HKDF_MAX_LENGTH: usize = 255 * 32;
+
+/// Size of the AES-GCM nonce
+pub const GCM_NONCE_SIZE: usize = 12;
+
+/// Size of the AES-GCM authentication tag appended to every ciphertext
+pub const GCM_TAG_SIZE: usize = 16;
+
+/// Size of an Ed25519 signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Secret bytes that are wiped from memory when dropped and never printed
+///
+/// Only the final buffer is wiped, so secrets should be built at their full
+/// size rather than grown in place.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    /// Join several secrets into one without intermediate copies
+    pub fn concat(parts: &[&[u8]]) -> Self {
+        let mut bytes = Vec::with_capacity(parts.iter().map(|part| part.len()).sum());
+        for part in parts {
+            bytes.extend_from_slice(part);
+        }
+        Self(bytes)
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.0.len())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+/// Overwrite a buffer with zeros in a way the compiler can't optimize away
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: the pointer comes from a valid mutable reference
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
 
 /// Key pair for encryption
+#[derive(Clone)]
 pub struct KeyPair {
-    pub private: Vec<u8>,
+    pub private: SecretBytes,
     pub public: Vec<u8>,
 }
 
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("private", &self.private)
+            .field("public", &hex::encode(&self.public))
+            .finish()
+    }
+}
+
+impl KeyPair {
+    /// X25519 Diffie-Hellman agreement with another party's public key
+    pub fn dh(&self, their_public: &[u8]) -> Result<SecretBytes, WhatsAppError> {
+        Selected::x25519(&self.private, their_public)
+    }
+
+    /// Sign a message with this Ed25519 key pair
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        Crypto::ed25519_sign(&self.private, message)
+    }
+}
+
+/// Counter producing the 96-bit AES-GCM nonces of a key
+///
+/// The counter is stored big-endian in the last four bytes of the nonce, as
+/// the Noise transport expects. Each nonce is handed out only once.
+#[derive(Debug, Default)]
+pub struct NonceCounter {
+    counter: u64,
+}
+
+impl NonceCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the nonce for the next message
+    pub fn next_nonce(&mut self) -> Result<[u8; GCM_NONCE_SIZE], WhatsAppError> {
+        let counter = u32::try_from(self.counter)
+            .map_err(|_| WhatsAppError::CryptoError("Nonce counter exhausted".to_string()))?;
+        self.counter += 1;
+
+        let mut nonce = [0u8; GCM_NONCE_SIZE];
+        nonce[8..].copy_from_slice(&counter.to_be_bytes());
+        Ok(nonce)
+    }
+}
+
 /// Implements cryptographic functions for WhatsApp
 pub struct Crypto;
 
@@ -23,14 +158,93 @@ impl Crypto {
         bytes
     }
 
-    /// Generate a key pair for encryption
+    /// Generate an X25519 key pair
     pub fn generate_key_pair() -> Result<KeyPair, WhatsAppError> {
-        // In a real implementation, this would use proper curve25519 functions
-        // For this port example, we'll use random bytes as a placeholder
-        let private = Self::random_bytes(32);
-        let public = Self::random_bytes(32);
+        Selected::generate_x25519()
+    }
+
+    /// HMAC-SHA512 signature
+    pub fn hmac_sha512(key: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key)
+            .map_err(|e| WhatsAppError::CryptoError(e.to_string()))?;
+
+        mac.update(data);
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    /// HMAC-SHA512 truncated to its first `length` bytes
+    pub fn hmac_sha512_trunc(key: &[u8], data: &[u8], length: usize) -> Result<Vec<u8>, WhatsAppError> {
+        let mut mac = Self::hmac_sha512(key, data)?;
+        mac.truncate(length);
+        Ok(mac)
+    }
+
+    /// Verify an HMAC-SHA256 in constant time
+    ///
+    /// The expected MAC may be truncated, in which case it is compared with
+    /// the start of the computed one. An empty MAC never matches.
+    pub fn verify_hmac_sha256(key: &[u8], data: &[u8], expected: &[u8]) -> Result<(), WhatsAppError> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key)
+            .map_err(|e| WhatsAppError::CryptoError(e.to_string()))?;
+
+        mac.update(data);
+        mac.verify_truncated_left(expected)
+            .map_err(|_| WhatsAppError::CryptoError("HMAC mismatch".to_string()))
+    }
+
+    /// Compare two byte strings in constant time
+    ///
+    /// Only the contents are protected; strings of different lengths return
+    /// early.
+    pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+
+    /// Generate an Ed25519 key pair for signing
+    pub fn generate_signing_key_pair() -> Result<KeyPair, WhatsAppError> {
+        Selected::generate_ed25519()
+    }
+
+    /// Sign a message with an Ed25519 private key
+    pub fn ed25519_sign(private: &[u8], message: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        Selected::ed25519_sign(private, message)
+    }
+
+    /// Verify the Ed25519 signature of a message
+    pub fn ed25519_verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<(), WhatsAppError> {
+        if signature.len() != SIGNATURE_SIZE {
+            return Err(WhatsAppError::CryptoError(format!("Ed25519 signature must be {} bytes, got {}", SIGNATURE_SIZE, signature.len())));
+        }
+
+        match Selected::ed25519_verify(public, message, signature)? {
+            true => Ok(()),
+            false => Err(WhatsAppError::CryptoError("Invalid Ed25519 signature".to_string())),
+        }
+    }
 
-        Ok(KeyPair { private, public })
+    /// XEdDSA signature of a message with an X25519 private key
+    pub fn xeddsa_sign(private: &[u8], message: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let private: &[u8; 32] = private.try_into()
+            .map_err(|_| WhatsAppError::CryptoError(format!("Curve25519 private key must be 32 bytes, got {}", private.len())))?;
+
+        let random: [u8; 64] = Self::random_bytes(64).try_into().unwrap();
+        Ok(curve25519::sign(private, message, &random).to_vec())
+    }
+
+    /// Verify the XEdDSA signature of a message against an X25519 public key
+    pub fn xeddsa_verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<(), WhatsAppError> {
+        let public: &[u8; 32] = public.try_into()
+            .map_err(|_| WhatsAppError::CryptoError(format!("Curve25519 public key must be 32 bytes, got {}", public.len())))?;
+        if signature.len() != SIGNATURE_SIZE {
+            return Err(WhatsAppError::CryptoError(format!("XEdDSA signature must be {} bytes, got {}", SIGNATURE_SIZE, signature.len())));
+        }
+
+        let invalid = || WhatsAppError::CryptoError("Invalid XEdDSA signature".to_string());
+        let edwards = curve25519::edwards_public_key(public, signature[63] >> 7).ok_or_else(invalid)?;
+        let mut signature = signature.to_vec();
+        signature[63] &= 0x7f;
+
+        Self::ed25519_verify(&edwards, message, &signature).map_err(|_| invalid())
     }
 
     /// HMAC-SHA256 signature
@@ -44,58 +258,103 @@ impl Crypto {
         Ok(result.into_bytes().to_vec())
     }
 
-    /// HKDF (HMAC-based Key Derivation Function)
-    pub fn hkdf(master: &[u8], app_info: &[u8], length: usize) -> Result<Vec<u8>, WhatsAppError> {
+    /// HKDF-SHA256 with the zero salt most WhatsApp derivations use
+    pub fn hkdf(master: &[u8], app_info: &[u8], length: usize) -> Result<SecretBytes, WhatsAppError> {
+        Self::hkdf_with_salt(None, master, app_info, length)
+    }
+
+    /// HKDF-SHA256 (RFC 5869); without a salt, a string of zeros is used
+    pub fn hkdf_with_salt(salt: Option<&[u8]>, master: &[u8], app_info: &[u8], length: usize) -> Result<SecretBytes, WhatsAppError> {
+        if length > HKDF_MAX_LENGTH {
+            return Err(WhatsAppError::CryptoError(format!("HKDF output of {} bytes is too long", length)));
+        }
+
         // Extract phase
-        let salt = [0u8; 32]; // Zero salt for WhatsApp
-        let prk = Self::hmac_sha256(&salt, master)?;
+        let prk = SecretBytes::new(Self::hmac_sha256(salt.unwrap_or(&[0u8; 32]), master)?);
 
-        // Expand phase
-        let mut output = Vec::new();
-        let mut t = Vec::new();
+        // Expand phase, with room for the last block so the output never moves
+        let mut output = SecretBytes::new(Vec::with_capacity(length + 32));
+        let mut t = SecretBytes::default();
         let mut counter = 1u8;
 
         while output.len() < length {
             // T(N) = HMAC-SHA-256(PRK, T(N-1) | info | N)
-            let mut input = t.clone();
-            input.extend_from_slice(app_info);
-            input.push(counter);
+            let mut input = SecretBytes::new(Vec::with_capacity(t.len() + app_info.len() + 1));
+            input.0.extend_from_slice(&t);
+            input.0.extend_from_slice(app_info);
+            input.0.push(counter);
 
-            t = Self::hmac_sha256(&prk, &input)?;
-            output.extend_from_slice(&t);
+            t = SecretBytes::new(Self::hmac_sha256(&prk, &input)?);
+            output.0.extend_from_slice(&t);
 
-            counter += 1;
+            counter = counter.wrapping_add(1);
         }
 
-        output.truncate(length);
+        zeroize(&mut output.0[length..]);
+        output.0.truncate(length);
         Ok(output)
     }
 
-    /// AES-256-CBC encrypt
+    /// PBKDF2-HMAC-SHA256 key derivation from a passphrase
+    pub fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, length: usize) -> Result<SecretBytes, WhatsAppError> {
+        let mut key = SecretBytes::new(vec![0u8; length]);
+        Selected::pbkdf2_sha256(passphrase, salt, iterations, &mut key.0)?;
+        Ok(key)
+    }
+
+    /// AES-256-CBC encrypt with PKCS#7 padding
     pub fn aes_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
-        // In a real implementation, this would use proper AES encryption
-        // For this port example, we'll use a placeholder
+        check_aes_params(key, iv)?;
 
-        // This is a simplified version - a real implementation would use the proper
-        // AES-256-CBC mode encryption with padding
+        Selected::aes_cbc_encrypt(key, iv, data)
+    }
 
-        // Create a simple XOR encryption as placeholder
-        let mut output = Vec::with_capacity(data.len());
+    /// AES-256-CBC decrypt, removing PKCS#7 padding
+    pub fn aes_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        check_aes_params(key, iv)?;
+        if data.is_empty() || !data.len().is_multiple_of(AES_BLOCK_SIZE) {
+            return Err(WhatsAppError::CryptoError(format!("Ciphertext length {} is not a multiple of the block size", data.len())));
+        }
 
-        for (i, &byte) in data.iter().enumerate() {
-            let key_byte = key[i % key.len()];
-            let iv_byte = iv[i % iv.len()];
-            output.push(byte ^ key_byte ^ iv_byte);
+        Selected::aes_cbc_decrypt(key, iv, data)?
+            .ok_or_else(|| WhatsAppError::CryptoError("Bad padding in decrypted data".to_string()))
+    }
+
+    /// AES-256-CBC encrypt or decrypt whole blocks without padding, continuing
+    /// the chain of an earlier chunk when its last ciphertext block is the IV
+    pub fn aes_cbc_blocks(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>, WhatsAppError> {
+        check_aes_params(key, iv)?;
+        if !data.len().is_multiple_of(AES_BLOCK_SIZE) {
+            return Err(WhatsAppError::CryptoError(format!("Data length {} is not a multiple of the block size", data.len())));
         }
 
-        Ok(output)
+        Selected::aes_cbc_blocks(key, iv, data, encrypt)
     }
 
-    /// AES-256-CBC decrypt
-    pub fn aes_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
-        // In a real implementation, this would use proper AES decryption
-        // For this port, we'll just call our encrypt function since XOR is symmetric
-        Self::aes_encrypt(key, iv, data)
+    /// AES-256-CTR encrypt or decrypt, with the IV as the initial counter block
+    pub fn aes_ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        check_aes_params(key, iv)?;
+
+        Selected::aes_ctr(key, iv, data)
+    }
+
+    /// AES-256-GCM encrypt, appending the authentication tag to the ciphertext
+    pub fn aes_gcm_encrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        check_gcm_params(key, nonce)?;
+
+        Selected::aes_gcm_encrypt(key, nonce, aad, data)
+    }
+
+    /// AES-256-GCM decrypt, verifying the tag at the end of the ciphertext and the associated data
+    pub fn aes_gcm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        check_gcm_params(key, nonce)?;
+        if data.len() < GCM_TAG_SIZE {
+            return Err(WhatsAppError::CryptoError("Ciphertext shorter than its tag".to_string()));
+        }
+
+        let (ciphertext, tag) = data.split_at(data.len() - GCM_TAG_SIZE);
+        Selected::aes_gcm_decrypt(key, nonce, aad, ciphertext, tag)?
+            .ok_or_else(|| WhatsAppError::CryptoError("AES-GCM authentication failed".to_string()))
     }
 
     /// Base64 encode
@@ -103,6 +362,11 @@ impl Crypto {
         general_purpose::STANDARD.encode(data)
     }
 
+    /// URL-safe base64 encode without padding
+    pub fn base64_url_encode(data: &[u8]) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(data)
+    }
+
     /// Base64 decode
     pub fn base64_decode(data: &str) -> Result<Vec<u8>, WhatsAppError> {
         general_purpose::STANDARD.decode(data)
@@ -115,4 +379,47 @@ impl Crypto {
         hasher.update(data);
         hasher.finalize().to_vec()
     }
+
+    /// SHA-256 hash truncated to its first `length` bytes
+    pub fn sha256_trunc(data: &[u8], length: usize) -> Vec<u8> {
+        let mut hash = Self::sha256(data);
+        hash.truncate(length);
+        hash
+    }
+
+    /// SHA-1 hash, only for protocols that require it such as the WebSocket handshake
+    pub fn sha1(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().to_vec()
+    }
+
+    /// Calculate SHA-512 hash
+    pub fn sha512(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha512::new();
+        hasher.update(data);
+        hasher.finalize().to_vec()
+    }
+}
+
+fn check_aes_params(key: &[u8], iv: &[u8]) -> Result<(), WhatsAppError> {
+    if key.len() != 32 {
+        return Err(WhatsAppError::CryptoError(format!("AES-256 key must be 32 bytes, got {}", key.len())));
+    }
+    if iv.len() != AES_BLOCK_SIZE {
+        return Err(WhatsAppError::CryptoError(format!("AES IV must be {} bytes, got {}", AES_BLOCK_SIZE, iv.len())));
+    }
+
+    Ok(())
+}
+
+fn check_gcm_params(key: &[u8], nonce: &[u8]) -> Result<(), WhatsAppError> {
+    if key.len() != 32 {
+        return Err(WhatsAppError::CryptoError(format!("AES-256 key must be 32 bytes, got {}", key.len())));
+    }
+    if nonce.len() != GCM_NONCE_SIZE {
+        return Err(WhatsAppError::CryptoError(format!("AES-GCM nonce must be {} bytes, got {}", GCM_NONCE_SIZE, nonce.len())));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,153 @@
+use super::{KeyPair, SecretBytes};
+use crate::error::WhatsAppError;
+
+// Backends not selected when several are enabled go unused, see `Selected`
+#[cfg(any(feature = "rustcrypto-backend", feature = "ring-backend"))]
+#[cfg_attr(feature = "openssl-backend", allow(dead_code))]
+mod block_modes;
+#[cfg(feature = "openssl-backend")]
+mod openssl;
+#[cfg(feature = "ring-backend")]
+#[cfg_attr(feature = "openssl-backend", allow(dead_code))]
+mod ring;
+#[cfg(feature = "rustcrypto-backend")]
+#[cfg_attr(any(feature = "openssl-backend", feature = "ring-backend"), allow(dead_code))]
+mod rustcrypto;
+
+#[cfg(not(any(feature = "openssl-backend", feature = "ring-backend", feature = "rustcrypto-backend")))]
+compile_error!("A crypto backend feature must be enabled, `rustcrypto-backend`, `ring-backend` or `openssl-backend`");
+
+/// Backend the crypto layer is compiled against
+///
+/// The pure-Rust backend is the default, so enabling `ring-backend` or
+/// `openssl-backend` on top of it selects that one instead. OpenSSL wins if
+/// both are enabled.
+#[cfg(feature = "openssl-backend")]
+pub(crate) type Selected = self::openssl::OpenSsl;
+#[cfg(all(feature = "ring-backend", not(feature = "openssl-backend")))]
+pub(crate) type Selected = self::ring::Ring;
+#[cfg(all(feature = "rustcrypto-backend", not(any(feature = "openssl-backend", feature = "ring-backend"))))]
+pub(crate) type Selected = rustcrypto::RustCrypto;
+
+/// Primitives a crypto backend provides
+///
+/// `Crypto` checks key and nonce sizes before calling into the backend, and
+/// builds everything else (HKDF, HMAC, hashes) on top of these.
+pub(crate) trait Backend {
+    fn generate_x25519() -> Result<KeyPair, WhatsAppError>;
+    fn x25519(private: &[u8], public: &[u8]) -> Result<SecretBytes, WhatsAppError>;
+
+    fn generate_ed25519() -> Result<KeyPair, WhatsAppError>;
+    fn ed25519_sign(private: &[u8], message: &[u8]) -> Result<Vec<u8>, WhatsAppError>;
+    fn ed25519_verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<bool, WhatsAppError>;
+
+    fn aes_cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError>;
+    /// Returns `None` when the padding is invalid
+    fn aes_cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError>;
+    /// Encrypts or decrypts whole blocks without padding, for data processed in chunks
+    fn aes_cbc_blocks(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>, WhatsAppError>;
+
+    /// Encrypts or decrypts, as both are the same operation
+    fn aes_ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError>;
+
+    /// Returns the ciphertext with the tag appended
+    fn aes_gcm_encrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError>;
+    /// Returns `None` when authentication fails
+    fn aes_gcm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError>;
+
+    fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) -> Result<(), WhatsAppError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    /// Key of the AES-256 examples in NIST SP 800-38A
+    const AES_KEY: &str = "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4";
+    const AES_PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172a";
+
+    #[test]
+    fn x25519_matches_rfc_7748() {
+        let alice = bytes("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob_public = bytes("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+
+        let shared = Selected::x25519(&alice, &bob_public).unwrap();
+        assert_eq!(hex::encode(&shared[..]), "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert!(Selected::x25519(&alice, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn ed25519_matches_rfc_8032() {
+        let seed = bytes("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let public = bytes("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+
+        let signature = Selected::ed25519_sign(&seed, b"").unwrap();
+        assert_eq!(
+            hex::encode(&signature),
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        );
+        assert!(Selected::ed25519_verify(&public, b"", &signature).unwrap());
+        assert!(!Selected::ed25519_verify(&public, b"x", &signature).unwrap());
+    }
+
+    #[test]
+    fn generated_key_pairs_work() {
+        let (alice, bob) = (Selected::generate_x25519().unwrap(), Selected::generate_x25519().unwrap());
+        assert_eq!(
+            Selected::x25519(&alice.private, &bob.public).unwrap(),
+            Selected::x25519(&bob.private, &alice.public).unwrap(),
+        );
+
+        let key = Selected::generate_ed25519().unwrap();
+        let signature = Selected::ed25519_sign(&key.private, b"message").unwrap();
+        assert!(Selected::ed25519_verify(&key.public, b"message", &signature).unwrap());
+    }
+
+    #[test]
+    fn aes_cbc_and_ctr_match_sp_800_38a() {
+        let (key, plaintext) = (bytes(AES_KEY), bytes(AES_PLAINTEXT));
+
+        let iv = bytes("000102030405060708090a0b0c0d0e0f");
+        let encrypted = Selected::aes_cbc_blocks(&key, &iv, &plaintext, true).unwrap();
+        assert_eq!(hex::encode(&encrypted), "f58c4c04d6e5f1ba779eabfb5f7bfbd6");
+        assert_eq!(Selected::aes_cbc_blocks(&key, &iv, &encrypted, false).unwrap(), plaintext);
+
+        // PKCS#7 adds a whole block of padding to block-sized data
+        let padded = Selected::aes_cbc_encrypt(&key, &iv, &plaintext).unwrap();
+        assert_eq!(padded[..16], encrypted[..]);
+        assert_eq!(padded.len(), 32);
+        assert_eq!(Selected::aes_cbc_decrypt(&key, &iv, &padded).unwrap(), Some(plaintext.clone()));
+        assert_eq!(Selected::aes_cbc_decrypt(&key, &iv, &encrypted).unwrap(), None);
+
+        let counter = bytes("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        assert_eq!(hex::encode(Selected::aes_ctr(&key, &counter, &plaintext).unwrap()), "601ec313775789a5b7a7f504bbf3d228");
+    }
+
+    #[test]
+    fn aes_gcm_matches_the_gcm_spec() {
+        // Test cases 13 and 14 of the GCM specification
+        let (key, nonce) = ([0u8; 32], [0u8; 12]);
+        assert_eq!(hex::encode(Selected::aes_gcm_encrypt(&key, &nonce, &[], &[]).unwrap()), "530f8afbc74536b9a963b4f1c4cb738b");
+
+        let sealed = Selected::aes_gcm_encrypt(&key, &nonce, &[], &[0; 16]).unwrap();
+        assert_eq!(hex::encode(&sealed), "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919");
+
+        let (ciphertext, tag) = sealed.split_at(16);
+        assert_eq!(Selected::aes_gcm_decrypt(&key, &nonce, &[], ciphertext, tag).unwrap(), Some(vec![0; 16]));
+        assert_eq!(Selected::aes_gcm_decrypt(&key, &nonce, b"aad", ciphertext, tag).unwrap(), None);
+    }
+
+    #[test]
+    fn pbkdf2_matches_rfc_7914() {
+        let mut output = [0u8; 64];
+        Selected::pbkdf2_sha256(b"passwd", b"salt", 1, &mut output).unwrap();
+        assert_eq!(
+            hex::encode(output),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783",
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! AES-CBC and AES-CTR from the RustCrypto block mode crates
+//!
+//! Shared by the pure-Rust backend and ring, which has no unauthenticated
+//! AES modes of its own.
+
+use aes::Aes256;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, InvalidLength, KeyIvInit, StreamCipher};
+use aes::cipher::block_padding::{NoPadding, Pkcs7};
+
+use crate::error::WhatsAppError;
+
+pub(super) fn cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+    let cipher = cbc::Encryptor::<Aes256>::new_from_slices(key, iv).map_err(length_error)?;
+    Ok(cipher.encrypt_padded_vec_mut::<Pkcs7>(data))
+}
+
+pub(super) fn cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+    let cipher = cbc::Decryptor::<Aes256>::new_from_slices(key, iv).map_err(length_error)?;
+    Ok(cipher.decrypt_padded_vec_mut::<Pkcs7>(data).ok())
+}
+
+pub(super) fn cbc_blocks(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>, WhatsAppError> {
+    if encrypt {
+        let cipher = cbc::Encryptor::<Aes256>::new_from_slices(key, iv).map_err(length_error)?;
+        Ok(cipher.encrypt_padded_vec_mut::<NoPadding>(data))
+    } else {
+        let cipher = cbc::Decryptor::<Aes256>::new_from_slices(key, iv).map_err(length_error)?;
+        cipher.decrypt_padded_vec_mut::<NoPadding>(data)
+            .map_err(|_| WhatsAppError::CryptoError("Data is not whole AES blocks".to_string()))
+    }
+}
+
+pub(super) fn ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+    // The whole IV is the counter, as with OpenSSL
+    let mut cipher = ctr::Ctr128BE::<Aes256>::new_from_slices(key, iv).map_err(length_error)?;
+    let mut output = data.to_vec();
+    cipher.apply_keystream(&mut output);
+    Ok(output)
+}
+
+pub(super) fn length_error(error: InvalidLength) -> WhatsAppError {
+    WhatsAppError::CryptoError(error.to_string())
+}
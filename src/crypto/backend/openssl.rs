@@ -0,0 +1,99 @@
+use openssl::derive::Deriver;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5;
+use openssl::pkey::{Id, PKey};
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{self, Cipher, Crypter, Mode};
+
+use super::Backend;
+use crate::crypto::{GCM_TAG_SIZE, KeyPair, SecretBytes};
+use crate::error::WhatsAppError;
+
+/// Backend using the system's OpenSSL
+pub(crate) struct OpenSsl;
+
+impl Backend for OpenSsl {
+    fn generate_x25519() -> Result<KeyPair, WhatsAppError> {
+        raw_key_pair(PKey::generate_x25519().map_err(openssl_error)?)
+    }
+
+    fn x25519(private: &[u8], public: &[u8]) -> Result<SecretBytes, WhatsAppError> {
+        let private = PKey::private_key_from_raw_bytes(private, Id::X25519).map_err(openssl_error)?;
+        let public = PKey::public_key_from_raw_bytes(public, Id::X25519).map_err(openssl_error)?;
+
+        let mut deriver = Deriver::new(&private).map_err(openssl_error)?;
+        deriver.set_peer(&public).map_err(openssl_error)?;
+        deriver.derive_to_vec().map(SecretBytes::new).map_err(openssl_error)
+    }
+
+    fn generate_ed25519() -> Result<KeyPair, WhatsAppError> {
+        raw_key_pair(PKey::generate_ed25519().map_err(openssl_error)?)
+    }
+
+    fn ed25519_sign(private: &[u8], message: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let key = PKey::private_key_from_raw_bytes(private, Id::ED25519).map_err(openssl_error)?;
+
+        let mut signer = Signer::new_without_digest(&key).map_err(openssl_error)?;
+        signer.sign_oneshot_to_vec(message).map_err(openssl_error)
+    }
+
+    fn ed25519_verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<bool, WhatsAppError> {
+        let key = PKey::public_key_from_raw_bytes(public, Id::ED25519).map_err(openssl_error)?;
+        let mut verifier = Verifier::new_without_digest(&key).map_err(openssl_error)?;
+
+        Ok(verifier.verify_oneshot(signature, message).unwrap_or(false))
+    }
+
+    fn aes_cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        symm::encrypt(Cipher::aes_256_cbc(), key, Some(iv), data).map_err(openssl_error)
+    }
+
+    fn aes_cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+        Ok(symm::decrypt(Cipher::aes_256_cbc(), key, Some(iv), data).ok())
+    }
+
+    fn aes_cbc_blocks(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>, WhatsAppError> {
+        let cipher = Cipher::aes_256_cbc();
+        let mode = if encrypt { Mode::Encrypt } else { Mode::Decrypt };
+        let mut crypter = Crypter::new(cipher, mode, key, Some(iv)).map_err(openssl_error)?;
+        crypter.pad(false);
+
+        let mut output = vec![0u8; data.len() + cipher.block_size()];
+        let mut length = crypter.update(data, &mut output).map_err(openssl_error)?;
+        length += crypter.finalize(&mut output[length..]).map_err(openssl_error)?;
+        output.truncate(length);
+        Ok(output)
+    }
+
+    fn aes_ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        symm::encrypt(Cipher::aes_256_ctr(), key, Some(iv), data).map_err(openssl_error)
+    }
+
+    fn aes_gcm_encrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let mut tag = [0u8; GCM_TAG_SIZE];
+        let mut ciphertext = symm::encrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, data, &mut tag)
+            .map_err(openssl_error)?;
+        ciphertext.extend_from_slice(&tag);
+        Ok(ciphertext)
+    }
+
+    fn aes_gcm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+        Ok(symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, ciphertext, tag).ok())
+    }
+
+    fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) -> Result<(), WhatsAppError> {
+        pkcs5::pbkdf2_hmac(passphrase, salt, iterations as usize, MessageDigest::sha256(), output)
+            .map_err(openssl_error)
+    }
+}
+
+fn raw_key_pair(key: PKey<openssl::pkey::Private>) -> Result<KeyPair, WhatsAppError> {
+    Ok(KeyPair {
+        private: SecretBytes::new(key.raw_private_key().map_err(openssl_error)?),
+        public: key.raw_public_key().map_err(openssl_error)?,
+    })
+}
+
+fn openssl_error(error: openssl::error::ErrorStack) -> WhatsAppError {
+    WhatsAppError::CryptoError(error.to_string())
+}
@@ -0,0 +1,126 @@
+use std::num::NonZeroU32;
+
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair as _, UnparsedPublicKey};
+
+use super::{Backend, block_modes};
+use crate::crypto::{Crypto, KeyPair, SecretBytes, curve25519};
+use crate::error::WhatsAppError;
+
+/// u coordinate of the Curve25519 base point
+const BASE_POINT: [u8; 32] = {
+    let mut point = [0; 32];
+    point[0] = 9;
+    point
+};
+
+/// Backend built on ring, with AES-CBC and AES-CTR from the RustCrypto crates
+///
+/// ring only does X25519 with ephemeral keys, so static key agreement uses
+/// the crate's own Montgomery ladder.
+pub(crate) struct Ring;
+
+impl Backend for Ring {
+    fn generate_x25519() -> Result<KeyPair, WhatsAppError> {
+        let private = random_key();
+        Ok(KeyPair {
+            public: curve25519::x25519(&private, &BASE_POINT).to_vec(),
+            private: SecretBytes::new(private.to_vec()),
+        })
+    }
+
+    fn x25519(private: &[u8], public: &[u8]) -> Result<SecretBytes, WhatsAppError> {
+        let shared = SecretBytes::new(curve25519::x25519(&key_bytes(private)?, &key_bytes(public)?).to_vec());
+
+        // Low-order points give an all-zero secret, which the other backends refuse too
+        if Crypto::constant_time_eq(&shared, &[0; 32]) {
+            return Err(WhatsAppError::CryptoError("X25519 public key is a low-order point".to_string()));
+        }
+        Ok(shared)
+    }
+
+    fn generate_ed25519() -> Result<KeyPair, WhatsAppError> {
+        let seed = random_key();
+        Ok(KeyPair {
+            public: ed25519_key(&seed)?.public_key().as_ref().to_vec(),
+            private: SecretBytes::new(seed.to_vec()),
+        })
+    }
+
+    fn ed25519_sign(private: &[u8], message: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        Ok(ed25519_key(private)?.sign(message).as_ref().to_vec())
+    }
+
+    fn ed25519_verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<bool, WhatsAppError> {
+        key_bytes(public)?;
+        Ok(UnparsedPublicKey::new(&ED25519, public).verify(message, signature).is_ok())
+    }
+
+    fn aes_cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        block_modes::cbc_encrypt(key, iv, data)
+    }
+
+    fn aes_cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+        block_modes::cbc_decrypt(key, iv, data)
+    }
+
+    fn aes_cbc_blocks(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>, WhatsAppError> {
+        block_modes::cbc_blocks(key, iv, data, encrypt)
+    }
+
+    fn aes_ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        block_modes::ctr(key, iv, data)
+    }
+
+    fn aes_gcm_encrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let mut sealed = data.to_vec();
+        gcm_key(key)?.seal_in_place_append_tag(gcm_nonce(nonce)?, Aad::from(aad), &mut sealed)
+            .map_err(|_| WhatsAppError::CryptoError("AES-GCM encryption failed".to_string()))?;
+        Ok(sealed)
+    }
+
+    fn aes_gcm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+        let mut message = [ciphertext, tag].concat();
+        let key = gcm_key(key)?;
+        let opened = key.open_in_place(gcm_nonce(nonce)?, Aad::from(aad), &mut message)
+            .map(|plaintext| plaintext.len());
+
+        Ok(opened.ok().map(|len| {
+            message.truncate(len);
+            message
+        }))
+    }
+
+    fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) -> Result<(), WhatsAppError> {
+        let iterations = NonZeroU32::new(iterations)
+            .ok_or_else(|| WhatsAppError::CryptoError("PBKDF2 needs at least one iteration".to_string()))?;
+        pbkdf2::derive(PBKDF2_HMAC_SHA256, iterations, salt, passphrase, output);
+        Ok(())
+    }
+}
+
+fn random_key() -> [u8; 32] {
+    Crypto::random_bytes(32).try_into().unwrap()
+}
+
+fn key_bytes(key: &[u8]) -> Result<[u8; 32], WhatsAppError> {
+    key.try_into()
+        .map_err(|_| WhatsAppError::CryptoError(format!("Curve25519 key must be 32 bytes, got {}", key.len())))
+}
+
+fn ed25519_key(seed: &[u8]) -> Result<Ed25519KeyPair, WhatsAppError> {
+    Ed25519KeyPair::from_seed_unchecked(seed)
+        .map_err(|e| WhatsAppError::CryptoError(format!("Invalid Ed25519 private key: {}", e)))
+}
+
+fn gcm_key(key: &[u8]) -> Result<LessSafeKey, WhatsAppError> {
+    UnboundKey::new(&AES_256_GCM, key)
+        .map(LessSafeKey::new)
+        .map_err(|_| WhatsAppError::CryptoError(format!("AES-256-GCM key must be 32 bytes, got {}", key.len())))
+}
+
+fn gcm_nonce(nonce: &[u8]) -> Result<Nonce, WhatsAppError> {
+    Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| WhatsAppError::CryptoError(format!("AES-GCM nonce must be 12 bytes, got {}", nonce.len())))
+}
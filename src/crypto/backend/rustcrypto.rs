@@ -0,0 +1,101 @@
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{Backend, block_modes};
+use super::block_modes::length_error;
+use crate::crypto::{Crypto, KeyPair, SecretBytes};
+use crate::error::WhatsAppError;
+
+/// Pure-Rust backend built on the RustCrypto and dalek crates
+pub(crate) struct RustCrypto;
+
+impl Backend for RustCrypto {
+    fn generate_x25519() -> Result<KeyPair, WhatsAppError> {
+        let private = StaticSecret::from(random_key());
+        Ok(KeyPair {
+            public: PublicKey::from(&private).as_bytes().to_vec(),
+            private: SecretBytes::new(private.to_bytes().to_vec()),
+        })
+    }
+
+    fn x25519(private: &[u8], public: &[u8]) -> Result<SecretBytes, WhatsAppError> {
+        let private = StaticSecret::from(key_bytes(private)?);
+        let shared = private.diffie_hellman(&PublicKey::from(key_bytes(public)?));
+
+        // OpenSSL refuses low-order points too, which would give an all-zero secret
+        if !shared.was_contributory() {
+            return Err(WhatsAppError::CryptoError("X25519 public key is a low-order point".to_string()));
+        }
+        Ok(SecretBytes::new(shared.as_bytes().to_vec()))
+    }
+
+    fn generate_ed25519() -> Result<KeyPair, WhatsAppError> {
+        let seed = random_key();
+        Ok(KeyPair {
+            public: SigningKey::from_bytes(&seed).verifying_key().to_bytes().to_vec(),
+            private: SecretBytes::new(seed.to_vec()),
+        })
+    }
+
+    fn ed25519_sign(private: &[u8], message: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let key = SigningKey::from_bytes(&key_bytes(private)?);
+        Ok(key.sign(message).to_bytes().to_vec())
+    }
+
+    fn ed25519_verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<bool, WhatsAppError> {
+        // Keys that aren't a point on the curve can't have signed anything
+        let Ok(key) = VerifyingKey::from_bytes(&key_bytes(public)?) else {
+            return Ok(false);
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return Ok(false);
+        };
+
+        Ok(key.verify(message, &signature).is_ok())
+    }
+
+    fn aes_cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        block_modes::cbc_encrypt(key, iv, data)
+    }
+
+    fn aes_cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+        block_modes::cbc_decrypt(key, iv, data)
+    }
+
+    fn aes_cbc_blocks(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>, WhatsAppError> {
+        block_modes::cbc_blocks(key, iv, data, encrypt)
+    }
+
+    fn aes_ctr(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        block_modes::ctr(key, iv, data)
+    }
+
+    fn aes_gcm_encrypt(key: &[u8], nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, WhatsAppError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(length_error)?;
+        cipher.encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|_| WhatsAppError::CryptoError("AES-GCM encryption failed".to_string()))
+    }
+
+    fn aes_gcm_decrypt(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Option<Vec<u8>>, WhatsAppError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(length_error)?;
+        let message = [ciphertext, tag].concat();
+        Ok(cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: &message, aad }).ok())
+    }
+
+    fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) -> Result<(), WhatsAppError> {
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, output);
+        Ok(())
+    }
+}
+
+fn random_key() -> [u8; 32] {
+    Crypto::random_bytes(32).try_into().unwrap()
+}
+
+fn key_bytes(key: &[u8]) -> Result<[u8; 32], WhatsAppError> {
+    key.try_into()
+        .map_err(|_| WhatsAppError::CryptoError(format!("Curve25519 key must be 32 bytes, got {}", key.len())))
+}
@@ -0,0 +1,516 @@
+//! Edwards25519 arithmetic for XEdDSA, and X25519 for backends without it
+//!
+//! Backends only expose X25519 and Ed25519 over their own key formats, while
+//! XEdDSA signs with the Edwards form of a Montgomery private key, so the
+//! scalar multiplication is done here. ring has no X25519 with static private
+//! keys, which Signal needs, so that backend uses the Montgomery ladder here.
+//! Field elements use five 51-bit limbs; scalar multiplication and scalar
+//! reduction don't branch on secret data.
+
+use super::{Crypto, SecretBytes};
+
+const MASK_51: u64 = (1 << 51) - 1;
+
+/// Field element modulo 2^255 - 19
+#[derive(Clone, Copy)]
+struct Field([u64; 5]);
+
+impl Field {
+    const ZERO: Self = Self([0; 5]);
+    const ONE: Self = Self([1, 0, 0, 0, 0]);
+
+    /// (A - 2) / 4 for the Montgomery curve constant A = 486662
+    #[cfg(feature = "ring-backend")]
+    #[cfg_attr(feature = "openssl-backend", allow(dead_code))]
+    const A24: Self = Self([121665, 0, 0, 0, 0]);
+
+    /// 2d, where d = -121665/121666 is the curve constant
+    const D2: Self = Self([
+        0x69b9426b2f159, 0x35050762add7a, 0x3cf44c0038052, 0x6738cc7407977, 0x2406d9dc56dff,
+    ]);
+
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let load = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        Self([
+            load(0) & MASK_51,
+            (load(6) >> 3) & MASK_51,
+            (load(12) >> 6) & MASK_51,
+            (load(19) >> 1) & MASK_51,
+            (load(24) >> 12) & MASK_51,
+        ])
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut limbs = self.carry().0;
+
+        // Subtract p once if the value is at least p
+        let mut q = (limbs[0] + 19) >> 51;
+        for limb in &limbs[1..] {
+            q = (limb + q) >> 51;
+        }
+        limbs[0] += 19 * q;
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK_51;
+        }
+        limbs[4] &= MASK_51;
+
+        let mut bytes = [0u8; 32];
+        let mut acc = 0u128;
+        let mut bits = 0;
+        let mut out = 0;
+        for limb in limbs {
+            acc |= (limb as u128) << bits;
+            bits += 51;
+            while bits >= 8 && out < 32 {
+                bytes[out] = acc as u8;
+                acc >>= 8;
+                bits -= 8;
+                out += 1;
+            }
+        }
+        if out < 32 {
+            bytes[out] = acc as u8;
+        }
+
+        bytes
+    }
+
+    /// Propagate carries so every limb fits in 51 bits, plus a small excess in the first
+    fn carry(self) -> Self {
+        let mut limbs = self.0;
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK_51;
+        }
+        limbs[0] += 19 * (limbs[4] >> 51);
+        limbs[4] &= MASK_51;
+
+        Self(limbs)
+    }
+
+    fn add(self, other: Self) -> Self {
+        let mut limbs = self.0;
+        for (limb, other) in limbs.iter_mut().zip(other.0) {
+            *limb += other;
+        }
+
+        Self(limbs).carry()
+    }
+
+    fn sub(self, other: Self) -> Self {
+        // Add 16p first so the limbs can't underflow
+        let mut limbs = [
+            self.0[0] + 0x7ffffffffffed0,
+            self.0[1] + 0x7ffffffffffff0,
+            self.0[2] + 0x7ffffffffffff0,
+            self.0[3] + 0x7ffffffffffff0,
+            self.0[4] + 0x7ffffffffffff0,
+        ];
+        for (limb, other) in limbs.iter_mut().zip(other.carry().0) {
+            *limb -= other;
+        }
+
+        Self(limbs).carry()
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let [a0, a1, a2, a3, a4] = self.0.map(u128::from);
+        let [b0, b1, b2, b3, b4] = other.0.map(u128::from);
+        let (b1_19, b2_19, b3_19, b4_19) = (b1 * 19, b2 * 19, b3 * 19, b4 * 19);
+
+        let c0 = a0 * b0 + a4 * b1_19 + a3 * b2_19 + a2 * b3_19 + a1 * b4_19;
+        let mut c1 = a1 * b0 + a0 * b1 + a4 * b2_19 + a3 * b3_19 + a2 * b4_19;
+        let mut c2 = a2 * b0 + a1 * b1 + a0 * b2 + a4 * b3_19 + a3 * b4_19;
+        let mut c3 = a3 * b0 + a2 * b1 + a1 * b2 + a0 * b3 + a4 * b4_19;
+        let mut c4 = a4 * b0 + a3 * b1 + a2 * b2 + a1 * b3 + a0 * b4;
+
+        c1 += c0 >> 51;
+        c2 += c1 >> 51;
+        c3 += c2 >> 51;
+        c4 += c3 >> 51;
+        let carry = (c4 >> 51) as u64;
+
+        let mut limbs = [c0, c1, c2, c3, c4].map(|c| c as u64 & MASK_51);
+        limbs[0] += carry * 19;
+        limbs[1] += limbs[0] >> 51;
+        limbs[0] &= MASK_51;
+
+        Self(limbs)
+    }
+
+    /// Multiplicative inverse, computed as self^(p - 2)
+    fn invert(self) -> Self {
+        // p - 2 = 2^255 - 21: every bit is set except bits 2 and 4
+        let mut result = Self::ONE;
+        for bit in (0..255).rev() {
+            result = result.mul(result);
+            if bit != 2 && bit != 4 {
+                result = result.mul(self);
+            }
+        }
+
+        result
+    }
+
+    /// Take `other` when `choice` is 1, keep `self` when it is 0
+    fn select(self, other: Self, choice: u64) -> Self {
+        let mask = choice.wrapping_neg();
+        let mut limbs = self.0;
+        for (limb, other) in limbs.iter_mut().zip(other.0) {
+            *limb ^= mask & (*limb ^ other);
+        }
+
+        Self(limbs)
+    }
+}
+
+/// Point on the Edwards curve in extended coordinates
+#[derive(Clone, Copy)]
+struct Point {
+    x: Field,
+    y: Field,
+    z: Field,
+    t: Field,
+}
+
+impl Point {
+    const IDENTITY: Self = Self { x: Field::ZERO, y: Field::ONE, z: Field::ONE, t: Field::ZERO };
+
+    /// Base point of Ed25519
+    const BASE: Self = Self {
+        x: Field([0x62d608f25d51a, 0x412a4b4f6592a, 0x75b7171a4b31d, 0x1ff60527118fe, 0x216936d3cd6e5]),
+        y: Field([0x6666666666658, 0x4cccccccccccc, 0x1999999999999, 0x3333333333333, 0x6666666666666]),
+        z: Field::ONE,
+        t: Field([0x68ab3a5b7dda3, 0x00eea2a5eadbb, 0x2af8df483c27e, 0x332b375274732, 0x67875f0fd78b7]),
+    };
+
+    /// Complete addition formula, also used for doubling
+    fn add(self, other: Self) -> Self {
+        let a = self.y.sub(self.x).mul(other.y.sub(other.x));
+        let b = self.y.add(self.x).mul(other.y.add(other.x));
+        let c = self.t.mul(Field::D2).mul(other.t);
+        let d = self.z.add(self.z).mul(other.z);
+        let (e, f, g, h) = (b.sub(a), d.sub(c), d.add(c), b.add(a));
+
+        Self { x: e.mul(f), y: g.mul(h), z: f.mul(g), t: e.mul(h) }
+    }
+
+    fn select(self, other: Self, choice: u64) -> Self {
+        Self {
+            x: self.x.select(other.x, choice),
+            y: self.y.select(other.y, choice),
+            z: self.z.select(other.z, choice),
+            t: self.t.select(other.t, choice),
+        }
+    }
+
+    /// Multiply the base point by a little-endian scalar
+    fn base_mul(scalar: &[u8; 32]) -> Self {
+        let mut result = Self::IDENTITY;
+        for bit in (0..256).rev() {
+            result = result.add(result);
+            let choice = ((scalar[bit / 8] >> (bit % 8)) & 1) as u64;
+            result = result.select(result.add(Self::BASE), choice);
+        }
+
+        result
+    }
+
+    /// Encode as the y coordinate with the sign of x in the top bit
+    fn compress(self) -> [u8; 32] {
+        let z_inv = self.z.invert();
+        let mut bytes = self.y.mul(z_inv).to_bytes();
+        bytes[31] |= (self.x.mul(z_inv).to_bytes()[0] & 1) << 7;
+        bytes
+    }
+}
+
+/// Order of the base point, as little-endian 64-bit limbs
+const ORDER: [u64; 4] = [0x5812631a5cf5d3ed, 0x14def9dea2f79cd6, 0, 0x1000000000000000];
+
+/// Reduce a little-endian integer modulo the group order
+fn reduce(bytes: &[u8]) -> [u8; 32] {
+    let mut r = [0u64; 4];
+
+    for bit in (0..bytes.len() * 8).rev() {
+        // r < order < 2^253, so shifting in a bit can't overflow
+        for i in (1..4).rev() {
+            r[i] = (r[i] << 1) | (r[i - 1] >> 63);
+        }
+        r[0] = (r[0] << 1) | ((bytes[bit / 8] >> (bit % 8)) & 1) as u64;
+
+        let mut difference = [0u64; 4];
+        let mut borrow = 0u64;
+        for i in 0..4 {
+            let (value, borrow1) = r[i].overflowing_sub(ORDER[i]);
+            let (value, borrow2) = value.overflowing_sub(borrow);
+            difference[i] = value;
+            borrow = (borrow1 | borrow2) as u64;
+        }
+
+        // Keep the difference unless subtracting borrowed
+        let mask = borrow.wrapping_sub(1);
+        for i in 0..4 {
+            r[i] ^= mask & (r[i] ^ difference[i]);
+        }
+    }
+
+    let mut result = [0u8; 32];
+    for (chunk, limb) in result.chunks_exact_mut(8).zip(r) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    result
+}
+
+/// Compute a * b + c modulo the group order
+fn mul_add(a: &[u8; 32], b: &[u8; 32], c: &[u8; 32]) -> [u8; 32] {
+    let limbs = |bytes: &[u8; 32]| -> [u64; 4] {
+        std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+    };
+    let (a, b, c) = (limbs(a), limbs(b), limbs(c));
+
+    let mut product = [0u64; 9];
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let value = product[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+            product[i + j] = value as u64;
+            carry = value >> 64;
+        }
+        product[i + 4] = carry as u64;
+    }
+
+    let mut carry = 0u128;
+    for (i, limb) in product.iter_mut().enumerate() {
+        let value = *limb as u128 + c.get(i).copied().unwrap_or(0) as u128 + carry;
+        *limb = value as u64;
+        carry = value >> 64;
+    }
+
+    let bytes: Vec<u8> = product.iter().flat_map(|limb| limb.to_le_bytes()).collect();
+    reduce(&bytes)
+}
+
+/// Clamp a Curve25519 private key the way X25519 does
+fn clamp(private: &[u8; 32]) -> SecretBytes {
+    let mut scalar = SecretBytes::new(private.to_vec());
+    scalar.0[0] &= 248;
+    scalar.0[31] &= 127;
+    scalar.0[31] |= 64;
+    scalar
+}
+
+/// XEdDSA signature of a message with a Curve25519 private key
+///
+/// Uses the Signal variant: the Edwards public key isn't forced to a positive
+/// sign, its sign bit is carried in the top bit of the signature instead.
+pub(super) fn sign(private: &[u8; 32], message: &[u8], random: &[u8; 64]) -> [u8; 64] {
+    let scalar = clamp(private);
+    let scalar: &[u8; 32] = scalar[..].try_into().unwrap();
+    let public = Point::base_mul(scalar).compress();
+
+    let mut prefix = [0xffu8; 32];
+    prefix[0] = 0xfe;
+    let nonce = SecretBytes::new(Crypto::sha512(&[&prefix[..], scalar, message, random].concat()));
+    let nonce = SecretBytes::new(reduce(&nonce).to_vec());
+    let nonce: &[u8; 32] = nonce[..].try_into().unwrap();
+    let r = Point::base_mul(nonce).compress();
+
+    let h = reduce(&Crypto::sha512(&[&r[..], &public, message].concat()));
+    let s = mul_add(&h, scalar, nonce);
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&r);
+    signature[32..].copy_from_slice(&s);
+    signature[63] |= public[31] & 0x80;
+    signature
+}
+
+/// X25519 function of RFC 7748: the u coordinate of the private key times the point at `u`
+///
+/// Uses the Montgomery ladder with constant-time swaps. The result is all
+/// zeros for low-order points, which callers have to reject.
+#[cfg(feature = "ring-backend")]
+#[cfg_attr(feature = "openssl-backend", allow(dead_code))]
+pub(super) fn x25519(private: &[u8; 32], u: &[u8; 32]) -> [u8; 32] {
+    let scalar = clamp(private);
+    let x1 = Field::from_bytes(u);
+    let (mut x2, mut z2, mut x3, mut z3) = (Field::ONE, Field::ZERO, x1, Field::ONE);
+    let mut swap = 0;
+
+    for bit in (0..255).rev() {
+        let choice = ((scalar[bit / 8] >> (bit % 8)) & 1) as u64;
+        swap ^= choice;
+        (x2, x3) = (x2.select(x3, swap), x3.select(x2, swap));
+        (z2, z3) = (z2.select(z3, swap), z3.select(z2, swap));
+        swap = choice;
+
+        let (a, b) = (x2.add(z2), x2.sub(z2));
+        let (aa, bb) = (a.mul(a), b.mul(b));
+        let e = aa.sub(bb);
+        let (c, d) = (x3.add(z3), x3.sub(z3));
+        let (da, cb) = (d.mul(a), c.mul(b));
+
+        let (sum, difference) = (da.add(cb), da.sub(cb));
+        x3 = sum.mul(sum);
+        z3 = x1.mul(difference.mul(difference));
+        x2 = aa.mul(bb);
+        z2 = e.mul(aa.add(Field::A24.mul(e)));
+    }
+    x2 = x2.select(x3, swap);
+    z2 = z2.select(z3, swap);
+
+    x2.mul(z2.invert()).to_bytes()
+}
+
+/// Ed25519 encoding of the Edwards point for a Montgomery u coordinate, with the given sign
+///
+/// Returns `None` for the one u coordinate without an Edwards equivalent.
+pub(super) fn edwards_public_key(montgomery: &[u8; 32], sign: u8) -> Option<[u8; 32]> {
+    // y = (u - 1) / (u + 1)
+    let u = Field::from_bytes(montgomery);
+    let denominator = u.add(Field::ONE);
+    if denominator.to_bytes() == [0; 32] {
+        return None;
+    }
+
+    let mut key = u.sub(Field::ONE).mul(denominator.invert()).to_bytes();
+    key[31] |= sign << 7;
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret key, public key, message and signature from RFC 8032 section 7.1
+    const RFC_8032: [(&str, &str, &str, &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    fn bytes32(hex: &str) -> [u8; 32] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    /// Ed25519 scalar of an RFC 8032 secret key
+    fn secret_scalar(secret: &str) -> [u8; 32] {
+        let hash = Crypto::sha512(&hex::decode(secret).unwrap());
+        clamp(hash[..32].try_into().unwrap())[..].try_into().unwrap()
+    }
+
+    #[test]
+    fn base_point_multiples_encode_like_rfc_8032() {
+        for (secret, public, _, _) in RFC_8032 {
+            assert_eq!(hex::encode(Point::base_mul(&secret_scalar(secret)).compress()), public);
+        }
+    }
+
+    #[test]
+    fn scalar_arithmetic_reproduces_rfc_8032_signatures() {
+        for (secret, public, message, signature) in RFC_8032 {
+            let hash = Crypto::sha512(&hex::decode(secret).unwrap());
+            let scalar = secret_scalar(secret);
+            let message = hex::decode(message).unwrap();
+
+            let nonce = reduce(&Crypto::sha512(&[&hash[32..], &message].concat()));
+            let r = Point::base_mul(&nonce).compress();
+            let h = reduce(&Crypto::sha512(&[&r[..], &bytes32(public), &message].concat()));
+            let s = mul_add(&h, &scalar, &nonce);
+
+            assert_eq!(hex::encode([r, s].concat()), signature);
+        }
+    }
+
+    #[test]
+    fn montgomery_base_point_maps_to_edwards_base_point() {
+        let mut u = [0u8; 32];
+        u[0] = 9;
+        assert_eq!(
+            hex::encode(edwards_public_key(&u, 0).unwrap()),
+            "5866666666666666666666666666666666666666666666666666666666666666",
+        );
+        // u = -1 has no Edwards point
+        let mut minus_one = bytes32("ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f");
+        assert_eq!(edwards_public_key(&minus_one, 0), None);
+        minus_one[0] = 0xed;
+        assert!(edwards_public_key(&minus_one, 0).is_some());
+    }
+
+    #[test]
+    fn xeddsa_signatures_verify_with_the_montgomery_key() {
+        let key_pair = Crypto::generate_key_pair().unwrap();
+        let message = b"signed prekey";
+        let signature = Crypto::xeddsa_sign(&key_pair.private, message).unwrap();
+
+        assert!(Crypto::xeddsa_verify(&key_pair.public, message, &signature).is_ok());
+        assert!(Crypto::xeddsa_verify(&key_pair.public, b"another message", &signature).is_err());
+
+        let other = Crypto::generate_key_pair().unwrap();
+        assert!(Crypto::xeddsa_verify(&other.public, message, &signature).is_err());
+    }
+
+    #[test]
+    fn tampered_xeddsa_signatures_are_rejected() {
+        let key_pair = Crypto::generate_key_pair().unwrap();
+        let message = b"signed prekey";
+        let signature = Crypto::xeddsa_sign(&key_pair.private, message).unwrap();
+
+        for (index, bit) in [(0, 0x01), (31, 0x40), (32, 0x01), (62, 0x80), (63, 0x80)] {
+            let mut tampered = signature.clone();
+            tampered[index] ^= bit;
+            assert!(Crypto::xeddsa_verify(&key_pair.public, message, &tampered).is_err(), "byte {} accepted", index);
+        }
+        assert!(Crypto::xeddsa_verify(&key_pair.public, message, &signature[..63]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "ring-backend")]
+    fn x25519_matches_rfc_7748() {
+        // Section 5.2; the top bit of the second u coordinate is set and must be ignored
+        let vectors = [
+            (
+                "a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4",
+                "e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c",
+                "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552",
+            ),
+            (
+                "4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d",
+                "e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493",
+                "95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957",
+            ),
+        ];
+        for (scalar, u, expected) in vectors {
+            assert_eq!(hex::encode(x25519(&bytes32(scalar), &bytes32(u))), expected);
+        }
+
+        // The iterated test, feeding each output back in as the scalar
+        let (mut k, mut u) = (bytes32("0900000000000000000000000000000000000000000000000000000000000000"), [0u8; 32]);
+        u[0] = 9;
+        for iteration in 1..=1000 {
+            (k, u) = (x25519(&k, &u), k);
+            if iteration == 1 {
+                assert_eq!(hex::encode(k), "422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079");
+            }
+        }
+        assert_eq!(hex::encode(k), "684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51");
+    }
+}
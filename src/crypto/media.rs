@@ -0,0 +1,454 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::{AES_BLOCK_SIZE, Crypto, SecretBytes};
+use crate::error::{WhatsAppError, WhatsAppResult};
+
+/// Length of the truncated MAC appended to encrypted media
+const MAC_SIZE: usize = 10;
+
+/// HKDF info string of the key media retry requests and notifications are encrypted with
+const MEDIA_RETRY_INFO: &[u8] = b"WhatsApp Media Retry Notification";
+
+/// Kind of encrypted media, selecting the HKDF info string its keys are expanded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Image,
+    Video,
+    Audio,
+    Document,
+    Sticker,
+    History,
+    AppState,
+    LinkThumbnail,
+}
+
+impl MediaType {
+    fn info(&self) -> &'static [u8] {
+        match self {
+            MediaType::Image | MediaType::Sticker => b"WhatsApp Image Keys",
+            MediaType::Video => b"WhatsApp Video Keys",
+            MediaType::Audio => b"WhatsApp Audio Keys",
+            MediaType::Document => b"WhatsApp Document Keys",
+            MediaType::History => b"WhatsApp History Keys",
+            MediaType::AppState => b"WhatsApp App State Keys",
+            MediaType::LinkThumbnail => b"WhatsApp Link Thumbnail Keys",
+        }
+    }
+}
+
+/// Keys needed to download and decrypt media, as sent in the message referring to it
+#[derive(Debug, Clone)]
+pub struct MediaKeys {
+    pub media_key: SecretBytes,
+    /// SHA-256 of the plaintext
+    pub file_sha256: Option<Vec<u8>>,
+    /// SHA-256 of the encrypted file, MAC included
+    pub file_enc_sha256: Option<Vec<u8>>,
+}
+
+/// Media encrypted for upload
+#[derive(Debug, Clone)]
+pub struct EncryptedMedia {
+    /// Ciphertext followed by its MAC
+    pub data: Vec<u8>,
+    pub media_key: SecretBytes,
+    pub file_sha256: Vec<u8>,
+    pub file_enc_sha256: Vec<u8>,
+    pub file_length: u64,
+}
+
+impl EncryptedMedia {
+    /// Get the keys the recipient needs to decrypt the media
+    pub fn keys(&self) -> MediaKeys {
+        MediaKeys {
+            media_key: self.media_key.clone(),
+            file_sha256: Some(self.file_sha256.clone()),
+            file_enc_sha256: Some(self.file_enc_sha256.clone()),
+        }
+    }
+}
+
+/// Hashes and key of media encrypted in chunks, known once all of it went through
+#[derive(Debug, Clone)]
+pub struct MediaDigest {
+    pub media_key: SecretBytes,
+    pub file_sha256: Vec<u8>,
+    pub file_enc_sha256: Vec<u8>,
+    pub file_length: u64,
+}
+
+/// Length of media once encrypted, padding and MAC included
+pub fn encrypted_length(file_length: u64) -> u64 {
+    (file_length / AES_BLOCK_SIZE as u64 + 1) * AES_BLOCK_SIZE as u64 + MAC_SIZE as u64
+}
+
+/// Keys expanded from a media key
+struct ExpandedKeys {
+    iv: Vec<u8>,
+    cipher_key: SecretBytes,
+    mac_key: SecretBytes,
+}
+
+fn expand(media_type: MediaType, media_key: &[u8]) -> WhatsAppResult<ExpandedKeys> {
+    if media_key.len() != 32 {
+        return Err(WhatsAppError::CryptoError(format!("Media key must be 32 bytes, got {}", media_key.len())));
+    }
+
+    let expanded = Crypto::hkdf(media_key, media_type.info(), 112)?;
+    Ok(ExpandedKeys {
+        iv: expanded[..16].to_vec(),
+        cipher_key: expanded[16..48].into(),
+        mac_key: expanded[48..80].into(),
+    })
+}
+
+fn mac(keys: &ExpandedKeys, ciphertext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let mut mac = Crypto::hmac_sha256(&keys.mac_key, &mac_input(keys, ciphertext))?;
+    mac.truncate(MAC_SIZE);
+    Ok(mac)
+}
+
+fn mac_input(keys: &ExpandedKeys, ciphertext: &[u8]) -> Vec<u8> {
+    let mut input = keys.iv.clone();
+    input.extend_from_slice(ciphertext);
+    input
+}
+
+/// Encrypt media with a fresh media key
+pub fn encrypt_media(media_type: MediaType, plaintext: &[u8]) -> WhatsAppResult<EncryptedMedia> {
+    let media_key = SecretBytes::new(Crypto::random_bytes(32));
+    let keys = expand(media_type, &media_key)?;
+
+    let mut data = Crypto::aes_encrypt(&keys.cipher_key, &keys.iv, plaintext)?;
+    let mac = mac(&keys, &data)?;
+    data.extend_from_slice(&mac);
+
+    Ok(EncryptedMedia {
+        file_sha256: Crypto::sha256(plaintext),
+        file_enc_sha256: Crypto::sha256(&data),
+        file_length: plaintext.len() as u64,
+        media_key,
+        data,
+    })
+}
+
+/// Verify and decrypt downloaded media
+///
+/// The hashes are checked when the keys include them, the MAC always.
+pub fn decrypt_media(media_type: MediaType, keys: &MediaKeys, data: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    if data.len() < MAC_SIZE {
+        return Err(WhatsAppError::MediaError("Encrypted media too short".to_string()));
+    }
+    if let Some(expected) = &keys.file_enc_sha256
+        && !Crypto::constant_time_eq(&Crypto::sha256(data), expected)
+    {
+        return Err(WhatsAppError::MediaError("Encrypted media hash mismatch".to_string()));
+    }
+
+    let expanded = expand(media_type, &keys.media_key)?;
+    let (ciphertext, received_mac) = data.split_at(data.len() - MAC_SIZE);
+    Crypto::verify_hmac_sha256(&expanded.mac_key, &mac_input(&expanded, ciphertext), received_mac)
+        .map_err(|_| WhatsAppError::MediaError("Media MAC mismatch".to_string()))?;
+
+    let plaintext = Crypto::aes_decrypt(&expanded.cipher_key, &expanded.iv, ciphertext)?;
+    if let Some(expected) = &keys.file_sha256
+        && !Crypto::constant_time_eq(&Crypto::sha256(&plaintext), expected)
+    {
+        return Err(WhatsAppError::MediaError("Media hash mismatch".to_string()));
+    }
+
+    Ok(plaintext)
+}
+
+/// Encrypt a media retry request for the message with the given id, returning the ciphertext and nonce
+///
+/// Both sides derive the key from the media key of the message, so only
+/// devices that have the message can ask for it or read the answer.
+pub fn encrypt_media_retry(media_key: &[u8], message_id: &str, plaintext: &[u8]) -> WhatsAppResult<(Vec<u8>, Vec<u8>)> {
+    let key = Crypto::hkdf(media_key, MEDIA_RETRY_INFO, 32)?;
+    let nonce = Crypto::random_bytes(super::GCM_NONCE_SIZE);
+    let ciphertext = Crypto::aes_gcm_encrypt(&key, &nonce, message_id.as_bytes(), plaintext)?;
+    Ok((ciphertext, nonce))
+}
+
+/// Decrypt the answer to a media retry request for the message with the given id
+pub fn decrypt_media_retry(media_key: &[u8], message_id: &str, ciphertext: &[u8], nonce: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let key = Crypto::hkdf(media_key, MEDIA_RETRY_INFO, 32)?;
+    Crypto::aes_gcm_decrypt(&key, nonce, message_id.as_bytes(), ciphertext)
+        .map_err(|_| WhatsAppError::MediaError("Failed to decrypt media retry notification".to_string()))
+}
+
+/// Encrypts media chunk by chunk, for files too large to hold in memory
+pub struct MediaEncryptor {
+    keys: ExpandedKeys,
+    media_key: SecretBytes,
+    /// Last ciphertext block, chaining the next chunk
+    iv: Vec<u8>,
+    /// Plaintext short of a whole block
+    pending: Vec<u8>,
+    mac: Hmac<Sha256>,
+    file_sha256: Sha256,
+    file_enc_sha256: Sha256,
+    file_length: u64,
+}
+
+impl MediaEncryptor {
+    /// Start encrypting media with a fresh media key
+    pub fn new(media_type: MediaType) -> WhatsAppResult<Self> {
+        Self::with_key(media_type, SecretBytes::new(Crypto::random_bytes(32)))
+    }
+
+    /// Start encrypting media with the given media key, e.g. to encrypt it again the same way
+    pub fn with_key(media_type: MediaType, media_key: SecretBytes) -> WhatsAppResult<Self> {
+        let keys = expand(media_type, &media_key)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&keys.mac_key)
+            .map_err(|e| WhatsAppError::CryptoError(e.to_string()))?;
+        mac.update(&keys.iv);
+
+        Ok(Self {
+            iv: keys.iv.clone(),
+            keys,
+            media_key,
+            pending: Vec::new(),
+            mac,
+            file_sha256: Sha256::new(),
+            file_enc_sha256: Sha256::new(),
+            file_length: 0,
+        })
+    }
+
+    /// Encrypt the next chunk of plaintext, returning the ciphertext of its whole blocks
+    pub fn update(&mut self, plaintext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        self.file_sha256.update(plaintext);
+        self.file_length += plaintext.len() as u64;
+        self.pending.extend_from_slice(plaintext);
+
+        let whole = self.pending.len() - self.pending.len() % AES_BLOCK_SIZE;
+        if whole == 0 {
+            return Ok(Vec::new());
+        }
+        let blocks: Vec<u8> = self.pending.drain(..whole).collect();
+        let ciphertext = Crypto::aes_cbc_blocks(&self.keys.cipher_key, &self.iv, &blocks, true)?;
+        self.chain(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    /// Encrypt what is left with its padding, returning the last ciphertext with the MAC appended
+    pub fn finalize(mut self) -> WhatsAppResult<(Vec<u8>, MediaDigest)> {
+        let mut ciphertext = Crypto::aes_encrypt(&self.keys.cipher_key, &self.iv, &self.pending)?;
+        self.mac.update(&ciphertext);
+        let mac = self.mac.finalize().into_bytes();
+        ciphertext.extend_from_slice(&mac[..MAC_SIZE]);
+        self.file_enc_sha256.update(&ciphertext);
+
+        Ok((ciphertext, MediaDigest {
+            media_key: self.media_key,
+            file_sha256: self.file_sha256.finalize().to_vec(),
+            file_enc_sha256: self.file_enc_sha256.finalize().to_vec(),
+            file_length: self.file_length,
+        }))
+    }
+
+    fn chain(&mut self, ciphertext: &[u8]) {
+        self.mac.update(ciphertext);
+        self.file_enc_sha256.update(ciphertext);
+        self.iv = ciphertext[ciphertext.len() - AES_BLOCK_SIZE..].to_vec();
+    }
+}
+
+/// Decrypts downloaded media chunk by chunk, for files too large to hold in memory
+///
+/// Plaintext is handed out before the MAC at the end of the file is checked,
+/// so everything returned must be discarded when `finalize` fails.
+pub struct MediaDecryptor {
+    keys: ExpandedKeys,
+    expected: MediaKeys,
+    iv: Vec<u8>,
+    /// Ciphertext not decrypted yet, always holding back the last block and the MAC
+    pending: Vec<u8>,
+    mac: Hmac<Sha256>,
+    file_sha256: Sha256,
+    file_enc_sha256: Sha256,
+}
+
+impl MediaDecryptor {
+    pub fn new(media_type: MediaType, keys: &MediaKeys) -> WhatsAppResult<Self> {
+        let expanded = expand(media_type, &keys.media_key)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&expanded.mac_key)
+            .map_err(|e| WhatsAppError::CryptoError(e.to_string()))?;
+        mac.update(&expanded.iv);
+
+        Ok(Self {
+            iv: expanded.iv.clone(),
+            keys: expanded,
+            expected: keys.clone(),
+            pending: Vec::new(),
+            mac,
+            file_sha256: Sha256::new(),
+            file_enc_sha256: Sha256::new(),
+        })
+    }
+
+    /// Decrypt the next chunk of the downloaded file, returning the plaintext ready so far
+    pub fn update(&mut self, data: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+
+        // The last block is unpadded in `finalize`, so it has to stay behind
+        let available = self.pending.len().saturating_sub(MAC_SIZE + AES_BLOCK_SIZE);
+        let whole = available - available % AES_BLOCK_SIZE;
+        if whole == 0 {
+            return Ok(Vec::new());
+        }
+        let ciphertext: Vec<u8> = self.pending.drain(..whole).collect();
+        let plaintext = Crypto::aes_cbc_blocks(&self.keys.cipher_key, &self.iv, &ciphertext, false)?;
+
+        self.mac.update(&ciphertext);
+        self.file_enc_sha256.update(&ciphertext);
+        self.file_sha256.update(&plaintext);
+        self.iv = ciphertext[ciphertext.len() - AES_BLOCK_SIZE..].to_vec();
+        Ok(plaintext)
+    }
+
+    /// Decrypt the last block and check the MAC and hashes of the whole file
+    pub fn finalize(mut self) -> WhatsAppResult<Vec<u8>> {
+        if self.pending.len() < MAC_SIZE + AES_BLOCK_SIZE {
+            return Err(WhatsAppError::MediaError("Encrypted media too short".to_string()));
+        }
+        let (ciphertext, received_mac) = self.pending.split_at(self.pending.len() - MAC_SIZE);
+
+        self.file_enc_sha256.update(&self.pending);
+        if let Some(expected) = &self.expected.file_enc_sha256
+            && !Crypto::constant_time_eq(&self.file_enc_sha256.finalize(), expected)
+        {
+            return Err(WhatsAppError::MediaError("Encrypted media hash mismatch".to_string()));
+        }
+
+        self.mac.update(ciphertext);
+        let mac = self.mac.finalize().into_bytes();
+        if !Crypto::constant_time_eq(&mac[..MAC_SIZE], received_mac) {
+            return Err(WhatsAppError::MediaError("Media MAC mismatch".to_string()));
+        }
+
+        let plaintext = Crypto::aes_decrypt(&self.keys.cipher_key, &self.iv, ciphertext)?;
+        self.file_sha256.update(&plaintext);
+        if let Some(expected) = &self.expected.file_sha256
+            && !Crypto::constant_time_eq(&self.file_sha256.finalize(), expected)
+        {
+            return Err(WhatsAppError::MediaError("Media hash mismatch".to_string()));
+        }
+
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEDIA_TYPES: [MediaType; 8] = [
+        MediaType::Image,
+        MediaType::Video,
+        MediaType::Audio,
+        MediaType::Document,
+        MediaType::Sticker,
+        MediaType::History,
+        MediaType::AppState,
+        MediaType::LinkThumbnail,
+    ];
+
+    fn media_error(result: WhatsAppResult<Vec<u8>>) -> String {
+        match result {
+            Err(WhatsAppError::MediaError(message)) => message,
+            other => panic!("expected a media error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_every_media_type() {
+        let plaintext = b"media plaintext spanning more than one block";
+        for media_type in MEDIA_TYPES {
+            let encrypted = encrypt_media(media_type, plaintext).unwrap();
+            assert_eq!(decrypt_media(media_type, &encrypted.keys(), &encrypted.data).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn keys_depend_on_the_media_type() {
+        let encrypted = encrypt_media(MediaType::Image, b"photo").unwrap();
+
+        // Stickers share the image keys, everything else expands its own
+        assert!(decrypt_media(MediaType::Sticker, &encrypted.keys(), &encrypted.data).is_ok());
+        let keys = MediaKeys { file_enc_sha256: None, ..encrypted.keys() };
+        assert_eq!(media_error(decrypt_media(MediaType::Video, &keys, &encrypted.data)), "Media MAC mismatch");
+    }
+
+    #[test]
+    fn rejects_tampered_ciphertext() {
+        let encrypted = encrypt_media(MediaType::Document, b"document").unwrap();
+        let mut data = encrypted.data.clone();
+        data[0] ^= 1;
+
+        // Without the encrypted hash to catch it first, the MAC has to
+        let keys = MediaKeys { file_enc_sha256: None, ..encrypted.keys() };
+        assert_eq!(media_error(decrypt_media(MediaType::Document, &keys, &data)), "Media MAC mismatch");
+        assert_eq!(
+            media_error(decrypt_media(MediaType::Document, &encrypted.keys(), &data)),
+            "Encrypted media hash mismatch",
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_hashes() {
+        let encrypted = encrypt_media(MediaType::Audio, b"voice note").unwrap();
+
+        let keys = MediaKeys { file_sha256: Some(vec![0; 32]), ..encrypted.keys() };
+        assert_eq!(media_error(decrypt_media(MediaType::Audio, &keys, &encrypted.data)), "Media hash mismatch");
+
+        let keys = MediaKeys { file_enc_sha256: Some(vec![0; 32]), ..encrypted.keys() };
+        assert_eq!(
+            media_error(decrypt_media(MediaType::Audio, &keys, &encrypted.data)),
+            "Encrypted media hash mismatch",
+        );
+
+        let keys = MediaKeys { file_sha256: None, file_enc_sha256: None, ..encrypted.keys() };
+        assert_eq!(decrypt_media(MediaType::Audio, &keys, &encrypted.data).unwrap(), b"voice note");
+    }
+
+    #[test]
+    fn encrypted_length_matches_the_output() {
+        // Block-aligned input gets a whole block of padding
+        for length in [0, 1, 15, 16, 17, 32, 100] {
+            let encrypted = encrypt_media(MediaType::Video, &vec![7; length]).unwrap();
+            assert_eq!(encrypted.data.len() as u64, encrypted_length(length as u64), "length {}", length);
+            assert_eq!(encrypted.file_length, length as u64);
+        }
+    }
+
+    #[test]
+    fn chunked_encryption_matches_whole_decryption() {
+        let plaintext: Vec<u8> = (0..100).collect();
+        let mut encryptor = MediaEncryptor::new(MediaType::Image).unwrap();
+        let mut data = Vec::new();
+        for chunk in plaintext.chunks(7) {
+            data.extend(encryptor.update(chunk).unwrap());
+        }
+        let (last, digest) = encryptor.finalize().unwrap();
+        data.extend(last);
+
+        let keys = MediaKeys {
+            media_key: digest.media_key,
+            file_sha256: Some(digest.file_sha256),
+            file_enc_sha256: Some(digest.file_enc_sha256),
+        };
+        assert_eq!(decrypt_media(MediaType::Image, &keys, &data).unwrap(), plaintext);
+
+        let mut decryptor = MediaDecryptor::new(MediaType::Image, &keys).unwrap();
+        let mut decrypted = Vec::new();
+        for chunk in data.chunks(13) {
+            decrypted.extend(decryptor.update(chunk).unwrap());
+        }
+        decrypted.extend(decryptor.finalize().unwrap());
+        assert_eq!(decrypted, plaintext);
+    }
+}
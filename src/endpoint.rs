@@ -0,0 +1,201 @@
+use std::time::{Duration, Instant};
+
+/// Delay before retrying an endpoint after its first failure
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay before retrying a failing endpoint
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Health of a single server endpoint
+#[derive(Debug, Clone)]
+pub struct EndpointHealth {
+    pub url: String,
+    /// Failures since the last successful connection
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub total_connections: u64,
+    pub last_error: Option<String>,
+    pub last_failure: Option<Instant>,
+    pub last_connected: Option<Instant>,
+}
+
+impl EndpointHealth {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            consecutive_failures: 0,
+            total_failures: 0,
+            total_connections: 0,
+            last_error: None,
+            last_failure: None,
+            last_connected: None,
+        }
+    }
+
+    /// When the endpoint may be tried again, backing off exponentially on repeated failures
+    pub fn retry_at(&self) -> Option<Instant> {
+        let last_failure = self.last_failure?;
+        if self.consecutive_failures == 0 {
+            return None;
+        }
+
+        let backoff = BASE_BACKOFF
+            .saturating_mul(1 << (self.consecutive_failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        Some(last_failure + backoff)
+    }
+}
+
+/// Endpoints to connect to, rotated round-robin when they fail
+pub(crate) struct EndpointPool {
+    endpoints: Vec<EndpointHealth>,
+    current: usize,
+}
+
+impl EndpointPool {
+    pub(crate) fn new(urls: &[String]) -> Self {
+        Self {
+            endpoints: urls.iter().map(|url| EndpointHealth::new(url)).collect(),
+            current: 0,
+        }
+    }
+
+    /// URLs in the order they should be tried
+    ///
+    /// Starting from the endpoint that last worked, healthy endpoints come
+    /// first, followed by those still backing off, soonest retry first.
+    pub(crate) fn candidates(&self) -> Vec<String> {
+        let now = Instant::now();
+        let rotated = (0..self.endpoints.len())
+            .map(|offset| &self.endpoints[(self.current + offset) % self.endpoints.len()]);
+
+        let (ready, mut waiting): (Vec<_>, Vec<_>) = rotated
+            .partition(|endpoint| endpoint.retry_at().is_none_or(|retry_at| retry_at <= now));
+        waiting.sort_by_key(|endpoint| endpoint.retry_at());
+
+        ready.into_iter().chain(waiting).map(|endpoint| endpoint.url.clone()).collect()
+    }
+
+    pub(crate) fn record_success(&mut self, url: &str) {
+        if let Some(index) = self.position(url) {
+            let endpoint = &mut self.endpoints[index];
+            endpoint.consecutive_failures = 0;
+            endpoint.total_connections += 1;
+            endpoint.last_connected = Some(Instant::now());
+            self.current = index;
+        }
+    }
+
+    pub(crate) fn record_failure(&mut self, url: &str, error: &str) {
+        if let Some(index) = self.position(url) {
+            let endpoint = &mut self.endpoints[index];
+            endpoint.consecutive_failures += 1;
+            endpoint.total_failures += 1;
+            endpoint.last_error = Some(error.to_string());
+            endpoint.last_failure = Some(Instant::now());
+
+            // Move on so the next connection starts with another endpoint
+            if index == self.current {
+                self.current = (index + 1) % self.endpoints.len();
+            }
+        }
+    }
+
+    pub(crate) fn health(&self) -> Vec<EndpointHealth> {
+        self.endpoints.clone()
+    }
+
+    fn position(&self, url: &str) -> Option<usize> {
+        self.endpoints.iter().position(|endpoint| endpoint.url == url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(urls: &[&str]) -> EndpointPool {
+        EndpointPool::new(&urls.iter().map(|url| url.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut health = EndpointHealth::new("a");
+        assert_eq!(health.retry_at(), None);
+
+        let failed = Instant::now();
+        health.last_failure = Some(failed);
+        let backoffs: Vec<_> = [1, 2, 3, 6, 7, 8, 100, u32::MAX].into_iter()
+            .map(|failures| {
+                health.consecutive_failures = failures;
+                health.retry_at().unwrap() - failed
+            })
+            .collect();
+        assert_eq!(backoffs, [1, 2, 4, 32, 60, 60, 60, 60].map(Duration::from_secs));
+
+        // A success clears the backoff even though the failure is remembered
+        health.consecutive_failures = 0;
+        assert_eq!(health.retry_at(), None);
+    }
+
+    #[test]
+    fn failing_endpoints_move_to_the_back() {
+        let mut pool = pool(&["a", "b", "c"]);
+        assert_eq!(pool.candidates(), ["a", "b", "c"]);
+
+        pool.record_failure("a", "refused");
+        assert_eq!(pool.candidates(), ["b", "c", "a"]);
+
+        // Backing off endpoints come last, soonest retry first
+        pool.record_failure("b", "timed out");
+        assert_eq!(pool.candidates(), ["c", "a", "b"]);
+
+        let health = pool.health();
+        assert_eq!((health[0].consecutive_failures, health[0].total_failures), (1, 1));
+        assert_eq!(health[1].last_error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn endpoints_are_retried_once_their_backoff_passed() {
+        let mut pool = pool(&["a", "b"]);
+        pool.record_failure("a", "refused");
+        pool.endpoints[0].last_failure = Some(Instant::now() - BASE_BACKOFF);
+
+        assert_eq!(pool.candidates(), ["b", "a"]);
+        pool.record_failure("b", "refused");
+        assert_eq!(pool.candidates(), ["a", "b"]);
+    }
+
+    #[test]
+    fn sticks_with_the_endpoint_that_worked() {
+        let mut pool = pool(&["a", "b", "c"]);
+        pool.record_success("c");
+        assert_eq!(pool.candidates(), ["c", "a", "b"]);
+
+        pool.record_failure("b", "refused");
+        pool.record_success("b");
+        assert_eq!(pool.candidates(), ["b", "c", "a"]);
+
+        let health = pool.health();
+        assert_eq!((health[1].consecutive_failures, health[1].total_failures, health[1].total_connections), (0, 1, 1));
+        assert!(health[1].last_connected.is_some());
+
+        // Endpoints that aren't in the pool are ignored
+        pool.record_failure("d", "refused");
+        pool.record_success("d");
+        assert_eq!(pool.candidates(), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn single_endpoint_is_always_a_candidate() {
+        let mut pool = pool(&["a"]);
+        pool.record_failure("a", "refused");
+        pool.record_failure("a", "refused");
+        assert_eq!(pool.candidates(), ["a"]);
+        assert_eq!(pool.health()[0].consecutive_failures, 2);
+
+        pool.record_success("a");
+        assert_eq!(pool.candidates(), ["a"]);
+        assert_eq!(pool.health()[0].retry_at(), None);
+    }
+}
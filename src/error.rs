@@ -6,6 +6,12 @@ pub enum WhatsAppError {
     #[error("Connection error: {0}")]
     ConnectionError(String),
 
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    #[error("Send queue full: {0}")]
+    SendQueueFull(String),
+
     #[error("Authentication error: {0}")]
     AuthError(String),
 
@@ -42,6 +48,9 @@ pub enum WhatsAppError {
     #[error("Store error: {0}")]
     StoreError(String),
 
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+
     #[error("Unknown error: {0}")]
     UnknownError(String),
 }
@@ -0,0 +1,97 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use crate::{
+    error::{WhatsAppError, WhatsAppResult},
+    message::validation::MAX_DOCUMENT_SIZE,
+};
+
+/// Remote file fetched to be sent as media
+#[derive(Debug, Clone, Default)]
+pub struct FetchedMedia {
+    pub data: Vec<u8>,
+    /// MIME type the server claimed, without parameters
+    pub content_type: Option<String>,
+    /// Name of the file, from the response headers or the URL
+    pub file_name: Option<String>,
+}
+
+/// Fetches remote files for `Client::send_media_from_url`, e.g. with
+/// credentials or through a cache
+#[async_trait]
+pub trait MediaFetcher: Send + Sync {
+    async fn fetch(&self, url: &str) -> WhatsAppResult<FetchedMedia>;
+}
+
+/// Fetches files over plain HTTP(S), refusing any larger than WhatsApp accepts
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    max_size: u64,
+}
+
+impl HttpFetcher {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client, max_size: MAX_DOCUMENT_SIZE }
+    }
+
+    /// Refuse files larger than the given number of bytes
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+}
+
+#[async_trait]
+impl MediaFetcher for HttpFetcher {
+    async fn fetch(&self, url: &str) -> WhatsAppResult<FetchedMedia> {
+        let too_large = || WhatsAppError::MediaError(format!("{} is over the {} byte limit", url, self.max_size));
+
+        let response = self.client.get(url).send().await
+            .map_err(|e| WhatsAppError::MediaError(format!("Failed to fetch {}: {}", url, e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(WhatsAppError::MediaError(format!("Fetching {} failed with {}", url, status)));
+        }
+        if response.content_length().is_some_and(|length| length > self.max_size) {
+            return Err(too_large());
+        }
+
+        let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+        let content_type = header(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .filter(|value| !value.is_empty());
+        let file_name = header(reqwest::header::CONTENT_DISPOSITION)
+            .and_then(disposition_file_name)
+            .or_else(|| url_file_name(response.url()));
+
+        // The length header may be missing or wrong, so the limit holds while reading too
+        let mut data = Vec::new();
+        let mut chunks = response.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|e| WhatsAppError::MediaError(format!("Failed to fetch {}: {}", url, e)))?;
+            if (data.len() + chunk.len()) as u64 > self.max_size {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(FetchedMedia { data, content_type, file_name })
+    }
+}
+
+/// Get the file name from a `Content-Disposition` header like `attachment; filename="report.pdf"`
+fn disposition_file_name(value: &str) -> Option<String> {
+    value.split(';')
+        .filter_map(|part| part.trim().strip_prefix("filename="))
+        .map(|name| name.trim_matches('"').to_string())
+        .find(|name| !name.is_empty())
+}
+
+/// Get the file name from the last segment of a URL's path
+fn url_file_name(url: &url::Url) -> Option<String> {
+    url.path_segments()?
+        .next_back()
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+}
@@ -0,0 +1,343 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    JID,
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+    message::Message,
+};
+
+/// Longest group subject WhatsApp accepts, in characters
+pub const MAX_SUBJECT_LENGTH: usize = 100;
+
+/// What invite codes are prefixed with to make a link
+pub const INVITE_LINK_PREFIX: &str = "https://chat.whatsapp.com/";
+
+/// Get the invite code from an invite link, or take it as a code already
+pub fn invite_code(link: &str) -> &str {
+    let link = link.trim();
+    let code = link.strip_prefix(INVITE_LINK_PREFIX)
+        .or_else(|| link.strip_prefix("chat.whatsapp.com/"))
+        .unwrap_or(link);
+    code.split(['?', '/']).next().unwrap_or_default()
+}
+
+/// Group to create with `Client::create_group_with`
+#[derive(Debug, Clone)]
+pub struct CreateGroupRequest {
+    pub subject: String,
+    /// Who to add besides us
+    pub participants: Vec<JID>,
+    /// Community to link the group to, which we have to administer
+    pub linked_parent: Option<JID>,
+    /// Sent again with a retried request so the server creates the group only once
+    pub create_key: String,
+    /// Message sent to the group as soon as it exists, whatever chat it names
+    pub first_message: Option<Message>,
+}
+
+impl CreateGroupRequest {
+    /// Create a request with a fresh create key
+    pub fn new(subject: &str, participants: &[JID]) -> Self {
+        Self {
+            subject: subject.to_string(),
+            participants: participants.to_vec(),
+            linked_parent: None,
+            create_key: hex::encode(Crypto::random_bytes(8)).to_uppercase(),
+            first_message: None,
+        }
+    }
+}
+
+/// What a participant may do in a group
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParticipantRole {
+    #[default]
+    Member,
+    Admin,
+    /// Creator of the group, who can't be demoted
+    SuperAdmin,
+}
+
+/// How the participants of a group changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParticipantAction {
+    Add,
+    Remove,
+    Promote,
+    Demote,
+}
+
+impl ParticipantAction {
+    /// Parse the action of a group notification, participants leaving counting as removed
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add" => Some(ParticipantAction::Add),
+            "remove" | "leave" => Some(ParticipantAction::Remove),
+            "promote" => Some(ParticipantAction::Promote),
+            "demote" => Some(ParticipantAction::Demote),
+            _ => None,
+        }
+    }
+}
+
+/// Setting of a group only admins can change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupSetting {
+    /// Only admins can send messages
+    Announce,
+    /// Only admins can change the subject, description and photo
+    Locked,
+    /// Admins have to approve who joins
+    JoinApproval,
+}
+
+impl GroupSetting {
+    /// Name of the setting in requests and notifications
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupSetting::Announce => "announce",
+            GroupSetting::Locked => "locked",
+            GroupSetting::JoinApproval => "join_approval",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "announce" => Some(GroupSetting::Announce),
+            "locked" => Some(GroupSetting::Locked),
+            "join_approval" => Some(GroupSetting::JoinApproval),
+            _ => None,
+        }
+    }
+}
+
+/// Who can add participants to a group
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemberAddMode {
+    #[default]
+    AdminAdd,
+    AllMemberAdd,
+}
+
+/// How someone stopped being a participant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LeaveReason {
+    Left,
+    Removed,
+}
+
+/// Former participant of a group
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PastParticipant {
+    pub jid: JID,
+    pub reason: LeaveReason,
+    /// When they stopped being a participant, in seconds since the epoch
+    pub left_at: u64,
+}
+
+impl PastParticipant {
+    /// Parse a past participant like `{"jid":"...","reason":"removed","timestamp":1700000000}`
+    pub(crate) fn from_json(value: &serde_json::Value) -> WhatsAppResult<Self> {
+        let jid = value["jid"].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Past participant without a JID: {}", value)))?
+            .parse()?;
+        let reason = match value["reason"].as_str() {
+            Some("removed") => LeaveReason::Removed,
+            _ => LeaveReason::Left,
+        };
+
+        Ok(Self { jid, reason, left_at: value["timestamp"].as_u64().unwrap_or_default() })
+    }
+}
+
+/// Invite sent in place of adding someone whose privacy settings don't allow it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddRequest {
+    pub code: String,
+    pub expiration: u64,
+}
+
+/// Member of a group, or someone we tried to make one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupParticipant {
+    pub jid: JID,
+    pub role: ParticipantRole,
+    /// Why they couldn't be added, e.g. 403 when their privacy settings forbid
+    /// it, 408 when they left recently or 409 when they already are a member
+    pub error: Option<u16>,
+    /// Invite to send them instead, when their privacy settings forbid adding them
+    pub add_request: Option<AddRequest>,
+}
+
+impl GroupParticipant {
+    pub fn is_admin(&self) -> bool {
+        self.role != ParticipantRole::Member
+    }
+
+    /// Parse a participant like `{"jid":"...","type":"admin"}`, or
+    /// `{"jid":"...","error":403,"add_request":{"code":"...","expiration":...}}`
+    pub(crate) fn from_json(value: &serde_json::Value) -> WhatsAppResult<Self> {
+        let jid = value["jid"].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Group participant without a JID: {}", value)))?
+            .parse()?;
+        let role = match value["type"].as_str() {
+            Some("superadmin") => ParticipantRole::SuperAdmin,
+            Some("admin") => ParticipantRole::Admin,
+            _ => ParticipantRole::Member,
+        };
+        let add_request = value["add_request"]["code"].as_str().map(|code| AddRequest {
+            code: code.to_string(),
+            expiration: value["add_request"]["expiration"].as_u64().unwrap_or_default(),
+        });
+
+        Ok(Self {
+            jid,
+            role,
+            error: value["error"].as_u64().map(|code| code as u16),
+            add_request,
+        })
+    }
+}
+
+/// What is known about a group
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupInfo {
+    pub jid: JID,
+    pub subject: String,
+    pub owner: Option<JID>,
+    /// When the group was created, in seconds since the epoch
+    pub creation: u64,
+    pub description: Option<String>,
+    pub participants: Vec<GroupParticipant>,
+    /// Number of participants, which invite previews give without listing them all
+    pub size: u32,
+    /// Seconds until new messages disappear, zero when they don't
+    pub ephemeral_expiration: u32,
+    /// Only admins can send messages
+    pub announce: bool,
+    /// Only admins can change the subject, description and photo
+    pub locked: bool,
+    /// Admins have to approve who joins
+    pub join_approval: bool,
+    /// Group is a community, which other groups link to
+    pub is_parent: bool,
+    /// Community the group is linked to
+    pub linked_parent: Option<JID>,
+    /// Group is the announcement group of its community, which every member is in
+    pub is_default_subgroup: bool,
+    pub member_add_mode: MemberAddMode,
+    /// Admins of a community have to approve who joins its groups unless they say otherwise
+    pub default_membership_approval: bool,
+    /// Who used to be in the group, once fetched with `Client::get_past_participants`
+    pub past_participants: Vec<PastParticipant>,
+}
+
+impl GroupInfo {
+    /// Parse group metadata like `{"jid":"...@g.us","subject":"...","owner":"...",
+    /// "creation":1700000000,"description":"...","participants":[...],"size":12,"ephemeral":86400,
+    /// "announce":true,"locked":false,"join_approval":false,"parent":false,
+    /// "linked_parent":"<community>","default_subgroup":false,"member_add_mode":"admin_add",
+    /// "default_membership_approval":false}`
+    pub(crate) fn from_json(value: &serde_json::Value) -> WhatsAppResult<Self> {
+        let jid: JID = value["jid"].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Group without a JID: {}", value)))?
+            .parse()?;
+        if !jid.is_group() {
+            return Err(WhatsAppError::ParsingError(format!("{} is not a group", jid)));
+        }
+
+        let participants = value["participants"].as_array().into_iter().flatten()
+            .map(GroupParticipant::from_json)
+            .collect::<WhatsAppResult<Vec<_>>>()?;
+
+        Ok(Self {
+            jid,
+            subject: value["subject"].as_str().unwrap_or_default().to_string(),
+            owner: value["owner"].as_str().and_then(|owner| owner.parse().ok()),
+            creation: value["creation"].as_u64().unwrap_or_default(),
+            description: value["description"].as_str().map(str::to_string),
+            size: value["size"].as_u64().map_or(participants.len() as u32, |size| size as u32),
+            participants,
+            ephemeral_expiration: value["ephemeral"].as_u64().unwrap_or_default() as u32,
+            announce: value["announce"].as_bool().unwrap_or_default(),
+            locked: value["locked"].as_bool().unwrap_or_default(),
+            join_approval: value["join_approval"].as_bool().unwrap_or_default(),
+            is_parent: value["parent"].as_bool().unwrap_or_default(),
+            linked_parent: value["linked_parent"].as_str().and_then(|parent| parent.parse().ok()),
+            is_default_subgroup: value["default_subgroup"].as_bool().unwrap_or_default(),
+            member_add_mode: match value["member_add_mode"].as_str() {
+                Some("all_member_add") => MemberAddMode::AllMemberAdd,
+                _ => MemberAddMode::AdminAdd,
+            },
+            default_membership_approval: value["default_membership_approval"].as_bool().unwrap_or_default(),
+            past_participants: Vec::new(),
+        })
+    }
+
+    /// Whether the setting is turned on
+    pub fn setting(&self, setting: GroupSetting) -> bool {
+        match setting {
+            GroupSetting::Announce => self.announce,
+            GroupSetting::Locked => self.locked,
+            GroupSetting::JoinApproval => self.join_approval,
+        }
+    }
+
+    pub(crate) fn set_setting(&mut self, setting: GroupSetting, enabled: bool) {
+        match setting {
+            GroupSetting::Announce => self.announce = enabled,
+            GroupSetting::Locked => self.locked = enabled,
+            GroupSetting::JoinApproval => self.join_approval = enabled,
+        }
+    }
+
+    /// Get the participant with the given JID, ignoring the device
+    pub fn participant(&self, jid: &JID) -> Option<&GroupParticipant> {
+        self.participants.iter()
+            .find(|participant| participant.jid.user == jid.user && participant.jid.server == jid.server)
+    }
+}
+
+/// Group linked to a community, as the community lists it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkedGroup {
+    pub jid: JID,
+    pub subject: String,
+    /// Group is the announcement group of the community
+    pub is_default_subgroup: bool,
+}
+
+impl LinkedGroup {
+    /// Parse a linked group like `{"jid":"...@g.us","subject":"...","default_subgroup":false}`
+    pub(crate) fn from_json(value: &serde_json::Value) -> WhatsAppResult<Self> {
+        let jid = value["jid"].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Linked group without a JID: {}", value)))?
+            .parse()?;
+
+        Ok(Self {
+            jid,
+            subject: value["subject"].as_str().unwrap_or_default().to_string(),
+            is_default_subgroup: value["default_subgroup"].as_bool().unwrap_or_default(),
+        })
+    }
+}
+
+/// Request to join a group that needs admins to approve who joins
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JoinRequest {
+    pub jid: JID,
+    /// When they asked, in seconds since the epoch
+    pub requested_at: u64,
+}
+
+impl JoinRequest {
+    /// Parse a join request like `{"jid":"...","requested_at":1700000000}`
+    pub(crate) fn from_json(value: &serde_json::Value) -> WhatsAppResult<Self> {
+        let jid = value["jid"].as_str()
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Join request without a JID: {}", value)))?
+            .parse()?;
+
+        Ok(Self { jid, requested_at: value["requested_at"].as_u64().unwrap_or_default() })
+    }
+}
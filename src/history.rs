@@ -0,0 +1,309 @@
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::rc::Rc;
+use log::debug;
+use protobuf::CodedInputStream;
+use protobuf::rt::WireType;
+
+use crate::{
+    client::{ChatInfo, MessageStore},
+    error::{WhatsAppError, WhatsAppResult},
+    message::Message,
+    proto::{self, decode_fields, field_bool, field_bytes, field_string, field_u64},
+    zlib::ZlibDecoder,
+};
+
+/// `HistorySync.conversations` field number
+const CONVERSATIONS_FIELD: u32 = 2;
+
+/// History sync import settings
+#[derive(Debug, Clone)]
+pub struct HistorySyncConfig {
+    /// Upper bound in bytes on history data buffered in memory during an import
+    pub memory_limit: usize,
+    /// Maximum number of messages written to the store at once
+    pub batch_size: usize,
+}
+
+impl Default for HistorySyncConfig {
+    fn default() -> Self {
+        Self {
+            memory_limit: 16 * 1024 * 1024,
+            batch_size: 500,
+        }
+    }
+}
+
+/// Progress of a history sync import
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistorySyncProgress {
+    pub compressed_bytes: u64,
+    pub decompressed_bytes: u64,
+    pub conversations: u64,
+    pub messages: u64,
+    pub completed: bool,
+}
+
+/// Reader wrapper counting the bytes pulled through it
+struct CountingReader<R: Read> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Stream a zlib-compressed `HistorySync` blob into the message store
+///
+/// Conversations are decoded one at a time and their messages written in
+/// batches, so memory use stays around `memory_limit` regardless of the size
+/// of the blob. Half of the limit bounds the encoded conversation being
+/// decoded, the other half the batch awaiting a write. Both are measured by
+/// encoded size, which decoded messages exceed somewhat, so the limit is
+/// approximate.
+///
+/// Messages the store already has are skipped, so importing a blob twice
+/// doesn't duplicate them.
+pub fn import<R, F>(
+    reader: R,
+    config: &HistorySyncConfig,
+    store: &MessageStore,
+    mut on_progress: F,
+) -> WhatsAppResult<HistorySyncProgress>
+where
+    R: Read,
+    F: FnMut(&HistorySyncProgress),
+{
+    let compressed_bytes = Rc::new(Cell::new(0));
+    let mut decoder = ZlibDecoder::new(CountingReader {
+        inner: reader,
+        count: compressed_bytes.clone(),
+    });
+    let mut input = CodedInputStream::new(&mut decoder);
+
+    let conversation_limit = config.memory_limit / 2;
+    let batch_limit = config.memory_limit - conversation_limit;
+    let batch_size = config.batch_size.max(1);
+
+    let mut progress = HistorySyncProgress::default();
+    let mut batch: Vec<Message> = Vec::new();
+    let mut batch_bytes = 0;
+
+    while let Some(tag) = input.read_raw_tag_or_eof().map_err(proto::parse_error)? {
+        let wire_type = WireType::new(tag & 7)
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Invalid wire type in tag {}", tag)))?;
+
+        if tag >> 3 != CONVERSATIONS_FIELD || wire_type != WireType::LengthDelimited {
+            input.skip_field(wire_type).map_err(proto::parse_error)?;
+            continue;
+        }
+
+        let len = input.read_raw_varint32().map_err(proto::parse_error)?;
+        if len as usize > conversation_limit {
+            return Err(WhatsAppError::StoreError(format!(
+                "History sync conversation of {} bytes exceeds the memory limit of {} bytes",
+                len, config.memory_limit
+            )));
+        }
+
+        let data = input.read_raw_bytes(len).map_err(proto::parse_error)?;
+        let (chat, messages) = decode_conversation(&data)?;
+        drop(data);
+
+        debug!("History sync: {} messages in {}", messages.len(), chat.jid);
+        store.upsert_chat(chat);
+        progress.conversations += 1;
+
+        // Make room for the conversation before adding it, so the batch stays within its half
+        let mut flushed = false;
+        if batch_bytes + len as usize > batch_limit {
+            flush(store, &mut batch, &mut progress)?;
+            batch_bytes = 0;
+            flushed = true;
+        }
+        batch_bytes += len as usize;
+
+        for message in messages {
+            batch.push(message);
+            if batch.len() >= batch_size {
+                flush(store, &mut batch, &mut progress)?;
+                batch_bytes = 0;
+                flushed = true;
+            }
+        }
+
+        // Report progress once per written batch rather than per conversation
+        if flushed {
+            progress.compressed_bytes = compressed_bytes.get();
+            progress.decompressed_bytes = input.pos();
+            on_progress(&progress);
+        }
+    }
+
+    flush(store, &mut batch, &mut progress)?;
+
+    progress.compressed_bytes = compressed_bytes.get();
+    progress.decompressed_bytes = input.pos();
+    progress.completed = true;
+    on_progress(&progress);
+
+    Ok(progress)
+}
+
+/// Write the pending batch of messages along with the chat list
+fn flush(
+    store: &MessageStore,
+    batch: &mut Vec<Message>,
+    progress: &mut HistorySyncProgress,
+) -> WhatsAppResult<()> {
+    store.insert_messages(batch)?;
+    store.save_chats()?;
+
+    progress.messages += batch.len() as u64;
+    batch.clear();
+
+    Ok(())
+}
+
+/// Decode a `Conversation` into its chat-list entry and messages
+fn decode_conversation(data: &[u8]) -> WhatsAppResult<(ChatInfo, Vec<Message>)> {
+    let mut chat = ChatInfo::default();
+    let mut messages = Vec::new();
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => chat.jid = field_string(&value)?.parse()?,
+            2 => {
+                // HistorySyncMsg { message = 1; msgOrderID = 2 }
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    if field == 1 {
+                        messages.push(proto::decode_web_message_info(field_bytes(&value)?)?);
+                    }
+                }
+            }
+            9 => chat.ephemeral_expiration = field_u64(&value)? as u32,
+            6 => chat.unread_count = field_u64(&value)? as u32,
+            12 => chat.last_message_timestamp = field_u64(&value)?,
+            13 => chat.name = Some(field_string(&value)?),
+            16 => chat.archived = field_bool(&value)?,
+            19 => chat.marked_unread = field_bool(&value)?,
+            24 => chat.pinned = field_u64(&value)? != 0,
+            25 => chat.mute_end_time = field_u64(&value)?,
+            _ => {}
+        }
+    }
+
+    Ok((chat, messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{crypto::Crypto, proto::Encoder, zlib};
+
+    /// Encoded `Conversation` with three text messages, the same size for every index below 10
+    fn conversation(index: usize) -> Vec<u8> {
+        let jid = format!("1234567890{}@s.whatsapp.net", index);
+        let mut conversation = Encoder::new().bytes(1, jid.as_bytes());
+        for message in 0..3 {
+            let key = Encoder::new()
+                .bytes(1, jid.as_bytes())
+                .bytes(3, format!("3EB0{}{}", index, message).as_bytes())
+                .finish();
+            let content = Encoder::new().bytes(1, b"hello").finish();
+            let info = Encoder::new().bytes(1, &key).bytes(2, &content).u64(3, 1_700_000_000).finish();
+            conversation = conversation.bytes(2, &Encoder::new().bytes(1, &info).finish());
+        }
+        conversation.finish()
+    }
+
+    fn history_sync(conversations: usize) -> Vec<u8> {
+        let mut history = Encoder::new().u64(1, 0);
+        for index in 0..conversations {
+            history = history.bytes(CONVERSATIONS_FIELD, &conversation(index));
+        }
+        zlib::compress(&history.finish())
+    }
+
+    fn store() -> (MessageStore, String) {
+        let dir = std::env::temp_dir()
+            .join(format!("whatsandra-history-{}", hex::encode(Crypto::random_bytes(8))))
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(&dir).unwrap();
+        (MessageStore::new(&dir), dir)
+    }
+
+    #[test]
+    fn imports_in_batches_within_the_memory_limit() {
+        let (store, dir) = store();
+        let blob = history_sync(6);
+
+        // Room for two conversations in the batch, so every third one flushes it first
+        let config = HistorySyncConfig {
+            memory_limit: 4 * conversation(0).len() + 1,
+            batch_size: 1000,
+        };
+        let mut reports = Vec::new();
+        let progress = import(&blob[..], &config, &store, |progress| reports.push(progress.clone())).unwrap();
+
+        let batches: Vec<_> = reports.iter().map(|report| (report.conversations, report.messages, report.completed)).collect();
+        assert_eq!(batches, [(3, 6, false), (5, 12, false), (6, 18, true)]);
+        assert_eq!(progress.compressed_bytes, blob.len() as u64);
+        assert!(reports.windows(2).all(|pair| pair[0].decompressed_bytes < pair[1].decompressed_bytes));
+
+        let messages = fs::read_to_string(format!("{}/messages.jsonl", dir)).unwrap();
+        assert_eq!(messages.lines().count(), 18);
+        assert!(store.get_chat(&"12345678905@s.whatsapp.net".parse().unwrap()).is_some());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn batch_size_flushes_within_a_conversation() {
+        let (store, dir) = store();
+        let config = HistorySyncConfig { batch_size: 2, ..HistorySyncConfig::default() };
+
+        let mut reports = Vec::new();
+        import(&history_sync(2)[..], &config, &store, |progress| reports.push(progress.messages)).unwrap();
+        // Reported once per conversation that flushed, then once on completion
+        assert_eq!(reports, [2, 6, 6]);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reimporting_skips_stored_messages() {
+        let (store, dir) = store();
+        let blob = history_sync(3);
+
+        for _ in 0..2 {
+            import(&blob[..], &HistorySyncConfig::default(), &store, |_| {}).unwrap();
+        }
+
+        let messages = fs::read_to_string(format!("{}/messages.jsonl", dir)).unwrap();
+        assert_eq!(messages.lines().count(), 9);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_conversations_over_the_limit() {
+        let (store, dir) = store();
+        let config = HistorySyncConfig {
+            memory_limit: conversation(0).len(),
+            batch_size: 1000,
+        };
+
+        assert!(import(&history_sync(1)[..], &config, &store, |_| {}).is_err());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -10,13 +10,31 @@ pub mod message;
 pub mod client;
 pub mod websocket;
 pub mod crypto;
+pub mod adv;
+pub mod binary;
+pub mod appstate;
+pub mod endpoint;
+pub mod fetch;
+pub mod group;
+pub mod history;
+pub mod noise;
+pub mod payload;
+pub mod proxy;
+pub mod qr;
+pub mod ratelimit;
+pub mod signal;
+pub mod store;
+pub mod tls;
+pub mod transport;
+mod proto;
+mod zlib;
 
 // Re-export types
 pub use error::{WhatsAppError, WhatsAppResult};
 pub use client::LogLevel;
 
 /// Represents a WhatsApp JID (Jabber ID)
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct JID {
     pub user: String,
     pub server: String,
@@ -36,21 +54,60 @@ impl JID {
         self.server == "s.whatsapp.net"
     }
 
+    /// Whether this is a group, communities and the groups linked to them included
     pub fn is_group(&self) -> bool {
         self.server == "g.us"
     }
 
-    pub fn to_string(&self) -> String {
-        match self.device {
-            Some(device) => format!("{}@{}.{}", self.user, self.server, device),
-            None => format!("{}@{}", self.user, self.server),
-        }
+    /// JID statuses are posted to
+    pub fn status_broadcast() -> Self {
+        Self::new("status", "broadcast", None)
+    }
+
+    pub fn is_status_broadcast(&self) -> bool {
+        self.user == "status" && self.server == "broadcast"
+    }
+
+    /// Whether this is one of our broadcast lists, sent to each recipient as a direct message
+    pub fn is_broadcast_list(&self) -> bool {
+        self.server == "broadcast" && !self.is_status_broadcast()
     }
 }
 
+/// Writes JIDs in wire format, the same `FromStr` parses
 impl std::fmt::Display for JID {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        match self.device {
+            Some(device) => write!(f, "{}:{}@{}", self.user, device, self.server),
+            None => write!(f, "{}@{}", self.user, self.server),
+        }
+    }
+}
+
+/// Parses JIDs in wire format, e.g. `1234567890@s.whatsapp.net` or `1234567890:3@s.whatsapp.net`
+impl std::str::FromStr for JID {
+    type Err = WhatsAppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, server) = match s.split_once('@') {
+            Some((user, server)) => (user, server),
+            None => ("", s),
+        };
+
+        if server.is_empty() {
+            return Err(WhatsAppError::ParsingError(format!("Invalid JID: {}", s)));
+        }
+
+        let (user, device) = match user.split_once(':') {
+            Some((user, device)) => {
+                let device = device.parse()
+                    .map_err(|_| WhatsAppError::ParsingError(format!("Invalid JID device: {}", s)))?;
+                (user, Some(device))
+            }
+            None => (user, None),
+        };
+
+        Ok(Self::new(user, server, device))
     }
 }
 
@@ -83,6 +140,7 @@ pub struct ClientConfig {
 
 /// WhatsApp events
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     /// Connection established
     Connected,
@@ -90,27 +148,229 @@ pub enum Event {
     /// Connection lost
     Disconnected,
 
-    /// QR code generated for authentication
-    QRCodeGenerated(String),
+    /// Connection stopped responding to keepalives and will be re-established
+    ConnectionStale,
+
+    /// `Client::shutdown` finished closing the connection
+    ShutdownComplete,
+
+    /// Connection moved from the first state to the second
+    ConnectionStateChanged(client::ConnectionState, client::ConnectionState),
 
-    /// Authentication successful
-    LoggedIn(JID),
+    /// QR code generated for pairing with the phone
+    QRCodeGenerated(qr::QrCode),
 
-    /// Authentication lost
-    LoggedOut,
+    /// Every pairing ref expired before a QR code was scanned
+    QRTimeout,
+
+    /// Phone accepted the pairing and assigned this device a JID
+    PairSuccess {
+        jid: JID,
+        platform: String,
+        business_name: Option<String>,
+    },
+
+    /// Authentication successful, with who we are logged in as
+    LoggedIn {
+        jid: JID,
+        /// Hidden user ID of the account, if the server assigned one
+        lid: Option<JID>,
+        /// Platform of the primary phone, e.g. "android"
+        platform: String,
+        push_name: Option<String>,
+        business: bool,
+    },
+
+    /// Authentication lost, e.g. because the phone unlinked this device, with the reason
+    LoggedOut(String),
+
+    /// Session was taken over by another login
+    SessionConflict(String),
+
+    /// Server replaced this connection with another one of the same device
+    StreamReplaced(String),
+
+    /// Account temporarily banned from logging in
+    TemporaryBan {
+        reason: String,
+        expires_in: Option<std::time::Duration>,
+    },
 
     /// Message received
     MessageReceived(message::Message),
 
+    /// Someone reacted to the message with the key, or removed their reaction
+    ReactionReceived {
+        key: message::MessageKey,
+        /// Empty when the reaction was removed
+        emoji: String,
+        remover: bool,
+        sender: JID,
+    },
+
+    /// Sender edited one of their messages, with its new content
+    MessageEdited {
+        key: message::MessageKey,
+        message: message::Message,
+        sender: JID,
+    },
+
+    /// Message was deleted for everyone, by its author or by a group admin
+    MessageRevoked {
+        key: message::MessageKey,
+        revoked_by: JID,
+        admin: bool,
+    },
+
+    /// Vote on a poll, replacing the voter's earlier selection
+    PollVote {
+        poll_id: String,
+        voter: JID,
+        selected_options: Vec<String>,
+    },
+
+    /// Someone changed how long until new messages in the chat disappear, zero turning it off
+    DisappearingTimerChanged {
+        chat: JID,
+        sender: JID,
+        timer: std::time::Duration,
+    },
+
+    /// User tapped a button of one of our buttons messages
+    ButtonResponse {
+        chat: JID,
+        sender: JID,
+        response: message::interactive::ButtonsResponse,
+    },
+
+    /// User picked a row of one of our list messages
+    ListResponse {
+        chat: JID,
+        sender: JID,
+        response: message::interactive::ListResponse,
+    },
+
+    /// Contact posted a status
+    StatusPosted {
+        sender: JID,
+        message: message::Message,
+    },
+
+    /// Someone pinned a message at the top of a chat or unpinned it
+    MessagePinned {
+        key: message::MessageKey,
+        sender: JID,
+        pinned: bool,
+        /// How long until the pin expires
+        duration: Option<std::time::Duration>,
+    },
+
+    /// One of our devices starred or unstarred a message
+    MessageStarred {
+        key: message::MessageKey,
+        starred: bool,
+    },
+
+    /// One of our devices deleted a message for us only
+    MessageDeletedForMe {
+        key: message::MessageKey,
+    },
+
+    /// One of our devices deleted every message of a chat
+    ChatCleared {
+        chat: JID,
+    },
+
+    /// Message queued with `Client::schedule_message` went out
+    ScheduledMessageSent {
+        id: String,
+        chat: JID,
+    },
+
+    /// Images and videos sent together as an album, in the order they arrived
+    AlbumReceived(Vec<message::Message>),
+
+    /// Sender's phone answered `Client::request_media_retry`, with the new
+    /// direct path of the media when it was uploaded again
+    MediaRetry {
+        key: message::MessageKey,
+        result: message::MediaRetryResult,
+        direct_path: Option<String>,
+    },
+
+    /// Message couldn't be decrypted even after asking the sender for it again
+    UndecryptableMessage {
+        id: String,
+        chat: JID,
+        sender: JID,
+        error: error::WhatsAppError,
+    },
+
     /// Message status update
     MessageStatus(message::MessageReceipt),
 
-    /// Group update
-    GroupUpdate(JID, String),
+    /// Participants were added to or removed from a group, or made or unmade admins
+    GroupParticipantsChanged {
+        group: JID,
+        action: group::ParticipantAction,
+        /// Who made the change, if known
+        actor: Option<JID>,
+        participants: Vec<JID>,
+    },
+
+    /// Group was renamed
+    GroupSubjectChanged {
+        group: JID,
+        subject: String,
+        /// Who made the change, if known
+        actor: Option<JID>,
+    },
+
+    /// Admin of a group turned one of its settings on or off
+    GroupSettingsChanged {
+        group: JID,
+        setting: group::GroupSetting,
+        enabled: bool,
+        /// Who made the change, if known
+        actor: Option<JID>,
+    },
+
+    /// Someone asked to join a group whose admins approve who joins
+    GroupJoinRequest {
+        group: JID,
+        requester: JID,
+    },
+
+    /// We left a group with `Client::leave_group`
+    GroupLeft(JID),
+
+    /// We were made an admin of a group or stopped being one
+    GroupAdminStatusChanged {
+        group: JID,
+        admin: bool,
+        /// Who made the change, if known
+        actor: Option<JID>,
+    },
 
     /// Presence update
     Presence(JID, bool),
 
+    /// Someone started or stopped typing or recording in a chat
+    ChatPresence {
+        chat: JID,
+        sender: JID,
+        state: client::ChatPresence,
+    },
+
+    /// Identity key of a contact's device changed, so its security code did too
+    IdentityChanged(JID),
+
+    /// Message to the chat was delayed by the rate limiter for the given time
+    RateLimited(JID, std::time::Duration),
+
+    /// History sync import progress
+    HistorySyncProgress(history::HistorySyncProgress),
+
     /// Error event
     Error(error::WhatsAppError),
 
@@ -181,7 +441,7 @@ impl Client {
     /// Send a text message
     pub fn send_text_message(&self, to: JID, text: &str) -> Result<(), WhatsAppError> {
         // Would implement actual message sending logic here
-        println!("Sending message to {}: {}", to.to_string(), text);
+        println!("Sending message to {}: {}", to, text);
         Ok(())
     }
 
@@ -197,7 +457,7 @@ impl Client {
         // Would implement actual media message sending logic here
         println!(
             "Sending media to {}: {} ({}), caption: {:?}",
-            to.to_string(),
+            to,
             media_url,
             mime_type,
             caption
@@ -210,3 +470,26 @@ impl Client {
         *self.connected.lock().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jid_round_trips_through_its_wire_format() {
+        for jid in [
+            JID::new("1234567890", "s.whatsapp.net", None),
+            JID::new("1234567890", "s.whatsapp.net", Some(3)),
+            JID::new("123456789012345", "lid", Some(12)),
+            JID::new("120363025246125486", "g.us", None),
+        ] {
+            let wire = jid.to_string();
+            assert_eq!(wire.parse::<JID>().unwrap(), jid, "{} didn't round-trip", wire);
+        }
+    }
+
+    #[test]
+    fn device_jid_displays_as_user_device_at_server() {
+        assert_eq!(JID::new("1234567890", "s.whatsapp.net", Some(3)).to_string(), "1234567890:3@s.whatsapp.net");
+    }
+}
@@ -1,11 +1,13 @@
-use std::io;
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
 
 use whatsandra::{
     Event, WhatsAppError,
     client::{Client, ClientConfig, LogLevel},
 };
 
-fn main() -> Result<(), WhatsAppError> {
+#[tokio::main]
+async fn main() -> Result<(), WhatsAppError> {
     // Initialize env_logger
     env_logger::init();
 
@@ -13,6 +15,7 @@ fn main() -> Result<(), WhatsAppError> {
     let config = ClientConfig {
         store_path: "whatsapp_store".to_string(),
         log_level: LogLevel::Debug,
+        ..Default::default()
     };
 
     // Create the client
@@ -30,15 +33,16 @@ fn main() -> Result<(), WhatsAppError> {
             },
             Event::QRCodeGenerated(qr) => {
                 println!("🔐 Scan this QR code with your WhatsApp app:");
-                println!("{}", qr);
-
-                // In a real application, we would display the QR code as an image
+                println!("{}", qr.to_terminal_string());
+            },
+            Event::PairSuccess { jid, platform, .. } => {
+                println!("📱 Paired as {} with a {} phone", jid, platform);
             },
-            Event::LoggedIn(jid) => {
+            Event::LoggedIn { jid, .. } => {
                 println!("🎉 Logged in as {}", jid);
             },
-            Event::LoggedOut => {
-                println!("👋 Logged out");
+            Event::LoggedOut(reason) => {
+                println!("👋 Logged out: {}", reason);
             },
             Event::MessageReceived(msg) => {
                 if let Some(text) = &msg.text {
@@ -46,9 +50,12 @@ fn main() -> Result<(), WhatsAppError> {
 
                     // Echo the message back
                     if let Ok(reply) = client_clone.create_user_message(&msg.chat_jid.user, &format!("Echo: {}", text)) {
-                        if let Err(e) = client_clone.send_message(&reply) {
-                            println!("Failed to send reply: {:?}", e);
-                        }
+                        let client = client_clone.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = client.send_message(&reply).await {
+                                println!("Failed to send reply: {:?}", e);
+                            }
+                        });
                     }
                 }
             },
@@ -60,21 +67,20 @@ fn main() -> Result<(), WhatsAppError> {
 
     // Connect to WhatsApp
     println!("Connecting to WhatsApp...");
-    client.connect()?;
+    client.connect().await?;
 
-    // Generate QR code if not authenticated
+    // The QR code is emitted once the server sends a pairing ref
     if !client.is_authenticated() {
-        println!("Not authenticated, generating QR code...");
-        client.generate_qr_code()?;
+        println!("Not authenticated, waiting for a pairing QR code...");
     }
 
     // Main loop
     println!("Press ENTER to exit");
     let mut input = String::new();
-    io::stdin().read_line(&mut input).map_err(|e| WhatsAppError::IOError(e.to_string()))?;
+    BufReader::new(io::stdin()).read_line(&mut input).await.map_err(|e| WhatsAppError::IOError(e.to_string()))?;
 
-    // Disconnect
-    client.disconnect()?;
+    // Flush pending messages and disconnect
+    client.shutdown(Duration::from_secs(5)).await?;
 
     Ok(())
 }
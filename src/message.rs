@@ -4,6 +4,16 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::JID;
 
+pub mod interactive;
+pub mod sticker;
+pub mod thumbnail;
+pub mod validation;
+pub mod video;
+
+use interactive::{ButtonsMessage, Interactive, ListMessage};
+use thumbnail::ThumbnailConfig;
+use video::{Mp4Inspector, VideoInspector};
+
 /// Message types supported by WhatsApp
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageType {
@@ -16,6 +26,16 @@ pub enum MessageType {
     Location,
     Sticker,
     GroupInvite,
+    Reaction,
+    Protocol,
+    Poll,
+    PollUpdate,
+    Buttons,
+    List,
+    ButtonsResponse,
+    ListResponse,
+    Album,
+    PinInChat,
 }
 
 /// Information about a media attachment
@@ -27,6 +47,265 @@ pub struct MediaInfo {
     pub file_name: Option<String>,
     pub caption: Option<String>,
     pub url: Option<String>,
+    /// Path of the file on the media servers, valid on any media host
+    #[serde(default)]
+    pub direct_path: Option<String>,
+    /// Key the file is encrypted with on the media servers
+    #[serde(default)]
+    pub media_key: Option<Vec<u8>>,
+    /// SHA-256 of the encrypted file
+    #[serde(default)]
+    pub file_enc_sha256: Option<Vec<u8>>,
+    /// Length of audio or video
+    pub seconds: Option<u32>,
+    /// Audio was recorded as a push-to-talk voice note
+    #[serde(default)]
+    pub ptt: bool,
+    /// Loudness of a voice note in 64 steps from 0 to 100, drawn in place of the audio
+    pub waveform: Option<Vec<u8>>,
+    /// Number of pages of a document
+    #[serde(default)]
+    pub page_count: Option<u32>,
+    /// Small JPEG preview shown before the media is downloaded
+    #[serde(default)]
+    pub thumbnail: Option<Vec<u8>>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// Video is played muted and looping like a GIF
+    #[serde(default)]
+    pub gif_playback: bool,
+}
+
+/// Identifies a message in a chat
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MessageKey {
+    pub chat_jid: JID,
+    pub id: String,
+    pub from_me: bool,
+    /// Sender of the message in group chats
+    pub sender_jid: Option<JID>,
+}
+
+/// Emoji reaction to a message; an empty emoji removes an earlier reaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub key: MessageKey,
+    pub emoji: String,
+}
+
+/// Message a reply quotes, as carried in the reply's context info
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotedRef {
+    /// Id of the quoted message
+    pub stanza_id: String,
+    /// Author of the quoted message, when it isn't our own
+    pub participant: Option<JID>,
+    pub quoted_message: QuotedContent,
+}
+
+/// What of a quoted message is shown above the reply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotedContent {
+    pub message_type: MessageType,
+    /// Text, or caption of media
+    pub text: Option<String>,
+}
+
+/// Replies, mentions, forwarding and disappearing settings attached to a message
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quoted: Option<QuotedRef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentioned_jids: Vec<JID>,
+    #[serde(default)]
+    pub is_forwarded: bool,
+    /// Number of times the message was forwarded; four or more shows as "forwarded many times"
+    #[serde(default)]
+    pub forwarding_score: u32,
+    /// Disappearing message timer the message was sent with, in seconds
+    #[serde(default)]
+    pub expiration: Option<u32>,
+    /// When the disappearing message timer of the chat was last changed
+    #[serde(default)]
+    pub ephemeral_setting_timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_ad_reply: Option<ExternalAdReply>,
+    /// Fields not understood yet by field number, as text, a number or hex-encoded bytes
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extras: HashMap<u32, String>,
+}
+
+/// Link preview of an ad or website a message was sent in reply to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalAdReply {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub thumbnail_url: Option<String>,
+    pub media_url: Option<String>,
+    /// Small JPEG preview
+    pub thumbnail: Option<Vec<u8>>,
+    pub source_type: Option<String>,
+    pub source_id: Option<String>,
+    pub source_url: Option<String>,
+}
+
+/// Contact card shared in a chat
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub display_name: String,
+    pub vcard: String,
+}
+
+impl Contact {
+    /// Create a contact card for a name and WhatsApp phone number, e.g. "+1 555 0100"
+    pub fn new(display_name: &str, phone: &str) -> Self {
+        let wa_id: String = phone.chars().filter(char::is_ascii_digit).collect();
+        let vcard = format!(
+            "BEGIN:VCARD\nVERSION:3.0\nFN:{}\nTEL;type=CELL;type=VOICE;waid={}:{}\nEND:VCARD",
+            escape_vcard(display_name), wa_id, phone,
+        );
+
+        Self { display_name: display_name.to_string(), vcard }
+    }
+
+    /// Get the full name from the vCard
+    pub fn full_name(&self) -> Option<String> {
+        vcard_values(&self.vcard, "FN").next().map(unescape_vcard)
+    }
+
+    /// Get the phone numbers from the vCard as written in it
+    pub fn phone_numbers(&self) -> Vec<String> {
+        vcard_values(&self.vcard, "TEL").map(str::to_string).collect()
+    }
+
+    /// Get the WhatsApp JIDs of the phone numbers the vCard marks as on WhatsApp
+    pub fn whatsapp_jids(&self) -> Vec<JID> {
+        vcard_lines(&self.vcard, "TEL")
+            .filter_map(|(params, _)| {
+                params.split(';').find_map(|param| param.strip_prefix("waid="))
+            })
+            .map(|wa_id| JID::new(wa_id, "s.whatsapp.net", None))
+            .collect()
+    }
+}
+
+/// Iterate over the parameters and value of the vCard lines with the given property
+fn vcard_lines<'a>(vcard: &'a str, property: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    vcard.lines().filter_map(move |line| {
+        let (name, value) = line.trim_end_matches('\r').split_once(':')?;
+        // Properties may be grouped, e.g. `item1.TEL`
+        let name = name.rsplit_once('.').map_or(name, |(_, name)| name);
+        let (name, params) = name.split_once(';').unwrap_or((name, ""));
+        name.eq_ignore_ascii_case(property).then_some((params, value))
+    })
+}
+
+/// Iterate over the values of the vCard lines with the given property
+fn vcard_values<'a>(vcard: &'a str, property: &'a str) -> impl Iterator<Item = &'a str> {
+    vcard_lines(vcard, property).map(|(_, value)| value)
+}
+
+fn escape_vcard(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace(';', "\\;").replace('\n', "\\n")
+}
+
+fn unescape_vcard(value: &str) -> String {
+    value.replace("\\n", "\n").replace("\\,", ",").replace("\\;", ";").replace("\\\\", "\\")
+}
+
+/// Invite to join a group, sent by one of its admins
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupInvite {
+    pub group_jid: JID,
+    pub invite_code: String,
+    /// Unix time in seconds after which the invite can't be accepted
+    pub expiration: u64,
+    pub group_name: String,
+    pub caption: Option<String>,
+}
+
+impl GroupInvite {
+    /// Check whether the invite can no longer be accepted
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.expiration != 0 && self.expiration <= now
+    }
+
+    /// Join the group, the inviter being the admin who sent the invite
+    pub async fn accept(&self, client: &crate::client::Client, inviter: &JID) -> crate::error::WhatsAppResult<JID> {
+        client.accept_group_invite(self, inviter).await
+    }
+}
+
+/// Pin or unpin of a message at the top of a chat for everyone in it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinInChat {
+    pub key: MessageKey,
+    pub pinned: bool,
+    /// Seconds until a pin expires
+    pub duration: Option<u32>,
+}
+
+/// Album announcing how many images and videos follow it, each pointing back to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Album {
+    pub expected_images: u32,
+    pub expected_videos: u32,
+}
+
+/// Sender key of a group or status broadcast, handed to a member over its pairwise session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderKeyDistribution {
+    pub group_id: JID,
+    /// Serialized `SenderKeyDistributionMessage`
+    pub message: Vec<u8>,
+}
+
+/// Poll asking the chat to pick among options
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<String>,
+    /// How many options a voter may select, 0 for any number
+    pub selectable_count: u32,
+    /// Secret the votes on the poll are encrypted with
+    pub secret: Vec<u8>,
+}
+
+/// Encrypted vote on a poll, replacing the voter's earlier one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PollUpdate {
+    pub poll_key: MessageKey,
+    pub enc_payload: Vec<u8>,
+    pub enc_iv: Vec<u8>,
+}
+
+/// What a protocol message does to the message it refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProtocolMessageType {
+    Revoke,
+    MessageEdit,
+    /// Disappearing message timer of a chat changed
+    EphemeralSetting,
+}
+
+/// Message acting on an earlier message instead of being shown in the chat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtocolMessage {
+    pub key: MessageKey,
+    pub kind: ProtocolMessageType,
+    /// New content of an edited message
+    pub edited_message: Option<Box<Message>>,
+    /// New disappearing message timer in seconds, 0 to turn it off
+    #[serde(default)]
+    pub ephemeral_expiration: Option<u32>,
 }
 
 /// A WhatsApp message
@@ -40,11 +319,32 @@ pub struct Message {
     pub sender_jid: Option<JID>,
     pub text: Option<String>,
     pub media: Option<MediaInfo>,
-    pub quoted: Option<Box<Message>>,
-    pub mentioned_jids: Vec<JID>,
     pub is_ephemeral: bool,
-    pub ephemeral_expiration: Option<u32>,
-    pub context_info: HashMap<String, String>,
+    #[serde(default)]
+    pub context_info: ContextInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reaction: Option<Reaction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ProtocolMessage>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contacts: Vec<Contact>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_update: Option<PollUpdate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interactive: Option<Interactive>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_key_distribution: Option<SenderKeyDistribution>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<Album>,
+    /// Album the image or video belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_parent: Option<MessageKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin_in_chat: Option<PinInChat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_invite: Option<GroupInvite>,
 }
 
 impl Message {
@@ -64,16 +364,157 @@ impl Message {
             sender_jid: None,
             text: Some(text.to_string()),
             media: None,
-            quoted: None,
-            mentioned_jids: Vec::new(),
             is_ephemeral: false,
-            ephemeral_expiration: None,
-            context_info: HashMap::new(),
+            context_info: ContextInfo::default(),
+            reaction: None,
+            protocol: None,
+            contacts: Vec::new(),
+            poll: None,
+            poll_update: None,
+            interactive: None,
+            sender_key_distribution: None,
+            album: None,
+            album_parent: None,
+            pin_in_chat: None,
+            group_invite: None,
         }
     }
 
-    /// Create a new image message
+    /// Create a reaction to the message with the given key, or remove ours with an empty emoji
+    pub fn new_reaction(chat_jid: JID, key: MessageKey, emoji: &str) -> Self {
+        let mut message = Self::new_text(chat_jid, "");
+        message.message_type = MessageType::Reaction;
+        message.text = None;
+        message.reaction = Some(Reaction { key, emoji: emoji.to_string() });
+        message
+    }
+
+    /// Create a message pinning another for the given number of seconds, or unpinning it
+    pub fn new_pin(chat_jid: JID, key: MessageKey, pinned: bool, duration: Option<u32>) -> Self {
+        let mut message = Self::new_text(chat_jid, "");
+        message.message_type = MessageType::PinInChat;
+        message.text = None;
+        message.pin_in_chat = Some(PinInChat { key, pinned, duration: duration.filter(|_| pinned) });
+        message
+    }
+
+    /// Create a message inviting the chat to join a group until the expiration, in Unix seconds
+    pub fn new_group_invite(chat_jid: JID, group_jid: JID, invite_code: &str, expiration: u64, group_name: &str) -> Self {
+        let mut message = Self::new_text(chat_jid, "");
+        message.message_type = MessageType::GroupInvite;
+        message.text = None;
+        message.group_invite = Some(GroupInvite {
+            group_jid,
+            invite_code: invite_code.to_string(),
+            expiration,
+            group_name: group_name.to_string(),
+            caption: None,
+        });
+        message
+    }
+
+    /// Create a message sharing a contact card
+    pub fn new_contact(chat_jid: JID, display_name: &str, vcard: &str) -> Self {
+        Self::new_contacts_array(chat_jid, display_name, vec![Contact {
+            display_name: display_name.to_string(),
+            vcard: vcard.to_string(),
+        }])
+    }
+
+    /// Create a message sharing several contact cards under one display name
+    pub fn new_contacts_array(chat_jid: JID, display_name: &str, contacts: Vec<Contact>) -> Self {
+        let mut message = Self::new_text(chat_jid, display_name);
+        message.message_type = MessageType::Contact;
+        message.contacts = contacts;
+        message
+    }
+
+    /// Create a poll, letting voters select any number of options or only one
+    pub fn new_poll(chat_jid: JID, question: &str, options: &[&str], multi_select: bool) -> Self {
+        let mut message = Self::new_text(chat_jid, "");
+        message.message_type = MessageType::Poll;
+        message.text = None;
+        message.poll = Some(Poll {
+            question: question.to_string(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+            selectable_count: if multi_select { 0 } else { 1 },
+            secret: crate::crypto::Crypto::random_bytes(32),
+        });
+        message
+    }
+
+    /// Create a message offering quick reply buttons
+    pub fn new_buttons(chat_jid: JID, buttons: ButtonsMessage) -> Self {
+        let mut message = Self::new_text(chat_jid, &buttons.text);
+        message.message_type = MessageType::Buttons;
+        message.interactive = Some(Interactive::Buttons(buttons));
+        message
+    }
+
+    /// Create a message offering a menu of choices
+    pub fn new_list(chat_jid: JID, list: ListMessage) -> Self {
+        let mut message = Self::new_text(chat_jid, &list.description);
+        message.message_type = MessageType::List;
+        message.interactive = Some(Interactive::List(list));
+        message
+    }
+
+    /// Create an edit replacing the text of one of our messages
+    pub fn new_edit(chat_jid: JID, key: MessageKey, text: &str) -> Self {
+        let edited_message = Self::new_text(chat_jid.clone(), text);
+        let mut message = Self::new_protocol(chat_jid, key, ProtocolMessageType::MessageEdit);
+        if let Some(protocol) = &mut message.protocol {
+            protocol.edited_message = Some(Box::new(edited_message));
+        }
+        message
+    }
+
+    /// Create a revoke deleting one of our messages for everyone in the chat
+    pub fn new_revoke(chat_jid: JID, key: MessageKey) -> Self {
+        Self::new_protocol(chat_jid, key, ProtocolMessageType::Revoke)
+    }
+
+    /// Create a message setting the disappearing message timer of a chat, 0 turning it off
+    pub fn new_ephemeral_setting(chat_jid: JID, expiration_seconds: u32) -> Self {
+        let key = MessageKey { chat_jid: chat_jid.clone(), id: String::new(), from_me: true, sender_jid: None };
+        let mut message = Self::new_protocol(chat_jid, key, ProtocolMessageType::EphemeralSetting);
+        if let Some(protocol) = &mut message.protocol {
+            // The setting refers to no earlier message, so its key is its own
+            protocol.key.id = message.id.clone();
+            protocol.ephemeral_expiration = Some(expiration_seconds);
+        }
+        message
+    }
+
+    /// Create a message handing our sender key in a group to one of its members
+    pub fn new_sender_key_distribution(group_id: JID, distribution: &[u8]) -> Self {
+        let mut message = Self::new_text(group_id.clone(), "");
+        message.message_type = MessageType::Protocol;
+        message.text = None;
+        message.sender_key_distribution = Some(SenderKeyDistribution {
+            group_id,
+            message: distribution.to_vec(),
+        });
+        message
+    }
+
+    /// Create a protocol message acting on the message with the given key
+    fn new_protocol(chat_jid: JID, key: MessageKey, kind: ProtocolMessageType) -> Self {
+        let mut message = Self::new_text(chat_jid, "");
+        message.message_type = MessageType::Protocol;
+        message.text = None;
+        message.protocol = Some(ProtocolMessage { key, kind, edited_message: None, ephemeral_expiration: None });
+        message
+    }
+
+    /// Create a new image message with a preview generated from the image
     pub fn new_image(chat_jid: JID, mime_type: &str, data: &[u8], caption: Option<&str>) -> Self {
+        Self::new_media(chat_jid, MessageType::Image, mime_type, data, caption)
+            .thumbnail_from(data, &ThumbnailConfig::default())
+    }
+
+    /// Create a media message of the given type without a preview
+    fn new_media(chat_jid: JID, message_type: MessageType, mime_type: &str, data: &[u8], caption: Option<&str>) -> Self {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
@@ -86,7 +527,7 @@ impl Message {
             id: Self::generate_message_id(),
             from_me: true,
             timestamp: now,
-            message_type: MessageType::Image,
+            message_type,
             chat_jid,
             sender_jid: None,
             text: None,
@@ -97,31 +538,208 @@ impl Message {
                 file_name: None,
                 caption: caption.map(|s| s.to_string()),
                 url: None,
+                direct_path: None,
+                media_key: None,
+                file_enc_sha256: None,
+                seconds: None,
+                ptt: false,
+                waveform: None,
+                page_count: None,
+                thumbnail: None,
+                width: None,
+                height: None,
+                gif_playback: false,
             }),
-            quoted: None,
-            mentioned_jids: Vec::new(),
             is_ephemeral: false,
-            ephemeral_expiration: None,
-            context_info: HashMap::new(),
+            context_info: ContextInfo::default(),
+            reaction: None,
+            protocol: None,
+            contacts: Vec::new(),
+            poll: None,
+            poll_update: None,
+            interactive: None,
+            sender_key_distribution: None,
+            album: None,
+            album_parent: None,
+            pin_in_chat: None,
+            group_invite: None,
+        }
+    }
+
+    /// Create a new video message, reading the duration and dimensions of an MP4
+    pub fn new_video(chat_jid: JID, mime_type: &str, data: &[u8], caption: Option<&str>) -> Self {
+        let mut message = Self::new_media(chat_jid, MessageType::Video, mime_type, data, caption);
+        // Anything but an MP4 is sent without its details
+        if let Ok(details) = Mp4Inspector.inspect(data) {
+            message.set_video_details(details);
+        }
+        message
+    }
+
+    /// Create a new video message with the details and poster frame the inspector extracts
+    pub fn new_video_with(
+        chat_jid: JID,
+        mime_type: &str,
+        data: &[u8],
+        caption: Option<&str>,
+        inspector: &dyn VideoInspector,
+    ) -> crate::error::WhatsAppResult<Self> {
+        let mut message = Self::new_media(chat_jid, MessageType::Video, mime_type, data, caption);
+        message.set_video_details(inspector.inspect(data)?);
+        Ok(message)
+    }
+
+    fn set_video_details(&mut self, details: video::VideoDetails) {
+        // Poster frames may come at full size
+        let thumbnail = details.thumbnail.map(|frame| {
+            thumbnail::generate_thumbnail(&frame, &ThumbnailConfig::default())
+                .map_or(frame, |thumbnail| thumbnail.jpeg)
+        });
+        if let Some(media) = &mut self.media {
+            media.seconds = details.seconds;
+            media.width = details.width;
+            media.height = details.height;
+            media.thumbnail = thumbnail;
+        }
+    }
+
+    /// Create a document message, detecting its MIME type, counting the pages
+    /// of a PDF and generating a preview of an image
+    pub fn new_document(chat_jid: JID, file_name: &str, data: &[u8], caption: Option<&str>) -> Self {
+        let mime_type = detect_mime_type(file_name, data);
+        let mut message = Self::new_media(chat_jid, MessageType::Document, mime_type, data, caption);
+        if mime_type.starts_with("image/") {
+            message = message.thumbnail_from(data, &ThumbnailConfig::default());
+        }
+        if let Some(media) = &mut message.media {
+            media.file_name = Some(file_name.to_string());
+            if mime_type == "application/pdf" {
+                media.page_count = pdf_page_count(data);
+            }
         }
+        message
+    }
+
+    /// Create an audio message to be played like music rather than as a voice note
+    pub fn new_audio(chat_jid: JID, mime_type: &str, data: &[u8]) -> Self {
+        Self::new_media(chat_jid, MessageType::Audio, mime_type, data, None)
     }
 
-    /// Quote another message
+    /// Create a sticker message from a 512x512 WebP
+    pub fn new_sticker(chat_jid: JID, data: &[u8]) -> Self {
+        let mut message = Self::new_media(chat_jid, MessageType::Sticker, "image/webp", data, None);
+        let probe = validation::probe_media(data);
+        if let Some(media) = &mut message.media {
+            media.width = probe.width;
+            media.height = probe.height;
+        }
+        message
+    }
+
+    /// Create an album expecting the given number of images and videos to follow
+    pub fn new_album(chat_jid: JID, expected_images: u32, expected_videos: u32) -> Self {
+        let mut message = Self::new_text(chat_jid, "");
+        message.message_type = MessageType::Album;
+        message.text = None;
+        message.album = Some(Album { expected_images, expected_videos });
+        message
+    }
+
+    /// Create a push-to-talk voice note from encoded audio and the samples it was encoded from
+    pub fn new_voice_note(chat_jid: JID, mime_type: &str, data: &[u8], samples: &[i16], sample_rate: u32) -> Self {
+        let mut message = Self::new_media(chat_jid, MessageType::Audio, mime_type, data, None);
+        if let Some(media) = &mut message.media {
+            media.seconds = Some((samples.len() as u64).div_ceil(sample_rate.max(1) as u64) as u32);
+            media.ptt = true;
+            media.waveform = Some(waveform(samples));
+        }
+        message
+    }
+
+    /// Get the key identifying this message
+    pub fn key(&self) -> MessageKey {
+        MessageKey {
+            chat_jid: self.chat_jid.clone(),
+            id: self.id.clone(),
+            from_me: self.from_me,
+            sender_jid: self.sender_jid.clone(),
+        }
+    }
+
+    /// Reply to another message, quoting it
     pub fn quote(mut self, message: &Message) -> Self {
-        self.quoted = Some(Box::new(message.clone()));
+        let text = message.text.clone()
+            .or_else(|| message.media.as_ref().and_then(|media| media.caption.clone()));
+
+        self.context_info.quoted = Some(QuotedRef {
+            stanza_id: message.id.clone(),
+            participant: if message.from_me { None } else { message.sender_jid.clone() },
+            quoted_message: QuotedContent {
+                message_type: message.message_type.clone(),
+                text,
+            },
+        });
+        self
+    }
+
+    /// Attach a small JPEG preview to the media
+    pub fn thumbnail(mut self, jpeg: Vec<u8>) -> Self {
+        if let Some(media) = &mut self.media {
+            media.thumbnail = Some(jpeg);
+        }
+        self
+    }
+
+    /// Generate the preview from an image with the given size and quality,
+    /// keeping the current one when the image can't be decoded
+    ///
+    /// Image messages also get the dimensions of the image.
+    pub fn thumbnail_from(mut self, image: &[u8], config: &ThumbnailConfig) -> Self {
+        let Ok(thumbnail) = thumbnail::generate_thumbnail(image, config) else {
+            return self;
+        };
+        let is_image = self.message_type == MessageType::Image;
+        if let Some(media) = &mut self.media {
+            media.thumbnail = Some(thumbnail.jpeg);
+            if is_image {
+                media.width = Some(thumbnail.width);
+                media.height = Some(thumbnail.height);
+            }
+        }
+        self
+    }
+
+    /// Play the video muted and looping like a GIF
+    pub fn gif_playback(mut self) -> Self {
+        if let Some(media) = &mut self.media {
+            media.gif_playback = true;
+        }
+        self
+    }
+
+    /// Put the image or video in an album
+    pub fn in_album(mut self, album: &Message) -> Self {
+        self.album_parent = Some(album.key());
         self
     }
 
     /// Set message as ephemeral/disappearing
     pub fn make_ephemeral(mut self, expiration_seconds: u32) -> Self {
         self.is_ephemeral = true;
-        self.ephemeral_expiration = Some(expiration_seconds);
+        self.context_info.expiration = Some(expiration_seconds);
         self
     }
 
     /// Mention users in the message
     pub fn mention(mut self, jids: Vec<JID>) -> Self {
-        self.mentioned_jids = jids;
+        self.context_info.mentioned_jids = jids;
+        self
+    }
+
+    /// Mark the message as forwarded, counting one more hop than the original
+    pub fn forwarded(mut self, original: &Message) -> Self {
+        self.context_info.is_forwarded = true;
+        self.context_info.forwarding_score = original.context_info.forwarding_score + 1;
         self
     }
 
@@ -143,6 +761,98 @@ impl Message {
     }
 }
 
+/// Guess the MIME type of a file from its first bytes, or else from its extension
+pub fn detect_mime_type(file_name: &str, data: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"%PDF-", "application/pdf"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"GIF8", "image/gif"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"\xff\xfb", "audio/mpeg"),
+        (b"\xff\xf3", "audio/mpeg"),
+        (b"\xff\xf1", "audio/aac"),
+        (b"\xff\xf9", "audio/aac"),
+        (b"#!AMR", "audio/amr"),
+        (b"Rar!", "application/vnd.rar"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    ];
+    if let Some((_, mime_type)) = SIGNATURES.iter().find(|(signature, _)| data.starts_with(signature)) {
+        return mime_type;
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        // The major brand tells audio-only and 3GPP files apart
+        return match &data[8..11] {
+            b"M4A" => "audio/mp4",
+            b"3gp" => "video/3gpp",
+            _ => "video/mp4",
+        };
+    }
+    if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    // Office documents are zip archives, so their extension tells them apart
+    let extension = file_name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("pdf") => "application/pdf",
+        Some("doc") => "application/msword",
+        Some("docx") => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        Some("xls") => "application/vnd.ms-excel",
+        Some("xlsx") => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        Some("ppt") => "application/vnd.ms-powerpoint",
+        Some("pptx") => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        Some("txt") => "text/plain",
+        Some("csv") => "text/csv",
+        Some("zip") => "application/zip",
+        _ if data.starts_with(b"PK\x03\x04") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Count the pages of a PDF by its page objects, `None` if they are hidden in compressed streams
+fn pdf_page_count(data: &[u8]) -> Option<u32> {
+    let mut count = 0;
+    let mut rest = data;
+    while let Some(start) = rest.windows(5).position(|window| window == b"/Type") {
+        rest = &rest[start + 5..];
+        let value = rest.iter().position(|byte| !byte.is_ascii_whitespace()).map_or(rest, |skip| &rest[skip..]);
+        // `/Page` but not `/Pages`, the node holding them
+        if value.starts_with(b"/Page") && !value[5..].first().is_some_and(u8::is_ascii_alphanumeric) {
+            count += 1;
+        }
+    }
+
+    (count > 0).then_some(count)
+}
+
+/// Number of steps in a voice note's waveform
+pub const WAVEFORM_LENGTH: usize = 64;
+
+/// Compute the waveform of a voice note: the mean loudness of 64 equal
+/// stretches of the samples, scaled so the loudest is 100
+pub fn waveform(samples: &[i16]) -> Vec<u8> {
+    if samples.is_empty() {
+        return vec![0; WAVEFORM_LENGTH];
+    }
+
+    let levels: Vec<f64> = (0..WAVEFORM_LENGTH)
+        .map(|i| {
+            // Stretches overlap when there are fewer samples than steps
+            let start = i * samples.len() / WAVEFORM_LENGTH;
+            let end = ((i + 1) * samples.len() / WAVEFORM_LENGTH).max(start + 1);
+            let stretch = &samples[start..end];
+            stretch.iter().map(|&sample| (sample as f64).abs()).sum::<f64>() / stretch.len() as f64
+        })
+        .collect();
+
+    let loudest = levels.iter().cloned().fold(0f64, f64::max);
+    levels.iter()
+        .map(|&level| if loudest > 0.0 { (level / loudest * 100.0).round() as u8 } else { 0 })
+        .collect()
+}
+
 /// Message receipt status
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReceiptStatus {
@@ -153,6 +863,18 @@ pub enum ReceiptStatus {
     Failed,
 }
 
+/// Outcome of asking the sender's phone to upload media again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MediaRetryResult {
+    /// Media was uploaded again and can be downloaded from its new direct path
+    Success,
+    /// Phone no longer has the media
+    NotFound,
+    /// Phone couldn't decrypt the request
+    DecryptionError,
+    GeneralError,
+}
+
 /// Message receipt
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageReceipt {
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+
+/// Structured choices offered by a business, or a user's pick among them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interactive {
+    Buttons(ButtonsMessage),
+    List(ListMessage),
+    ButtonsResponse(ButtonsResponse),
+    ListResponse(ListResponse),
+}
+
+/// Text with up to three quick reply buttons under it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ButtonsMessage {
+    pub text: String,
+    pub footer: Option<String>,
+    pub buttons: Vec<Button>,
+}
+
+/// Quick reply button, answered with its id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Button {
+    pub id: String,
+    pub text: String,
+}
+
+impl ButtonsMessage {
+    /// Create a buttons message with the given text and no buttons yet
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            footer: None,
+            buttons: Vec::new(),
+        }
+    }
+
+    /// Set the smaller text shown under the message
+    pub fn footer(mut self, footer: &str) -> Self {
+        self.footer = Some(footer.to_string());
+        self
+    }
+
+    /// Add a button, answered with the given id
+    pub fn button(mut self, id: &str, text: &str) -> Self {
+        self.buttons.push(Button { id: id.to_string(), text: text.to_string() });
+        self
+    }
+}
+
+/// Menu of rows in sections, opened with a button
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListMessage {
+    pub title: String,
+    pub description: String,
+    /// Text of the button opening the menu
+    pub button_text: String,
+    pub footer: Option<String>,
+    pub sections: Vec<ListSection>,
+}
+
+/// Titled group of rows in a list message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListSection {
+    pub title: String,
+    pub rows: Vec<ListRow>,
+}
+
+/// Row of a list message, answered with its id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListRow {
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+}
+
+impl ListMessage {
+    /// Create a list message with no sections yet
+    pub fn new(title: &str, description: &str, button_text: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            description: description.to_string(),
+            button_text: button_text.to_string(),
+            footer: None,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Set the smaller text shown under the message
+    pub fn footer(mut self, footer: &str) -> Self {
+        self.footer = Some(footer.to_string());
+        self
+    }
+
+    /// Add a section with its rows
+    pub fn section(mut self, title: &str, rows: Vec<ListRow>) -> Self {
+        self.sections.push(ListSection { title: title.to_string(), rows });
+        self
+    }
+}
+
+impl ListRow {
+    /// Create a row answered with the given id
+    pub fn new(id: &str, title: &str, description: Option<&str>) -> Self {
+        Self {
+            id: id.to_string(),
+            title: title.to_string(),
+            description: description.map(str::to_string),
+        }
+    }
+}
+
+/// Button a user tapped in a buttons message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ButtonsResponse {
+    /// Id of the buttons message
+    pub stanza_id: String,
+    pub button_id: String,
+    pub display_text: String,
+}
+
+/// Row a user picked in a list message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListResponse {
+    /// Id of the list message
+    pub stanza_id: String,
+    pub row_id: String,
+    pub title: String,
+}
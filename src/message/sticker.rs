@@ -0,0 +1,246 @@
+use image::{ExtendedColorType, ImageReader, RgbaImage, codecs::webp::WebPEncoder, imageops};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use super::{Message, validation::{self, STICKER_DIMENSION}};
+use crate::{
+    JID,
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+};
+
+/// EXIF tag WhatsApp keeps the sticker pack JSON under
+const STICKER_EXIF_TAG: u16 = 0x5741;
+
+/// Pack a sticker belongs to, as WhatsApp stores it in the sticker's EXIF
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickerMetadata {
+    #[serde(rename = "sticker-pack-id", default)]
+    pub pack_id: String,
+    #[serde(rename = "sticker-pack-name", default)]
+    pub pack_name: String,
+    /// Who made the pack, shown as its author
+    #[serde(rename = "sticker-pack-publisher", default)]
+    pub publisher: String,
+    /// Emojis the sticker stands for, used to suggest it while typing
+    #[serde(default)]
+    pub emojis: Vec<String>,
+}
+
+/// Stickers made from a directory of images, named and tagged alike
+#[derive(Debug, Clone)]
+pub struct StickerPack {
+    pub id: String,
+    pub name: String,
+    pub publisher: String,
+    /// Emojis of each sticker, by the file name of its image without the extension
+    pub emojis: HashMap<String, Vec<String>>,
+}
+
+impl StickerPack {
+    /// Create a pack with a random id
+    pub fn new(name: &str, publisher: &str) -> Self {
+        Self {
+            id: hex::encode(Crypto::random_bytes(16)),
+            name: name.to_string(),
+            publisher: publisher.to_string(),
+            emojis: HashMap::new(),
+        }
+    }
+
+    /// Tag the sticker made from the image with the given file stem with emojis
+    pub fn with_emojis(mut self, file_stem: &str, emojis: &[&str]) -> Self {
+        self.emojis.insert(file_stem.to_string(), emojis.iter().map(|emoji| emoji.to_string()).collect());
+        self
+    }
+
+    /// Get the metadata to embed in the sticker made from the image with the given file stem
+    pub fn metadata(&self, file_stem: &str) -> StickerMetadata {
+        StickerMetadata {
+            pack_id: self.id.clone(),
+            pack_name: self.name.clone(),
+            publisher: self.publisher.clone(),
+            emojis: self.emojis.get(file_stem).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Convert every image in a directory into a sticker message of this pack
+    ///
+    /// Images are taken in file name order and anything that isn't an image is
+    /// skipped. Returns each message with the bytes to send it with.
+    pub fn stickers_from_dir(&self, chat_jid: &JID, dir: &Path) -> WhatsAppResult<Vec<(Message, Vec<u8>)>> {
+        let mut paths = fs::read_dir(dir)
+            .map_err(|e| WhatsAppError::IOError(format!("Can't read {}: {}", dir.display(), e)))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut stickers = Vec::new();
+        for path in paths {
+            let data = fs::read(&path)
+                .map_err(|e| WhatsAppError::IOError(format!("Can't read {}: {}", path.display(), e)))?;
+            if !validation::probe_media(&data).mime_type.starts_with("image/") {
+                continue;
+            }
+
+            let file_stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            let webp = make_sticker(&data, &self.metadata(file_stem))
+                .map_err(|e| WhatsAppError::MediaError(format!("{}: {}", path.display(), e)))?;
+            stickers.push((Message::new_sticker(chat_jid.clone(), &webp), webp));
+        }
+
+        Ok(stickers)
+    }
+}
+
+/// Turn an image into a 512x512 WebP sticker carrying the given metadata
+///
+/// Images are scaled to fit and padded with transparency. A WebP that is
+/// already 512x512 is kept as it is, so animated stickers stay animated.
+pub fn make_sticker(data: &[u8], metadata: &StickerMetadata) -> WhatsAppResult<Vec<u8>> {
+    let probe = validation::probe_media(data);
+    if probe.mime_type == "image/webp" && probe.width == Some(STICKER_DIMENSION) && probe.height == Some(STICKER_DIMENSION) {
+        return embed_metadata(data, metadata);
+    }
+
+    let image = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| WhatsAppError::MediaError(e.to_string()))?
+        .decode()
+        .map_err(|e| WhatsAppError::MediaError(format!("Can't decode image for a sticker: {}", e)))?;
+    let scaled = image.resize(STICKER_DIMENSION, STICKER_DIMENSION, imageops::FilterType::Lanczos3).to_rgba8();
+
+    let mut canvas = RgbaImage::new(STICKER_DIMENSION, STICKER_DIMENSION);
+    let x = (STICKER_DIMENSION - scaled.width()) / 2;
+    let y = (STICKER_DIMENSION - scaled.height()) / 2;
+    imageops::overlay(&mut canvas, &scaled, x as i64, y as i64);
+
+    let mut webp = Vec::new();
+    WebPEncoder::new_lossless(&mut webp)
+        .encode(&canvas, STICKER_DIMENSION, STICKER_DIMENSION, ExtendedColorType::Rgba8)
+        .map_err(|e| WhatsAppError::MediaError(format!("Can't encode sticker: {}", e)))?;
+
+    embed_metadata(&webp, metadata)
+}
+
+/// Store the pack metadata in a WebP's EXIF, replacing any already there
+pub fn embed_metadata(webp: &[u8], metadata: &StickerMetadata) -> WhatsAppResult<Vec<u8>> {
+    let chunks = webp_chunks(webp)
+        .ok_or_else(|| WhatsAppError::MediaError("Sticker isn't a valid WebP".to_string()))?;
+    let json = serde_json::to_vec(metadata)
+        .map_err(|e| WhatsAppError::SerializationError(e.to_string()))?;
+
+    // A TIFF header and a single entry pointing right past itself at the JSON
+    let mut exif = b"II\x2a\x00\x08\x00\x00\x00\x01\x00".to_vec();
+    exif.extend_from_slice(&STICKER_EXIF_TAG.to_le_bytes());
+    exif.extend_from_slice(&7u16.to_le_bytes());
+    exif.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    exif.extend_from_slice(&22u32.to_le_bytes());
+    exif.extend_from_slice(&json);
+
+    // Metadata chunks only count in the extended format, so simple files get a VP8X header
+    // with the EXIF flag set
+    let mut body = b"WEBP".to_vec();
+    let extended = chunks.first().is_some_and(|(kind, _)| kind == b"VP8X");
+    if !extended {
+        let probe = validation::probe_media(webp);
+        let (width, height) = probe.width.zip(probe.height)
+            .ok_or_else(|| WhatsAppError::MediaError("Can't read the size of the sticker".to_string()))?;
+        let alpha = if lossless_alpha(&chunks) { 0x10 } else { 0 };
+        let mut header = vec![alpha | 0x08, 0, 0, 0];
+        header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        push_chunk(&mut body, b"VP8X", &header);
+    }
+    for (kind, data) in &chunks {
+        match kind {
+            b"EXIF" => {}
+            b"VP8X" => {
+                let mut header = data.to_vec();
+                if let Some(flags) = header.first_mut() {
+                    *flags |= 0x08;
+                }
+                push_chunk(&mut body, kind, &header);
+            }
+            _ => push_chunk(&mut body, kind, data),
+        }
+    }
+    push_chunk(&mut body, b"EXIF", &exif);
+
+    let mut file = b"RIFF".to_vec();
+    file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    file.extend_from_slice(&body);
+    Ok(file)
+}
+
+/// Read the pack a received sticker belongs to from its EXIF, e.g. to show it when bridging
+pub fn read_metadata(webp: &[u8]) -> Option<StickerMetadata> {
+    let chunks = webp_chunks(webp)?;
+    let (_, exif) = chunks.iter().find(|(kind, _)| kind == b"EXIF")?;
+    // Some encoders keep the JPEG-style prefix
+    let tiff = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    let entry = (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(STICKER_EXIF_TAG))?;
+    let length = u32_at(entry + 4)? as usize;
+    // Values of up to four bytes sit in the entry itself
+    let offset = if length <= 4 { entry + 8 } else { u32_at(entry + 8)? as usize };
+    let json = tiff.get(offset..offset.checked_add(length)?)?;
+
+    serde_json::from_slice(json).ok()
+}
+
+/// Split a WebP file into its chunks
+fn webp_chunks(data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    if data.len() < 12 || !data.starts_with(b"RIFF") || &data[8..12] != b"WEBP" {
+        return None;
+    }
+
+    let mut chunks = Vec::new();
+    let mut rest = &data[12..];
+    while rest.len() >= 8 {
+        let kind: [u8; 4] = rest[..4].try_into().ok()?;
+        let size = u32::from_le_bytes(rest[4..8].try_into().ok()?) as usize;
+        let body = rest.get(8..8 + size)?;
+        chunks.push((kind, body));
+        // Chunks are padded to an even length
+        rest = rest.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    Some(chunks)
+}
+
+fn push_chunk(body: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    body.extend_from_slice(kind);
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        body.push(0);
+    }
+}
+
+/// Whether a simple lossless WebP says it uses its alpha channel
+fn lossless_alpha(chunks: &[([u8; 4], &[u8])]) -> bool {
+    // The flag follows the signature byte and the 14-bit width and height
+    chunks.iter().any(|(kind, data)| kind == b"VP8L" && data.len() > 4 && data[4] & 0x10 != 0)
+}
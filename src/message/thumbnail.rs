@@ -0,0 +1,91 @@
+use image::{GenericImageView, ImageReader, codecs::jpeg::JpegEncoder, imageops::FilterType};
+use std::io::Cursor;
+
+use crate::error::{WhatsAppError, WhatsAppResult};
+
+/// Size and quality of the JPEG previews generated for outgoing media
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailConfig {
+    /// Longest side of the preview in pixels; smaller images aren't scaled up
+    pub max_size: u32,
+    /// JPEG quality from 1 to 100
+    pub quality: u8,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        Self { max_size: 72, quality: 60 }
+    }
+}
+
+/// Preview generated from an image, with the size of the image it shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub jpeg: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decode an image and downscale it to a JPEG preview
+pub fn generate_thumbnail(data: &[u8], config: &ThumbnailConfig) -> WhatsAppResult<Thumbnail> {
+    let image = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| WhatsAppError::MediaError(e.to_string()))?
+        .decode()
+        .map_err(|e| WhatsAppError::MediaError(format!("Can't decode image for a thumbnail: {}", e)))?;
+    let (width, height) = image.dimensions();
+
+    let max_size = config.max_size.max(1);
+    let preview = if width > max_size || height > max_size {
+        image.thumbnail(max_size, max_size)
+    } else {
+        image
+    };
+
+    // JPEG has no alpha channel
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, config.quality.clamp(1, 100))
+        .encode_image(&preview.to_rgb8())
+        .map_err(|e| WhatsAppError::MediaError(format!("Can't encode thumbnail: {}", e)))?;
+
+    Ok(Thumbnail { jpeg, width, height })
+}
+
+/// Side of the square JPEG profile and group photos are set with
+pub const PROFILE_PICTURE_SIZE: u32 = 640;
+
+/// Side of the preview sent along with a profile or group photo
+pub const PROFILE_PREVIEW_SIZE: u32 = 96;
+
+/// Photo and preview to set as a profile or group picture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfilePicture {
+    pub jpeg: Vec<u8>,
+    pub preview: Vec<u8>,
+}
+
+/// Crop an image to a centered square and encode it at the sizes WhatsApp expects of profile pictures
+pub fn prepare_profile_picture(data: &[u8]) -> WhatsAppResult<ProfilePicture> {
+    let image = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| WhatsAppError::MediaError(e.to_string()))?
+        .decode()
+        .map_err(|e| WhatsAppError::MediaError(format!("Can't decode image for a profile picture: {}", e)))?;
+    let (width, height) = image.dimensions();
+
+    let side = width.min(height);
+    let square = image.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+    let encode = |size: u32, quality: u8| {
+        let resized = square.resize_exact(size, size, FilterType::Lanczos3);
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, quality)
+            .encode_image(&resized.to_rgb8())
+            .map_err(|e| WhatsAppError::MediaError(format!("Can't encode profile picture: {}", e)))?;
+        Ok::<_, WhatsAppError>(jpeg)
+    };
+
+    Ok(ProfilePicture {
+        jpeg: encode(PROFILE_PICTURE_SIZE, 85)?,
+        preview: encode(PROFILE_PREVIEW_SIZE, 60)?,
+    })
+}
@@ -0,0 +1,148 @@
+use image::ImageReader;
+use std::io::Cursor;
+
+use super::{Message, MessageType, detect_mime_type, video::{Mp4Inspector, VideoInspector}};
+use crate::error::{WhatsAppError, WhatsAppResult};
+
+/// Largest image WhatsApp accepts
+pub const MAX_IMAGE_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Largest video WhatsApp accepts
+pub const MAX_VIDEO_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Largest audio file WhatsApp accepts
+pub const MAX_AUDIO_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Largest document WhatsApp accepts
+pub const MAX_DOCUMENT_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Largest still sticker WhatsApp accepts
+pub const MAX_STICKER_SIZE: u64 = 100 * 1024;
+
+/// Largest animated sticker WhatsApp accepts
+pub const MAX_ANIMATED_STICKER_SIZE: u64 = 500 * 1024;
+
+/// Width and height stickers must have
+pub const STICKER_DIMENSION: u32 = 512;
+
+const IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png"];
+const VIDEO_TYPES: &[&str] = &["video/mp4", "video/3gpp"];
+const AUDIO_TYPES: &[&str] = &["audio/ogg", "audio/mpeg", "audio/mp4", "audio/aac", "audio/amr"];
+
+/// What can be told about media from its bytes alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaProbe {
+    pub mime_type: &'static str,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// WebP or GIF with more than one frame
+    pub animated: bool,
+}
+
+/// Sniff the MIME type and dimensions of media from its bytes
+pub fn probe_media(data: &[u8]) -> MediaProbe {
+    let mime_type = detect_mime_type("", data);
+    let mut probe = MediaProbe { mime_type, width: None, height: None, animated: false };
+
+    if mime_type.starts_with("image/") {
+        let dimensions = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        if let Some((width, height)) = dimensions {
+            probe.width = Some(width);
+            probe.height = Some(height);
+        }
+        probe.animated = match mime_type {
+            "image/webp" => webp_animated(data),
+            "image/gif" => gif_frames(data) > 1,
+            _ => false,
+        };
+    } else if mime_type == "video/mp4" && let Ok(details) = Mp4Inspector.inspect(data) {
+        probe.width = details.width;
+        probe.height = details.height;
+    }
+
+    probe
+}
+
+/// Check media against what WhatsApp accepts for the message before uploading it
+///
+/// Returns what was learned about the media, or a `MediaError` saying what to change.
+pub fn validate_media(message: &Message, data: &[u8]) -> WhatsAppResult<MediaProbe> {
+    let probe = probe_media(data);
+    let size = data.len() as u64;
+    let invalid = |reason: String| Err(WhatsAppError::MediaError(reason));
+
+    match message.message_type {
+        MessageType::Image => {
+            if !IMAGE_TYPES.contains(&probe.mime_type) {
+                return invalid(format!("Images must be JPEG or PNG, got {}; send it as a document instead", probe.mime_type));
+            }
+            if size > MAX_IMAGE_SIZE {
+                return invalid(format!("Image of {} bytes is over the {} byte limit; downscale it or send it as a document", size, MAX_IMAGE_SIZE));
+            }
+        }
+        MessageType::Video => {
+            if !VIDEO_TYPES.contains(&probe.mime_type) {
+                return invalid(format!("Videos must be MP4 or 3GPP, got {}; re-encode it as H.264 MP4", probe.mime_type));
+            }
+            if size > MAX_VIDEO_SIZE {
+                return invalid(format!("Video of {} bytes is over the {} byte limit; send it as a document", size, MAX_VIDEO_SIZE));
+            }
+        }
+        MessageType::Audio => {
+            if !AUDIO_TYPES.contains(&probe.mime_type) {
+                return invalid(format!("Audio must be Opus in Ogg, MP3, AAC, M4A or AMR, got {}", probe.mime_type));
+            }
+            let ptt = message.media.as_ref().is_some_and(|media| media.ptt);
+            if ptt && !ogg_opus(data) {
+                return invalid("Voice notes must be Opus in an Ogg container".to_string());
+            }
+            if probe.mime_type == "audio/ogg" && !ogg_opus(data) {
+                return invalid("Ogg audio must be encoded with Opus, not Vorbis".to_string());
+            }
+            if size > MAX_AUDIO_SIZE {
+                return invalid(format!("Audio of {} bytes is over the {} byte limit; send it as a document", size, MAX_AUDIO_SIZE));
+            }
+        }
+        MessageType::Document => {
+            if size > MAX_DOCUMENT_SIZE {
+                return invalid(format!("Document of {} bytes is over the {} byte limit", size, MAX_DOCUMENT_SIZE));
+            }
+        }
+        MessageType::Sticker => {
+            if probe.mime_type != "image/webp" {
+                return invalid(format!("Stickers must be WebP, got {}", probe.mime_type));
+            }
+            if probe.width != Some(STICKER_DIMENSION) || probe.height != Some(STICKER_DIMENSION) {
+                return invalid(format!(
+                    "Stickers must be {0}x{0}, got {1}x{2}; pad or scale it to fit",
+                    STICKER_DIMENSION, probe.width.unwrap_or_default(), probe.height.unwrap_or_default(),
+                ));
+            }
+            let limit = if probe.animated { MAX_ANIMATED_STICKER_SIZE } else { MAX_STICKER_SIZE };
+            if size > limit {
+                return invalid(format!("Sticker of {} bytes is over the {} byte limit; compress it harder", size, limit));
+            }
+        }
+        ref kind => return invalid(format!("{:?} messages carry no media", kind)),
+    }
+
+    Ok(probe)
+}
+
+/// Whether a WebP has the animation flag set in its extended header
+fn webp_animated(data: &[u8]) -> bool {
+    data.len() > 20 && &data[12..16] == b"VP8X" && data[20] & 0x02 != 0
+}
+
+/// Roughly count the frames of a GIF by the graphic control extensions preceding them
+fn gif_frames(data: &[u8]) -> usize {
+    data.windows(2).filter(|window| window == b"\x21\xf9").count()
+}
+
+/// Whether an Ogg file carries Opus, named in its first page
+fn ogg_opus(data: &[u8]) -> bool {
+    data.starts_with(b"OggS") && data[..data.len().min(128)].windows(8).any(|window| window == b"OpusHead")
+}
@@ -0,0 +1,105 @@
+use crate::error::{WhatsAppError, WhatsAppResult};
+
+/// What can be learned about a video before sending it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VideoDetails {
+    pub seconds: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Poster frame as a small JPEG
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+/// Extracts the details and poster frame of a video, e.g. by running ffmpeg
+pub trait VideoInspector: Send + Sync {
+    fn inspect(&self, data: &[u8]) -> WhatsAppResult<VideoDetails>;
+}
+
+/// Reads the duration and dimensions of an MP4 from its boxes
+///
+/// It doesn't decode frames, so it leaves the thumbnail to a more capable inspector.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Mp4Inspector;
+
+impl VideoInspector for Mp4Inspector {
+    fn inspect(&self, data: &[u8]) -> WhatsAppResult<VideoDetails> {
+        let moov = find_box(data, b"moov")
+            .ok_or_else(|| WhatsAppError::ParsingError("Video has no moov box".to_string()))?;
+        let mut details = VideoDetails::default();
+
+        if let Some(mvhd) = find_box(moov, b"mvhd") {
+            details.seconds = movie_seconds(mvhd);
+        }
+
+        // The first track with a picture is the video
+        let mut tracks = moov;
+        while let Some((trak, rest)) = next_box(tracks, b"trak") {
+            tracks = rest;
+            if let Some((width, height)) = find_box(trak, b"tkhd").and_then(track_dimensions) {
+                details.width = Some(width);
+                details.height = Some(height);
+                break;
+            }
+        }
+
+        Ok(details)
+    }
+}
+
+/// Find the body of the first box of the given type among sibling boxes
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    next_box(data, kind).map(|(body, _)| body)
+}
+
+/// Find the body of the next box of the given type, and the boxes after it
+fn next_box<'a>(mut data: &'a [u8], kind: &[u8; 4]) -> Option<(&'a [u8], &'a [u8])> {
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[0..4].try_into().ok()?) as usize;
+        let (header, size) = match size {
+            // The box runs to the end of its parent
+            0 => (8, data.len()),
+            // Large box with a 64-bit size after the type
+            1 => (16, u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize),
+            size => (8, size),
+        };
+        if size < header || size > data.len() {
+            return None;
+        }
+
+        if &data[4..8] == kind {
+            return Some((&data[header..size], &data[size..]));
+        }
+        data = &data[size..];
+    }
+
+    None
+}
+
+/// Read the duration of the movie header in whole seconds, rounded up
+fn movie_seconds(mvhd: &[u8]) -> Option<u32> {
+    let (timescale, duration) = match mvhd.first()? {
+        0 => (read_u32(mvhd, 12)? as u64, read_u32(mvhd, 16)? as u64),
+        _ => (read_u32(mvhd, 20)? as u64, u64::from_be_bytes(mvhd.get(24..32)?.try_into().ok()?)),
+    };
+
+    (timescale > 0).then(|| duration.div_ceil(timescale) as u32)
+}
+
+/// Read the width and height of a track header, `None` for tracks without a picture
+fn track_dimensions(tkhd: &[u8]) -> Option<(u32, u32)> {
+    let offset = match tkhd.first()? {
+        0 => 4 + 20,
+        _ => 4 + 32,
+    };
+    // Reserved, layer, alternate group, volume, reserved and the matrix come first
+    let offset = offset + 8 + 8 + 36;
+
+    // Dimensions are 16.16 fixed point
+    let width = read_u32(tkhd, offset)? >> 16;
+    let height = read_u32(tkhd, offset + 4)? >> 16;
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
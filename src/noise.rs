@@ -0,0 +1,365 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    crypto::{Crypto, KeyPair, NonceCounter, SecretBytes},
+    error::{WhatsAppError, WhatsAppResult},
+    proto::{self, Encoder},
+};
+
+/// Noise protocol name, padded to the 32 bytes of a SHA-256 hash
+pub const NOISE_START_PATTERN: &[u8] = b"Noise_XX_25519_AESGCM_SHA256\0\0\0\0";
+
+/// Header sent once before the first frame: "WA", magic value and dictionary version
+pub const WA_CONN_HEADER: [u8; 4] = [b'W', b'A', 6, 3];
+
+/// Keys and payload the client authenticates itself with during the handshake
+#[derive(Clone)]
+pub struct NoiseConfig {
+    /// Long-term Noise key pair of this device
+    pub static_key: KeyPair,
+    /// Encoded `ClientPayload` sent in the final handshake message
+    pub payload: Vec<u8>,
+}
+
+/// Symmetric state of a Noise handshake in progress
+struct HandshakeState {
+    hash: Vec<u8>,
+    salt: SecretBytes,
+    key: SecretBytes,
+    nonce: NonceCounter,
+}
+
+impl HandshakeState {
+    fn new(pattern: &[u8], header: &[u8]) -> Self {
+        let hash = if pattern.len() == 32 {
+            pattern.to_vec()
+        } else {
+            Crypto::sha256(pattern)
+        };
+
+        let mut state = Self {
+            salt: hash[..].into(),
+            key: hash[..].into(),
+            hash,
+            nonce: NonceCounter::new(),
+        };
+        state.authenticate(header);
+        state
+    }
+
+    /// Mix data into the handshake hash
+    fn authenticate(&mut self, data: &[u8]) {
+        let mut input = self.hash.clone();
+        input.extend_from_slice(data);
+        self.hash = Crypto::sha256(&input);
+    }
+
+    /// Encrypt with the current key, authenticating the handshake hash
+    fn encrypt(&mut self, plaintext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        let ciphertext = Crypto::aes_gcm_encrypt(&self.key, &self.nonce.next_nonce()?, &self.hash, plaintext)?;
+        self.authenticate(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    /// Decrypt with the current key, authenticating the handshake hash
+    fn decrypt(&mut self, ciphertext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        let plaintext = Crypto::aes_gcm_decrypt(&self.key, &self.nonce.next_nonce()?, &self.hash, ciphertext)?;
+        self.authenticate(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Mix a Diffie-Hellman shared secret into the key
+    fn mix_shared_secret(&mut self, key_pair: &KeyPair, public: &[u8]) -> WhatsAppResult<()> {
+        let secret = key_pair.dh(public)?;
+        self.mix_into_key(&secret)
+    }
+
+    fn mix_into_key(&mut self, data: &[u8]) -> WhatsAppResult<()> {
+        let (write, read) = extract_and_expand(&self.salt, data)?;
+        self.salt = write;
+        self.key = read;
+        self.nonce = NonceCounter::new();
+        Ok(())
+    }
+
+    /// Split the final state into the transport keys
+    fn finish(self) -> WhatsAppResult<NoiseCipher> {
+        let (write_key, read_key) = extract_and_expand(&self.salt, &[])?;
+
+        Ok(NoiseCipher {
+            write_key,
+            read_key,
+            write_nonce: NonceCounter::new(),
+            read_nonce: NonceCounter::new(),
+        })
+    }
+}
+
+/// Client side of the `Noise_XX_25519_AESGCM_SHA256` handshake
+pub struct NoiseHandshake {
+    state: HandshakeState,
+    ephemeral: KeyPair,
+}
+
+impl NoiseHandshake {
+    /// Start a handshake with a fresh ephemeral key
+    pub fn new() -> WhatsAppResult<Self> {
+        Ok(Self {
+            state: HandshakeState::new(NOISE_START_PATTERN, &WA_CONN_HEADER),
+            ephemeral: Crypto::generate_key_pair()?,
+        })
+    }
+
+    /// Build the encoded `HandshakeMessage` carrying our ephemeral key
+    pub fn client_hello(&mut self) -> Vec<u8> {
+        self.state.authenticate(&self.ephemeral.public);
+
+        let hello = Encoder::new().bytes(1, &self.ephemeral.public).finish();
+        Encoder::new().bytes(2, &hello).finish()
+    }
+
+    /// Process the server hello and build the encoded client finish message
+    pub fn process_server_hello(&mut self, message: &[u8], config: &NoiseConfig) -> WhatsAppResult<Vec<u8>> {
+        let (ephemeral, encrypted_static, encrypted_payload) = decode_server_hello(message)?;
+
+        self.state.authenticate(&ephemeral);
+        self.state.mix_shared_secret(&self.ephemeral, &ephemeral)?;
+
+        let server_static = self.state.decrypt(&encrypted_static)?;
+        self.state.mix_shared_secret(&self.ephemeral, &server_static)?;
+
+        let certificate = self.state.decrypt(&encrypted_payload)?;
+        verify_certificate(&certificate, &server_static)?;
+
+        let encrypted_static = self.state.encrypt(&config.static_key.public)?;
+        self.state.mix_shared_secret(&config.static_key, &ephemeral)?;
+        let encrypted_payload = self.state.encrypt(&config.payload)?;
+
+        let finish = Encoder::new()
+            .bytes(1, &encrypted_static)
+            .bytes(2, &encrypted_payload)
+            .finish();
+        Ok(Encoder::new().bytes(4, &finish).finish())
+    }
+
+    /// Complete the handshake and get the transport cipher
+    pub fn finish(self) -> WhatsAppResult<NoiseCipher> {
+        self.state.finish()
+    }
+}
+
+/// Transport encryption established by a completed handshake
+pub struct NoiseCipher {
+    write_key: SecretBytes,
+    read_key: SecretBytes,
+    write_nonce: NonceCounter,
+    read_nonce: NonceCounter,
+}
+
+impl NoiseCipher {
+    /// Encrypt an outgoing frame
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        Crypto::aes_gcm_encrypt(&self.write_key, &self.write_nonce.next_nonce()?, &[], plaintext)
+    }
+
+    /// Decrypt an incoming frame
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        Crypto::aes_gcm_decrypt(&self.read_key, &self.read_nonce.next_nonce()?, &[], ciphertext)
+    }
+}
+
+/// Decode the ephemeral key, encrypted static key and encrypted payload of a server hello
+fn decode_server_hello(message: &[u8]) -> WhatsAppResult<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let hello = proto::decode_fields(message)?
+        .into_iter()
+        .find(|(field, _)| *field == 3)
+        .ok_or_else(|| WhatsAppError::ProtocolError("Handshake message has no server hello".to_string()))?;
+
+    let (mut ephemeral, mut encrypted_static, mut payload) = (None, None, None);
+    for (field, value) in proto::decode_fields(proto::field_bytes(&hello.1)?)? {
+        match field {
+            1 => ephemeral = Some(proto::field_bytes(&value)?.to_vec()),
+            2 => encrypted_static = Some(proto::field_bytes(&value)?.to_vec()),
+            3 => payload = Some(proto::field_bytes(&value)?.to_vec()),
+            _ => {},
+        }
+    }
+
+    match (ephemeral, encrypted_static, payload) {
+        (Some(ephemeral), Some(encrypted_static), Some(payload)) if ephemeral.len() == 32 => {
+            Ok((ephemeral, encrypted_static, payload))
+        },
+        _ => Err(WhatsAppError::ProtocolError("Incomplete server hello".to_string())),
+    }
+}
+
+/// Public key of WhatsApp's root certificate, which signs the intermediate of every chain
+const WA_CERT_PUBLIC_KEY: [u8; 32] = [
+    0x14, 0x23, 0x75, 0x57, 0x4d, 0x0a, 0x58, 0x71, 0x66, 0xaa, 0xe7, 0x1e, 0xbe, 0x51, 0x64, 0x37,
+    0xc4, 0xa2, 0x8b, 0x73, 0xe3, 0x69, 0x5c, 0x6c, 0xe1, 0xf7, 0xf9, 0x54, 0x5d, 0xa8, 0xee, 0x6b,
+];
+
+/// Serial of WhatsApp's root certificate
+const WA_CERT_ISSUER_SERIAL: u64 = 0;
+
+/// `NoiseCertificate.Details` of a certificate in the server's chain
+#[derive(Default)]
+struct CertificateDetails {
+    serial: u64,
+    issuer_serial: u64,
+    key: Vec<u8>,
+    not_before: u64,
+    not_after: u64,
+}
+
+/// Check that the server's certificate chain comes from WhatsApp and was issued for its static key
+fn verify_certificate(certificate: &[u8], server_static: &[u8]) -> WhatsAppResult<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    verify_chain(certificate, server_static, &WA_CERT_PUBLIC_KEY, now)
+}
+
+/// Verify a `CertChain` against a root key: the root signs the intermediate, which signs the leaf
+fn verify_chain(certificate: &[u8], server_static: &[u8], root_key: &[u8], now: u64) -> WhatsAppResult<()> {
+    let intermediate = verify_link(&field(certificate, 2)?, root_key, WA_CERT_ISSUER_SERIAL, now)
+        .map_err(|e| WhatsAppError::ProtocolError(format!("Intermediate server certificate: {}", e)))?;
+    let leaf = verify_link(&field(certificate, 1)?, &intermediate.key, intermediate.serial, now)
+        .map_err(|e| WhatsAppError::ProtocolError(format!("Leaf server certificate: {}", e)))?;
+
+    if !Crypto::constant_time_eq(&leaf.key, server_static) {
+        return Err(WhatsAppError::ProtocolError("Server certificate key doesn't match its static key".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Check a `NoiseCertificate` was signed by its issuer and is valid now, returning its details
+fn verify_link(certificate: &[u8], issuer_key: &[u8], issuer_serial: u64, now: u64) -> WhatsAppResult<CertificateDetails> {
+    let encoded = field(certificate, 1)?;
+    let signature = field(certificate, 2)?;
+    Crypto::xeddsa_verify(issuer_key, &encoded, &signature)
+        .map_err(|_| WhatsAppError::ProtocolError("bad signature".to_string()))?;
+
+    let mut details = CertificateDetails::default();
+    for (field, value) in proto::decode_fields(&encoded)? {
+        match field {
+            1 => details.serial = proto::field_u64(&value)?,
+            2 => details.issuer_serial = proto::field_u64(&value)?,
+            3 => details.key = proto::field_bytes(&value)?.to_vec(),
+            4 => details.not_before = proto::field_u64(&value)?,
+            5 => details.not_after = proto::field_u64(&value)?,
+            _ => {},
+        }
+    }
+
+    if details.issuer_serial != issuer_serial {
+        return Err(WhatsAppError::ProtocolError(format!("issued by {}, expected {}", details.issuer_serial, issuer_serial)));
+    }
+    // Unset bounds mean the certificate doesn't expire that way
+    if details.not_before != 0 && now < details.not_before {
+        return Err(WhatsAppError::ProtocolError("not valid yet".to_string()));
+    }
+    if details.not_after != 0 && now > details.not_after {
+        return Err(WhatsAppError::ProtocolError("expired".to_string()));
+    }
+
+    Ok(details)
+}
+
+/// Get a length-delimited field of an encoded message
+fn field(data: &[u8], number: u32) -> WhatsAppResult<Vec<u8>> {
+    for (field, value) in proto::decode_fields(data)? {
+        if field == number {
+            return Ok(proto::field_bytes(&value)?.to_vec());
+        }
+    }
+
+    Err(WhatsAppError::ProtocolError(format!("Missing field {} in server certificate", number)))
+}
+
+/// HKDF-SHA256 with the given salt, split into two 32-byte keys
+fn extract_and_expand(salt: &[u8], data: &[u8]) -> WhatsAppResult<(SecretBytes, SecretBytes)> {
+    let derived = Crypto::hkdf_with_salt(Some(salt), data, &[], 64)?;
+    Ok((derived[..32].into(), derived[32..].into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate(details: &[u8], issuer: &KeyPair) -> Vec<u8> {
+        let signature = Crypto::xeddsa_sign(&issuer.private, details).unwrap();
+        Encoder::new().bytes(1, details).bytes(2, &signature).finish()
+    }
+
+    fn details(serial: u64, issuer_serial: u64, key: &[u8], not_after: u64) -> Vec<u8> {
+        Encoder::new()
+            .u64(1, serial)
+            .u64(2, issuer_serial)
+            .bytes(3, key)
+            .u64(4, 1_000)
+            .u64(5, not_after)
+            .finish()
+    }
+
+    struct Chain {
+        root: KeyPair,
+        intermediate: KeyPair,
+        server: KeyPair,
+    }
+
+    impl Chain {
+        fn new() -> Self {
+            Self {
+                root: Crypto::generate_key_pair().unwrap(),
+                intermediate: Crypto::generate_key_pair().unwrap(),
+                server: Crypto::generate_key_pair().unwrap(),
+            }
+        }
+
+        fn encode(&self, leaf_issuer: &KeyPair, leaf_issuer_serial: u64, not_after: u64) -> Vec<u8> {
+            let intermediate = certificate(&details(7, WA_CERT_ISSUER_SERIAL, &self.intermediate.public, 5_000), &self.root);
+            let leaf = certificate(&details(42, leaf_issuer_serial, &self.server.public, not_after), leaf_issuer);
+            Encoder::new().bytes(1, &leaf).bytes(2, &intermediate).finish()
+        }
+    }
+
+    #[test]
+    fn accepts_chain_signed_by_the_root() {
+        let chain = Chain::new();
+        let encoded = chain.encode(&chain.intermediate, 7, 5_000);
+        assert!(verify_chain(&encoded, &chain.server.public, &chain.root.public, 2_000).is_ok());
+    }
+
+    #[test]
+    fn rejects_chain_from_another_root() {
+        let chain = Chain::new();
+        let encoded = chain.encode(&chain.intermediate, 7, 5_000);
+        let other_root = Crypto::generate_key_pair().unwrap();
+        assert!(verify_chain(&encoded, &chain.server.public, &other_root.public, 2_000).is_err());
+    }
+
+    #[test]
+    fn rejects_leaf_not_signed_by_the_intermediate() {
+        let chain = Chain::new();
+        // Valid intermediate, but a self-signed leaf as a man in the middle would send
+        let encoded = chain.encode(&chain.server, 7, 5_000);
+        assert!(verify_chain(&encoded, &chain.server.public, &chain.root.public, 2_000).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_issuer_serial_expiry_and_key() {
+        let chain = Chain::new();
+        let wrong_serial = chain.encode(&chain.intermediate, 8, 5_000);
+        assert!(verify_chain(&wrong_serial, &chain.server.public, &chain.root.public, 2_000).is_err());
+
+        let expired = chain.encode(&chain.intermediate, 7, 1_500);
+        assert!(verify_chain(&expired, &chain.server.public, &chain.root.public, 2_000).is_err());
+        assert!(verify_chain(&expired, &chain.server.public, &chain.root.public, 500).is_err());
+
+        let encoded = chain.encode(&chain.intermediate, 7, 5_000);
+        assert!(verify_chain(&encoded, &chain.intermediate.public, &chain.root.public, 2_000).is_err());
+    }
+}
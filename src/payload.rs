@@ -0,0 +1,143 @@
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+    proto::Encoder,
+    signal::LocalIdentity,
+};
+
+/// `ClientPayload.UserAgent.Platform` of WhatsApp Web
+const USER_AGENT_PLATFORM_WEB: u64 = 14;
+
+/// `ClientPayload.ConnectType` of a connection over an unknown network
+const CONNECT_TYPE_WIFI_UNKNOWN: u64 = 1;
+
+/// `ClientPayload.ConnectReason` of a connection the user asked for
+const CONNECT_REASON_USER_ACTIVATED: u64 = 1;
+
+/// Key type prefix of the identity and signed prekey, as in Signal's serialized keys
+const KEY_TYPE_DJB: u8 = 5;
+
+/// Kind of client a linked device is shown as on the phone (`DeviceProps.PlatformType`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlatformType {
+    Unknown,
+    #[default]
+    Chrome,
+    Firefox,
+    Safari,
+    Edge,
+    Desktop,
+}
+
+impl PlatformType {
+    /// Value of the platform in `DeviceProps` and in pairing requests
+    pub fn id(self) -> u32 {
+        match self {
+            PlatformType::Unknown => 0,
+            PlatformType::Chrome => 1,
+            PlatformType::Firefox => 2,
+            PlatformType::Safari => 5,
+            PlatformType::Edge => 6,
+            PlatformType::Desktop => 7,
+        }
+    }
+}
+
+/// How this device is described in the phone's linked devices screen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceProps {
+    /// Operating system or application name the device is listed under
+    pub os: String,
+    pub platform_type: PlatformType,
+    /// Browser shown with the OS while entering a pairing code, e.g. "Chrome (Linux)"
+    pub browser_name: String,
+    /// Primary, secondary and tertiary version of the client
+    pub version: [u32; 3],
+}
+
+impl Default for DeviceProps {
+    fn default() -> Self {
+        Self {
+            os: "Linux".to_string(),
+            platform_type: PlatformType::default(),
+            browser_name: "Chrome".to_string(),
+            version: [0, 1, 0],
+        }
+    }
+}
+
+impl DeviceProps {
+    /// Name shown for the device while a pairing code is entered
+    pub fn display_name(&self) -> String {
+        format!("{} ({})", self.browser_name, self.os)
+    }
+
+    /// Encode as a `DeviceProps` message
+    pub fn encode(&self) -> Vec<u8> {
+        Encoder::new()
+            .bytes(1, self.os.as_bytes())
+            .bytes(2, &encode_version(self.version))
+            .u64(3, self.platform_type.id() as u64)
+            .finish()
+    }
+}
+
+/// Build the `ClientPayload` registering a new device, sent in the handshake before pairing
+///
+/// It carries our Signal identity and signed prekey, and the props the phone
+/// shows for the device once it is linked.
+pub fn registration_payload(identity: &LocalIdentity, props: &DeviceProps) -> Vec<u8> {
+    let pairing_data = Encoder::new()
+        .bytes(1, &identity.registration_id.to_be_bytes())
+        .bytes(2, &[KEY_TYPE_DJB])
+        .bytes(3, &identity.identity.public)
+        .bytes(4, &identity.signed_pre_key_id.to_be_bytes()[1..])
+        .bytes(5, &identity.signed_pre_key.public)
+        .bytes(6, &identity.signed_pre_key_signature)
+        .bytes(8, &props.encode())
+        .finish();
+
+    // Not passive, with an empty `WebInfo` saying we are a browser
+    Encoder::new()
+        .u64(3, 0)
+        .bytes(5, &encode_user_agent(props))
+        .bytes(6, &[])
+        .bytes(19, &pairing_data)
+        .finish()
+}
+
+/// Build the `ClientPayload` logging a paired device back in with its JID
+pub fn login_payload(jid: &JID, props: &DeviceProps) -> WhatsAppResult<Vec<u8>> {
+    let username: u64 = jid.user.parse()
+        .map_err(|_| WhatsAppError::AuthError(format!("Can't log in as {}, the user isn't a phone number", jid)))?;
+
+    // Passive until we are ready for offline messages, pulling them once logged in
+    Ok(Encoder::new()
+        .u64(1, username)
+        .u64(3, 1)
+        .bytes(5, &encode_user_agent(props))
+        .bytes(6, &[])
+        .u64(12, CONNECT_TYPE_WIFI_UNKNOWN)
+        .u64(13, CONNECT_REASON_USER_ACTIVATED)
+        .u64(18, jid.device.unwrap_or(0) as u64)
+        .u64(33, 1)
+        .finish())
+}
+
+/// Encode the `ClientPayload.UserAgent` of a web client with the given props
+fn encode_user_agent(props: &DeviceProps) -> Vec<u8> {
+    Encoder::new()
+        .u64(1, USER_AGENT_PLATFORM_WEB)
+        .bytes(2, &encode_version(props.version))
+        .bytes(5, props.os.as_bytes())
+        .finish()
+}
+
+/// Encode an `AppVersion`
+fn encode_version(version: [u32; 3]) -> Vec<u8> {
+    Encoder::new()
+        .u64(1, version[0] as u64)
+        .u64(2, version[1] as u64)
+        .u64(3, version[2] as u64)
+        .finish()
+}
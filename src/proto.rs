@@ -0,0 +1,931 @@
+use protobuf::{CodedInputStream, UnknownValue};
+use protobuf::rt::WireType;
+
+use crate::{
+    JID,
+    error::{WhatsAppError, WhatsAppResult},
+    message::interactive::{
+        Button, ButtonsMessage, ButtonsResponse, Interactive, ListMessage, ListResponse, ListRow, ListSection,
+    },
+    message::{Album, Contact, ContextInfo, ExternalAdReply, GroupInvite, MediaInfo, Message, MessageKey, MessageType, Poll, PollUpdate, ProtocolMessage, ProtocolMessageType, QuotedContent, QuotedRef, PinInChat, Reaction, SenderKeyDistribution},
+};
+
+/// Field number of `ContextInfo` in text and media messages
+const CONTEXT_INFO_FIELD: u32 = 17;
+
+/// `ProtocolMessage.Type` of a message deleted for everyone
+const PROTOCOL_TYPE_REVOKE: u64 = 0;
+
+/// `ProtocolMessage.Type` of a changed disappearing message timer
+const PROTOCOL_TYPE_EPHEMERAL_SETTING: u64 = 3;
+
+/// `ProtocolMessage.Type` of an edited message
+const PROTOCOL_TYPE_MESSAGE_EDIT: u64 = 14;
+
+/// `PinInChatMessage.Type` of a message pinned for everyone in the chat
+const PIN_TYPE_PIN_FOR_ALL: u64 = 1;
+
+/// `MessageAssociation.AssociationType` of an image or video in an album
+const ASSOCIATION_TYPE_MEDIA_ALBUM: u64 = 1;
+
+/// Decode the top-level fields of an encoded protobuf message
+pub(crate) fn decode_fields(data: &[u8]) -> WhatsAppResult<Vec<(u32, UnknownValue)>> {
+    let mut input = CodedInputStream::from_bytes(data);
+    let mut fields = Vec::new();
+
+    while let Some(tag) = input.read_raw_tag_or_eof().map_err(parse_error)? {
+        let wire_type = WireType::new(tag & 7)
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Invalid wire type in tag {}", tag)))?;
+        if tag >> 3 == 0 {
+            return Err(WhatsAppError::ParsingError("Invalid field number 0".to_string()));
+        }
+        let value = input.read_unknown(wire_type).map_err(parse_error)?;
+        fields.push((tag >> 3, value));
+    }
+
+    Ok(fields)
+}
+
+/// Convert a protobuf error into a parsing error
+pub(crate) fn parse_error(error: protobuf::Error) -> WhatsAppError {
+    WhatsAppError::ParsingError(error.to_string())
+}
+
+/// Read a length-delimited field as raw bytes
+pub(crate) fn field_bytes(value: &UnknownValue) -> WhatsAppResult<&[u8]> {
+    match value {
+        UnknownValue::LengthDelimited(data) => Ok(data),
+        _ => Err(WhatsAppError::ParsingError("Expected length-delimited field".to_string())),
+    }
+}
+
+/// Read a length-delimited field as a UTF-8 string
+pub(crate) fn field_string(value: &UnknownValue) -> WhatsAppResult<String> {
+    String::from_utf8(field_bytes(value)?.to_vec())
+        .map_err(|e| WhatsAppError::ParsingError(e.to_string()))
+}
+
+/// Read a varint or fixed-width field as an unsigned integer
+pub(crate) fn field_u64(value: &UnknownValue) -> WhatsAppResult<u64> {
+    match value {
+        UnknownValue::Varint(v) | UnknownValue::Fixed64(v) => Ok(*v),
+        UnknownValue::Fixed32(v) => Ok(*v as u64),
+        _ => Err(WhatsAppError::ParsingError("Expected numeric field".to_string())),
+    }
+}
+
+/// Read a varint field as a boolean
+pub(crate) fn field_bool(value: &UnknownValue) -> WhatsAppResult<bool> {
+    field_u64(value).map(|v| v != 0)
+}
+
+/// Builder encoding a protobuf message field by field
+#[derive(Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a length-delimited field
+    pub(crate) fn bytes(mut self, field: u32, data: &[u8]) -> Self {
+        self.write_varint(((field as u64) << 3) | WireType::LengthDelimited as u64);
+        self.write_varint(data.len() as u64);
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Append a varint field
+    pub(crate) fn u64(mut self, field: u32, value: u64) -> Self {
+        self.write_varint(((field as u64) << 3) | WireType::Varint as u64);
+        self.write_varint(value);
+        self
+    }
+
+    /// Get the encoded message
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+}
+
+/// Decode a `WebMessageInfo` as stored in history sync blobs
+pub(crate) fn decode_web_message_info(data: &[u8]) -> WhatsAppResult<Message> {
+    let mut message = empty_message();
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => decode_message_key(field_bytes(&value)?, &mut message)?,
+            2 => decode_message_content(field_bytes(&value)?, &mut message)?,
+            3 => message.timestamp = field_u64(&value)?,
+            5 => message.sender_jid = Some(field_string(&value)?.parse()?),
+            _ => {}
+        }
+    }
+
+    Ok(message)
+}
+
+/// Decode a `Message` as sent over a Signal session, without its id or addressing
+pub(crate) fn decode_message(data: &[u8]) -> WhatsAppResult<Message> {
+    let mut message = empty_message();
+    decode_message_content(data, &mut message)?;
+    Ok(message)
+}
+
+/// Message without an id, addressing or content, for decoding into
+fn empty_message() -> Message {
+    Message {
+        id: String::new(),
+        from_me: false,
+        timestamp: 0,
+        message_type: MessageType::Text,
+        chat_jid: JID::new("", "", None),
+        sender_jid: None,
+        text: None,
+        media: None,
+        is_ephemeral: false,
+        context_info: ContextInfo::default(),
+        reaction: None,
+        protocol: None,
+        contacts: Vec::new(),
+        poll: None,
+        poll_update: None,
+        interactive: None,
+        sender_key_distribution: None,
+        album: None,
+        album_parent: None,
+        pin_in_chat: None,
+        group_invite: None,
+    }
+}
+
+/// Decode a `MessageKey` into the id and addressing fields of a message
+fn decode_message_key(data: &[u8], message: &mut Message) -> WhatsAppResult<()> {
+    let key = decode_key(data)?;
+    message.chat_jid = key.chat_jid;
+    message.from_me = key.from_me;
+    message.id = key.id;
+    if key.sender_jid.is_some() {
+        message.sender_jid = key.sender_jid;
+    }
+
+    Ok(())
+}
+
+/// Decode a `MessageKey`
+fn decode_key(data: &[u8]) -> WhatsAppResult<MessageKey> {
+    let mut key = MessageKey {
+        chat_jid: JID::new("", "", None),
+        id: String::new(),
+        from_me: false,
+        sender_jid: None,
+    };
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => key.chat_jid = field_string(&value)?.parse()?,
+            2 => key.from_me = field_bool(&value)?,
+            3 => key.id = field_string(&value)?,
+            4 => key.sender_jid = Some(field_string(&value)?.parse()?),
+            _ => {}
+        }
+    }
+
+    Ok(key)
+}
+
+/// Decode the `Message` content union into a message
+pub(crate) fn decode_message_content(data: &[u8], message: &mut Message) -> WhatsAppResult<()> {
+    let mut message_secret = None;
+    let mut add_on_duration = None;
+
+    for (field, value) in decode_fields(data)? {
+        // Text and media messages carry replies and mentions in their context info
+        if matches!(field, 3 | 6 | 7 | 8 | 9 | 26) {
+            for (field, value) in decode_fields(field_bytes(&value)?)? {
+                if field == CONTEXT_INFO_FIELD {
+                    message.context_info = decode_context_info(field_bytes(&value)?)?;
+                }
+            }
+        }
+
+        match field {
+            1 => {
+                message.message_type = MessageType::Text;
+                message.text = Some(field_string(&value)?);
+            }
+            2 => {
+                let mut group_id = None;
+                let mut distribution = Vec::new();
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => group_id = Some(field_string(&value)?.parse()?),
+                        2 => distribution = field_bytes(&value)?.to_vec(),
+                        _ => {}
+                    }
+                }
+                message.sender_key_distribution = group_id
+                    .map(|group_id| SenderKeyDistribution { group_id, message: distribution });
+            }
+            3 => {
+                message.message_type = MessageType::Image;
+                message.media = Some(decode_media(field_bytes(&value)?, &MEDIA_FIELDS_IMAGE)?);
+            }
+            4 => {
+                message.message_type = MessageType::Contact;
+                let contact = decode_contact(field_bytes(&value)?)?;
+                message.text = Some(contact.display_name.clone());
+                message.contacts.push(contact);
+            }
+            5 => message.message_type = MessageType::Location,
+            6 => {
+                message.message_type = MessageType::Text;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    if field == 1 {
+                        message.text = Some(field_string(&value)?);
+                    }
+                }
+            }
+            7 => {
+                message.message_type = MessageType::Document;
+                let mut media = decode_media(field_bytes(&value)?, &MEDIA_FIELDS_DOCUMENT)?;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    if field == 6 {
+                        media.page_count = Some(field_u64(&value)? as u32);
+                    }
+                }
+                message.media = Some(media);
+            }
+            8 => {
+                message.message_type = MessageType::Audio;
+                let mut media = decode_media(field_bytes(&value)?, &MEDIA_FIELDS_AUDIO)?;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        5 => media.seconds = Some(field_u64(&value)? as u32),
+                        6 => media.ptt = field_bool(&value)?,
+                        19 => media.waveform = Some(field_bytes(&value)?.to_vec()),
+                        _ => {}
+                    }
+                }
+                message.media = Some(media);
+            }
+            9 => {
+                message.message_type = MessageType::Video;
+                let mut media = decode_media(field_bytes(&value)?, &MEDIA_FIELDS_VIDEO)?;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        5 => media.seconds = Some(field_u64(&value)? as u32),
+                        8 => media.gif_playback = field_bool(&value)?,
+                        9 => media.height = Some(field_u64(&value)? as u32),
+                        10 => media.width = Some(field_u64(&value)? as u32),
+                        _ => {}
+                    }
+                }
+                message.media = Some(media);
+            }
+            13 => {
+                message.message_type = MessageType::Contact;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => message.text = Some(field_string(&value)?),
+                        2 => message.contacts.push(decode_contact(field_bytes(&value)?)?),
+                        _ => {}
+                    }
+                }
+            }
+            26 => {
+                message.message_type = MessageType::Sticker;
+                message.media = Some(decode_media(field_bytes(&value)?, &MEDIA_FIELDS_STICKER)?);
+            }
+            12 => {
+                message.message_type = MessageType::Protocol;
+                message.protocol = decode_protocol_message(field_bytes(&value)?)?;
+            }
+            28 => {
+                message.message_type = MessageType::GroupInvite;
+                let invite = decode_group_invite(field_bytes(&value)?)?;
+                message.text = invite.caption.clone();
+                message.group_invite = Some(invite);
+            }
+            35 => {
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        3 => message_secret = Some(field_bytes(&value)?.to_vec()),
+                        5 => add_on_duration = Some(field_u64(&value)? as u32),
+                        10 => message.album_parent = decode_album_association(field_bytes(&value)?)?,
+                        _ => {}
+                    }
+                }
+            }
+            36 => {
+                message.message_type = MessageType::List;
+                let list = decode_list(field_bytes(&value)?)?;
+                message.text = Some(list.description.clone());
+                message.interactive = Some(Interactive::List(list));
+            }
+            39 => {
+                message.message_type = MessageType::ListResponse;
+                let mut response = ListResponse { stanza_id: String::new(), row_id: String::new(), title: String::new() };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => response.title = field_string(&value)?,
+                        3 => {
+                            for (field, value) in decode_fields(field_bytes(&value)?)? {
+                                if field == 1 {
+                                    response.row_id = field_string(&value)?;
+                                }
+                            }
+                        }
+                        4 => response.stanza_id = decode_stanza_id(field_bytes(&value)?)?,
+                        _ => {}
+                    }
+                }
+                message.interactive = Some(Interactive::ListResponse(response));
+            }
+            42 => {
+                message.message_type = MessageType::Buttons;
+                let buttons = decode_buttons(field_bytes(&value)?)?;
+                message.text = Some(buttons.text.clone());
+                message.interactive = Some(Interactive::Buttons(buttons));
+            }
+            43 => {
+                message.message_type = MessageType::ButtonsResponse;
+                let mut response = ButtonsResponse { stanza_id: String::new(), button_id: String::new(), display_text: String::new() };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => response.button_id = field_string(&value)?,
+                        2 => response.display_text = field_string(&value)?,
+                        3 => response.stanza_id = decode_stanza_id(field_bytes(&value)?)?,
+                        _ => {}
+                    }
+                }
+                message.interactive = Some(Interactive::ButtonsResponse(response));
+            }
+            46 => {
+                message.message_type = MessageType::Reaction;
+                let mut reaction = Reaction { key: decode_key(&[])?, emoji: String::new() };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => reaction.key = decode_key(field_bytes(&value)?)?,
+                        2 => reaction.emoji = field_string(&value)?,
+                        _ => {}
+                    }
+                }
+                message.reaction = Some(reaction);
+            }
+            49 | 60 | 64 => {
+                message.message_type = MessageType::Poll;
+                let mut poll = Poll {
+                    question: String::new(),
+                    options: Vec::new(),
+                    selectable_count: 0,
+                    secret: Vec::new(),
+                };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        2 => poll.question = field_string(&value)?,
+                        3 => {
+                            for (field, value) in decode_fields(field_bytes(&value)?)? {
+                                if field == 1 {
+                                    poll.options.push(field_string(&value)?);
+                                }
+                            }
+                        }
+                        4 => poll.selectable_count = field_u64(&value)? as u32,
+                        _ => {}
+                    }
+                }
+                message.poll = Some(poll);
+            }
+            50 => {
+                message.message_type = MessageType::PollUpdate;
+                let mut poll_key = None;
+                let mut enc_payload = Vec::new();
+                let mut enc_iv = Vec::new();
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => poll_key = Some(decode_key(field_bytes(&value)?)?),
+                        2 => {
+                            for (field, value) in decode_fields(field_bytes(&value)?)? {
+                                match field {
+                                    1 => enc_payload = field_bytes(&value)?.to_vec(),
+                                    2 => enc_iv = field_bytes(&value)?.to_vec(),
+                                    _ => {}
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                message.poll_update = poll_key.map(|poll_key| PollUpdate { poll_key, enc_payload, enc_iv });
+            }
+            40 => {
+                // Messages sent while a disappearing timer is on are wrapped in a `FutureProofMessage`
+                message.is_ephemeral = true;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    if field == 1 {
+                        decode_message_content(field_bytes(&value)?, message)?;
+                    }
+                }
+            }
+            83 => {
+                message.message_type = MessageType::Album;
+                let mut album = Album { expected_images: 0, expected_videos: 0 };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        2 => album.expected_images = field_u64(&value)? as u32,
+                        3 => album.expected_videos = field_u64(&value)? as u32,
+                        _ => {}
+                    }
+                }
+                message.album = Some(album);
+            }
+            93 => {
+                message.message_type = MessageType::PinInChat;
+                let mut key = None;
+                let mut kind = 0;
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => key = Some(decode_key(field_bytes(&value)?)?),
+                        2 => kind = field_u64(&value)?,
+                        _ => {}
+                    }
+                }
+                message.pin_in_chat = key.map(|key| PinInChat { key, pinned: kind == PIN_TYPE_PIN_FOR_ALL, duration: None });
+            }
+            _ => {}
+        }
+    }
+
+    // The secret comes in the context info, which may precede the poll
+    if let (Some(poll), Some(secret)) = (&mut message.poll, message_secret) {
+        poll.secret = secret;
+    }
+    if let Some(pin) = &mut message.pin_in_chat
+        && pin.pinned
+    {
+        pin.duration = add_on_duration;
+    }
+
+    Ok(())
+}
+
+/// Decode a `MessageAssociation` into the key of the album it puts a message in
+fn decode_album_association(data: &[u8]) -> WhatsAppResult<Option<MessageKey>> {
+    let mut kind = None;
+    let mut parent = None;
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => kind = Some(field_u64(&value)?),
+            2 => parent = Some(decode_key(field_bytes(&value)?)?),
+            _ => {}
+        }
+    }
+
+    Ok(parent.filter(|_| kind == Some(ASSOCIATION_TYPE_MEDIA_ALBUM)))
+}
+
+/// Decode a `GroupInviteMessage`
+fn decode_group_invite(data: &[u8]) -> WhatsAppResult<GroupInvite> {
+    let mut group_jid = None;
+    let mut invite = GroupInvite {
+        group_jid: JID::new("", "", None),
+        invite_code: String::new(),
+        expiration: 0,
+        group_name: String::new(),
+        caption: None,
+    };
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => group_jid = Some(field_string(&value)?.parse()?),
+            2 => invite.invite_code = field_string(&value)?,
+            3 => invite.expiration = field_u64(&value)?,
+            4 => invite.group_name = field_string(&value)?,
+            6 => invite.caption = Some(field_string(&value)?),
+            _ => {}
+        }
+    }
+
+    invite.group_jid = group_jid
+        .ok_or_else(|| WhatsAppError::ParsingError("Group invite has no group".to_string()))?;
+    Ok(invite)
+}
+
+/// Decode a `ContextInfo` into what a message quotes, mentions and was forwarded or sent with
+fn decode_context_info(data: &[u8]) -> WhatsAppResult<ContextInfo> {
+    let mut context_info = ContextInfo::default();
+    let mut stanza_id = None;
+    let mut participant = None;
+    let mut quoted_message = None;
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => stanza_id = Some(field_string(&value)?),
+            2 => participant = Some(field_string(&value)?.parse()?),
+            3 => {
+                // Only what is shown above the reply, not the whole message
+                let mut quoted = empty_message();
+                decode_message_content(field_bytes(&value)?, &mut quoted)?;
+                quoted_message = Some(QuotedContent {
+                    text: quoted.text.or_else(|| quoted.media.and_then(|media| media.caption)),
+                    message_type: quoted.message_type,
+                });
+            }
+            15 => context_info.mentioned_jids.push(field_string(&value)?.parse()?),
+            21 => context_info.forwarding_score = field_u64(&value)? as u32,
+            22 => context_info.is_forwarded = field_bool(&value)?,
+            25 => context_info.expiration = Some(field_u64(&value)? as u32),
+            26 => context_info.ephemeral_setting_timestamp = Some(field_u64(&value)?),
+            28 => context_info.external_ad_reply = Some(decode_external_ad_reply(field_bytes(&value)?)?),
+            _ => {
+                let extra = match &value {
+                    UnknownValue::LengthDelimited(data) => String::from_utf8(data.clone())
+                        .unwrap_or_else(|_| hex::encode(data)),
+                    value => field_u64(value)?.to_string(),
+                };
+                context_info.extras.insert(field, extra);
+            }
+        }
+    }
+
+    if let (Some(stanza_id), Some(quoted_message)) = (stanza_id, quoted_message) {
+        context_info.quoted = Some(QuotedRef { stanza_id, participant, quoted_message });
+    }
+
+    Ok(context_info)
+}
+
+/// Decode an `ExternalAdReplyInfo`
+fn decode_external_ad_reply(data: &[u8]) -> WhatsAppResult<ExternalAdReply> {
+    let mut reply = ExternalAdReply::default();
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => reply.title = Some(field_string(&value)?),
+            2 => reply.body = Some(field_string(&value)?),
+            4 => reply.thumbnail_url = Some(field_string(&value)?),
+            5 => reply.media_url = Some(field_string(&value)?),
+            6 => reply.thumbnail = Some(field_bytes(&value)?.to_vec()),
+            7 => reply.source_type = Some(field_string(&value)?),
+            8 => reply.source_id = Some(field_string(&value)?),
+            9 => reply.source_url = Some(field_string(&value)?),
+            _ => {}
+        }
+    }
+
+    Ok(reply)
+}
+
+/// Get the id of the quoted message from a `ContextInfo`
+fn decode_stanza_id(data: &[u8]) -> WhatsAppResult<String> {
+    for (field, value) in decode_fields(data)? {
+        if field == 1 {
+            return field_string(&value);
+        }
+    }
+
+    Ok(String::new())
+}
+
+/// Decode a `ButtonsMessage` with text content
+fn decode_buttons(data: &[u8]) -> WhatsAppResult<ButtonsMessage> {
+    let mut buttons = ButtonsMessage::new("");
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            6 => buttons.text = field_string(&value)?,
+            7 => buttons.footer = Some(field_string(&value)?),
+            9 => {
+                // Button { buttonId = 1; buttonText = 2 { displayText = 1 } }
+                let mut button = Button { id: String::new(), text: String::new() };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => button.id = field_string(&value)?,
+                        2 => {
+                            for (field, value) in decode_fields(field_bytes(&value)?)? {
+                                if field == 1 {
+                                    button.text = field_string(&value)?;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                buttons.buttons.push(button);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(buttons)
+}
+
+/// Decode a `ListMessage`
+fn decode_list(data: &[u8]) -> WhatsAppResult<ListMessage> {
+    let mut list = ListMessage::new("", "", "");
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => list.title = field_string(&value)?,
+            2 => list.description = field_string(&value)?,
+            3 => list.button_text = field_string(&value)?,
+            5 => {
+                let mut section = ListSection { title: String::new(), rows: Vec::new() };
+                for (field, value) in decode_fields(field_bytes(&value)?)? {
+                    match field {
+                        1 => section.title = field_string(&value)?,
+                        2 => {
+                            let mut row = ListRow::new("", "", None);
+                            for (field, value) in decode_fields(field_bytes(&value)?)? {
+                                match field {
+                                    1 => row.title = field_string(&value)?,
+                                    2 => row.description = Some(field_string(&value)?),
+                                    3 => row.id = field_string(&value)?,
+                                    _ => {}
+                                }
+                            }
+                            section.rows.push(row);
+                        }
+                        _ => {}
+                    }
+                }
+                list.sections.push(section);
+            }
+            7 => list.footer = Some(field_string(&value)?),
+            _ => {}
+        }
+    }
+
+    Ok(list)
+}
+
+/// Decode a `ContactMessage`
+fn decode_contact(data: &[u8]) -> WhatsAppResult<Contact> {
+    let mut contact = Contact { display_name: String::new(), vcard: String::new() };
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => contact.display_name = field_string(&value)?,
+            16 => contact.vcard = field_string(&value)?,
+            _ => {}
+        }
+    }
+
+    Ok(contact)
+}
+
+/// Decode a `ProtocolMessage`, if it is of a type we act on
+fn decode_protocol_message(data: &[u8]) -> WhatsAppResult<Option<ProtocolMessage>> {
+    let mut key = None;
+    let mut kind = None;
+    let mut edited_message = None;
+    let mut ephemeral_expiration = None;
+
+    for (field, value) in decode_fields(data)? {
+        match field {
+            1 => key = Some(decode_key(field_bytes(&value)?)?),
+            2 => kind = match field_u64(&value)? {
+                PROTOCOL_TYPE_REVOKE => Some(ProtocolMessageType::Revoke),
+                PROTOCOL_TYPE_EPHEMERAL_SETTING => Some(ProtocolMessageType::EphemeralSetting),
+                PROTOCOL_TYPE_MESSAGE_EDIT => Some(ProtocolMessageType::MessageEdit),
+                _ => None,
+            },
+            4 => ephemeral_expiration = Some(field_u64(&value)? as u32),
+            14 => {
+                let mut message = empty_message();
+                decode_message_content(field_bytes(&value)?, &mut message)?;
+                edited_message = Some(Box::new(message));
+            }
+            _ => {}
+        }
+    }
+
+    // Timer changes refer to no earlier message, so they may have no key
+    if kind == Some(ProtocolMessageType::EphemeralSetting) && key.is_none() {
+        key = Some(decode_key(&[])?);
+    }
+
+    Ok(key.zip(kind).map(|(key, kind)| ProtocolMessage { key, kind, edited_message, ephemeral_expiration }))
+}
+
+/// Field numbers of the common attributes in each media message type
+struct MediaFields {
+    url: u32,
+    mime_type: u32,
+    sha256: u32,
+    file_length: u32,
+    media_key: u32,
+    file_enc_sha256: u32,
+    direct_path: u32,
+    caption: Option<u32>,
+    file_name: Option<u32>,
+    thumbnail: Option<u32>,
+}
+
+const MEDIA_FIELDS_IMAGE: MediaFields = MediaFields {
+    url: 1,
+    mime_type: 2,
+    sha256: 4,
+    file_length: 5,
+    media_key: 8,
+    file_enc_sha256: 9,
+    direct_path: 11,
+    caption: Some(3),
+    file_name: None,
+    thumbnail: Some(16),
+};
+
+const MEDIA_FIELDS_VIDEO: MediaFields = MediaFields {
+    url: 1,
+    mime_type: 2,
+    sha256: 3,
+    file_length: 4,
+    media_key: 6,
+    file_enc_sha256: 11,
+    direct_path: 13,
+    caption: Some(7),
+    file_name: None,
+    thumbnail: Some(16),
+};
+
+const MEDIA_FIELDS_AUDIO: MediaFields = MediaFields {
+    url: 1,
+    mime_type: 2,
+    sha256: 3,
+    file_length: 4,
+    media_key: 7,
+    file_enc_sha256: 8,
+    direct_path: 9,
+    caption: None,
+    file_name: None,
+    thumbnail: None,
+};
+
+const MEDIA_FIELDS_DOCUMENT: MediaFields = MediaFields {
+    url: 1,
+    mime_type: 2,
+    sha256: 4,
+    file_length: 5,
+    media_key: 7,
+    file_enc_sha256: 9,
+    direct_path: 10,
+    caption: Some(20),
+    file_name: Some(8),
+    thumbnail: Some(16),
+};
+
+const MEDIA_FIELDS_STICKER: MediaFields = MediaFields {
+    url: 1,
+    mime_type: 5,
+    sha256: 2,
+    file_length: 9,
+    media_key: 4,
+    file_enc_sha256: 3,
+    direct_path: 8,
+    caption: None,
+    file_name: None,
+    thumbnail: None,
+};
+
+fn decode_media(data: &[u8], fields: &MediaFields) -> WhatsAppResult<MediaInfo> {
+    let mut media = MediaInfo {
+        mime_type: String::new(),
+        sha256: Vec::new(),
+        file_length: 0,
+        file_name: None,
+        caption: None,
+        url: None,
+        direct_path: None,
+        media_key: None,
+        file_enc_sha256: None,
+        seconds: None,
+        ptt: false,
+        waveform: None,
+        page_count: None,
+        thumbnail: None,
+        width: None,
+        height: None,
+        gif_playback: false,
+    };
+
+    for (field, value) in decode_fields(data)? {
+        if field == fields.url {
+            media.url = Some(field_string(&value)?);
+        } else if field == fields.mime_type {
+            media.mime_type = field_string(&value)?;
+        } else if field == fields.sha256 {
+            media.sha256 = field_bytes(&value)?.to_vec();
+        } else if field == fields.file_length {
+            media.file_length = field_u64(&value)?;
+        } else if field == fields.media_key {
+            media.media_key = Some(field_bytes(&value)?.to_vec());
+        } else if field == fields.file_enc_sha256 {
+            media.file_enc_sha256 = Some(field_bytes(&value)?.to_vec());
+        } else if field == fields.direct_path {
+            media.direct_path = Some(field_string(&value)?);
+        } else if Some(field) == fields.caption {
+            media.caption = Some(field_string(&value)?);
+        } else if Some(field) == fields.file_name {
+            media.file_name = Some(field_string(&value)?);
+        } else if Some(field) == fields.thumbnail {
+            media.thumbnail = Some(field_bytes(&value)?.to_vec());
+        }
+    }
+
+    Ok(media)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn web_message_info() -> Vec<u8> {
+        let key = Encoder::new()
+            .bytes(1, b"120363025246125486@g.us")
+            .u64(2, 0)
+            .bytes(3, b"3EB0C431C26A1916E07E")
+            .bytes(4, b"1234567890:3@s.whatsapp.net")
+            .finish();
+        let content = Encoder::new().bytes(1, "héllo".as_bytes()).finish();
+        Encoder::new()
+            .bytes(1, &key)
+            .bytes(2, &content)
+            .u64(3, 1_700_000_000)
+            .finish()
+    }
+
+    #[test]
+    fn round_trips_fields() {
+        let encoded = Encoder::new()
+            .u64(1, 0)
+            .u64(2, u64::MAX)
+            .bytes(3, b"")
+            .bytes(536_870_911, &[0xff; 300])
+            .finish();
+
+        let fields = decode_fields(&encoded).unwrap();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(field_u64(&fields[0].1).unwrap(), 0);
+        assert_eq!(field_u64(&fields[1].1).unwrap(), u64::MAX);
+        assert_eq!(field_bytes(&fields[2].1).unwrap(), b"");
+        assert_eq!(fields[3].0, 536_870_911);
+        assert_eq!(field_bytes(&fields[3].1).unwrap(), [0xff; 300]);
+        assert!(field_bytes(&fields[0].1).is_err());
+        assert!(field_u64(&fields[2].1).is_err());
+    }
+
+    #[test]
+    fn decodes_web_message_info() {
+        let message = decode_web_message_info(&web_message_info()).unwrap();
+        assert_eq!(message.chat_jid, JID::new("120363025246125486", "g.us", None));
+        assert_eq!(message.sender_jid, Some(JID::new("1234567890", "s.whatsapp.net", Some(3))));
+        assert_eq!(message.id, "3EB0C431C26A1916E07E");
+        assert!(!message.from_me);
+        assert_eq!(message.timestamp, 1_700_000_000);
+        assert_eq!(message.message_type, MessageType::Text);
+        assert_eq!(message.text.as_deref(), Some("héllo"));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let encoded = web_message_info();
+        // Cutting anywhere but between top-level fields leaves one of them incomplete
+        let boundaries = [0, encoded.len() - 16, encoded.len() - 6, encoded.len()];
+        for len in (0..encoded.len()).filter(|len| !boundaries.contains(len)) {
+            assert!(decode_web_message_info(&encoded[..len]).is_err(), "{} of {} bytes decoded", len, encoded.len());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        for data in [
+            // Invalid wire type
+            &[0x0f][..],
+            // Length past the end of the data
+            &[0x0a, 0x05, 1, 2],
+            // Varint longer than ten bytes
+            &[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+            // Field number 0
+            &[0x00, 0x01],
+        ] {
+            assert!(decode_fields(data).is_err(), "{:?} decoded", data);
+        }
+
+        // Text that isn't UTF-8
+        let content = Encoder::new().bytes(1, &[0xc3, 0x28]).finish();
+        assert!(decode_message(&content).is_err());
+    }
+}
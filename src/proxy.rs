@@ -0,0 +1,192 @@
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::{
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+};
+
+/// Maximum size of the response to an HTTP CONNECT request
+const MAX_CONNECT_RESPONSE_SIZE: usize = 16 * 1024;
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_AUTH_VERSION: u8 = 0x01;
+const SOCKS_AUTH_NONE: u8 = 0x00;
+const SOCKS_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+const SOCKS_ADDR_IPV4: u8 = 0x01;
+const SOCKS_ADDR_DOMAIN: u8 = 0x03;
+const SOCKS_ADDR_IPV6: u8 = 0x04;
+
+/// Credentials for authenticating with a proxy
+#[derive(Debug, Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Proxy the WebSocket connection is tunneled through
+#[derive(Debug, Clone)]
+pub enum ProxyConfig {
+    /// SOCKS5 proxy at `host:port`
+    Socks5 {
+        address: String,
+        auth: Option<ProxyAuth>,
+    },
+    /// HTTP proxy at `host:port` supporting the CONNECT method
+    Http {
+        address: String,
+        auth: Option<ProxyAuth>,
+    },
+}
+
+impl ProxyConfig {
+    /// Open a TCP stream to the target host through the proxy
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> WhatsAppResult<TcpStream> {
+        match self {
+            ProxyConfig::Socks5 { address, auth } => {
+                debug!("Connecting to {}:{} through SOCKS5 proxy {}", host, port, address);
+
+                let mut stream = TcpStream::connect(address.as_str()).await
+                    .map_err(|e| WhatsAppError::ConnectionError(format!("Failed to reach SOCKS5 proxy {}: {}", address, e)))?;
+
+                socks5_authenticate(&mut stream, auth.as_ref()).await?;
+                socks5_connect(&mut stream, host, port).await?;
+
+                Ok(stream)
+            },
+            ProxyConfig::Http { address, auth } => {
+                debug!("Connecting to {}:{} through HTTP proxy {}", host, port, address);
+
+                let mut stream = TcpStream::connect(address.as_str()).await
+                    .map_err(|e| WhatsAppError::ConnectionError(format!("Failed to reach HTTP proxy {}: {}", address, e)))?;
+
+                http_connect(&mut stream, host, port, auth.as_ref()).await?;
+
+                Ok(stream)
+            },
+        }
+    }
+}
+
+/// Negotiate an authentication method with the SOCKS5 proxy
+async fn socks5_authenticate(stream: &mut TcpStream, auth: Option<&ProxyAuth>) -> WhatsAppResult<()> {
+    let greeting: &[u8] = match auth {
+        Some(_) => &[SOCKS_VERSION, 2, SOCKS_AUTH_NONE, SOCKS_AUTH_PASSWORD],
+        None => &[SOCKS_VERSION, 1, SOCKS_AUTH_NONE],
+    };
+    stream.write_all(greeting).await.map_err(io_error)?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.map_err(io_error)?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(WhatsAppError::ConnectionError(format!("Unexpected SOCKS version {}", choice[0])));
+    }
+
+    match (choice[1], auth) {
+        (SOCKS_AUTH_NONE, _) => Ok(()),
+        (SOCKS_AUTH_PASSWORD, Some(auth)) => {
+            // Username/password authentication (RFC 1929)
+            if auth.username.len() > 255 || auth.password.len() > 255 {
+                return Err(WhatsAppError::ConnectionError("SOCKS5 credentials are too long".to_string()));
+            }
+
+            let mut request = vec![SOCKS_AUTH_VERSION, auth.username.len() as u8];
+            request.extend_from_slice(auth.username.as_bytes());
+            request.push(auth.password.len() as u8);
+            request.extend_from_slice(auth.password.as_bytes());
+            stream.write_all(&request).await.map_err(io_error)?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await.map_err(io_error)?;
+            if status[1] != 0 {
+                return Err(WhatsAppError::ConnectionError("SOCKS5 proxy rejected the credentials".to_string()));
+            }
+
+            Ok(())
+        },
+        (SOCKS_AUTH_UNACCEPTABLE, _) => {
+            Err(WhatsAppError::ConnectionError("SOCKS5 proxy accepted none of the offered authentication methods".to_string()))
+        },
+        (method, _) => Err(WhatsAppError::ConnectionError(format!("SOCKS5 proxy chose unsupported authentication method {}", method))),
+    }
+}
+
+/// Ask the SOCKS5 proxy to connect to the target, letting it resolve the host name
+async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> WhatsAppResult<()> {
+    if host.len() > 255 {
+        return Err(WhatsAppError::ConnectionError(format!("Host name {} is too long for SOCKS5", host)));
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0x00, SOCKS_ADDR_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.map_err(io_error)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(io_error)?;
+    if reply[1] != 0 {
+        return Err(WhatsAppError::ConnectionError(format!("SOCKS5 proxy failed to connect to {}:{}: {}", host, port, socks5_reply_message(reply[1]))));
+    }
+
+    // Skip the bound address and port
+    let address_len = match reply[3] {
+        SOCKS_ADDR_IPV4 => 4,
+        SOCKS_ADDR_IPV6 => 16,
+        SOCKS_ADDR_DOMAIN => stream.read_u8().await.map_err(io_error)? as usize,
+        kind => return Err(WhatsAppError::ConnectionError(format!("Unknown SOCKS5 address type {}", kind))),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await.map_err(io_error)?;
+
+    Ok(())
+}
+
+/// Open a tunnel to the target with an HTTP CONNECT request
+async fn http_connect(stream: &mut TcpStream, host: &str, port: u16, auth: Option<&ProxyAuth>) -> WhatsAppResult<()> {
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some(auth) = auth {
+        let credentials = Crypto::base64_encode(format!("{}:{}", auth.username, auth.password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.map_err(io_error)?;
+
+    // Read the response one byte at a time so no tunneled data is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_CONNECT_RESPONSE_SIZE {
+            return Err(WhatsAppError::ConnectionError("HTTP proxy response too large".to_string()));
+        }
+        response.push(stream.read_u8().await.map_err(io_error)?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some("407") => Err(WhatsAppError::ConnectionError("HTTP proxy requires authentication".to_string())),
+        _ => Err(WhatsAppError::ConnectionError(format!("HTTP proxy refused to connect to {}:{}: {}", host, port, status))),
+    }
+}
+
+/// Describe a SOCKS5 reply code (RFC 1928)
+fn socks5_reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn io_error(error: std::io::Error) -> WhatsAppError {
+    WhatsAppError::ConnectionError(format!("Proxy connection failed: {}", error))
+}
@@ -0,0 +1,665 @@
+use std::fmt::Write as _;
+
+use crate::{
+    error::{WhatsAppError, WhatsAppResult},
+    zlib,
+};
+
+/// Light modules around the code that scanners need to find it
+const QUIET_ZONE: usize = 4;
+
+/// Pixels per module in PNG output
+const PNG_SCALE: usize = 8;
+
+/// Error correction codewords per block, by level and version
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 4] = [
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28, 30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28],
+    [0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30, 30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+];
+
+/// Error correction blocks, by level and version
+const ECC_BLOCKS: [[u8; 41]; 4] = [
+    [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13, 14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25],
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49],
+    [0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29, 34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68],
+    [0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32, 35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81],
+];
+
+/// How much of a QR code can be damaged while it still scans
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EcLevel {
+    /// About 7% of the codewords can be restored
+    #[default]
+    Low,
+    /// About 15%
+    Medium,
+    /// About 25%
+    Quartile,
+    /// About 30%
+    High,
+}
+
+impl EcLevel {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Bits identifying the level in the format information
+    fn format_bits(self) -> u32 {
+        match self {
+            EcLevel::Low => 1,
+            EcLevel::Medium => 0,
+            EcLevel::Quartile => 3,
+            EcLevel::High => 2,
+        }
+    }
+}
+
+/// QR code encoding text in byte mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    data: String,
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode text with the lowest error correction level, which keeps the code small
+    pub fn new(data: &str) -> WhatsAppResult<Self> {
+        Self::with_ec_level(data, EcLevel::Low)
+    }
+
+    /// Encode text with the given error correction level, using the smallest version it fits in
+    pub fn with_ec_level(data: &str, level: EcLevel) -> WhatsAppResult<Self> {
+        let bytes = data.as_bytes();
+        let version = (1..=40)
+            .find(|&version| 4 + count_bits(version) + bytes.len() * 8 <= data_codewords(version, level) * 8)
+            .ok_or_else(|| WhatsAppError::SerializationError(format!("{} bytes are too long for a QR code", bytes.len())))?;
+
+        let codewords = add_ecc_and_interleave(&encode_data(bytes, version, level), version, level);
+
+        let mut matrix = Matrix::new(version);
+        matrix.draw_function_patterns(version, level);
+        matrix.draw_codewords(&codewords);
+
+        // Keep the mask that makes the code easiest to scan
+        let (_, mask) = (0..8)
+            .map(|mask| {
+                let mut candidate = matrix.clone();
+                candidate.apply_mask(mask);
+                candidate.draw_format_bits(level, mask);
+                (candidate.penalty(), mask)
+            })
+            .min()
+            .unwrap();
+        matrix.apply_mask(mask);
+        matrix.draw_format_bits(level, mask);
+
+        Ok(Self {
+            data: data.to_string(),
+            size: matrix.size,
+            modules: matrix.modules,
+        })
+    }
+
+    /// Text encoded in the code
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    /// Width and height of the code in modules, without the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module at a column and row is dark; modules outside the code are light
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// Render with half-block characters, two rows of modules per line
+    ///
+    /// Light modules are drawn as blocks, so the code scans on terminals
+    /// with light text on a dark background.
+    pub fn to_terminal_string(&self) -> String {
+        let total = self.size + 2 * QUIET_ZONE;
+        let light = |x: usize, y: usize| !self.module_with_quiet_zone(x, y);
+
+        let mut output = String::new();
+        for y in (0..total).step_by(2) {
+            for x in 0..total {
+                let bottom = y + 1 < total && light(x, y + 1);
+                output.push(match (light(x, y), bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            output.push('\n');
+        }
+
+        output
+    }
+
+    /// Render as a black and white PNG image
+    pub fn to_png_bytes(&self) -> Vec<u8> {
+        let width = (self.size + 2 * QUIET_ZONE) * PNG_SCALE;
+        let row_bytes = width.div_ceil(8);
+
+        // One bit per pixel, each row starting with filter type 0
+        let mut pixels = Vec::with_capacity((row_bytes + 1) * width);
+        for y in 0..width {
+            pixels.push(0);
+            let mut row = vec![0u8; row_bytes];
+            for x in 0..width {
+                if !self.module_with_quiet_zone(x / PNG_SCALE, y / PNG_SCALE) {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            pixels.extend_from_slice(&row);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        // Bit depth 1, greyscale, default compression, filtering and no interlacing
+        header.extend_from_slice(&[1, 0, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_png_chunk(&mut png, b"IHDR", &header);
+        write_png_chunk(&mut png, b"IDAT", &zlib::compress(&pixels));
+        write_png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Render as an SVG image, one unit per module
+    pub fn to_svg(&self) -> String {
+        let total = self.size + 2 * QUIET_ZONE;
+
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.is_dark(x, y) {
+                    let _ = write!(path, "M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE);
+                }
+            }
+        }
+
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {total} {total}\" shape-rendering=\"crispEdges\">\
+             <rect width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>\
+             <path d=\"{path}\" fill=\"#000000\"/></svg>\n"
+        )
+    }
+
+    fn module_with_quiet_zone(&self, x: usize, y: usize) -> bool {
+        x >= QUIET_ZONE && y >= QUIET_ZONE && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE)
+    }
+}
+
+/// Module grid under construction, tracking which modules belong to function patterns
+#[derive(Clone)]
+struct Matrix {
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl Matrix {
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        Self {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize, level: EcLevel) {
+        let size = self.size;
+
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_finder(x, y);
+        }
+
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Skip the corners taken by finder patterns
+                let corner = (i == 0 && (j == 0 || j == last)) || (i == last && j == 0);
+                if !corner {
+                    self.draw_alignment(x, y);
+                }
+            }
+        }
+
+        // Reserve the format areas; the real bits are drawn once the mask is chosen
+        self.draw_format_bits(level, 0);
+        self.draw_version(version);
+    }
+
+    fn draw_finder(&mut self, center_x: usize, center_y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (x, y) = (center_x as i32 + dx, center_y as i32 + dy);
+                if (0..self.size as i32).contains(&x) && (0..self.size as i32).contains(&y) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, center_x: usize, center_y: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let (x, y) = ((center_x as i32 + dx) as usize, (center_y as i32 + dy) as usize);
+                self.set_function(x, y, dx.abs().max(dy.abs()) != 1);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, level: EcLevel, mask: u32) {
+        let data = level.format_bits() << 3 | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        // Copy around the top left finder
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        // Copy split between the other two finders
+        let size = self.size;
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+
+        let mut remainder = version as u32;
+        for _ in 0..12 {
+            remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+        }
+        let bits = (version as u32) << 12 | remainder;
+
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Place the codewords in the zigzag order, two columns at a time from the right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+
+        let mut right = size - 1;
+        while right >= 1 {
+            // The vertical timing pattern shifts the columns left of it
+            if right == 6 {
+                right = 5;
+            }
+
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward { size - 1 - vertical } else { vertical };
+                for x in [right, right - 1] {
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.function[y * self.size + x] {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// Penalty score of the specification, lower scanning more reliably
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+
+        let lines = (0..size).flat_map(|i| {
+            [
+                (0..size).map(|j| self.get(j, i)).collect::<Vec<_>>(),
+                (0..size).map(|j| self.get(i, j)).collect::<Vec<_>>(),
+            ]
+        });
+        for line in lines {
+            // Runs of five or more modules of one color
+            for run in line.chunk_by(|a, b| a == b) {
+                if run.len() >= 5 {
+                    penalty += run.len() - 2;
+                }
+            }
+
+            // Patterns looking like part of a finder
+            for window in line.windows(11) {
+                let pattern = [true, false, true, true, true, false, true];
+                if (window[..7] == pattern && window[7..].iter().all(|&dark| !dark))
+                    || (window[4..] == pattern && window[..4].iter().all(|&dark| !dark))
+                {
+                    penalty += 40;
+                }
+            }
+        }
+
+        // Two by two blocks of one color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.get(x, y);
+                if color == self.get(x + 1, y) && color == self.get(x, y + 1) && color == self.get(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Imbalance between dark and light modules, in 5% steps from 50%
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        let steps = (dark * 20).abs_diff(total * 10).div_ceil(total).saturating_sub(1);
+        penalty + steps * 10
+    }
+}
+
+/// Bits of the character count for byte mode
+fn count_bits(version: usize) -> usize {
+    if version <= 9 { 8 } else { 16 }
+}
+
+/// Modules available for codewords once function patterns are placed
+fn raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+
+    modules
+}
+
+fn data_codewords(version: usize, level: EcLevel) -> usize {
+    let level = level.index();
+    raw_data_modules(version) / 8
+        - ECC_CODEWORDS_PER_BLOCK[level][version] as usize * ECC_BLOCKS[level][version] as usize
+}
+
+/// Centers of the alignment patterns along each axis
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+
+    let count = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2 };
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| version * 4 + 10 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// Mode indicator, length, data, terminator and padding, filling the data codewords
+fn encode_data(data: &[u8], version: usize, level: EcLevel) -> Vec<u8> {
+    let capacity = data_codewords(version, level) * 8;
+    let mut bits = BitBuffer::default();
+
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, count_bits(version));
+    for &byte in data {
+        bits.push(byte as u32, 8);
+    }
+    bits.push(0, (capacity - bits.len).min(4));
+    bits.push(0, (8 - bits.len % 8) % 8);
+
+    let mut codewords = bits.bytes;
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if codewords.len() * 8 >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+
+    codewords
+}
+
+/// Split the data into blocks, add Reed-Solomon codewords to each and interleave them
+fn add_ecc_and_interleave(data: &[u8], version: usize, level: EcLevel) -> Vec<u8> {
+    let blocks = ECC_BLOCKS[level.index()][version] as usize;
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[level.index()][version] as usize;
+    let raw_codewords = raw_data_modules(version) / 8;
+    let short_blocks = blocks - raw_codewords % blocks;
+    let short_len = raw_codewords / blocks;
+
+    let divisor = reed_solomon_divisor(ecc_len);
+    let mut offset = 0;
+    let blocks: Vec<Vec<u8>> = (0..blocks)
+        .map(|i| {
+            let len = short_len - ecc_len + usize::from(i >= short_blocks);
+            let mut block = data[offset..offset + len].to_vec();
+            offset += len;
+
+            let ecc = reed_solomon_remainder(&block, &divisor);
+            // Pad short blocks so every block lines up while interleaving
+            if i < short_blocks {
+                block.push(0);
+            }
+            block.extend(ecc);
+            block
+        })
+        .collect();
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_len - ecc_len || j >= short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+
+    result
+}
+
+/// Generator polynomial of the given degree, without its leading term
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (value, &coefficient) in result.iter_mut().zip(divisor) {
+            *value ^= gf_multiply(coefficient, factor);
+        }
+    }
+
+    result
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z = 0u16;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((y as u16 >> i) & 1) * x as u16;
+    }
+
+    z as u8
+}
+
+#[derive(Default)]
+struct BitBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 != 0 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc32(&[&kind[..], data].concat()).to_be_bytes());
+}
+
+/// CRC-32 as used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+
+    #[test]
+    fn reed_solomon_matches_the_iso_example() {
+        // "01234567" as a 1-M code, from ISO/IEC 18004 annex I
+        let data = [0x10, 0x20, 0x0c, 0x56, 0x61, 0x80, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11];
+        assert_eq!(data_codewords(1, EcLevel::Medium), data.len());
+
+        let ecc = reed_solomon_remainder(&data, &reed_solomon_divisor(10));
+        assert_eq!(ecc, [0xa5, 0x24, 0xd4, 0xc1, 0xed, 0x36, 0xc7, 0x87, 0x2c, 0x55]);
+        assert_eq!(add_ecc_and_interleave(&data, 1, EcLevel::Medium), [&data[..], &ecc].concat());
+    }
+
+    #[test]
+    fn data_capacity_matches_the_standard() {
+        for (version, level, codewords) in [
+            (1, EcLevel::Low, 19),
+            (1, EcLevel::Medium, 16),
+            (1, EcLevel::Quartile, 13),
+            (1, EcLevel::High, 9),
+            (10, EcLevel::Low, 274),
+            (40, EcLevel::Low, 2956),
+            (40, EcLevel::High, 1276),
+        ] {
+            assert_eq!(data_codewords(version, level), codewords, "version {} {:?}", version, level);
+        }
+    }
+
+    #[test]
+    fn picks_the_smallest_version_that_fits() {
+        assert_eq!(QrCode::new(&"a".repeat(17)).unwrap().size(), 21);
+        assert_eq!(QrCode::new(&"a".repeat(18)).unwrap().size(), 25);
+        assert_eq!(QrCode::new(&"a".repeat(2953)).unwrap().size(), 177);
+        assert!(QrCode::new(&"a".repeat(2954)).is_err());
+    }
+
+    #[test]
+    fn pairing_payload_fits_version_10() {
+        // Reference, then the base64 Noise key, identity key and ADV secret
+        let key = Crypto::base64_encode(&[0x5a; 32]);
+        let payload = [format!("2@{}", "R".repeat(98)), key.clone(), key.clone(), key].join(",");
+        assert_eq!(payload.len(), 235);
+
+        let qr = QrCode::new(&payload).unwrap();
+        assert_eq!(qr.size(), 17 + 4 * 10);
+        assert_eq!(qr.data(), payload);
+        // Finder pattern corners
+        assert!(qr.is_dark(0, 0) && qr.is_dark(qr.size() - 1, 0) && qr.is_dark(0, qr.size() - 1));
+        assert!(!qr.is_dark(7, 7));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::JID;
+use crate::error::{WhatsAppError, WhatsAppResult};
+
+/// Most per-chat buckets kept; idle ones are dropped first, then the least recently used
+const MAX_CHAT_BUCKETS: usize = 1024;
+
+/// Sustained rate and burst size of a token bucket
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Messages allowed per second once the burst is used up
+    pub per_second: f64,
+    /// Messages that can be sent back to back after being idle
+    pub burst: u32,
+}
+
+impl RateLimit {
+    fn validate(&self, name: &str) -> WhatsAppResult<()> {
+        if !(self.per_second.is_finite() && self.per_second > 0.0) {
+            return Err(WhatsAppError::ConfigError(format!(
+                "{} rate limit must allow a positive number of messages per second, got {}",
+                name, self.per_second,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Limits on outgoing messages; `None` disables a limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitConfig {
+    /// Limit across all chats
+    pub global: Option<RateLimit>,
+    /// Limit applied to each chat separately
+    pub per_chat: Option<RateLimit>,
+}
+
+impl RateLimitConfig {
+    /// Check that every limit refills, as a limit that never does would block sending for good
+    pub fn validate(&self) -> WhatsAppResult<()> {
+        if let Some(global) = &self.global {
+            global.validate("Global")?;
+        }
+        if let Some(per_chat) = &self.per_chat {
+            per_chat.validate("Per-chat")?;
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket that lets reservations go into debt so waiting senders queue up in order
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64)
+    }
+
+    /// Take a token, returning how long to wait until it is actually available
+    fn reserve(&mut self, now: Instant) -> Duration {
+        self.tokens = self.tokens_at(now) - 1.0;
+        self.updated = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / self.limit.per_second).unwrap_or(Duration::MAX)
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.limit.burst as f64
+    }
+}
+
+/// Rate limiter for outgoing messages
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    global: Mutex<Option<TokenBucket>>,
+    chats: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> WhatsAppResult<Self> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            global: Mutex::new(config.global.map(TokenBucket::new)),
+            chats: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Reserve a message to the chat, returning how long to delay it
+    pub(crate) fn reserve(&self, chat: &JID) -> Duration {
+        let now = Instant::now();
+
+        let global = self.global.lock().unwrap().as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.reserve(now));
+
+        let per_chat = match self.config.per_chat {
+            Some(limit) => {
+                let mut chats = self.chats.lock().unwrap();
+                let chat = chat.to_string();
+                if chats.len() >= MAX_CHAT_BUCKETS && !chats.contains_key(&chat) {
+                    chats.retain(|_, bucket| !bucket.is_full(now));
+
+                    // Forgetting a busy chat lets it burst again, which beats growing without bound
+                    if chats.len() >= MAX_CHAT_BUCKETS
+                        && let Some(oldest) = chats.iter().min_by_key(|(_, bucket)| bucket.updated).map(|(chat, _)| chat.clone())
+                    {
+                        chats.remove(&oldest);
+                    }
+                }
+                chats.entry(chat)
+                    .or_insert_with(|| TokenBucket::new(limit))
+                    .reserve(now)
+            },
+            None => Duration::ZERO,
+        };
+
+        global.max(per_chat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(user: &str) -> JID {
+        JID::new(user, "s.whatsapp.net", None)
+    }
+
+    fn limiter(global: Option<RateLimit>, per_chat: Option<RateLimit>) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig { global, per_chat }).unwrap()
+    }
+
+    #[test]
+    fn rejects_limits_that_never_refill() {
+        for per_second in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let limit = Some(RateLimit { per_second, burst: 5 });
+            assert!(matches!(RateLimiter::new(RateLimitConfig { global: limit, per_chat: None }), Err(WhatsAppError::ConfigError(_))));
+            assert!(matches!(RateLimiter::new(RateLimitConfig { global: None, per_chat: limit }), Err(WhatsAppError::ConfigError(_))));
+        }
+        assert!(RateLimiter::new(RateLimitConfig::default()).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_then_queues_senders() {
+        let limiter = limiter(Some(RateLimit { per_second: 2.0, burst: 3 }), None);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.reserve(&chat("1")), Duration::ZERO);
+        }
+        // Each further message waits behind the ones already queued
+        assert_eq!(limiter.reserve(&chat("1")), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(&chat("2")), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_up_to_the_burst() {
+        let limiter = limiter(Some(RateLimit { per_second: 2.0, burst: 2 }), None);
+        limiter.reserve(&chat("1"));
+        limiter.reserve(&chat("1"));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.reserve(&chat("1")), Duration::ZERO);
+        assert_eq!(limiter.reserve(&chat("1")), Duration::from_millis(500));
+
+        // Idling longer than it takes to refill saves up no more than the burst
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.reserve(&chat("1")), Duration::ZERO);
+        assert_eq!(limiter.reserve(&chat("1")), Duration::ZERO);
+        assert_eq!(limiter.reserve(&chat("1")), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn applies_the_stricter_of_global_and_per_chat_limits() {
+        let limiter = limiter(
+            Some(RateLimit { per_second: 10.0, burst: 2 }),
+            Some(RateLimit { per_second: 1.0, burst: 1 }),
+        );
+
+        assert_eq!(limiter.reserve(&chat("1")), Duration::ZERO);
+        // The chat's own bucket is empty while the global one still has a token
+        assert_eq!(limiter.reserve(&chat("1")), Duration::from_secs(1));
+        // Another chat only waits for the global bucket
+        assert_eq!(limiter.reserve(&chat("2")), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bounds_the_per_chat_buckets() {
+        let limiter = limiter(None, Some(RateLimit { per_second: 1.0, burst: 1 }));
+
+        limiter.reserve(&chat("oldest"));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        for user in 1..MAX_CHAT_BUCKETS {
+            limiter.reserve(&chat(&user.to_string()));
+        }
+        assert_eq!(limiter.chats.lock().unwrap().len(), MAX_CHAT_BUCKETS);
+
+        // Every chat is busy, so the least recently used one makes room
+        limiter.reserve(&chat("new"));
+        {
+            let chats = limiter.chats.lock().unwrap();
+            assert_eq!(chats.len(), MAX_CHAT_BUCKETS);
+            assert!(!chats.contains_key(&chat("oldest").to_string()));
+        }
+
+        // Once they refilled, the idle chats are all dropped
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.reserve(&chat("newer"));
+        assert_eq!(limiter.chats.lock().unwrap().len(), 1);
+    }
+}
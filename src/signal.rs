@@ -0,0 +1,1111 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::{
+    JID,
+    crypto::{Crypto, KeyPair, SecretBytes},
+    error::{WhatsAppError, WhatsAppResult},
+    proto::{self, Encoder},
+    store::SignalStore,
+};
+
+pub mod fingerprint;
+pub mod group;
+
+/// Version of the Signal message format, sent in both nibbles of the first byte
+const MESSAGE_VERSION: u8 = 3;
+
+/// Type byte in front of serialized Curve25519 public keys
+const DJB_KEY_TYPE: u8 = 0x05;
+
+/// Length of the truncated MAC at the end of a `SignalMessage`
+const MAC_SIZE: usize = 8;
+
+/// Number of receiving chains kept for messages still arriving on old ratchet keys
+const MAX_RECEIVER_CHAINS: usize = 5;
+
+/// Largest number of messages a counter may skip ahead in a chain
+const MAX_FORWARD_JUMPS: u32 = 2000;
+
+/// Number of keys kept for skipped messages that may still arrive out of order
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Public keys a device publishes so others can start sessions with it
+#[derive(Debug, Clone)]
+pub struct PreKeyBundle {
+    pub registration_id: u32,
+    pub device_id: u32,
+    pub identity_key: Vec<u8>,
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key: Vec<u8>,
+    pub signed_pre_key_signature: Vec<u8>,
+    /// One-time prekey, if the device has any left
+    pub pre_key_id: Option<u32>,
+    pub pre_key: Option<Vec<u8>>,
+}
+
+/// Identity and prekeys of this device
+pub struct LocalIdentity {
+    pub registration_id: u32,
+    pub identity: KeyPair,
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key: KeyPair,
+    /// XEdDSA signature of the signed prekey by the identity key
+    pub signed_pre_key_signature: Vec<u8>,
+    /// One-time prekeys not used by any session yet
+    pub pre_keys: HashMap<u32, KeyPair>,
+}
+
+impl LocalIdentity {
+    /// Generate a new identity with the given number of one-time prekeys
+    pub fn generate(pre_key_count: u32) -> WhatsAppResult<Self> {
+        let pre_keys = (1..=pre_key_count)
+            .map(|id| Ok((id, Crypto::generate_key_pair()?)))
+            .collect::<WhatsAppResult<_>>()?;
+
+        let identity = Crypto::generate_key_pair()?;
+        let signed_pre_key = Crypto::generate_key_pair()?;
+        let signed_pre_key_signature = Crypto::xeddsa_sign(&identity.private, &serialize_key(&signed_pre_key.public))?;
+
+        Ok(Self {
+            registration_id: rand::thread_rng().gen_range(1..16380),
+            identity,
+            signed_pre_key_id: 1,
+            signed_pre_key,
+            signed_pre_key_signature,
+            pre_keys,
+        })
+    }
+}
+
+/// Chain key of one direction of a session, with the index of its next message
+#[derive(Clone)]
+struct ChainKey {
+    key: SecretBytes,
+    index: u32,
+}
+
+impl ChainKey {
+    fn message_keys(&self) -> WhatsAppResult<MessageKeys> {
+        let seed = SecretBytes::new(Crypto::hmac_sha256(&self.key, &[0x01])?);
+        let derived = Crypto::hkdf(&seed, b"WhisperMessageKeys", 80)?;
+
+        Ok(MessageKeys {
+            cipher_key: derived[..32].into(),
+            mac_key: derived[32..64].into(),
+            iv: derived[64..].into(),
+        })
+    }
+
+    fn next(&self) -> WhatsAppResult<ChainKey> {
+        Ok(ChainKey {
+            key: SecretBytes::new(Crypto::hmac_sha256(&self.key, &[0x02])?),
+            index: self.index + 1,
+        })
+    }
+}
+
+/// Keys encrypting and authenticating a single message
+#[derive(Clone)]
+struct MessageKeys {
+    cipher_key: SecretBytes,
+    mac_key: SecretBytes,
+    iv: SecretBytes,
+}
+
+/// Keys of a message that was skipped in a receiving chain
+#[derive(Clone)]
+struct SkippedKey {
+    ratchet_key: Vec<u8>,
+    counter: u32,
+    keys: MessageKeys,
+}
+
+/// Prekeys the session was started with, sent along until the other side replies
+#[derive(Clone)]
+struct PendingPreKey {
+    pre_key_id: Option<u32>,
+    signed_pre_key_id: u32,
+    base_key: Vec<u8>,
+}
+
+/// Signal session with one remote device
+#[derive(Clone)]
+pub struct SessionState {
+    local_registration_id: u32,
+    local_identity: Vec<u8>,
+    remote_identity: Vec<u8>,
+    root_key: SecretBytes,
+    sender_ratchet: KeyPair,
+    sender_chain: ChainKey,
+    receiver_chains: Vec<(Vec<u8>, ChainKey)>,
+    skipped_keys: VecDeque<SkippedKey>,
+    previous_counter: u32,
+    pending_pre_key: Option<PendingPreKey>,
+    base_key: Vec<u8>,
+}
+
+impl SessionState {
+    /// Start a session with a device from its prekey bundle (X3DH, initiating side)
+    pub fn initiate(local: &LocalIdentity, bundle: &PreKeyBundle) -> WhatsAppResult<Self> {
+        let their_identity = public_key(&bundle.identity_key)?;
+        let their_signed_pre_key = public_key(&bundle.signed_pre_key)?;
+
+        Crypto::xeddsa_verify(&their_identity, &serialize_key(&their_signed_pre_key), &bundle.signed_pre_key_signature)
+            .map_err(|_| WhatsAppError::CryptoError("Invalid signed prekey signature".to_string()))?;
+
+        let base_key = Crypto::generate_key_pair()?;
+
+        let mut agreements = vec![
+            local.identity.dh(&their_signed_pre_key)?,
+            base_key.dh(&their_identity)?,
+            base_key.dh(&their_signed_pre_key)?,
+        ];
+        if let Some(pre_key) = &bundle.pre_key {
+            agreements.push(base_key.dh(&public_key(pre_key)?)?);
+        }
+        let (root_key, chain_key) = derive_initial_keys(&agreements)?;
+
+        let sender_ratchet = Crypto::generate_key_pair()?;
+        let (root_key, sender_chain) = create_chain(&root_key, &their_signed_pre_key, &sender_ratchet)?;
+
+        Ok(Self {
+            local_registration_id: local.registration_id,
+            local_identity: local.identity.public.clone(),
+            remote_identity: their_identity,
+            root_key,
+            sender_ratchet,
+            sender_chain,
+            receiver_chains: vec![(their_signed_pre_key, chain_key)],
+            skipped_keys: VecDeque::new(),
+            previous_counter: 0,
+            pending_pre_key: Some(PendingPreKey {
+                pre_key_id: bundle.pre_key.as_ref().and(bundle.pre_key_id),
+                signed_pre_key_id: bundle.signed_pre_key_id,
+                base_key: base_key.public.clone(),
+            }),
+            base_key: base_key.public,
+        })
+    }
+
+    /// Accept a session started by another device and decrypt its first message (X3DH, responding side)
+    ///
+    /// The one-time prekey the message used is removed from the local identity.
+    pub fn accept(local: &mut LocalIdentity, message: &PreKeySignalMessage) -> WhatsAppResult<(Self, Vec<u8>)> {
+        if message.signed_pre_key_id != local.signed_pre_key_id {
+            return Err(WhatsAppError::CryptoError(format!("Unknown signed prekey {}", message.signed_pre_key_id)));
+        }
+        let signed_pre_key = &local.signed_pre_key;
+        let pre_key = match message.pre_key_id {
+            Some(id) => Some(local.pre_keys.get(&id)
+                .ok_or_else(|| WhatsAppError::CryptoError(format!("Unknown prekey {}", id)))?),
+            None => None,
+        };
+
+        let mut agreements = vec![
+            signed_pre_key.dh(&message.identity_key)?,
+            local.identity.dh(&message.base_key)?,
+            signed_pre_key.dh(&message.base_key)?,
+        ];
+        if let Some(pre_key) = pre_key {
+            agreements.push(pre_key.dh(&message.base_key)?);
+        }
+        let (root_key, sender_chain) = derive_initial_keys(&agreements)?;
+
+        let mut session = Self {
+            local_registration_id: local.registration_id,
+            local_identity: local.identity.public.clone(),
+            remote_identity: message.identity_key.clone(),
+            root_key,
+            sender_ratchet: signed_pre_key.clone(),
+            sender_chain,
+            receiver_chains: Vec::new(),
+            skipped_keys: VecDeque::new(),
+            previous_counter: 0,
+            pending_pre_key: None,
+            base_key: message.base_key.clone(),
+        };
+        let plaintext = session.decrypt(&message.message)?;
+
+        if let Some(id) = message.pre_key_id {
+            local.pre_keys.remove(&id);
+        }
+
+        Ok((session, plaintext))
+    }
+
+    /// Serialize the session into a record for a `SessionStore`
+    pub fn serialize(&self) -> SecretBytes {
+        let mut encoder = Encoder::new()
+            .u64(1, self.local_registration_id as u64)
+            .bytes(2, &self.local_identity)
+            .bytes(3, &self.remote_identity)
+            .bytes(4, &self.root_key)
+            .bytes(5, &Encoder::new().bytes(1, &self.sender_ratchet.public).bytes(2, &self.sender_ratchet.private).finish())
+            .bytes(6, &encode_chain(&self.sender_chain))
+            .u64(9, self.previous_counter as u64)
+            .bytes(11, &self.base_key);
+
+        for (ratchet_key, chain) in &self.receiver_chains {
+            encoder = encoder.bytes(7, &Encoder::new().bytes(1, ratchet_key).bytes(2, &encode_chain(chain)).finish());
+        }
+        for skipped in &self.skipped_keys {
+            encoder = encoder.bytes(8, &Encoder::new()
+                .bytes(1, &skipped.ratchet_key)
+                .u64(2, skipped.counter as u64)
+                .bytes(3, &skipped.keys.cipher_key)
+                .bytes(4, &skipped.keys.mac_key)
+                .bytes(5, &skipped.keys.iv)
+                .finish());
+        }
+        if let Some(pending) = &self.pending_pre_key {
+            let mut pending_encoder = Encoder::new()
+                .u64(2, pending.signed_pre_key_id as u64)
+                .bytes(3, &pending.base_key);
+            if let Some(id) = pending.pre_key_id {
+                pending_encoder = pending_encoder.u64(1, id as u64);
+            }
+            encoder = encoder.bytes(10, &pending_encoder.finish());
+        }
+
+        SecretBytes::new(encoder.finish())
+    }
+
+    /// Restore a session from a record made by `serialize`
+    pub fn deserialize(record: &[u8]) -> WhatsAppResult<Self> {
+        let mut session = Self {
+            local_registration_id: 0,
+            local_identity: Vec::new(),
+            remote_identity: Vec::new(),
+            root_key: SecretBytes::default(),
+            sender_ratchet: KeyPair { private: SecretBytes::default(), public: Vec::new() },
+            sender_chain: ChainKey { key: SecretBytes::default(), index: 0 },
+            receiver_chains: Vec::new(),
+            skipped_keys: VecDeque::new(),
+            previous_counter: 0,
+            pending_pre_key: None,
+            base_key: Vec::new(),
+        };
+
+        for (field, value) in proto::decode_fields(record)? {
+            match field {
+                1 => session.local_registration_id = proto::field_u64(&value)? as u32,
+                2 => session.local_identity = proto::field_bytes(&value)?.to_vec(),
+                3 => session.remote_identity = proto::field_bytes(&value)?.to_vec(),
+                4 => session.root_key = proto::field_bytes(&value)?.into(),
+                5 => {
+                    for (field, value) in proto::decode_fields(proto::field_bytes(&value)?)? {
+                        match field {
+                            1 => session.sender_ratchet.public = proto::field_bytes(&value)?.to_vec(),
+                            2 => session.sender_ratchet.private = proto::field_bytes(&value)?.into(),
+                            _ => {},
+                        }
+                    }
+                },
+                6 => session.sender_chain = decode_chain(proto::field_bytes(&value)?)?,
+                7 => {
+                    let mut ratchet_key = Vec::new();
+                    let mut chain = None;
+                    for (field, value) in proto::decode_fields(proto::field_bytes(&value)?)? {
+                        match field {
+                            1 => ratchet_key = proto::field_bytes(&value)?.to_vec(),
+                            2 => chain = Some(decode_chain(proto::field_bytes(&value)?)?),
+                            _ => {},
+                        }
+                    }
+                    let chain = chain.ok_or_else(|| corrupt_record("receiver chain without a key"))?;
+                    session.receiver_chains.push((ratchet_key, chain));
+                },
+                8 => {
+                    let mut skipped = SkippedKey {
+                        ratchet_key: Vec::new(),
+                        counter: 0,
+                        keys: MessageKeys {
+                            cipher_key: SecretBytes::default(),
+                            mac_key: SecretBytes::default(),
+                            iv: SecretBytes::default(),
+                        },
+                    };
+                    for (field, value) in proto::decode_fields(proto::field_bytes(&value)?)? {
+                        match field {
+                            1 => skipped.ratchet_key = proto::field_bytes(&value)?.to_vec(),
+                            2 => skipped.counter = proto::field_u64(&value)? as u32,
+                            3 => skipped.keys.cipher_key = proto::field_bytes(&value)?.into(),
+                            4 => skipped.keys.mac_key = proto::field_bytes(&value)?.into(),
+                            5 => skipped.keys.iv = proto::field_bytes(&value)?.into(),
+                            _ => {},
+                        }
+                    }
+                    session.skipped_keys.push_back(skipped);
+                },
+                9 => session.previous_counter = proto::field_u64(&value)? as u32,
+                10 => {
+                    let mut pending = PendingPreKey { pre_key_id: None, signed_pre_key_id: 0, base_key: Vec::new() };
+                    for (field, value) in proto::decode_fields(proto::field_bytes(&value)?)? {
+                        match field {
+                            1 => pending.pre_key_id = Some(proto::field_u64(&value)? as u32),
+                            2 => pending.signed_pre_key_id = proto::field_u64(&value)? as u32,
+                            3 => pending.base_key = proto::field_bytes(&value)?.to_vec(),
+                            _ => {},
+                        }
+                    }
+                    session.pending_pre_key = Some(pending);
+                },
+                11 => session.base_key = proto::field_bytes(&value)?.to_vec(),
+                _ => {},
+            }
+        }
+
+        if session.root_key.len() != 32 || session.sender_ratchet.private.is_empty() || session.remote_identity.is_empty() {
+            return Err(corrupt_record("missing keys"));
+        }
+
+        Ok(session)
+    }
+
+    /// Base key identifying the X3DH agreement the session was started with
+    pub fn base_key(&self) -> &[u8] {
+        &self.base_key
+    }
+
+    /// Identity key of the remote device
+    pub fn remote_identity(&self) -> &[u8] {
+        &self.remote_identity
+    }
+
+    /// Encrypt a message for the remote device
+    ///
+    /// Until the remote device has replied, messages are wrapped in a
+    /// `PreKeySignalMessage` so it can set up its side of the session.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> WhatsAppResult<CiphertextMessage> {
+        let keys = self.sender_chain.message_keys()?;
+        let ciphertext = Crypto::aes_encrypt(&keys.cipher_key, &keys.iv, plaintext)?;
+
+        let mut message = SignalMessage {
+            ratchet_key: self.sender_ratchet.public.clone(),
+            counter: self.sender_chain.index,
+            previous_counter: self.previous_counter,
+            ciphertext,
+            serialized: Vec::new(),
+        };
+        message.serialized = message.encode(&keys.mac_key, &self.local_identity, &self.remote_identity)?;
+        self.sender_chain = self.sender_chain.next()?;
+
+        Ok(match &self.pending_pre_key {
+            Some(pending) => {
+                let mut pre_key_message = PreKeySignalMessage {
+                    registration_id: self.local_registration_id,
+                    pre_key_id: pending.pre_key_id,
+                    signed_pre_key_id: pending.signed_pre_key_id,
+                    base_key: pending.base_key.clone(),
+                    identity_key: self.local_identity.clone(),
+                    message,
+                    serialized: Vec::new(),
+                };
+                pre_key_message.serialized = pre_key_message.encode();
+                CiphertextMessage::PreKey(pre_key_message)
+            },
+            None => CiphertextMessage::Signal(message),
+        })
+    }
+
+    /// Decrypt a message from the remote device
+    ///
+    /// Messages may arrive out of order: keys of skipped messages are kept, up
+    /// to a limit, until they arrive. The session is left untouched if the
+    /// message can't be decrypted.
+    pub fn decrypt(&mut self, message: &SignalMessage) -> WhatsAppResult<Vec<u8>> {
+        let mut state = self.clone();
+        let plaintext = state.decrypt_with_state(message)?;
+        *self = state;
+        Ok(plaintext)
+    }
+
+    fn decrypt_with_state(&mut self, message: &SignalMessage) -> WhatsAppResult<Vec<u8>> {
+        if !self.receiver_chains.iter().any(|(ratchet_key, _)| *ratchet_key == message.ratchet_key) {
+            self.ratchet(&message.ratchet_key)?;
+        }
+
+        let keys = self.message_keys(&message.ratchet_key, message.counter)?;
+        message.verify_mac(&keys.mac_key, &self.remote_identity, &self.local_identity)?;
+        let plaintext = Crypto::aes_decrypt(&keys.cipher_key, &keys.iv, &message.ciphertext)?;
+
+        // The remote device has the session now, so stop sending prekey messages
+        self.pending_pre_key = None;
+
+        Ok(plaintext)
+    }
+
+    /// Step the Diffie-Hellman ratchet for a new ratchet key of the remote device
+    fn ratchet(&mut self, their_ratchet: &[u8]) -> WhatsAppResult<()> {
+        let (root_key, receiver_chain) = create_chain(&self.root_key, their_ratchet, &self.sender_ratchet)?;
+        let sender_ratchet = Crypto::generate_key_pair()?;
+        let (root_key, sender_chain) = create_chain(&root_key, their_ratchet, &sender_ratchet)?;
+
+        self.receiver_chains.push((their_ratchet.to_vec(), receiver_chain));
+        if self.receiver_chains.len() > MAX_RECEIVER_CHAINS {
+            self.receiver_chains.remove(0);
+        }
+
+        self.previous_counter = self.sender_chain.index.saturating_sub(1);
+        self.root_key = root_key;
+        self.sender_ratchet = sender_ratchet;
+        self.sender_chain = sender_chain;
+        Ok(())
+    }
+
+    /// Get the keys of a message in a receiving chain
+    ///
+    /// Messages before the chain's position must have been skipped earlier.
+    /// Otherwise the chain advances past the message, keeping the keys of any
+    /// messages skipped on the way.
+    fn message_keys(&mut self, ratchet_key: &[u8], counter: u32) -> WhatsAppResult<MessageKeys> {
+        let chain = self.receiver_chains.iter_mut()
+            .find(|(key, _)| key == ratchet_key)
+            .map(|(_, chain)| chain)
+            .ok_or_else(|| WhatsAppError::CryptoError("Missing receiving chain".to_string()))?;
+
+        if counter < chain.index {
+            let position = self.skipped_keys.iter()
+                .position(|skipped| skipped.ratchet_key == ratchet_key && skipped.counter == counter)
+                .ok_or_else(|| WhatsAppError::CryptoError(format!("Message {} was already received", counter)))?;
+            return self.skipped_keys.remove(position)
+                .map(|skipped| skipped.keys)
+                .ok_or_else(|| WhatsAppError::CryptoError("Missing skipped message keys".to_string()));
+        }
+        if counter - chain.index > MAX_FORWARD_JUMPS {
+            return Err(WhatsAppError::CryptoError(format!("Message {} is too far ahead of the chain", counter)));
+        }
+
+        while chain.index < counter {
+            self.skipped_keys.push_back(SkippedKey {
+                ratchet_key: ratchet_key.to_vec(),
+                counter: chain.index,
+                keys: chain.message_keys()?,
+            });
+            if self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+                self.skipped_keys.pop_front();
+            }
+            *chain = chain.next()?;
+        }
+
+        let keys = chain.message_keys()?;
+        *chain = chain.next()?;
+        Ok(keys)
+    }
+}
+
+/// Encrypts and decrypts messages with the sessions of remote devices
+///
+/// Devices are addressed by their JID. Sessions are started from prekey
+/// bundles when sending, or from prekey messages when receiving. With a
+/// store, sessions are saved after every change and loaded on first use,
+/// and prekeys are looked up there too.
+pub struct SessionCipher {
+    identity: LocalIdentity,
+    sessions: HashMap<JID, SessionState>,
+    store: Option<Arc<dyn SignalStore>>,
+}
+
+impl SessionCipher {
+    pub fn new(identity: LocalIdentity) -> Self {
+        Self {
+            identity,
+            sessions: HashMap::new(),
+            store: None,
+        }
+    }
+
+    /// Persist sessions and prekeys in the store, saving the identity's prekeys to it
+    pub fn with_store(mut self, store: Arc<dyn SignalStore>) -> WhatsAppResult<Self> {
+        store.store_signed_pre_key(self.identity.signed_pre_key_id, &self.identity.signed_pre_key)?;
+        for (id, key) in &self.identity.pre_keys {
+            store.store_pre_key(*id, key)?;
+        }
+
+        self.store = Some(store);
+        Ok(self)
+    }
+
+    /// Get the identity and prekeys of this device
+    pub fn identity(&self) -> &LocalIdentity {
+        &self.identity
+    }
+
+    /// Check if there is a session with the device
+    pub fn has_session(&mut self, device: &JID) -> WhatsAppResult<bool> {
+        Ok(self.load_session(device)?.is_some())
+    }
+
+    /// Start a session with the device from its prekey bundle
+    pub fn process_bundle(&mut self, device: &JID, bundle: &PreKeyBundle) -> WhatsAppResult<()> {
+        let session = SessionState::initiate(&self.identity, bundle)?;
+        self.save_session(device, session)
+    }
+
+    /// Forget the session with the device, so the next message starts a new one
+    pub fn delete_session(&mut self, device: &JID) -> WhatsAppResult<()> {
+        self.sessions.remove(device);
+        match &self.store {
+            Some(store) => store.delete_session(device),
+            None => Ok(()),
+        }
+    }
+
+    /// Encrypt a message for the device
+    pub fn encrypt(&mut self, device: &JID, plaintext: &[u8]) -> WhatsAppResult<CiphertextMessage> {
+        let message = self.session(device)?.encrypt(plaintext)?;
+        self.persist(device)?;
+        Ok(message)
+    }
+
+    /// Decrypt a message from the device with the existing session
+    pub fn decrypt(&mut self, device: &JID, message: &SignalMessage) -> WhatsAppResult<Vec<u8>> {
+        let plaintext = self.session(device)?.decrypt(message)?;
+        self.persist(device)?;
+        Ok(plaintext)
+    }
+
+    /// Decrypt a prekey message from the device, setting up the session it starts unless it already exists
+    pub fn decrypt_pre_key_message(&mut self, device: &JID, message: &PreKeySignalMessage) -> WhatsAppResult<Vec<u8>> {
+        // Prekey messages keep coming until we reply, all for the same session
+        if let Some(session) = self.load_session(device)?
+            && session.base_key() == message.base_key
+        {
+            let plaintext = session.decrypt(&message.message)?;
+            self.persist(device)?;
+            return Ok(plaintext);
+        }
+
+        // The prekey may have been generated before a restart
+        if let (Some(id), Some(store)) = (message.pre_key_id, &self.store)
+            && !self.identity.pre_keys.contains_key(&id)
+            && let Some(key) = store.load_pre_key(id)?
+        {
+            self.identity.pre_keys.insert(id, key);
+        }
+
+        let (session, plaintext) = SessionState::accept(&mut self.identity, message)?;
+        if let (Some(id), Some(store)) = (message.pre_key_id, &self.store) {
+            store.remove_pre_key(id)?;
+        }
+        self.save_session(device, session)?;
+        Ok(plaintext)
+    }
+
+    /// Get the session with the device, loading it from the store if needed
+    fn load_session(&mut self, device: &JID) -> WhatsAppResult<Option<&mut SessionState>> {
+        if !self.sessions.contains_key(device)
+            && let Some(store) = &self.store
+            && let Some(record) = store.load_session(device)?
+        {
+            self.sessions.insert(device.clone(), SessionState::deserialize(&record)?);
+        }
+
+        Ok(self.sessions.get_mut(device))
+    }
+
+    fn session(&mut self, device: &JID) -> WhatsAppResult<&mut SessionState> {
+        self.load_session(device)?
+            .ok_or_else(|| WhatsAppError::CryptoError(format!("No session with {}", device)))
+    }
+
+    fn save_session(&mut self, device: &JID, session: SessionState) -> WhatsAppResult<()> {
+        self.sessions.insert(device.clone(), session);
+        self.persist(device)
+    }
+
+    /// Write the session with the device to the store
+    fn persist(&self, device: &JID) -> WhatsAppResult<()> {
+        if let (Some(store), Some(session)) = (&self.store, self.sessions.get(device)) {
+            store.store_session(device, &session.serialize())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Encrypted message of an established session
+#[derive(Debug, Clone)]
+pub struct SignalMessage {
+    pub ratchet_key: Vec<u8>,
+    pub counter: u32,
+    pub previous_counter: u32,
+    pub ciphertext: Vec<u8>,
+    serialized: Vec<u8>,
+}
+
+impl SignalMessage {
+    /// Decode a serialized message; the MAC is checked when decrypting
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        if data.len() < 1 + MAC_SIZE {
+            return Err(WhatsAppError::CryptoError("Signal message too short".to_string()));
+        }
+        check_version(data[0])?;
+
+        let (mut ratchet_key, mut counter, mut previous_counter, mut ciphertext) = (None, 0, 0, None);
+        for (field, value) in proto::decode_fields(&data[1..data.len() - MAC_SIZE])? {
+            match field {
+                1 => ratchet_key = Some(public_key(proto::field_bytes(&value)?)?),
+                2 => counter = proto::field_u64(&value)? as u32,
+                3 => previous_counter = proto::field_u64(&value)? as u32,
+                4 => ciphertext = Some(proto::field_bytes(&value)?.to_vec()),
+                _ => {},
+            }
+        }
+
+        match (ratchet_key, ciphertext) {
+            (Some(ratchet_key), Some(ciphertext)) => Ok(Self {
+                ratchet_key,
+                counter,
+                previous_counter,
+                ciphertext,
+                serialized: data.to_vec(),
+            }),
+            _ => Err(WhatsAppError::CryptoError("Incomplete signal message".to_string())),
+        }
+    }
+
+    /// Get the serialized message
+    pub fn serialize(&self) -> &[u8] {
+        &self.serialized
+    }
+
+    fn encode(&self, mac_key: &[u8], sender_identity: &[u8], receiver_identity: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        let mut data = vec![version_byte()];
+        data.extend(Encoder::new()
+            .bytes(1, &serialize_key(&self.ratchet_key))
+            .u64(2, self.counter as u64)
+            .u64(3, self.previous_counter as u64)
+            .bytes(4, &self.ciphertext)
+            .finish());
+
+        let mac = compute_mac(mac_key, sender_identity, receiver_identity, &data)?;
+        data.extend_from_slice(&mac);
+        Ok(data)
+    }
+
+    fn verify_mac(&self, mac_key: &[u8], sender_identity: &[u8], receiver_identity: &[u8]) -> WhatsAppResult<()> {
+        let (data, mac) = self.serialized.split_at(self.serialized.len() - MAC_SIZE);
+        Crypto::verify_hmac_sha256(mac_key, &mac_input(sender_identity, receiver_identity, data), mac)
+            .map_err(|_| WhatsAppError::CryptoError("Bad signal message MAC".to_string()))
+    }
+}
+
+/// First message of a session, carrying what the recipient needs to set up its side
+#[derive(Debug, Clone)]
+pub struct PreKeySignalMessage {
+    pub registration_id: u32,
+    pub pre_key_id: Option<u32>,
+    pub signed_pre_key_id: u32,
+    pub base_key: Vec<u8>,
+    pub identity_key: Vec<u8>,
+    pub message: SignalMessage,
+    serialized: Vec<u8>,
+}
+
+impl PreKeySignalMessage {
+    /// Decode a serialized prekey message
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        let Some((&version, body)) = data.split_first() else {
+            return Err(WhatsAppError::CryptoError("Empty prekey message".to_string()));
+        };
+        check_version(version)?;
+
+        let mut registration_id = 0;
+        let (mut pre_key_id, mut signed_pre_key_id) = (None, None);
+        let (mut base_key, mut identity_key, mut message) = (None, None, None);
+        for (field, value) in proto::decode_fields(body)? {
+            match field {
+                1 => pre_key_id = Some(proto::field_u64(&value)? as u32),
+                2 => base_key = Some(public_key(proto::field_bytes(&value)?)?),
+                3 => identity_key = Some(public_key(proto::field_bytes(&value)?)?),
+                4 => message = Some(SignalMessage::decode(proto::field_bytes(&value)?)?),
+                5 => registration_id = proto::field_u64(&value)? as u32,
+                6 => signed_pre_key_id = Some(proto::field_u64(&value)? as u32),
+                _ => {},
+            }
+        }
+
+        match (signed_pre_key_id, base_key, identity_key, message) {
+            (Some(signed_pre_key_id), Some(base_key), Some(identity_key), Some(message)) => Ok(Self {
+                registration_id,
+                pre_key_id,
+                signed_pre_key_id,
+                base_key,
+                identity_key,
+                message,
+                serialized: data.to_vec(),
+            }),
+            _ => Err(WhatsAppError::CryptoError("Incomplete prekey message".to_string())),
+        }
+    }
+
+    /// Get the serialized message
+    pub fn serialize(&self) -> &[u8] {
+        &self.serialized
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new().u64(5, self.registration_id as u64);
+        if let Some(pre_key_id) = self.pre_key_id {
+            encoder = encoder.u64(1, pre_key_id as u64);
+        }
+        let body = encoder
+            .u64(6, self.signed_pre_key_id as u64)
+            .bytes(2, &serialize_key(&self.base_key))
+            .bytes(3, &serialize_key(&self.identity_key))
+            .bytes(4, self.message.serialize())
+            .finish();
+
+        let mut data = vec![version_byte()];
+        data.extend(body);
+        data
+    }
+}
+
+/// Encrypted message ready to be sent to a device
+#[derive(Debug, Clone)]
+pub enum CiphertextMessage {
+    PreKey(PreKeySignalMessage),
+    Signal(SignalMessage),
+}
+
+impl CiphertextMessage {
+    /// Encryption type as named in message stanzas
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CiphertextMessage::PreKey(_) => "pkmsg",
+            CiphertextMessage::Signal(_) => "msg",
+        }
+    }
+
+    /// Get the serialized message
+    pub fn serialize(&self) -> &[u8] {
+        match self {
+            CiphertextMessage::PreKey(message) => message.serialize(),
+            CiphertextMessage::Signal(message) => message.serialize(),
+        }
+    }
+}
+
+/// Derive the root and first chain key from the X3DH key agreements
+fn derive_initial_keys(agreements: &[SecretBytes]) -> WhatsAppResult<(SecretBytes, ChainKey)> {
+    let mut parts: Vec<&[u8]> = vec![&[0xff; 32]];
+    parts.extend(agreements.iter().map(|agreement| &agreement[..]));
+
+    let derived = Crypto::hkdf(&SecretBytes::concat(&parts), b"WhisperText", 64)?;
+    Ok((derived[..32].into(), ChainKey { key: derived[32..].into(), index: 0 }))
+}
+
+/// Derive the next root key and a new chain from a ratchet key agreement
+fn create_chain(root_key: &[u8], their_ratchet: &[u8], our_ratchet: &KeyPair) -> WhatsAppResult<(SecretBytes, ChainKey)> {
+    let secret = our_ratchet.dh(their_ratchet)?;
+    let derived = Crypto::hkdf_with_salt(Some(root_key), &secret, b"WhisperRatchet", 64)?;
+    Ok((derived[..32].into(), ChainKey { key: derived[32..].into(), index: 0 }))
+}
+
+fn encode_chain(chain: &ChainKey) -> Vec<u8> {
+    Encoder::new().bytes(1, &chain.key).u64(2, chain.index as u64).finish()
+}
+
+fn decode_chain(data: &[u8]) -> WhatsAppResult<ChainKey> {
+    let mut chain = ChainKey { key: SecretBytes::default(), index: 0 };
+    for (field, value) in proto::decode_fields(data)? {
+        match field {
+            1 => chain.key = proto::field_bytes(&value)?.into(),
+            2 => chain.index = proto::field_u64(&value)? as u32,
+            _ => {},
+        }
+    }
+
+    if chain.key.len() != 32 {
+        return Err(corrupt_record("chain key is not 32 bytes"));
+    }
+    Ok(chain)
+}
+
+fn corrupt_record(reason: &str) -> WhatsAppError {
+    WhatsAppError::StoreError(format!("Corrupt session record: {}", reason))
+}
+
+/// Truncated MAC over both identities and the serialized message
+fn compute_mac(mac_key: &[u8], sender_identity: &[u8], receiver_identity: &[u8], data: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    let mut mac = Crypto::hmac_sha256(mac_key, &mac_input(sender_identity, receiver_identity, data))?;
+    mac.truncate(MAC_SIZE);
+    Ok(mac)
+}
+
+fn mac_input(sender_identity: &[u8], receiver_identity: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut input = serialize_key(sender_identity);
+    input.extend(serialize_key(receiver_identity));
+    input.extend_from_slice(data);
+    input
+}
+
+fn version_byte() -> u8 {
+    MESSAGE_VERSION << 4 | MESSAGE_VERSION
+}
+
+fn check_version(version: u8) -> WhatsAppResult<()> {
+    if version >> 4 != MESSAGE_VERSION {
+        return Err(WhatsAppError::CryptoError(format!("Unsupported signal message version {}", version >> 4)));
+    }
+
+    Ok(())
+}
+
+/// Prefix a raw Curve25519 public key with its type byte
+fn serialize_key(key: &[u8]) -> Vec<u8> {
+    let mut serialized = vec![DJB_KEY_TYPE];
+    serialized.extend_from_slice(key);
+    serialized
+}
+
+/// Get the raw Curve25519 public key from a key with or without its type byte
+pub(crate) fn public_key(data: &[u8]) -> WhatsAppResult<Vec<u8>> {
+    match data {
+        [DJB_KEY_TYPE, key @ ..] if key.len() == 32 => Ok(key.to_vec()),
+        key if key.len() == 32 => Ok(key.to_vec()),
+        _ => Err(WhatsAppError::CryptoError(format!("Invalid public key of {} bytes", data.len()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(identity: &LocalIdentity, pre_key_id: Option<u32>) -> PreKeyBundle {
+        PreKeyBundle {
+            registration_id: identity.registration_id,
+            device_id: 1,
+            identity_key: identity.identity.public.clone(),
+            signed_pre_key_id: identity.signed_pre_key_id,
+            signed_pre_key: identity.signed_pre_key.public.clone(),
+            signed_pre_key_signature: identity.signed_pre_key_signature.clone(),
+            pre_key_id,
+            pre_key: pre_key_id.map(|id| identity.pre_keys[&id].public.clone()),
+        }
+    }
+
+    fn devices() -> (JID, JID) {
+        (JID::new("1111", "s.whatsapp.net", Some(1)), JID::new("2222", "s.whatsapp.net", Some(1)))
+    }
+
+    /// Start a session from Alice to Bob, returning the ciphers once Bob has the first message
+    fn start_session(pre_key_id: Option<u32>) -> (SessionCipher, SessionCipher) {
+        let (alice_jid, bob_jid) = devices();
+        let mut alice = SessionCipher::new(LocalIdentity::generate(2).unwrap());
+        let mut bob = SessionCipher::new(LocalIdentity::generate(2).unwrap());
+
+        alice.process_bundle(&bob_jid, &bundle(bob.identity(), pre_key_id)).unwrap();
+        let message = alice.encrypt(&bob_jid, b"hello").unwrap();
+        assert_eq!(message.kind(), "pkmsg");
+
+        let message = PreKeySignalMessage::decode(message.serialize()).unwrap();
+        assert_eq!(message.pre_key_id, pre_key_id);
+        assert_eq!(bob.decrypt_pre_key_message(&alice_jid, &message).unwrap(), b"hello");
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_session_from_bundle_with_one_time_pre_key() {
+        let (alice, bob) = start_session(Some(1));
+        assert!(!bob.identity().pre_keys.contains_key(&1));
+        assert!(bob.identity().pre_keys.contains_key(&2));
+        assert_eq!(alice.identity().pre_keys.len(), 2);
+    }
+
+    #[test]
+    fn test_session_from_bundle_without_one_time_pre_key() {
+        let (_, bob) = start_session(None);
+        assert_eq!(bob.identity().pre_keys.len(), 2);
+    }
+
+    #[test]
+    fn test_bad_signed_pre_key_signature() {
+        let (_, bob_jid) = devices();
+        let mut alice = SessionCipher::new(LocalIdentity::generate(0).unwrap());
+        let bob = LocalIdentity::generate(1).unwrap();
+
+        let mut forged = bundle(&bob, Some(1));
+        forged.signed_pre_key_signature[0] ^= 1;
+        assert!(alice.process_bundle(&bob_jid, &forged).is_err());
+        assert!(!alice.has_session(&bob_jid).unwrap());
+
+        // A signature by another identity is no better
+        let mallory = LocalIdentity::generate(0).unwrap();
+        let mut forged = bundle(&bob, Some(1));
+        forged.signed_pre_key_signature = mallory.signed_pre_key_signature;
+        assert!(alice.process_bundle(&bob_jid, &forged).is_err());
+    }
+
+    #[test]
+    fn test_pre_key_message_until_reply() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = start_session(Some(1));
+
+        // Without a reply Alice keeps sending prekey messages for the same session
+        let message = alice.encrypt(&bob_jid, b"again").unwrap();
+        assert_eq!(message.kind(), "pkmsg");
+        let message = PreKeySignalMessage::decode(message.serialize()).unwrap();
+        assert_eq!(bob.decrypt_pre_key_message(&alice_jid, &message).unwrap(), b"again");
+
+        let reply = bob.encrypt(&alice_jid, b"hi").unwrap();
+        assert_eq!(reply.kind(), "msg");
+        let reply = SignalMessage::decode(reply.serialize()).unwrap();
+        assert_eq!(alice.decrypt(&bob_jid, &reply).unwrap(), b"hi");
+
+        let message = alice.encrypt(&bob_jid, b"bye").unwrap();
+        assert_eq!(message.kind(), "msg");
+        let message = SignalMessage::decode(message.serialize()).unwrap();
+        assert_eq!(bob.decrypt(&alice_jid, &message).unwrap(), b"bye");
+    }
+
+    /// Encrypt a message from an established session, decoded as the other side receives it
+    fn send(from: &mut SessionCipher, to: &JID, plaintext: &[u8]) -> SignalMessage {
+        let message = from.encrypt(to, plaintext).unwrap();
+        assert_eq!(message.kind(), "msg");
+        SignalMessage::decode(message.serialize()).unwrap()
+    }
+
+    /// Start a session and let Bob reply, so both sides send plain signal messages
+    fn established() -> (SessionCipher, SessionCipher) {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = start_session(Some(1));
+        let reply = send(&mut bob, &alice_jid, b"reply");
+        assert_eq!(alice.decrypt(&bob_jid, &reply).unwrap(), b"reply");
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_ping_pong_across_ratchet_steps() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = established();
+
+        let mut ratchet_keys = Vec::new();
+        for round in 0..5u8 {
+            for i in 0..3u8 {
+                let message = send(&mut alice, &bob_jid, &[round, i]);
+                assert_eq!(message.counter, i as u32);
+                assert_eq!(bob.decrypt(&alice_jid, &message).unwrap(), [round, i]);
+                ratchet_keys.push(message.ratchet_key);
+            }
+
+            let message = send(&mut bob, &alice_jid, &[round]);
+            assert_eq!(alice.decrypt(&bob_jid, &message).unwrap(), [round]);
+        }
+
+        // Every round trip moves Alice to a new ratchet key
+        ratchet_keys.dedup();
+        assert_eq!(ratchet_keys.len(), 5);
+    }
+
+    #[test]
+    fn test_reverse_order_within_chain() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = established();
+
+        let messages: Vec<_> = (0..5u8).map(|i| send(&mut alice, &bob_jid, &[i])).collect();
+        for (i, message) in messages.iter().enumerate().rev() {
+            assert_eq!(bob.decrypt(&alice_jid, message).unwrap(), [i as u8]);
+        }
+
+        // Skipped keys are used up once their message arrives
+        assert!(bob.decrypt(&alice_jid, &messages[2]).is_err());
+    }
+
+    #[test]
+    fn test_reverse_order_across_ratchet_step() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = established();
+
+        let first = send(&mut alice, &bob_jid, b"first");
+        let second = send(&mut alice, &bob_jid, b"second");
+        assert_eq!(bob.decrypt(&alice_jid, &first).unwrap(), b"first");
+
+        let reply = send(&mut bob, &alice_jid, b"reply");
+        assert_eq!(alice.decrypt(&bob_jid, &reply).unwrap(), b"reply");
+        let third = send(&mut alice, &bob_jid, b"third");
+        assert_ne!(third.ratchet_key, second.ratchet_key);
+        assert_eq!(third.previous_counter, 1);
+
+        assert_eq!(bob.decrypt(&alice_jid, &third).unwrap(), b"third");
+        assert_eq!(bob.decrypt(&alice_jid, &second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_forward_jump_limit() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = established();
+
+        let messages: Vec<_> = (0..MAX_FORWARD_JUMPS + 2)
+            .map(|i| send(&mut alice, &bob_jid, &i.to_be_bytes()))
+            .collect();
+
+        let too_far = &messages[MAX_FORWARD_JUMPS as usize + 1];
+        assert!(bob.decrypt(&alice_jid, too_far).is_err());
+
+        // The failed jump skipped nothing, and the largest allowed jump still works
+        let furthest = &messages[MAX_FORWARD_JUMPS as usize];
+        assert_eq!(bob.decrypt(&alice_jid, furthest).unwrap(), MAX_FORWARD_JUMPS.to_be_bytes());
+        assert_eq!(bob.sessions[&alice_jid].skipped_keys.len(), MAX_SKIPPED_KEYS);
+        assert_eq!(bob.decrypt(&alice_jid, &messages[0]).unwrap(), 0u32.to_be_bytes());
+        assert_eq!(bob.decrypt(&alice_jid, too_far).unwrap(), (MAX_FORWARD_JUMPS + 1).to_be_bytes());
+    }
+
+    #[test]
+    fn test_tampered_mac() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = established();
+
+        let message = send(&mut alice, &bob_jid, b"hello");
+        let mut tampered = message.serialize().to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = SignalMessage::decode(&tampered).unwrap();
+
+        let before = bob.sessions[&alice_jid].serialize();
+        assert!(bob.decrypt(&alice_jid, &tampered).is_err());
+        assert_eq!(&bob.sessions[&alice_jid].serialize()[..], &before[..]);
+        assert_eq!(bob.decrypt(&alice_jid, &message).unwrap(), b"hello");
+
+        // A tampered message from a new ratchet step must not move the session either
+        let reply = send(&mut bob, &alice_jid, b"reply");
+        alice.decrypt(&bob_jid, &reply).unwrap();
+        let message = send(&mut alice, &bob_jid, b"stepped");
+        let mut tampered = message.serialize().to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+
+        let before = bob.sessions[&alice_jid].serialize();
+        assert!(bob.decrypt(&alice_jid, &SignalMessage::decode(&tampered).unwrap()).is_err());
+        assert_eq!(&bob.sessions[&alice_jid].serialize()[..], &before[..]);
+        assert_eq!(bob.decrypt(&alice_jid, &message).unwrap(), b"stepped");
+    }
+
+    #[test]
+    fn test_session_record_round_trip() {
+        let (alice_jid, bob_jid) = devices();
+        let (mut alice, mut bob) = established();
+
+        // Leave a skipped message behind so the record has to carry its keys
+        let skipped = send(&mut alice, &bob_jid, b"skipped");
+        let delivered = send(&mut alice, &bob_jid, b"delivered");
+        assert_eq!(bob.decrypt(&alice_jid, &delivered).unwrap(), b"delivered");
+
+        for cipher in [&mut alice, &mut bob] {
+            let device = cipher.sessions.keys().next().unwrap().clone();
+            let record = cipher.sessions.remove(&device).unwrap().serialize();
+            let session = SessionState::deserialize(&record).unwrap();
+            assert_eq!(&session.serialize()[..], &record[..]);
+            cipher.sessions.insert(device, session);
+        }
+
+        assert_eq!(bob.decrypt(&alice_jid, &skipped).unwrap(), b"skipped");
+        let reply = send(&mut bob, &alice_jid, b"reply");
+        assert_eq!(alice.decrypt(&bob_jid, &reply).unwrap(), b"reply");
+        let message = send(&mut alice, &bob_jid, b"after");
+        assert_eq!(bob.decrypt(&alice_jid, &message).unwrap(), b"after");
+    }
+}
@@ -0,0 +1,126 @@
+use super::serialize_key;
+use crate::crypto::Crypto;
+use crate::proto::Encoder;
+
+/// Version of the fingerprint hash, hashed in front of the identity key
+const FINGERPRINT_VERSION: u16 = 0;
+
+/// Version of the scannable fingerprint format
+const SCANNABLE_VERSION: u64 = 1;
+
+/// Number of times the identity key is hashed in, to slow down collision searches
+const ITERATIONS: usize = 5200;
+
+/// Safety number to compare with a contact to verify end-to-end encryption
+///
+/// Both sides compute the same number, so it can be read out or scanned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    /// 60 digits: 30 for each side, in a fixed order
+    pub digits: String,
+    /// Encoded `CombinedFingerprints` for the QR code
+    pub scannable: Vec<u8>,
+}
+
+impl SafetyNumber {
+    /// Compute the safety number from the stable identifiers (phone numbers) and identity keys of both sides
+    pub fn new(local_id: &str, local_key: &[u8], remote_id: &str, remote_key: &[u8]) -> Self {
+        let local = fingerprint(local_id, local_key);
+        let remote = fingerprint(remote_id, remote_key);
+
+        let (local_digits, remote_digits) = (digits(&local), digits(&remote));
+        let digits = if local_digits <= remote_digits {
+            local_digits + &remote_digits
+        } else {
+            remote_digits + &local_digits
+        };
+
+        let scannable = Encoder::new()
+            .u64(1, SCANNABLE_VERSION)
+            .bytes(2, &Encoder::new().bytes(1, &local[..32]).finish())
+            .bytes(3, &Encoder::new().bytes(1, &remote[..32]).finish())
+            .finish();
+
+        Self { digits, scannable }
+    }
+
+    /// Format the digits in twelve groups of five, as shown to users
+    pub fn to_display_string(&self) -> String {
+        self.digits.as_bytes()
+            .chunks(5)
+            .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn fingerprint(id: &str, key: &[u8]) -> Vec<u8> {
+    let key = serialize_key(key);
+    let mut hash = [&FINGERPRINT_VERSION.to_be_bytes()[..], &key, id.as_bytes()].concat();
+
+    for _ in 0..ITERATIONS {
+        hash.extend_from_slice(&key);
+        hash = Crypto::sha512(&hash);
+    }
+
+    hash
+}
+
+/// Encode the first 30 bytes of a fingerprint as six 5-digit groups
+fn digits(fingerprint: &[u8]) -> String {
+    fingerprint[..30].chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, &byte| value << 8 | byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Identities of libsignal's NumericFingerprintGeneratorTest, without their type byte
+    const ALICE_ID: &str = "+14152222222";
+    const ALICE_KEY: &str = "06863bc66d02b40d27b8d49ca7c09e9239236f9d7d25d6fcca5ce13c7064d868";
+    const BOB_ID: &str = "+14153333333";
+    const BOB_KEY: &str = "f781b6fb32fed9ba1cf2de978d4d5da28dc34046ae814402b5c0dbd96fda907b";
+
+    fn safety_number(local_id: &str, local_key: &str, remote_id: &str, remote_key: &str) -> SafetyNumber {
+        SafetyNumber::new(local_id, &hex::decode(local_key).unwrap(), remote_id, &hex::decode(remote_key).unwrap())
+    }
+
+    #[test]
+    fn matches_libsignal() {
+        let alice = safety_number(ALICE_ID, ALICE_KEY, BOB_ID, BOB_KEY);
+        assert_eq!(alice.digits, "300354477692869396892869876765458257569162576843440918079131");
+        assert_eq!(
+            hex::encode(&alice.scannable),
+            concat!(
+                "080112220a201e301a0353dce3dbe7684cb8336e85136cdc0ee96219494ada305d62a7bd61df",
+                "1a220a20d62cbf73a11592015b6b9f1682ac306fea3aaf3885b84d12bca631e9d4fb3a4d",
+            ),
+        );
+    }
+
+    #[test]
+    fn both_sides_display_the_same_number() {
+        let alice = safety_number(ALICE_ID, ALICE_KEY, BOB_ID, BOB_KEY);
+        let bob = safety_number(BOB_ID, BOB_KEY, ALICE_ID, ALICE_KEY);
+
+        assert_eq!(alice.to_display_string(), bob.to_display_string());
+        assert_eq!(
+            alice.to_display_string(),
+            "30035 44776 92869 39689 28698 76765 45825 75691 62576 84344 09180 79131",
+        );
+        // Each side's own fingerprint comes first in what it scans
+        assert_ne!(alice.scannable, bob.scannable);
+    }
+
+    #[test]
+    fn depends_on_both_identities() {
+        let alice = safety_number(ALICE_ID, ALICE_KEY, BOB_ID, BOB_KEY);
+        assert_ne!(alice, safety_number(ALICE_ID, ALICE_KEY, BOB_ID, ALICE_KEY));
+        assert_ne!(alice, safety_number(ALICE_ID, ALICE_KEY, "+14154444444", BOB_KEY));
+    }
+}
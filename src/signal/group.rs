@@ -0,0 +1,587 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use rand::Rng;
+
+use super::{check_version, public_key, serialize_key, version_byte};
+use crate::{
+    JID,
+    crypto::{Crypto, SecretBytes},
+    error::{WhatsAppError, WhatsAppResult},
+    proto::{self, Encoder},
+    store::SenderKeyStore,
+};
+
+/// Length of the XEdDSA signature at the end of a `SenderKeyMessage`
+const SIGNATURE_SIZE: usize = 64;
+
+/// Largest number of messages an iteration may skip ahead in a sender chain
+const MAX_FORWARD_JUMPS: u32 = 2000;
+
+/// Number of keys kept for skipped messages that may still arrive out of order
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Chain key of a sender, ratcheted forward for every message
+#[derive(Clone)]
+struct SenderChainKey {
+    iteration: u32,
+    seed: SecretBytes,
+}
+
+impl SenderChainKey {
+    fn message_key(&self) -> WhatsAppResult<SenderMessageKey> {
+        let seed = SecretBytes::new(Crypto::hmac_sha256(&self.seed, &[0x01])?);
+        SenderMessageKey::new(self.iteration, seed)
+    }
+
+    fn next(&self) -> WhatsAppResult<SenderChainKey> {
+        Ok(SenderChainKey {
+            iteration: self.iteration + 1,
+            seed: SecretBytes::new(Crypto::hmac_sha256(&self.seed, &[0x02])?),
+        })
+    }
+}
+
+/// Key encrypting a single group message
+struct SenderMessageKey {
+    iteration: u32,
+    seed: SecretBytes,
+    cipher_key: SecretBytes,
+    iv: SecretBytes,
+}
+
+impl SenderMessageKey {
+    fn new(iteration: u32, seed: SecretBytes) -> WhatsAppResult<Self> {
+        let derived = Crypto::hkdf(&seed, b"WhisperGroup", 48)?;
+
+        Ok(Self {
+            iteration,
+            seed,
+            cipher_key: derived[16..].into(),
+            iv: derived[..16].into(),
+        })
+    }
+}
+
+/// Sender key of one member of a group, ours including the private signing key
+struct SenderKeyState {
+    key_id: u32,
+    chain: SenderChainKey,
+    signing_public: Vec<u8>,
+    signing_private: Option<SecretBytes>,
+    skipped: VecDeque<SenderMessageKey>,
+}
+
+impl SenderKeyState {
+    fn serialize(&self) -> SecretBytes {
+        let mut encoder = Encoder::new()
+            .u64(1, self.key_id as u64)
+            .u64(2, self.chain.iteration as u64)
+            .bytes(3, &self.chain.seed)
+            .bytes(4, &self.signing_public);
+        if let Some(private) = &self.signing_private {
+            encoder = encoder.bytes(5, private);
+        }
+        for key in &self.skipped {
+            encoder = encoder.bytes(6, &Encoder::new().u64(1, key.iteration as u64).bytes(2, &key.seed).finish());
+        }
+
+        SecretBytes::new(encoder.finish())
+    }
+
+    fn deserialize(record: &[u8]) -> WhatsAppResult<Self> {
+        let corrupt = |reason: &str| WhatsAppError::StoreError(format!("Corrupt sender key record: {}", reason));
+
+        let mut state = SenderKeyState {
+            key_id: 0,
+            chain: SenderChainKey { iteration: 0, seed: SecretBytes::default() },
+            signing_public: Vec::new(),
+            signing_private: None,
+            skipped: VecDeque::new(),
+        };
+        for (field, value) in proto::decode_fields(record)? {
+            match field {
+                1 => state.key_id = proto::field_u64(&value)? as u32,
+                2 => state.chain.iteration = proto::field_u64(&value)? as u32,
+                3 => state.chain.seed = proto::field_bytes(&value)?.into(),
+                4 => state.signing_public = proto::field_bytes(&value)?.to_vec(),
+                5 => state.signing_private = Some(proto::field_bytes(&value)?.into()),
+                6 => {
+                    let (mut iteration, mut seed) = (0, SecretBytes::default());
+                    for (field, value) in proto::decode_fields(proto::field_bytes(&value)?)? {
+                        match field {
+                            1 => iteration = proto::field_u64(&value)? as u32,
+                            2 => seed = proto::field_bytes(&value)?.into(),
+                            _ => {},
+                        }
+                    }
+                    state.skipped.push_back(SenderMessageKey::new(iteration, seed)?);
+                },
+                _ => {},
+            }
+        }
+
+        if state.chain.seed.len() != 32 {
+            return Err(corrupt("chain key is not 32 bytes"));
+        }
+        if state.signing_public.len() != 32 {
+            return Err(corrupt("signing key is not 32 bytes"));
+        }
+        Ok(state)
+    }
+
+    /// Get the key of the message with the given iteration, ratcheting the chain up to it
+    fn message_key(&mut self, iteration: u32) -> WhatsAppResult<SenderMessageKey> {
+        if iteration < self.chain.iteration {
+            // Only keys of skipped messages are left for earlier iterations
+            return self.skipped.iter()
+                .position(|key| key.iteration == iteration)
+                .and_then(|index| self.skipped.remove(index))
+                .ok_or_else(|| WhatsAppError::CryptoError(format!("Group message {} was already received", iteration)));
+        }
+        if iteration - self.chain.iteration > MAX_FORWARD_JUMPS {
+            return Err(WhatsAppError::CryptoError(format!("Group message {} is too far ahead", iteration)));
+        }
+
+        while self.chain.iteration < iteration {
+            self.skipped.push_back(self.chain.message_key()?);
+            if self.skipped.len() > MAX_SKIPPED_KEYS {
+                self.skipped.pop_front();
+            }
+            self.chain = self.chain.next()?;
+        }
+
+        let key = self.chain.message_key()?;
+        self.chain = self.chain.next()?;
+        Ok(key)
+    }
+}
+
+/// Sender key a member hands to the others over their pairwise sessions, so they can decrypt its group messages
+#[derive(Debug, Clone)]
+pub struct SenderKeyDistributionMessage {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub chain_key: Vec<u8>,
+    pub signing_key: Vec<u8>,
+    serialized: Vec<u8>,
+}
+
+impl SenderKeyDistributionMessage {
+    fn new(key_id: u32, iteration: u32, chain_key: &[u8], signing_key: &[u8]) -> Self {
+        let mut serialized = vec![version_byte()];
+        serialized.extend(Encoder::new()
+            .u64(1, key_id as u64)
+            .u64(2, iteration as u64)
+            .bytes(3, chain_key)
+            .bytes(4, &serialize_key(signing_key))
+            .finish());
+
+        Self {
+            key_id,
+            iteration,
+            chain_key: chain_key.to_vec(),
+            signing_key: signing_key.to_vec(),
+            serialized,
+        }
+    }
+
+    /// Decode a serialized distribution message
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        let Some((&version, body)) = data.split_first() else {
+            return Err(WhatsAppError::CryptoError("Empty sender key distribution message".to_string()));
+        };
+        check_version(version)?;
+
+        let (mut key_id, mut iteration, mut chain_key, mut signing_key) = (None, 0, None, None);
+        for (field, value) in proto::decode_fields(body)? {
+            match field {
+                1 => key_id = Some(proto::field_u64(&value)? as u32),
+                2 => iteration = proto::field_u64(&value)? as u32,
+                3 => chain_key = Some(proto::field_bytes(&value)?.to_vec()),
+                4 => signing_key = Some(public_key(proto::field_bytes(&value)?)?),
+                _ => {},
+            }
+        }
+
+        match (key_id, chain_key, signing_key) {
+            (Some(key_id), Some(chain_key), Some(signing_key)) if chain_key.len() == 32 => Ok(Self {
+                key_id,
+                iteration,
+                chain_key,
+                signing_key,
+                serialized: data.to_vec(),
+            }),
+            _ => Err(WhatsAppError::CryptoError("Incomplete sender key distribution message".to_string())),
+        }
+    }
+
+    /// Get the serialized message
+    pub fn serialize(&self) -> &[u8] {
+        &self.serialized
+    }
+}
+
+/// Group message encrypted once with the sender's sender key, signed with its signing key
+#[derive(Debug, Clone)]
+pub struct SenderKeyMessage {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    serialized: Vec<u8>,
+}
+
+impl SenderKeyMessage {
+    /// Decode a serialized message; the signature is checked when decrypting
+    pub fn decode(data: &[u8]) -> WhatsAppResult<Self> {
+        if data.len() < 1 + SIGNATURE_SIZE {
+            return Err(WhatsAppError::CryptoError("Sender key message too short".to_string()));
+        }
+        check_version(data[0])?;
+
+        let (mut key_id, mut iteration, mut ciphertext) = (None, 0, None);
+        for (field, value) in proto::decode_fields(&data[1..data.len() - SIGNATURE_SIZE])? {
+            match field {
+                1 => key_id = Some(proto::field_u64(&value)? as u32),
+                2 => iteration = proto::field_u64(&value)? as u32,
+                3 => ciphertext = Some(proto::field_bytes(&value)?.to_vec()),
+                _ => {},
+            }
+        }
+
+        match (key_id, ciphertext) {
+            (Some(key_id), Some(ciphertext)) => Ok(Self {
+                key_id,
+                iteration,
+                ciphertext,
+                serialized: data.to_vec(),
+            }),
+            _ => Err(WhatsAppError::CryptoError("Incomplete sender key message".to_string())),
+        }
+    }
+
+    /// Get the serialized message
+    pub fn serialize(&self) -> &[u8] {
+        &self.serialized
+    }
+
+    fn verify_signature(&self, signing_key: &[u8]) -> WhatsAppResult<()> {
+        let (data, signature) = self.serialized.split_at(self.serialized.len() - SIGNATURE_SIZE);
+        Crypto::xeddsa_verify(signing_key, data, signature)
+            .map_err(|_| WhatsAppError::CryptoError("Bad sender key message signature".to_string()))
+    }
+}
+
+/// Signal group cipher, encrypting a message once for every member of a group
+///
+/// Each member has its own sender key per group, identified by the group and
+/// the member's device JID. Ours is created on first use and handed to the
+/// other members with `distribution_message`. With a store, sender keys are
+/// saved after every change and loaded on first use.
+pub struct GroupCipher {
+    states: HashMap<(JID, JID), SenderKeyState>,
+    store: Option<Arc<dyn SenderKeyStore>>,
+}
+
+impl Default for GroupCipher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GroupCipher {
+    pub fn new() -> Self {
+        Self {
+            states: HashMap::new(),
+            store: None,
+        }
+    }
+
+    /// Persist sender keys in the store
+    pub fn with_store(mut self, store: Arc<dyn SenderKeyStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Get the distribution message of our sender key in the group, creating the key if needed
+    pub fn distribution_message(&mut self, group: &JID, own: &JID) -> WhatsAppResult<SenderKeyDistributionMessage> {
+        let state = self.own_state(group, own)?;
+        Ok(SenderKeyDistributionMessage::new(
+            state.key_id,
+            state.chain.iteration,
+            &state.chain.seed,
+            &state.signing_public,
+        ))
+    }
+
+    /// Store the sender key another member of the group distributed
+    pub fn process_distribution(&mut self, group: &JID, sender: &JID, message: &SenderKeyDistributionMessage) -> WhatsAppResult<()> {
+        let state = SenderKeyState {
+            key_id: message.key_id,
+            chain: SenderChainKey { iteration: message.iteration, seed: message.chain_key.as_slice().into() },
+            signing_public: message.signing_key.clone(),
+            signing_private: None,
+            skipped: VecDeque::new(),
+        };
+
+        self.states.insert((group.clone(), sender.clone()), state);
+        self.persist(group, sender)
+    }
+
+    /// Forget our sender key in the group, so a new one is distributed, e.g. after a member left
+    pub fn rotate(&mut self, group: &JID, own: &JID) -> WhatsAppResult<()> {
+        self.states.remove(&(group.clone(), own.clone()));
+        if let Some(store) = &self.store {
+            store.store_sender_key(group, own, &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget every sender key in the group, ours included, e.g. after leaving it
+    ///
+    /// Keys of other members that were never loaded stay in the store until
+    /// they distribute new ones.
+    pub fn forget_group(&mut self, group: &JID, own: &JID) -> WhatsAppResult<()> {
+        let mut senders: Vec<JID> = self.states.keys()
+            .filter(|(key_group, _)| key_group == group)
+            .map(|(_, sender)| sender.clone())
+            .collect();
+        if !senders.contains(own) {
+            senders.push(own.clone());
+        }
+
+        for sender in senders {
+            self.states.remove(&(group.clone(), sender.clone()));
+            if let Some(store) = &self.store {
+                store.store_sender_key(group, &sender, &[])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt a message for the group with our sender key
+    pub fn encrypt(&mut self, group: &JID, own: &JID, plaintext: &[u8]) -> WhatsAppResult<SenderKeyMessage> {
+        let state = self.own_state(group, own)?;
+        let Some(signing_private) = state.signing_private.clone() else {
+            return Err(WhatsAppError::CryptoError(format!("Sender key in {} isn't ours", group)));
+        };
+        let iteration = state.chain.iteration;
+        let key = state.message_key(iteration)?;
+        let key_id = state.key_id;
+
+        let ciphertext = Crypto::aes_encrypt(&key.cipher_key, &key.iv, plaintext)?;
+        let mut serialized = vec![version_byte()];
+        serialized.extend(Encoder::new()
+            .u64(1, key_id as u64)
+            .u64(2, iteration as u64)
+            .bytes(3, &ciphertext)
+            .finish());
+        let signature = Crypto::xeddsa_sign(&signing_private, &serialized)?;
+        serialized.extend_from_slice(&signature);
+
+        self.persist(group, own)?;
+        Ok(SenderKeyMessage { key_id, iteration, ciphertext, serialized })
+    }
+
+    /// Decrypt a group message with the sender key its sender distributed
+    pub fn decrypt(&mut self, group: &JID, sender: &JID, message: &SenderKeyMessage) -> WhatsAppResult<Vec<u8>> {
+        let state = self.load_state(group, sender)?
+            .ok_or_else(|| WhatsAppError::CryptoError(format!("No sender key of {} in {}", sender, group)))?;
+        if state.key_id != message.key_id {
+            return Err(WhatsAppError::CryptoError(format!("No sender key {} of {} in {}", message.key_id, sender, group)));
+        }
+        message.verify_signature(&state.signing_public)?;
+
+        let key = state.message_key(message.iteration)?;
+        let plaintext = Crypto::aes_decrypt(&key.cipher_key, &key.iv, &message.ciphertext)?;
+
+        self.persist(group, sender)?;
+        Ok(plaintext)
+    }
+
+    /// Get our sender key in the group, creating it on first use
+    fn own_state(&mut self, group: &JID, own: &JID) -> WhatsAppResult<&mut SenderKeyState> {
+        if self.load_state(group, own)?.is_none() {
+            let signing = Crypto::generate_key_pair()?;
+            let state = SenderKeyState {
+                key_id: rand::thread_rng().gen_range(1..i32::MAX as u32),
+                chain: SenderChainKey { iteration: 0, seed: SecretBytes::new(Crypto::random_bytes(32)) },
+                signing_public: signing.public.clone(),
+                signing_private: Some(signing.private),
+                skipped: VecDeque::new(),
+            };
+            self.states.insert((group.clone(), own.clone()), state);
+            self.persist(group, own)?;
+        }
+
+        self.load_state(group, own)?
+            .ok_or_else(|| WhatsAppError::CryptoError(format!("No sender key in {}", group)))
+    }
+
+    /// Get the sender key of a member of the group, loading it from the store if needed
+    fn load_state(&mut self, group: &JID, sender: &JID) -> WhatsAppResult<Option<&mut SenderKeyState>> {
+        let name = (group.clone(), sender.clone());
+        if !self.states.contains_key(&name)
+            && let Some(store) = &self.store
+            && let Some(record) = store.load_sender_key(group, sender)?
+            && !record.is_empty()
+        {
+            self.states.insert(name.clone(), SenderKeyState::deserialize(&record)?);
+        }
+
+        Ok(self.states.get_mut(&name))
+    }
+
+    /// Write the sender key of a member of the group to the store
+    fn persist(&self, group: &JID, sender: &JID) -> WhatsAppResult<()> {
+        if let (Some(store), Some(state)) = (&self.store, self.states.get(&(group.clone(), sender.clone()))) {
+            store.store_sender_key(group, sender, &state.serialize())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Sender key store kept in memory, standing in for a restart
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<(JID, JID), Vec<u8>>>);
+
+    impl SenderKeyStore for MemoryStore {
+        fn load_sender_key(&self, group: &JID, sender: &JID) -> WhatsAppResult<Option<SecretBytes>> {
+            Ok(self.0.lock().unwrap().get(&(group.clone(), sender.clone())).map(|record| record.as_slice().into()))
+        }
+
+        fn store_sender_key(&self, group: &JID, sender: &JID, record: &[u8]) -> WhatsAppResult<()> {
+            self.0.lock().unwrap().insert((group.clone(), sender.clone()), record.to_vec());
+            Ok(())
+        }
+    }
+
+    fn group() -> JID {
+        JID::new("1234-5678", "g.us", None)
+    }
+
+    fn alice() -> JID {
+        JID::new("1111", "s.whatsapp.net", Some(1))
+    }
+
+    /// Alice's cipher and Bob's, which received Alice's sender key
+    fn members() -> (GroupCipher, GroupCipher) {
+        let mut alice_cipher = GroupCipher::new();
+        let distribution = alice_cipher.distribution_message(&group(), &alice()).unwrap();
+
+        let mut bob_cipher = GroupCipher::new();
+        let received = SenderKeyDistributionMessage::decode(distribution.serialize()).unwrap();
+        bob_cipher.process_distribution(&group(), &alice(), &received).unwrap();
+        (alice_cipher, bob_cipher)
+    }
+
+    fn encrypt(cipher: &mut GroupCipher, plaintext: &str) -> SenderKeyMessage {
+        let message = cipher.encrypt(&group(), &alice(), plaintext.as_bytes()).unwrap();
+        SenderKeyMessage::decode(message.serialize()).unwrap()
+    }
+
+    fn decrypt(cipher: &mut GroupCipher, message: &SenderKeyMessage) -> WhatsAppResult<String> {
+        cipher.decrypt(&group(), &alice(), message).map(|plaintext| String::from_utf8(plaintext).unwrap())
+    }
+
+    #[test]
+    fn decrypts_in_order() {
+        let (mut alice_cipher, mut bob_cipher) = members();
+
+        for (iteration, text) in ["one", "two", "three"].into_iter().enumerate() {
+            let message = encrypt(&mut alice_cipher, text);
+            assert_eq!(message.iteration, iteration as u32);
+            assert_eq!(decrypt(&mut bob_cipher, &message).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn decrypts_out_of_order() {
+        let (mut alice_cipher, mut bob_cipher) = members();
+        let messages: Vec<_> = ["one", "two", "three", "four"].into_iter().map(|text| encrypt(&mut alice_cipher, text)).collect();
+
+        assert_eq!(decrypt(&mut bob_cipher, &messages[2]).unwrap(), "three");
+        assert_eq!(decrypt(&mut bob_cipher, &messages[0]).unwrap(), "one");
+        assert_eq!(decrypt(&mut bob_cipher, &messages[3]).unwrap(), "four");
+        assert_eq!(decrypt(&mut bob_cipher, &messages[1]).unwrap(), "two");
+
+        // Each message key is only handed out once
+        assert!(decrypt(&mut bob_cipher, &messages[0]).is_err());
+        assert!(decrypt(&mut bob_cipher, &messages[3]).is_err());
+    }
+
+    #[test]
+    fn limits_forward_jumps() {
+        let (mut alice_cipher, mut bob_cipher) = members();
+        let state = alice_cipher.states.get_mut(&(group(), alice())).unwrap();
+        for _ in 0..=MAX_FORWARD_JUMPS {
+            state.chain = state.chain.next().unwrap();
+        }
+
+        let message = encrypt(&mut alice_cipher, "too far");
+        assert_eq!(message.iteration, MAX_FORWARD_JUMPS + 1);
+        assert!(decrypt(&mut bob_cipher, &message).is_err());
+    }
+
+    #[test]
+    fn rejects_forged_messages() {
+        let (mut alice_cipher, mut bob_cipher) = members();
+        let message = encrypt(&mut alice_cipher, "hello");
+
+        let mut forged = message.serialize().to_vec();
+        let last = forged.len() - SIGNATURE_SIZE - 1;
+        forged[last] ^= 1;
+        assert!(decrypt(&mut bob_cipher, &SenderKeyMessage::decode(&forged).unwrap()).is_err());
+
+        // Nothing was ratcheted by the forgery
+        assert_eq!(decrypt(&mut bob_cipher, &message).unwrap(), "hello");
+    }
+
+    #[test]
+    fn rotated_key_needs_a_new_distribution() {
+        let (mut alice_cipher, mut bob_cipher) = members();
+        let old_key_id = alice_cipher.distribution_message(&group(), &alice()).unwrap().key_id;
+
+        alice_cipher.rotate(&group(), &alice()).unwrap();
+        let distribution = alice_cipher.distribution_message(&group(), &alice()).unwrap();
+        assert_ne!(distribution.key_id, old_key_id);
+        assert_eq!(distribution.iteration, 0);
+
+        // Bob still only has the old key, which can't read anything new
+        let message = encrypt(&mut alice_cipher, "after rotation");
+        assert!(decrypt(&mut bob_cipher, &message).is_err());
+
+        bob_cipher.process_distribution(&group(), &alice(), &distribution).unwrap();
+        assert_eq!(decrypt(&mut bob_cipher, &message).unwrap(), "after rotation");
+    }
+
+    #[test]
+    fn sender_keys_survive_a_restart() {
+        let (alice_store, bob_store) = (Arc::new(MemoryStore::default()), Arc::new(MemoryStore::default()));
+        let mut alice_cipher = GroupCipher::new().with_store(alice_store.clone());
+        let distribution = alice_cipher.distribution_message(&group(), &alice()).unwrap();
+        let mut bob_cipher = GroupCipher::new().with_store(bob_store.clone());
+        bob_cipher.process_distribution(&group(), &alice(), &distribution).unwrap();
+
+        let first = encrypt(&mut alice_cipher, "one");
+        let second = encrypt(&mut alice_cipher, "two");
+        assert_eq!(decrypt(&mut bob_cipher, &second).unwrap(), "two");
+
+        // The chain position and the key skipped for the first message are restored
+        let mut alice_cipher = GroupCipher::new().with_store(alice_store);
+        let mut bob_cipher = GroupCipher::new().with_store(bob_store);
+        let third = encrypt(&mut alice_cipher, "three");
+        assert_eq!(third.iteration, 2);
+        assert_eq!(decrypt(&mut bob_cipher, &third).unwrap(), "three");
+        assert_eq!(decrypt(&mut bob_cipher, &first).unwrap(), "one");
+    }
+}
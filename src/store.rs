@@ -0,0 +1,160 @@
+use serde_json::json;
+
+use crate::{
+    JID,
+    client::DeviceStore,
+    crypto::{Crypto, KeyPair, SecretBytes},
+    error::{WhatsAppError, WhatsAppResult},
+};
+
+/// Persistence of the identity keys of remote devices
+///
+/// WhatsApp accepts changed identity keys and only tells the user, which is
+/// what the default trust check does; implementations can be stricter.
+pub trait IdentityKeyStore: Send + Sync {
+    /// Get the saved identity key of a device
+    fn get_identity(&self, device: &JID) -> WhatsAppResult<Option<Vec<u8>>>;
+
+    /// Save the identity key of a device, returning whether it replaced a different key
+    fn save_identity(&self, device: &JID, identity_key: &[u8]) -> WhatsAppResult<bool>;
+
+    /// Check whether an identity key may be used with the device
+    fn is_trusted_identity(&self, _device: &JID, _identity_key: &[u8]) -> WhatsAppResult<bool> {
+        Ok(true)
+    }
+}
+
+/// Persistence of Signal sessions as opaque records
+pub trait SessionStore: Send + Sync {
+    fn load_session(&self, device: &JID) -> WhatsAppResult<Option<SecretBytes>>;
+    fn store_session(&self, device: &JID, record: &[u8]) -> WhatsAppResult<()>;
+    fn delete_session(&self, device: &JID) -> WhatsAppResult<()>;
+}
+
+/// Persistence of the one-time prekeys of this device
+pub trait PreKeyStore: Send + Sync {
+    fn load_pre_key(&self, id: u32) -> WhatsAppResult<Option<KeyPair>>;
+    fn store_pre_key(&self, id: u32, key: &KeyPair) -> WhatsAppResult<()>;
+    /// Remove a prekey once a session has used it
+    fn remove_pre_key(&self, id: u32) -> WhatsAppResult<()>;
+}
+
+/// Persistence of the signed prekeys of this device
+pub trait SignedPreKeyStore: Send + Sync {
+    fn load_signed_pre_key(&self, id: u32) -> WhatsAppResult<Option<KeyPair>>;
+    fn store_signed_pre_key(&self, id: u32, key: &KeyPair) -> WhatsAppResult<()>;
+}
+
+/// Persistence of group sender keys as opaque records
+pub trait SenderKeyStore: Send + Sync {
+    fn load_sender_key(&self, group: &JID, sender: &JID) -> WhatsAppResult<Option<SecretBytes>>;
+    fn store_sender_key(&self, group: &JID, sender: &JID, record: &[u8]) -> WhatsAppResult<()>;
+}
+
+/// Everything the Signal layer keeps across restarts
+///
+/// Implemented for every type implementing the individual stores, so a
+/// backend only has to implement those.
+pub trait SignalStore: SessionStore + PreKeyStore + SignedPreKeyStore + SenderKeyStore {}
+
+impl<T: SessionStore + PreKeyStore + SignedPreKeyStore + SenderKeyStore> SignalStore for T {}
+
+impl IdentityKeyStore for DeviceStore {
+    fn get_identity(&self, device: &JID) -> WhatsAppResult<Option<Vec<u8>>> {
+        self.get(&format!("identity:{}", device))
+            .map(|key| Crypto::base64_decode(&key))
+            .transpose()
+    }
+
+    fn save_identity(&self, device: &JID, identity_key: &[u8]) -> WhatsAppResult<bool> {
+        let previous = self.get_identity(device)?;
+        if previous.as_deref() == Some(identity_key) {
+            return Ok(false);
+        }
+
+        self.set(&format!("identity:{}", device), &Crypto::base64_encode(identity_key))?;
+        Ok(previous.is_some())
+    }
+}
+
+impl SessionStore for DeviceStore {
+    fn load_session(&self, device: &JID) -> WhatsAppResult<Option<SecretBytes>> {
+        self.get_record(&format!("session:{}", device))
+    }
+
+    fn store_session(&self, device: &JID, record: &[u8]) -> WhatsAppResult<()> {
+        self.set(&format!("session:{}", device), &Crypto::base64_encode(record))
+    }
+
+    fn delete_session(&self, device: &JID) -> WhatsAppResult<()> {
+        self.remove(&format!("session:{}", device))
+    }
+}
+
+impl PreKeyStore for DeviceStore {
+    fn load_pre_key(&self, id: u32) -> WhatsAppResult<Option<KeyPair>> {
+        self.get_key_pair(&format!("prekey:{}", id))
+    }
+
+    fn store_pre_key(&self, id: u32, key: &KeyPair) -> WhatsAppResult<()> {
+        self.set_key_pair(&format!("prekey:{}", id), key)
+    }
+
+    fn remove_pre_key(&self, id: u32) -> WhatsAppResult<()> {
+        self.remove(&format!("prekey:{}", id))
+    }
+}
+
+impl SignedPreKeyStore for DeviceStore {
+    fn load_signed_pre_key(&self, id: u32) -> WhatsAppResult<Option<KeyPair>> {
+        self.get_key_pair(&format!("signed_prekey:{}", id))
+    }
+
+    fn store_signed_pre_key(&self, id: u32, key: &KeyPair) -> WhatsAppResult<()> {
+        self.set_key_pair(&format!("signed_prekey:{}", id), key)
+    }
+}
+
+impl SenderKeyStore for DeviceStore {
+    fn load_sender_key(&self, group: &JID, sender: &JID) -> WhatsAppResult<Option<SecretBytes>> {
+        self.get_record(&format!("sender_key:{}:{}", group, sender))
+    }
+
+    fn store_sender_key(&self, group: &JID, sender: &JID, record: &[u8]) -> WhatsAppResult<()> {
+        self.set(&format!("sender_key:{}:{}", group, sender), &Crypto::base64_encode(record))
+    }
+}
+
+impl DeviceStore {
+    fn get_record(&self, key: &str) -> WhatsAppResult<Option<SecretBytes>> {
+        self.get(key)
+            .map(|record| Crypto::base64_decode(&record).map(SecretBytes::new))
+            .transpose()
+    }
+
+    fn get_key_pair(&self, key: &str) -> WhatsAppResult<Option<KeyPair>> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+
+        let value: serde_json::Value = serde_json::from_str(&value)
+            .map_err(|e| WhatsAppError::StoreError(format!("Corrupt key pair {}: {}", key, e)))?;
+        let field = |name: &str| match value[name].as_str() {
+            Some(field) => Crypto::base64_decode(field),
+            None => Err(WhatsAppError::StoreError(format!("Key pair {} has no {} key", key, name))),
+        };
+
+        Ok(Some(KeyPair {
+            private: SecretBytes::new(field("private")?),
+            public: field("public")?,
+        }))
+    }
+
+    fn set_key_pair(&self, key: &str, key_pair: &KeyPair) -> WhatsAppResult<()> {
+        let value = json!({
+            "private": Crypto::base64_encode(&key_pair.private),
+            "public": Crypto::base64_encode(&key_pair.public),
+        });
+        self.set(key, &value.to_string())
+    }
+}
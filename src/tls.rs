@@ -0,0 +1,210 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsConnector, client::TlsStream, rustls};
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+
+use crate::{
+    crypto::Crypto,
+    error::{WhatsAppError, WhatsAppResult},
+};
+
+/// Tag of a constructed DER sequence
+const DER_SEQUENCE: u8 = 0x30;
+
+/// Pinned identity of the server's TLS certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsPin {
+    /// SHA-256 hash of the DER-encoded leaf certificate
+    Certificate([u8; 32]),
+    /// SHA-256 hash of the leaf certificate's DER-encoded SubjectPublicKeyInfo
+    PublicKey([u8; 32]),
+}
+
+/// Parses pins in the `sha256/<base64>` format used for public key pinning
+impl std::str::FromStr for TlsPin {
+    type Err = WhatsAppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s.strip_prefix("sha256/")
+            .ok_or_else(|| WhatsAppError::ParsingError(format!("Invalid TLS pin: {}", s)))?;
+        let hash = Crypto::base64_decode(encoded)?;
+        let hash = hash.try_into()
+            .map_err(|_| WhatsAppError::ParsingError(format!("Invalid TLS pin length: {}", s)))?;
+
+        Ok(TlsPin::PublicKey(hash))
+    }
+}
+
+/// Perform the TLS handshake, verifying the certificate chain and any pins
+///
+/// TLS always comes from rustls with the Mozilla root certificates, whatever
+/// the crypto backend. Pins are checked by the certificate verifier, so a
+/// handshake with a certificate matching none of them fails before any data
+/// is sent.
+pub(crate) async fn connect<S>(host: &str, stream: S, pins: &[TlsPin]) -> WhatsAppResult<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = client_config(pins);
+    let name = rustls::ServerName::try_from(host)
+        .map_err(|e| WhatsAppError::TlsError(format!("Invalid server name {}: {}", host, e)))?;
+
+    TlsConnector::from(Arc::new(config)).connect(name, stream).await
+        .map_err(|e| WhatsAppError::TlsError(format!("Handshake with {} failed: {}", host, e)))
+}
+
+fn client_config(pins: &[TlsPin]) -> rustls::ClientConfig {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+
+    let verifier = PinningVerifier {
+        chain: WebPkiVerifier::new(roots, None),
+        pins: pins.to_vec(),
+    };
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+/// Certificate verifier checking the chain against the roots, then the pins if there are any
+struct PinningVerifier {
+    chain: WebPkiVerifier,
+    pins: Vec<TlsPin>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.chain.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+
+        if !self.pins.is_empty() {
+            verify_pins(&end_entity.0, &self.pins).map_err(|e| rustls::Error::General(e.to_string()))?;
+        }
+        Ok(verified)
+    }
+}
+
+/// Check that the DER-encoded certificate matches at least one pin
+fn verify_pins(der: &[u8], pins: &[TlsPin]) -> WhatsAppResult<()> {
+    let certificate_hash = Crypto::sha256(der);
+    let public_key = subject_public_key_info(der)
+        .ok_or_else(|| WhatsAppError::TlsError("Can't find the public key in the certificate".to_string()))?;
+    let public_key_hash = Crypto::sha256(public_key);
+
+    let matched = pins.iter().any(|pin| match pin {
+        TlsPin::Certificate(hash) => Crypto::constant_time_eq(&hash[..], &certificate_hash),
+        TlsPin::PublicKey(hash) => Crypto::constant_time_eq(&hash[..], &public_key_hash),
+    });
+
+    if matched {
+        Ok(())
+    } else {
+        Err(WhatsAppError::TlsError(format!(
+            "Certificate does not match any pin (public key sha256/{})",
+            Crypto::base64_encode(&public_key_hash)
+        )))
+    }
+}
+
+/// Get the DER-encoded SubjectPublicKeyInfo of a DER-encoded X.509 certificate
+fn subject_public_key_info(der: &[u8]) -> Option<&[u8]> {
+    let certificate = sequence_contents(der)?;
+    let mut tbs = sequence_contents(certificate)?;
+
+    // Skip the optional version, then the serial number, signature algorithm,
+    // issuer, validity and subject
+    if tbs.first() == Some(&0xa0) {
+        tbs = skip_element(tbs)?;
+    }
+    for _ in 0..5 {
+        tbs = skip_element(tbs)?;
+    }
+
+    let (tag, header, length) = der_header(tbs)?;
+    (tag == DER_SEQUENCE).then(|| &tbs[..header + length])
+}
+
+/// Read the tag, header length and content length of the DER element at the start of the data
+fn der_header(data: &[u8]) -> Option<(u8, usize, usize)> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (header, length) = if first < 0x80 {
+        (2, first)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = data.get(2..2 + count)?.iter().fold(0, |length, &byte| length << 8 | byte as usize);
+        (2 + count, length)
+    };
+
+    header.checked_add(length).filter(|&end| end <= data.len())?;
+    Some((tag, header, length))
+}
+
+fn sequence_contents(data: &[u8]) -> Option<&[u8]> {
+    let (tag, header, length) = der_header(data)?;
+    (tag == DER_SEQUENCE).then(|| &data[header..header + length])
+}
+
+fn skip_element(data: &[u8]) -> Option<&[u8]> {
+    let (_, header, length) = der_header(data)?;
+    Some(&data[header + length..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed Ed25519 certificate for web.whatsapp.net
+    const CERTIFICATE: &str = concat!(
+        "3082014a3081fda003020102021419935fd5b817391ccb6310ce6c6287b9202ce12a300506032b6570301b3119301706",
+        "035504030c107765622e77686174736170702e6e6574301e170d3236313031353231303131315a170d33363130313232",
+        "31303131315a301b3119301706035504030c107765622e77686174736170702e6e6574302a300506032b657003210045",
+        "4815d71554661cf0f797bd01cd8c4fa7da9f2467995ff8a982a18656cb38cca3533051301d0603551d0e041604144642",
+        "7fc618b46b88c9840564d95e1283c2906adb301f0603551d2304183016801446427fc618b46b88c9840564d95e1283c2",
+        "906adb300f0603551d130101ff040530030101ff300506032b6570034100b14e8f035ebfe565d0cf21fab8b831bb25a3",
+        "b6db3f66e1bb5cec9a54dddc178b67fdd377d16a280fdb054efa0c5d4ca5cf59bd436565d9f236962dd7e4a51a01",
+    );
+
+    /// Its SubjectPublicKeyInfo, as `openssl pkey -pubin -outform der` gives it
+    const PUBLIC_KEY: &str = "302a300506032b6570032100454815d71554661cf0f797bd01cd8c4fa7da9f2467995ff8a982a18656cb38cc";
+
+    #[test]
+    fn finds_the_public_key_of_a_certificate() {
+        let der = hex::decode(CERTIFICATE).unwrap();
+        assert_eq!(hex::encode(subject_public_key_info(&der).unwrap()), PUBLIC_KEY);
+    }
+
+    #[test]
+    fn matches_certificate_and_public_key_pins() {
+        let der = hex::decode(CERTIFICATE).unwrap();
+        let public_key_hash = Crypto::sha256(&hex::decode(PUBLIC_KEY).unwrap());
+        let pin: TlsPin = format!("sha256/{}", Crypto::base64_encode(&public_key_hash)).parse().unwrap();
+
+        assert!(verify_pins(&der, &[pin]).is_ok());
+        assert!(verify_pins(&der, &[TlsPin::Certificate(Crypto::sha256(&der).try_into().unwrap())]).is_ok());
+        assert!(verify_pins(&der, &[TlsPin::PublicKey([0; 32])]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let der = hex::decode(CERTIFICATE).unwrap();
+        for len in 0..der.len() {
+            assert!(subject_public_key_info(&der[..len]).is_none(), "{} of {} bytes parsed", len, der.len());
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    Event,
+    error::WhatsAppResult,
+    noise::NoiseConfig,
+    websocket::WebSocketMessage,
+};
+
+/// Something received from a transport
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum TransportEvent {
+    /// Connection lifecycle event such as `Connected` or `Disconnected`
+    Event(Event),
+    /// Connection open and the protocol handshake started
+    Handshaking,
+    /// Text frame from the server
+    Text(String),
+    /// Binary frame from the server, already decrypted
+    Binary(Vec<u8>),
+}
+
+/// Snapshot of a transport's outgoing queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Messages waiting to be written
+    pub depth: usize,
+    /// Maximum number of messages that can wait
+    pub capacity: usize,
+    /// Messages rejected because the queue was full
+    pub rejected: u64,
+}
+
+/// Traffic counters of a transport since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// WebSocket frames written, including control frames
+    pub frames_sent: u64,
+    /// WebSocket frames read, including control frames
+    pub frames_received: u64,
+    /// Connections opened after the first one
+    pub reconnects: u64,
+    /// Round-trip time of the last answered keepalive ping
+    pub rtt: Option<Duration>,
+    /// How long the current connection has been open, `None` while disconnected
+    pub uptime: Option<Duration>,
+}
+
+/// Connection to the WhatsApp servers
+///
+/// `Client` only talks to the server through this trait, so tests can inject
+/// an in-memory transport and applications can wrap the connection, e.g. to
+/// record or replay traffic. `WebSocketHandler` is the default implementation.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Open the connection
+    async fn connect(&self) -> WhatsAppResult<()>;
+
+    /// Queue a message for sending, waiting for room when the queue is full
+    ///
+    /// Fails with `WhatsAppError::SendQueueFull` if no room frees up in time.
+    async fn send(&self, message: WebSocketMessage) -> WhatsAppResult<()>;
+
+    /// Queue a message for sending, failing with `WhatsAppError::SendQueueFull` instead of waiting
+    fn try_send(&self, message: WebSocketMessage) -> WhatsAppResult<()>;
+
+    /// Wait for the next frame or connection event, `None` once the transport is gone for good
+    ///
+    /// Events from successive connections arrive through the same stream; each
+    /// connection reports `Event::Connected` when it opens and `Event::Disconnected`
+    /// when it ends.
+    async fn recv(&self) -> Option<TransportEvent>;
+
+    /// Close the connection after sending everything already queued
+    async fn close(&self) -> WhatsAppResult<()>;
+
+    /// Drop the connection immediately, discarding anything still queued
+    fn abort(&self);
+
+    /// Check if the connection is open
+    fn is_connected(&self) -> bool;
+
+    /// Authenticate with the given keys and payload in the handshake of the next connection
+    ///
+    /// Transports without a Noise handshake, like in-memory ones, ignore it.
+    fn set_handshake(&self, _noise: NoiseConfig) {}
+
+    /// Get the current state of the outgoing queue
+    fn queue_metrics(&self) -> QueueMetrics {
+        QueueMetrics::default()
+    }
+
+    /// Get the traffic counters of the connection
+    fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats::default()
+    }
+}
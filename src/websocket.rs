@@ -1,15 +1,139 @@
+use std::io::Read;
 use std::sync::{Arc, Mutex};
-use websocket::client::ClientBuilder;
-use websocket::OwnedMessage;
-use std::thread;
-use tokio::sync::mpsc::{self, Sender, Receiver};
-use log::{debug, error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{Instant, interval_at, sleep_until};
+use url::Url;
 
 use crate::{
-    Event,
-    error::{WhatsAppError, WhatsAppResult}
+    Event, WHATSAPP_WEB_URL,
+    crypto::Crypto,
+    endpoint::{EndpointHealth, EndpointPool},
+    error::{WhatsAppError, WhatsAppResult},
+    noise::{NoiseCipher, NoiseConfig, NoiseHandshake, WA_CONN_HEADER},
+    proxy::ProxyConfig,
+    tls::{self, TlsPin},
+    transport::{ConnectionStats, QueueMetrics, Transport, TransportEvent},
+    zlib::{self, ZlibDecoder},
 };
 
+/// GUID appended to the handshake key to compute the accept key (RFC 6455)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of the opening handshake response
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+
+/// Maximum size of a single incoming message
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximum size of a length-prefixed protocol frame
+const MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Frame flag marking the rest of the frame as zlib-compressed
+const FLAG_COMPRESSED: u8 = 0x02;
+
+/// Keepalive settings for the WebSocket connection
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    /// How often to send a keepalive ping
+    pub interval: Duration,
+    /// How long to wait for a pong before the connection is considered stale
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(20),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Limits of the outgoing message queue
+#[derive(Debug, Clone, Copy)]
+pub struct SendQueueConfig {
+    /// Number of messages that can wait to be written to the socket
+    pub capacity: usize,
+    /// How long `send` waits for room in a full queue before giving up
+    pub timeout: Duration,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Compression of the protocol frames exchanged over the connection
+///
+/// When enabled, every frame starts with a flags byte and frames flagged as
+/// compressed are inflated transparently on receive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionConfig {
+    /// Deflate outgoing frames of at least this many bytes, `None` to never compress
+    pub deflate_threshold: Option<usize>,
+}
+
+/// Byte stream the WebSocket runs over
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Established connection, ready to exchange messages
+struct Connection {
+    stream: Box<dyn Stream>,
+    codec: FrameCodec,
+    cipher: Option<NoiseCipher>,
+}
+
+/// Traffic counters shared between the handler and its connection task
+#[derive(Default)]
+struct LinkStats {
+    stats: ConnectionStats,
+    connections: u64,
+    connected_at: Option<Instant>,
+}
+
+impl LinkStats {
+    fn connected(&mut self) {
+        if self.connections > 0 {
+            self.stats.reconnects += 1;
+        }
+        self.connections += 1;
+        self.connected_at = Some(Instant::now());
+    }
+
+    fn sent(&mut self, bytes: usize) {
+        self.stats.bytes_sent += bytes as u64;
+        self.stats.frames_sent += 1;
+    }
+
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            uptime: self.connected_at.map(|connected_at| connected_at.elapsed()),
+            ..self.stats
+        }
+    }
+}
+
 /// WebSocket message types
 pub enum WebSocketMessage {
     Text(String),
@@ -19,234 +143,1006 @@ pub enum WebSocketMessage {
     Close,
 }
 
-/// Converts between websocket crate's messages and our enum
-impl From<OwnedMessage> for WebSocketMessage {
-    fn from(msg: OwnedMessage) -> Self {
-        match msg {
-            OwnedMessage::Text(text) => WebSocketMessage::Text(text),
-            OwnedMessage::Binary(data) => WebSocketMessage::Binary(data),
-            OwnedMessage::Ping(_) => WebSocketMessage::Ping,
-            OwnedMessage::Pong(_) => WebSocketMessage::Pong,
-            OwnedMessage::Close(_) => WebSocketMessage::Close,
+impl WebSocketMessage {
+    /// Encode the message as a single client frame
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            WebSocketMessage::Text(text) => Frame::encode(OPCODE_TEXT, text.as_bytes()),
+            WebSocketMessage::Binary(data) => Frame::encode(OPCODE_BINARY, data),
+            WebSocketMessage::Ping => Frame::encode(OPCODE_PING, &[]),
+            WebSocketMessage::Pong => Frame::encode(OPCODE_PONG, &[]),
+            WebSocketMessage::Close => Frame::encode(OPCODE_CLOSE, &[]),
         }
     }
 }
 
-impl Into<OwnedMessage> for WebSocketMessage {
-    fn into(self) -> OwnedMessage {
-        match self {
-            WebSocketMessage::Text(text) => OwnedMessage::Text(text),
-            WebSocketMessage::Binary(data) => OwnedMessage::Binary(data),
-            WebSocketMessage::Ping => OwnedMessage::Ping(vec![]),
-            WebSocketMessage::Pong => OwnedMessage::Pong(vec![]),
-            WebSocketMessage::Close => OwnedMessage::Close(None),
+/// A single WebSocket frame
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Encode a masked client-to-server frame
+    fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+
+        let mask = Crypto::random_bytes(4);
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+
+        frame
+    }
+
+    /// Decode a frame from the start of the buffer, returning it and its encoded length
+    fn decode(buf: &[u8]) -> WhatsAppResult<Option<(Frame, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+
+        let (len, mut offset) = match buf[1] & 0x7f {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4)
+            },
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len) as usize, 10)
+            },
+            len => (len as usize, 2),
+        };
+
+        if len > MAX_MESSAGE_SIZE {
+            return Err(WhatsAppError::ProtocolError(format!("Frame of {} bytes is too large", len)));
+        }
+
+        let mask = if masked {
+            if buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            offset += 4;
+            Some([buf[offset - 4], buf[offset - 3], buf[offset - 2], buf[offset - 1]])
+        } else {
+            None
+        };
+
+        if buf.len() < offset + len {
+            return Ok(None);
+        }
+
+        let mut payload = buf[offset..offset + len].to_vec();
+        if let Some(mask) = mask {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        Ok(Some((Frame { fin, opcode, payload }, offset + len)))
+    }
+
+    /// Read exactly one frame from the stream
+    async fn read(stream: &mut Box<dyn Stream>) -> WhatsAppResult<Frame> {
+        let mut buf = vec![0u8; 2];
+        stream.read_exact(&mut buf).await
+            .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+
+        let extended = match buf[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if buf[1] & 0x80 != 0 { 4 } else { 0 };
+        buf.resize(2 + extended + mask, 0);
+        stream.read_exact(&mut buf[2..]).await
+            .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+
+        let len = match extended {
+            2 => u16::from_be_bytes([buf[2], buf[3]]) as usize,
+            8 => u64::from_be_bytes(buf[2..10].try_into().unwrap_or_default()) as usize,
+            _ => (buf[1] & 0x7f) as usize,
+        };
+        if len > MAX_MESSAGE_SIZE {
+            return Err(WhatsAppError::ProtocolError(format!("Frame of {} bytes is too large", len)));
         }
+
+        let header = buf.len();
+        buf.resize(header + len, 0);
+        stream.read_exact(&mut buf[header..]).await
+            .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+
+        Frame::decode(&buf)?
+            .map(|(frame, _)| frame)
+            .ok_or_else(|| WhatsAppError::ProtocolError("Incomplete frame".to_string()))
+    }
+}
+
+/// Splits and combines the length-prefixed protocol frames carried in binary messages
+///
+/// Every frame is prefixed with its 3-byte big-endian length. A single binary
+/// message may hold several frames, and a frame may span several messages.
+#[derive(Default)]
+pub struct FrameCodec {
+    header: Option<Vec<u8>>,
+    buf: Vec<u8>,
+    compression: Option<CompressionConfig>,
+}
+
+impl FrameCodec {
+    /// Create a codec for a connection without a header
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a codec sending the given connection header in front of the first frame
+    pub fn with_header(header: &[u8]) -> Self {
+        Self {
+            header: Some(header.to_vec()),
+            ..Self::default()
+        }
+    }
+
+    /// Add a flags byte to every frame and compress according to the config
+    pub fn with_compression(mut self, compression: Option<CompressionConfig>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Add the flags byte to an outgoing frame, deflating it if it is large enough
+    pub fn pack(&self, frame: &[u8]) -> Vec<u8> {
+        let Some(compression) = self.compression else {
+            return frame.to_vec();
+        };
+
+        if compression.deflate_threshold.is_some_and(|threshold| frame.len() >= threshold) {
+            let compressed = zlib::compress(frame);
+            if compressed.len() < frame.len() {
+                let mut packed = vec![FLAG_COMPRESSED];
+                packed.extend_from_slice(&compressed);
+                return packed;
+            }
+        }
+
+        let mut packed = vec![0];
+        packed.extend_from_slice(frame);
+        packed
+    }
+
+    /// Strip the flags byte from an incoming frame, inflating it if it is compressed
+    pub fn unpack(&self, frame: Vec<u8>) -> WhatsAppResult<Vec<u8>> {
+        if self.compression.is_none() {
+            return Ok(frame);
+        }
+
+        let Some((&flags, data)) = frame.split_first() else {
+            return Err(WhatsAppError::ProtocolError("Empty frame".to_string()));
+        };
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(data.to_vec());
+        }
+
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(data)
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|e| WhatsAppError::ProtocolError(format!("Failed to inflate frame: {}", e)))?;
+        if inflated.len() > MAX_MESSAGE_SIZE {
+            return Err(WhatsAppError::ProtocolError("Inflated frame too large".to_string()));
+        }
+
+        Ok(inflated)
+    }
+
+    /// Encode a frame for sending
+    pub fn encode(&mut self, frame: &[u8]) -> WhatsAppResult<Vec<u8>> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(WhatsAppError::ProtocolError(format!("Frame of {} bytes is too large", frame.len())));
+        }
+
+        let mut data = self.header.take().unwrap_or_default();
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes()[1..]);
+        data.extend_from_slice(frame);
+        Ok(data)
+    }
+
+    /// Add data received in a binary message
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame, if one has been received
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.buf.len() < 3 {
+            return None;
+        }
+
+        let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]) as usize;
+        if self.buf.len() < 3 + len {
+            return None;
+        }
+
+        let frame = self.buf[3..3 + len].to_vec();
+        self.buf.drain(..3 + len);
+        Some(frame)
     }
 }
 
 /// WebSocket connection handler
 pub struct WebSocketHandler {
-    url: String,
-    tx: Arc<Mutex<Option<Sender<WebSocketMessage>>>>,
-    event_callback: Arc<Mutex<Box<dyn Fn(Event) + Send + Sync>>>,
+    endpoints: Mutex<EndpointPool>,
+    origin: String,
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+    tx: Mutex<Option<Sender<WebSocketMessage>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    abort_handle: Mutex<Option<AbortHandle>>,
+    incoming: UnboundedSender<TransportEvent>,
+    receiver: tokio::sync::Mutex<UnboundedReceiver<TransportEvent>>,
     connected: Arc<Mutex<bool>>,
+    keepalive: Option<KeepaliveConfig>,
+    stale_timeout: Option<Duration>,
+    send_queue: SendQueueConfig,
+    rejected: AtomicU64,
+    proxy: Option<ProxyConfig>,
+    tls_pins: Vec<TlsPin>,
+    noise: Mutex<Option<NoiseConfig>>,
+    compression: Option<CompressionConfig>,
+    stats: Arc<Mutex<LinkStats>>,
 }
 
 impl WebSocketHandler {
     /// Create a new WebSocket handler
-    pub fn new<F>(url: &str, event_callback: F) -> Self
-    where
-        F: Fn(Event) + Send + Sync + 'static,
-    {
+    pub fn new(url: &str) -> Self {
+        let (incoming, receiver) = mpsc::unbounded_channel();
+
         Self {
-            url: url.to_string(),
-            tx: Arc::new(Mutex::new(None)),
-            event_callback: Arc::new(Mutex::new(Box::new(event_callback))),
+            endpoints: Mutex::new(EndpointPool::new(&[url.to_string()])),
+            origin: WHATSAPP_WEB_URL.to_string(),
+            user_agent: None,
+            headers: Vec::new(),
+            tx: Mutex::new(None),
+            task: Mutex::new(None),
+            abort_handle: Mutex::new(None),
+            incoming,
+            receiver: tokio::sync::Mutex::new(receiver),
             connected: Arc::new(Mutex::new(false)),
+            keepalive: None,
+            stale_timeout: None,
+            send_queue: SendQueueConfig::default(),
+            rejected: AtomicU64::new(0),
+            proxy: None,
+            tls_pins: Vec::new(),
+            noise: Mutex::new(None),
+            compression: None,
+            stats: Arc::new(Mutex::new(LinkStats::default())),
         }
     }
 
-    /// Connect to the WhatsApp WebSocket server
-    pub fn connect(&self) -> WhatsAppResult<()> {
-        let url = self.url.clone();
-        let tx_clone = self.tx.clone();
-        let event_callback = self.event_callback.clone();
-        let connected = self.connected.clone();
+    /// Fail over to these endpoints, in order, when the primary one can't be reached
+    pub fn with_fallback_endpoints(self, fallbacks: Vec<String>) -> Self {
+        let mut urls: Vec<String> = self.endpoints.lock().unwrap().health()
+            .into_iter()
+            .map(|endpoint| endpoint.url)
+            .collect();
+        urls.extend(fallbacks);
 
-        // Create a channel for sending messages to the WebSocket
-        let (sender, receiver) = mpsc::channel::<WebSocketMessage>(100);
+        *self.endpoints.lock().unwrap() = EndpointPool::new(&urls);
+        self
+    }
+
+    /// Get the health of each endpoint
+    pub fn endpoint_health(&self) -> Vec<EndpointHealth> {
+        self.endpoints.lock().unwrap().health()
+    }
+
+    /// Set the `Origin` header sent with the opening handshake
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.origin = origin.to_string();
+        self
+    }
+
+    /// Set the `User-Agent` header sent with the opening handshake
+    pub fn with_user_agent(mut self, user_agent: Option<String>) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Send extra headers with the opening handshake
+    pub fn with_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Send keepalive pings and report the connection as stale when they go unanswered
+    pub fn with_keepalive(mut self, keepalive: Option<KeepaliveConfig>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Report the connection as stale and drop it when nothing was received for this long
+    ///
+    /// Catches half-open connections that would otherwise never be noticed.
+    pub fn with_stale_timeout(mut self, stale_timeout: Option<Duration>) -> Self {
+        self.stale_timeout = stale_timeout;
+        self
+    }
+
+    /// Set the capacity and timeout of the outgoing message queue
+    pub fn with_send_queue(mut self, send_queue: SendQueueConfig) -> Self {
+        self.send_queue = send_queue;
+        self
+    }
+
+    /// Tunnel the connection through a proxy
+    pub fn with_proxy(mut self, proxy: Option<ProxyConfig>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Only accept server certificates matching one of the pins
+    pub fn with_tls_pins(mut self, pins: Vec<TlsPin>) -> Self {
+        self.tls_pins = pins;
+        self
+    }
+
+    /// Frame binary messages with a flags byte, inflating compressed frames and optionally deflating large ones
+    pub fn with_compression(mut self, compression: Option<CompressionConfig>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Perform the Noise handshake on the next connect and encrypt binary frames with it
+    pub fn set_noise(&self, noise: Option<NoiseConfig>) {
+        *self.noise.lock().unwrap() = noise;
+    }
+
+    /// Open the connection to an endpoint, including the Noise handshake if enabled
+    async fn establish(&self, url: &str) -> WhatsAppResult<Connection> {
+        let mut stream = self.open(url).await?;
+
+        let noise = self.noise.lock().unwrap().clone();
+        match noise {
+            Some(config) => {
+                let _ = self.incoming.send(TransportEvent::Handshaking);
+                let mut codec = FrameCodec::with_header(&WA_CONN_HEADER);
+                let cipher = Self::noise_handshake(&mut stream, &mut codec, &config).await?;
+                let codec = codec.with_compression(self.compression);
+                Ok(Connection { stream, codec, cipher: Some(cipher) })
+            },
+            None => {
+                let codec = FrameCodec::new().with_compression(self.compression);
+                Ok(Connection { stream, codec, cipher: None })
+            },
+        }
+    }
+
+    /// Open the TCP/TLS stream and perform the opening handshake
+    async fn open(&self, url: &str) -> WhatsAppResult<Box<dyn Stream>> {
+        let url = Url::parse(url).map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+        let host = url.host_str()
+            .ok_or_else(|| WhatsAppError::ConnectionError(format!("No host in {}", url)))?;
+        let port = url.port_or_known_default()
+            .ok_or_else(|| WhatsAppError::ConnectionError(format!("No port for {}", url)))?;
+
+        let tcp = match &self.proxy {
+            Some(proxy) => proxy.connect(host, port).await?,
+            None => TcpStream::connect((host, port)).await
+                .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?,
+        };
 
-        // Store the sender
-        *tx_clone.lock().unwrap() = Some(sender);
+        let mut stream: Box<dyn Stream> = match url.scheme() {
+            "wss" => Box::new(tls::connect(host, tcp, &self.tls_pins).await?),
+            "ws" => Box::new(tcp),
+            scheme => {
+                return Err(WhatsAppError::ConnectionError(format!("Unsupported scheme: {}", scheme)));
+            },
+        };
 
-        // Start the WebSocket handler in a separate thread
-        thread::spawn(move || {
-            if let Err(err) = Self::run_websocket(url, receiver, event_callback.clone(), connected.clone()) {
-                error!("WebSocket error: {:?}", err);
+        self.handshake(&mut stream, &url).await?;
+
+        Ok(stream)
+    }
+
+    /// Perform the HTTP upgrade handshake
+    async fn handshake(&self, stream: &mut Box<dyn Stream>, url: &Url) -> WhatsAppResult<()> {
+        let key = Crypto::base64_encode(&Crypto::random_bytes(16));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Origin: {}\r\n",
+            path, host, key, self.origin
+        );
+
+        let user_agent = self.user_agent.iter().map(|agent| ("User-Agent", agent.as_str()));
+        let headers = self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        for (name, value) in user_agent.chain(headers) {
+            if [name, value].iter().any(|part| part.contains(['\r', '\n'])) {
+                return Err(WhatsAppError::ConnectionError(format!("Invalid handshake header {}", name)));
+            }
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
 
-                // Notify that we're disconnected
-                let callback = event_callback.lock().unwrap();
-                callback(Event::Disconnected);
+        stream.write_all(request.as_bytes()).await
+            .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
 
-                // Update connection status
-                *connected.lock().unwrap() = false;
+        // Read the response one byte at a time so no frame data is consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > MAX_HANDSHAKE_SIZE {
+                return Err(WhatsAppError::ConnectionError("Handshake response too large".to_string()));
             }
-        });
+            let byte = stream.read_u8().await
+                .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+            response.push(byte);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let mut lines = response.lines();
+
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(WhatsAppError::ConnectionError(format!("Handshake rejected: {}", status)));
+        }
+
+        let expected = Crypto::base64_encode(&Crypto::sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()));
+        let accepted = lines
+            .filter_map(|line| line.split_once(':'))
+            .any(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected
+            });
+
+        if !accepted {
+            return Err(WhatsAppError::ConnectionError("Invalid Sec-WebSocket-Accept".to_string()));
+        }
 
         Ok(())
     }
 
-    /// Run the WebSocket connection
-    fn run_websocket(
-        url: String,
-        mut receiver: Receiver<WebSocketMessage>,
-        event_callback: Arc<Mutex<Box<dyn Fn(Event) + Send + Sync>>>,
-        connected: Arc<Mutex<bool>>,
-    ) -> WhatsAppResult<()> {
-        // Build the WebSocket client
-        let client = ClientBuilder::new(&url)
-            .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?
-            .connect_insecure()
+    /// Run the Noise XX handshake over binary frames
+    async fn noise_handshake(
+        stream: &mut Box<dyn Stream>,
+        codec: &mut FrameCodec,
+        config: &NoiseConfig,
+    ) -> WhatsAppResult<NoiseCipher> {
+        let mut handshake = NoiseHandshake::new()?;
+
+        let hello = codec.encode(&handshake.client_hello())?;
+        stream.write_all(&Frame::encode(OPCODE_BINARY, &hello)).await
             .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
 
-        let (mut receiver_ws, mut sender_ws) = client.split()
+        let server_hello = loop {
+            if let Some(frame) = codec.next_frame() {
+                break frame;
+            }
+            codec.push(&Self::read_binary(stream).await?);
+        };
+
+        let finish = codec.encode(&handshake.process_server_hello(&server_hello, config)?)?;
+        stream.write_all(&Frame::encode(OPCODE_BINARY, &finish)).await
             .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
 
-        // Set connected status
-        *connected.lock().unwrap() = true;
+        debug!("Noise handshake complete");
+        handshake.finish()
+    }
 
-        // Notify that we're connected
-        let callback = event_callback.lock().unwrap();
-        callback(Event::Connected);
-        drop(callback);
-
-        // Create a channel for communicating with the WebSocket writer
-        let (tx_ws, mut rx_ws) = mpsc::channel::<OwnedMessage>(100);
-
-        // Handle incoming messages in a separate thread
-        let event_callback_clone = event_callback.clone();
-        let connected_clone = connected.clone();
-        let tx_ws_clone = tx_ws.clone();
-
-        thread::spawn(move || {
-            loop {
-                match receiver_ws.recv_message() {
-                    Ok(message) => {
-                        let ws_message: WebSocketMessage = message.into();
-                        match ws_message {
-                            WebSocketMessage::Text(text) => {
-                                debug!("Received text message: {}", text);
-                                // Parse and handle the message
-                                // In a real implementation, we would parse JSON/protobuf messages
-                                // and dispatch appropriate events
-                            },
-                            WebSocketMessage::Binary(data) => {
-                                debug!("Received binary message: {} bytes", data.len());
-                                // Parse and handle binary message
-                                // In a real implementation, we would parse protobuf messages
+    /// Read the next binary message directly from the stream, answering pings
+    async fn read_binary(stream: &mut Box<dyn Stream>) -> WhatsAppResult<Vec<u8>> {
+        loop {
+            let frame = Frame::read(stream).await?;
+            match frame.opcode {
+                OPCODE_PING => {
+                    stream.write_all(&Frame::encode(OPCODE_PONG, &frame.payload)).await
+                        .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+                },
+                OPCODE_PONG => {},
+                OPCODE_BINARY if frame.fin => return Ok(frame.payload),
+                OPCODE_CLOSE => {
+                    return Err(WhatsAppError::ConnectionError("Connection closed during handshake".to_string()));
+                },
+                opcode => {
+                    return Err(WhatsAppError::ProtocolError(format!("Unexpected opcode {} during handshake", opcode)));
+                },
+            }
+        }
+    }
+
+    /// Run the WebSocket connection until it is closed
+    async fn run_websocket(
+        connection: Connection,
+        receiver: Receiver<WebSocketMessage>,
+        incoming: UnboundedSender<TransportEvent>,
+        connected: Arc<Mutex<bool>>,
+        stats: Arc<Mutex<LinkStats>>,
+        keepalive: Option<KeepaliveConfig>,
+        stale_timeout: Option<Duration>,
+    ) {
+        if let Err(err) = Self::process(connection, receiver, &incoming, &stats, keepalive, stale_timeout).await {
+            error!("WebSocket error: {:?}", err);
+        }
+
+        // Update connection status
+        *connected.lock().unwrap() = false;
+        stats.lock().unwrap().connected_at = None;
+
+        // Notify that we're disconnected
+        let _ = incoming.send(TransportEvent::Event(Event::Disconnected));
+    }
+
+    /// Move frames in both directions until either side closes the connection
+    async fn process(
+        connection: Connection,
+        mut receiver: Receiver<WebSocketMessage>,
+        incoming: &UnboundedSender<TransportEvent>,
+        stats: &Mutex<LinkStats>,
+        keepalive: Option<KeepaliveConfig>,
+        stale_timeout: Option<Duration>,
+    ) -> WhatsAppResult<()> {
+        let Connection { stream, mut codec, mut cipher } = connection;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut buf = Vec::with_capacity(8192);
+        let mut fragments: Option<(u8, Vec<u8>)> = None;
+
+        // The timer only fires when keepalive is enabled
+        let ping_interval = keepalive.map_or(Duration::from_secs(3600), |keepalive| keepalive.interval);
+        let mut ping_timer = interval_at(Instant::now() + ping_interval, ping_interval);
+        // When the oldest unanswered ping went out, its pong being due a timeout later
+        let mut ping_sent: Option<Instant> = None;
+        let mut last_received = Instant::now();
+
+        loop {
+            tokio::select! {
+                read = reader.read_buf(&mut buf) => {
+                    let read = read.map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+                    if read == 0 {
+                        return Err(WhatsAppError::ConnectionError("Connection reset by server".to_string()));
+                    }
+                    stats.lock().unwrap().stats.bytes_received += read as u64;
+                    last_received = Instant::now();
+
+                    while let Some((frame, len)) = Frame::decode(&buf)? {
+                        buf.drain(..len);
+                        stats.lock().unwrap().stats.frames_received += 1;
+
+                        match frame.opcode {
+                            OPCODE_PING => {
+                                let pong = Frame::encode(OPCODE_PONG, &frame.payload);
+                                writer.write_all(&pong).await
+                                    .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+                                stats.lock().unwrap().sent(pong.len());
                             },
-                            WebSocketMessage::Ping => {
-                                // Respond with pong via channel
-                                let runtime = tokio::runtime::Runtime::new().unwrap();
-                                if let Err(e) = runtime.block_on(async {
-                                    tx_ws_clone.send(OwnedMessage::Pong(vec![])).await
-                                }) {
-                                    error!("Failed to queue pong: {:?}", e);
-                                    break;
+                            OPCODE_PONG => {
+                                if let Some(sent) = ping_sent.take() {
+                                    stats.lock().unwrap().stats.rtt = Some(sent.elapsed());
                                 }
                             },
-                            WebSocketMessage::Close => {
+                            OPCODE_CLOSE => {
                                 info!("WebSocket connection closed by server");
-                                break;
+                                let _ = writer.write_all(&Frame::encode(OPCODE_CLOSE, &[])).await;
+                                return Ok(());
+                            },
+                            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                                let (opcode, payload) = match fragments.take() {
+                                    Some((opcode, mut payload)) if frame.opcode == OPCODE_CONTINUATION => {
+                                        payload.extend_from_slice(&frame.payload);
+                                        (opcode, payload)
+                                    },
+                                    None if frame.opcode != OPCODE_CONTINUATION => (frame.opcode, frame.payload),
+                                    _ => return Err(WhatsAppError::ProtocolError("Unexpected continuation frame".to_string())),
+                                };
+
+                                if payload.len() > MAX_MESSAGE_SIZE {
+                                    return Err(WhatsAppError::ProtocolError("Message too large".to_string()));
+                                }
+
+                                if !frame.fin {
+                                    fragments = Some((opcode, payload));
+                                    continue;
+                                }
+
+                                if opcode == OPCODE_TEXT {
+                                    let text = String::from_utf8(payload)
+                                        .map_err(|e| WhatsAppError::ProtocolError(e.to_string()))?;
+                                    Self::handle_text(text, incoming);
+                                } else {
+                                    debug!("Received binary message: {} bytes", payload.len());
+
+                                    codec.push(&payload);
+                                    while let Some(frame) = codec.next_frame() {
+                                        let frame = match &mut cipher {
+                                            Some(cipher) => cipher.decrypt(&frame)?,
+                                            None => frame,
+                                        };
+                                        let frame = codec.unpack(frame)?;
+                                        let _ = incoming.send(TransportEvent::Binary(frame));
+                                    }
+                                }
+                            },
+                            opcode => {
+                                return Err(WhatsAppError::ProtocolError(format!("Unknown opcode {}", opcode)));
                             },
-                            _ => {}
                         }
-                    },
-                    Err(e) => {
-                        error!("Error receiving message: {:?}", e);
-                        break;
                     }
-                }
-            }
+                },
+                _ = ping_timer.tick(), if keepalive.is_some() => {
+                    let ping = Frame::encode(OPCODE_PING, &[]);
+                    writer.write_all(&ping).await
+                        .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+                    stats.lock().unwrap().sent(ping.len());
+                    ping_sent.get_or_insert_with(Instant::now);
+                },
+                _ = sleep_until(ping_sent.unwrap_or_else(Instant::now) + keepalive.unwrap_or_default().timeout), if ping_sent.is_some() => {
+                    warn!("No keepalive response for {:?}", ping_sent.map(|sent| sent.elapsed()).unwrap_or_default());
 
-            // Update connection status when the loop breaks
-            *connected_clone.lock().unwrap() = false;
+                    let _ = incoming.send(TransportEvent::Event(Event::ConnectionStale));
 
-            // Notify that we're disconnected
-            let callback = event_callback_clone.lock().unwrap();
-            callback(Event::Disconnected);
-        });
+                    return Err(WhatsAppError::ConnectionError("Keepalive timed out".to_string()));
+                },
+                _ = sleep_until(last_received + stale_timeout.unwrap_or_default()), if stale_timeout.is_some() => {
+                    warn!("Nothing received for {:?}", last_received.elapsed());
 
-        // Thread for handling WebSocket writer
-        thread::spawn(move || {
-            let runtime = tokio::runtime::Runtime::new().unwrap();
+                    let _ = incoming.send(TransportEvent::Event(Event::ConnectionStale));
 
-            runtime.block_on(async {
-                while let Some(message) = rx_ws.recv().await {
-                    if let Err(e) = sender_ws.send_message(&message) {
-                        error!("Failed to send message: {:?}", e);
-                        break;
+                    return Err(WhatsAppError::ConnectionError("Connection silent for too long".to_string()));
+                },
+                message = receiver.recv() => {
+                    let message = message.unwrap_or(WebSocketMessage::Close);
+                    let frame = match &message {
+                        WebSocketMessage::Binary(data) => {
+                            let data = codec.pack(data);
+                            let data = match &mut cipher {
+                                Some(cipher) => cipher.encrypt(&data)?,
+                                None => data,
+                            };
+                            Frame::encode(OPCODE_BINARY, &codec.encode(&data)?)
+                        },
+                        _ => message.encode(),
+                    };
+                    writer.write_all(&frame).await
+                        .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+                    stats.lock().unwrap().sent(frame.len());
+
+                    if let WebSocketMessage::Close = message {
+                        let _ = writer.shutdown().await;
+                        return Ok(());
                     }
-                }
-            });
-        });
+                },
+            }
+        }
+    }
 
-        // Process outgoing messages from our channel
-        let runtime = tokio::runtime::Runtime::new()
-            .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+    /// Handle a complete text message from the server
+    fn handle_text(text: String, incoming: &UnboundedSender<TransportEvent>) {
+        debug!("Received text message: {}", text);
+
+        let event = match Self::parse_command(&text) {
+            Some(event) => TransportEvent::Event(event),
+            None => TransportEvent::Text(text),
+        };
+        let _ = incoming.send(event);
+    }
 
-        runtime.block_on(async {
-            while let Some(message) = receiver.recv().await {
-                // Convert our message to websocket message
-                let ws_message: OwnedMessage = message.into();
+    /// Parse server commands sent as JSON text frames, e.g.
+    /// `["Cmd",{"type":"disconnect","kind":"replaced"}]`
+    fn parse_command(text: &str) -> Option<Event> {
+        let value: serde_json::Value = serde_json::from_str(text).ok()?;
+        let array = value.as_array()?;
 
-                // Send the message using the channel
-                if let Err(e) = tx_ws.send(ws_message).await {
-                    error!("Failed to queue message: {:?}", e);
-                    break;
+        if array.first()?.as_str()? != "Cmd" {
+            return None;
+        }
+
+        let command = array.get(1)?;
+        match command.get("type")?.as_str()? {
+            "disconnect" => {
+                let kind = command.get("kind").and_then(|kind| kind.as_str()).unwrap_or("unknown");
+                match kind {
+                    "replaced" | "conflict" => Some(Event::SessionConflict(kind.to_string())),
+                    _ => None,
                 }
+            },
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for WebSocketHandler {
+    /// Connect to the WhatsApp WebSocket server
+    ///
+    /// Endpoints are tried in turn until one accepts the connection.
+    async fn connect(&self) -> WhatsAppResult<()> {
+        let candidates = self.endpoints.lock().unwrap().candidates();
+
+        let mut last_error = None;
+        let mut established = None;
+        for url in candidates {
+            match self.establish(&url).await {
+                Ok(connection) => {
+                    info!("Connected to {}", url);
+                    self.endpoints.lock().unwrap().record_success(&url);
+                    established = Some(connection);
+                    break;
+                },
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", url, e);
+                    self.endpoints.lock().unwrap().record_failure(&url, &e.to_string());
+                    last_error = Some(e);
+                },
             }
-        });
+        }
+
+        let Some(connection) = established else {
+            return Err(last_error.unwrap_or_else(|| WhatsAppError::ConnectionError("No endpoints configured".to_string())));
+        };
+
+        // Create a channel for sending messages to the WebSocket
+        let (sender, receiver) = mpsc::channel::<WebSocketMessage>(self.send_queue.capacity);
+        *self.tx.lock().unwrap() = Some(sender);
+
+        // Set connected status
+        *self.connected.lock().unwrap() = true;
+        self.stats.lock().unwrap().connected();
+
+        // Notify that we're connected
+        let _ = self.incoming.send(TransportEvent::Event(Event::Connected));
+
+        // Run the connection in a single task handling both directions
+        let task = tokio::spawn(Self::run_websocket(
+            connection,
+            receiver,
+            self.incoming.clone(),
+            self.connected.clone(),
+            self.stats.clone(),
+            self.keepalive,
+            self.stale_timeout,
+        ));
+        *self.abort_handle.lock().unwrap() = Some(task.abort_handle());
+        *self.task.lock().unwrap() = Some(task);
 
         Ok(())
     }
 
-    /// Send a message through the WebSocket
-    pub fn send(&self, message: WebSocketMessage) -> WhatsAppResult<()> {
-        let tx = self.tx.lock().unwrap();
+    /// Queue a message, waiting up to the send timeout for room in the queue
+    async fn send(&self, message: WebSocketMessage) -> WhatsAppResult<()> {
+        let sender = self.tx.lock().unwrap().clone()
+            .ok_or_else(|| WhatsAppError::ConnectionError("Not connected".to_string()))?;
 
-        if let Some(sender) = &*tx {
-            let runtime = tokio::runtime::Runtime::new()
-                .map_err(|e| WhatsAppError::ConnectionError(e.to_string()))?;
+        match sender.send_timeout(message, self.send_queue.timeout).await {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(_)) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(WhatsAppError::SendQueueFull(format!("No room in the queue after {:?}", self.send_queue.timeout)))
+            },
+            Err(SendTimeoutError::Closed(_)) => {
+                Err(WhatsAppError::ConnectionError("Failed to send message: connection closed".to_string()))
+            },
+        }
+    }
 
-            runtime.block_on(async {
-                sender.send(message).await.map_err(|e| {
-                    WhatsAppError::ConnectionError(format!("Failed to send message: {}", e))
-                })
-            })
-        } else {
-            Err(WhatsAppError::ConnectionError("Not connected".to_string()))
+    /// Queue a message if there is room, without waiting
+    fn try_send(&self, message: WebSocketMessage) -> WhatsAppResult<()> {
+        let sender = self.tx.lock().unwrap().clone()
+            .ok_or_else(|| WhatsAppError::ConnectionError("Not connected".to_string()))?;
+
+        match sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(WhatsAppError::SendQueueFull(format!("{} messages already queued", self.send_queue.capacity)))
+            },
+            Err(TrySendError::Closed(_)) => {
+                Err(WhatsAppError::ConnectionError("Failed to send message: connection closed".to_string()))
+            },
+        }
+    }
+
+    /// Get the depth and capacity of the outgoing queue
+    fn queue_metrics(&self) -> QueueMetrics {
+        let depth = self.tx.lock().unwrap().as_ref()
+            .map_or(0, |sender| sender.max_capacity() - sender.capacity());
+
+        QueueMetrics {
+            depth,
+            capacity: self.send_queue.capacity,
+            rejected: self.rejected.load(Ordering::Relaxed),
         }
     }
 
+    /// Get the traffic counters, including the uptime of the current connection
+    fn connection_stats(&self) -> ConnectionStats {
+        self.stats.lock().unwrap().snapshot()
+    }
+
+    /// Wait for the next frame or connection event
+    async fn recv(&self) -> Option<TransportEvent> {
+        self.receiver.lock().await.recv().await
+    }
+
     /// Check if the WebSocket is connected
-    pub fn is_connected(&self) -> bool {
+    fn is_connected(&self) -> bool {
         *self.connected.lock().unwrap()
     }
 
-    /// Disconnect from the WebSocket server
-    pub fn disconnect(&self) -> WhatsAppResult<()> {
-        // Send close message
-        self.send(WebSocketMessage::Close)?;
+    /// Perform the Noise handshake with the client's keys on the next connect
+    fn set_handshake(&self, noise: NoiseConfig) {
+        self.set_noise(Some(noise));
+    }
+
+    /// Close the connection and wait for the connection task to finish
+    async fn close(&self) -> WhatsAppResult<()> {
+        let sender = self.tx.lock().unwrap().take();
+        let task = self.task.lock().unwrap().take();
 
-        // Clear the sender
-        let mut tx = self.tx.lock().unwrap();
-        *tx = None;
+        let Some(sender) = sender else {
+            return Err(WhatsAppError::ConnectionError("Not connected".to_string()));
+        };
+
+        // Send close message; this fails if the task already stopped
+        let _ = sender.send(WebSocketMessage::Close).await;
+
+        if let Some(task) = task {
+            let _ = task.await;
+        }
 
         // Update connection status
-        let mut connected = self.connected.lock().unwrap();
-        *connected = false;
+        *self.connected.lock().unwrap() = false;
 
         Ok(())
     }
+
+    /// Drop the connection immediately without flushing the queue
+    fn abort(&self) {
+        self.tx.lock().unwrap().take();
+        self.task.lock().unwrap().take();
+
+        if let Some(handle) = self.abort_handle.lock().unwrap().take()
+            && !handle.is_finished()
+        {
+            handle.abort();
+            *self.connected.lock().unwrap() = false;
+            self.stats.lock().unwrap().connected_at = None;
+            let _ = self.incoming.send(TransportEvent::Event(Event::Disconnected));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unmasked server frame with the given first byte
+    fn server_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn encodes_lengths_at_their_boundaries() {
+        for (len, marker, header) in [(0, 0, 2), (125, 125, 2), (126, 126, 4), (65535, 126, 4), (65536, 127, 10)] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = Frame::encode(OPCODE_BINARY, &payload);
+
+            assert_eq!(frame[0], 0x80 | OPCODE_BINARY);
+            assert_eq!(frame[1], 0x80 | marker, "length marker of {}", len);
+            assert_eq!(frame.len(), header + 4 + len);
+
+            let mask = &frame[header..header + 4];
+            let unmasked: Vec<u8> = frame[header + 4..].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+            assert_eq!(unmasked, payload);
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let encoded = Frame::encode(OPCODE_TEXT, &payload);
+
+            let (frame, used) = Frame::decode(&encoded).unwrap().unwrap();
+            assert!(frame.fin);
+            assert_eq!(frame.opcode, OPCODE_TEXT);
+            assert_eq!(frame.payload, payload);
+            assert_eq!(used, encoded.len());
+        }
+    }
+
+    #[test]
+    fn decodes_unmasked_server_frames() {
+        for len in [125, 126, 65536] {
+            let payload = vec![0xab; len];
+            let encoded = server_frame(0x80 | OPCODE_BINARY, &payload);
+
+            let (frame, used) = Frame::decode(&encoded).unwrap().unwrap();
+            assert_eq!(frame.payload, payload);
+            assert_eq!(used, encoded.len());
+        }
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_partial_frame() {
+        for len in [125, 126, 65536] {
+            let encoded = server_frame(0x80 | OPCODE_BINARY, &vec![1; len]);
+            for cut in [0, 1, 2, 3, 9, encoded.len() - 1] {
+                assert!(Frame::decode(&encoded[..cut]).unwrap().is_none(), "{} of {} bytes decoded", cut, encoded.len());
+            }
+        }
+    }
+
+    #[test]
+    fn splits_fragments_and_control_frames() {
+        let mut buf = server_frame(OPCODE_TEXT, b"hel");
+        buf.extend(server_frame(0x80 | OPCODE_PING, b"?"));
+        buf.extend(server_frame(0x80, b"lo"));
+        buf.extend(server_frame(0x80 | OPCODE_CLOSE, &[0x03, 0xe8]));
+
+        let mut frames = Vec::new();
+        while let Some((frame, used)) = Frame::decode(&buf).unwrap() {
+            buf.drain(..used);
+            frames.push((frame.fin, frame.opcode, frame.payload));
+        }
+
+        assert!(buf.is_empty());
+        assert_eq!(frames, [
+            (false, OPCODE_TEXT, b"hel".to_vec()),
+            (true, OPCODE_PING, b"?".to_vec()),
+            (true, 0, b"lo".to_vec()),
+            (true, OPCODE_CLOSE, vec![0x03, 0xe8]),
+        ]);
+    }
+
+    #[test]
+    fn rejects_frames_over_the_size_limit() {
+        let mut header = vec![0x80 | OPCODE_BINARY, 127];
+        header.extend_from_slice(&(MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes());
+        assert!(Frame::decode(&header).is_err());
+    }
 }
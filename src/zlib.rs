@@ -0,0 +1,638 @@
+use std::io::{self, Read};
+
+/// Size of the DEFLATE back-reference window
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Size of the internal input buffer
+const INPUT_BUFFER_SIZE: usize = 8 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which code length code lengths are transmitted
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Canonical Huffman decoding table
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build a table from a list of code lengths indexed by symbol
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+
+        // Reject over-subscribed code sets
+        let mut left: i32 = 1;
+        for &count in counts.iter().skip(1) {
+            left <<= 1;
+            left -= count as i32;
+            if left < 0 {
+                return Err(invalid_data("Over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts, symbols })
+    }
+
+    /// Fixed literal/length table from RFC 1951 section 3.2.6
+    fn fixed_literals() -> Self {
+        let mut lengths = [0u8; 288];
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..].fill(8);
+        Self::new(&lengths).expect("fixed literal table is valid")
+    }
+
+    /// Fixed distance table from RFC 1951 section 3.2.6
+    fn fixed_distances() -> Self {
+        Self::new(&[5u8; 30]).expect("fixed distance table is valid")
+    }
+}
+
+/// Decoder progress through the zlib stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Header,
+    BlockHeader,
+    Stored(usize),
+    Codes,
+    Trailer,
+    Done,
+}
+
+/// Streaming zlib decoder
+///
+/// Decompresses on demand as the output is read, so memory usage is bounded by
+/// the 32 KiB DEFLATE window regardless of the size of the stream.
+pub struct ZlibDecoder<R: Read> {
+    inner: R,
+    input: Box<[u8]>,
+    input_pos: usize,
+    input_len: usize,
+    bit_buf: u32,
+    bit_count: u32,
+    state: State,
+    final_block: bool,
+    literals: Option<Huffman>,
+    distances: Option<Huffman>,
+    window: Box<[u8]>,
+    window_pos: usize,
+    copy_len: usize,
+    copy_dist: usize,
+    total_out: u64,
+    adler_a: u32,
+    adler_b: u32,
+}
+
+impl<R: Read> ZlibDecoder<R> {
+    /// Create a new decoder reading compressed data from `inner`
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            input: vec![0u8; INPUT_BUFFER_SIZE].into_boxed_slice(),
+            input_pos: 0,
+            input_len: 0,
+            bit_buf: 0,
+            bit_count: 0,
+            state: State::Header,
+            final_block: false,
+            literals: None,
+            distances: None,
+            window: vec![0u8; WINDOW_SIZE].into_boxed_slice(),
+            window_pos: 0,
+            copy_len: 0,
+            copy_dist: 0,
+            total_out: 0,
+            adler_a: 1,
+            adler_b: 0,
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<u8> {
+        if self.input_pos == self.input_len {
+            self.input_len = loop {
+                match self.inner.read(&mut self.input) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Truncated zlib stream",
+                        ));
+                    }
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+            self.input_pos = 0;
+        }
+
+        let byte = self.input[self.input_pos];
+        self.input_pos += 1;
+        Ok(byte)
+    }
+
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.bit_count < count {
+            let byte = self.next_byte()? as u32;
+            self.bit_buf |= byte << self.bit_count;
+            self.bit_count += 8;
+        }
+
+        let value = self.bit_buf & ((1u32 << count) - 1);
+        self.bit_buf >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+
+    fn decode_symbol(&mut self, table: &Huffman) -> io::Result<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+
+        for len in 1..16 {
+            code |= self.bits(1)? as i32;
+            let count = table.counts[len] as i32;
+            if code - first < count {
+                return Ok(table.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+
+        Err(invalid_data("Invalid Huffman code"))
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let cmf = self.bits(8)?;
+        let flg = self.bits(8)?;
+
+        if cmf & 0x0f != 8 {
+            return Err(invalid_data("Unsupported zlib compression method"));
+        }
+        if (cmf >> 4) > 7 {
+            return Err(invalid_data("Invalid zlib window size"));
+        }
+        if (cmf << 8 | flg) % 31 != 0 {
+            return Err(invalid_data("Invalid zlib header checksum"));
+        }
+        if flg & 0x20 != 0 {
+            return Err(invalid_data("Preset zlib dictionaries are not supported"));
+        }
+
+        Ok(())
+    }
+
+    fn read_block_header(&mut self) -> io::Result<State> {
+        if self.final_block {
+            return Ok(State::Trailer);
+        }
+
+        self.final_block = self.bits(1)? == 1;
+
+        match self.bits(2)? {
+            0 => {
+                self.align_to_byte();
+                let len = self.bits(16)?;
+                let nlen = self.bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(invalid_data("Stored block length mismatch"));
+                }
+                Ok(State::Stored(len as usize))
+            }
+            1 => {
+                self.literals = Some(Huffman::fixed_literals());
+                self.distances = Some(Huffman::fixed_distances());
+                Ok(State::Codes)
+            }
+            2 => {
+                self.read_dynamic_tables()?;
+                Ok(State::Codes)
+            }
+            _ => Err(invalid_data("Invalid DEFLATE block type")),
+        }
+    }
+
+    fn read_dynamic_tables(&mut self) -> io::Result<()> {
+        let literal_count = self.bits(5)? as usize + 257;
+        let distance_count = self.bits(5)? as usize + 1;
+        let code_length_count = self.bits(4)? as usize + 4;
+
+        if literal_count > 286 || distance_count > 30 {
+            return Err(invalid_data("Too many DEFLATE codes"));
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for &index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+            code_lengths[index] = self.bits(3)? as u8;
+        }
+        let code_length_table = Huffman::new(&code_lengths)?;
+
+        let mut lengths = vec![0u8; literal_count + distance_count];
+        let mut index = 0;
+        while index < lengths.len() {
+            let symbol = self.decode_symbol(&code_length_table)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    if index == 0 {
+                        return Err(invalid_data("Repeat with no previous code length"));
+                    }
+                    (lengths[index - 1], 3 + self.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.bits(3)? as usize),
+                _ => (0, 11 + self.bits(7)? as usize),
+            };
+
+            if index + repeat > lengths.len() {
+                return Err(invalid_data("Too many code lengths"));
+            }
+            lengths[index..index + repeat].fill(value);
+            index += repeat;
+        }
+
+        if lengths[256] == 0 {
+            return Err(invalid_data("Missing end-of-block code"));
+        }
+
+        self.literals = Some(Huffman::new(&lengths[..literal_count])?);
+        self.distances = Some(Huffman::new(&lengths[literal_count..])?);
+        Ok(())
+    }
+
+    /// Decode the next literal or back-reference, returning the new state
+    fn read_code(&mut self, buf: &mut [u8], written: &mut usize) -> io::Result<State> {
+        let literals = self.literals.take().ok_or_else(|| invalid_data("Missing literal table"))?;
+        let result = self.read_code_with(&literals, buf, written);
+        self.literals = Some(literals);
+        result
+    }
+
+    fn read_code_with(
+        &mut self,
+        literals: &Huffman,
+        buf: &mut [u8],
+        written: &mut usize,
+    ) -> io::Result<State> {
+        let symbol = self.decode_symbol(literals)? as usize;
+
+        if symbol < 256 {
+            self.emit(symbol as u8, buf, written);
+            return Ok(State::Codes);
+        }
+        if symbol == 256 {
+            return Ok(State::BlockHeader);
+        }
+
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(invalid_data("Invalid length symbol"));
+        }
+        let length = LENGTH_BASE[symbol] as usize + self.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+        let distances = self.distances.take().ok_or_else(|| invalid_data("Missing distance table"))?;
+        let dist_symbol = self.decode_symbol(&distances);
+        self.distances = Some(distances);
+        let dist_symbol = dist_symbol? as usize;
+
+        if dist_symbol >= DIST_BASE.len() {
+            return Err(invalid_data("Invalid distance symbol"));
+        }
+        let distance = DIST_BASE[dist_symbol] as usize + self.bits(DIST_EXTRA[dist_symbol] as u32)? as usize;
+
+        if distance as u64 > self.total_out || distance > WINDOW_SIZE {
+            return Err(invalid_data("Distance too far back"));
+        }
+
+        self.copy_len = length;
+        self.copy_dist = distance;
+        Ok(State::Codes)
+    }
+
+    fn read_trailer(&mut self) -> io::Result<()> {
+        self.align_to_byte();
+
+        let mut expected = 0u32;
+        for _ in 0..4 {
+            expected = expected << 8 | self.next_byte()? as u32;
+        }
+
+        if expected != (self.adler_b << 16 | self.adler_a) {
+            return Err(invalid_data("zlib checksum mismatch"));
+        }
+
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8, buf: &mut [u8], written: &mut usize) {
+        self.window[self.window_pos] = byte;
+        self.window_pos = (self.window_pos + 1) % WINDOW_SIZE;
+
+        self.adler_a = (self.adler_a + byte as u32) % 65521;
+        self.adler_b = (self.adler_b + self.adler_a) % 65521;
+
+        buf[*written] = byte;
+        *written += 1;
+        self.total_out += 1;
+    }
+}
+
+impl<R: Read> Read for ZlibDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+
+        while written < buf.len() {
+            if self.copy_len > 0 {
+                let byte = self.window[(self.window_pos + WINDOW_SIZE - self.copy_dist) % WINDOW_SIZE];
+                self.emit(byte, buf, &mut written);
+                self.copy_len -= 1;
+                continue;
+            }
+
+            self.state = match self.state {
+                State::Header => {
+                    self.read_header()?;
+                    State::BlockHeader
+                }
+                State::BlockHeader => self.read_block_header()?,
+                State::Stored(0) => State::BlockHeader,
+                State::Stored(remaining) => {
+                    let byte = self.next_byte()?;
+                    self.emit(byte, buf, &mut written);
+                    State::Stored(remaining - 1)
+                }
+                State::Codes => self.read_code(buf, &mut written)?,
+                State::Trailer => {
+                    self.read_trailer()?;
+                    State::Done
+                }
+                State::Done => break,
+            };
+        }
+
+        Ok(written)
+    }
+}
+
+/// Number of bits used to hash three bytes when looking for matches
+const HASH_BITS: u32 = 15;
+
+/// How many earlier positions with the same hash are tried for each match
+const MAX_CHAIN: usize = 64;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Writes bits least significant first, as DEFLATE expects
+struct BitWriter {
+    out: Vec<u8>,
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bit_buf |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which DEFLATE stores most significant bit first
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.out.push(self.bit_buf as u8);
+        }
+        self.out
+    }
+}
+
+/// Write a literal/length symbol with the fixed Huffman code
+fn write_literal(writer: &mut BitWriter, symbol: usize) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let symbol = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+    write_literal(writer, 257 + symbol);
+    writer.write((length - LENGTH_BASE[symbol] as usize) as u32, LENGTH_EXTRA[symbol] as u32);
+
+    let symbol = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+    writer.write_code(symbol as u32, 5);
+    writer.write((distance - DIST_BASE[symbol] as usize) as u32, DIST_EXTRA[symbol] as u32);
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Record a position in the hash chains used to find matches
+fn insert(head: &mut [usize], prev: &mut [usize], data: &[u8], pos: usize) {
+    if pos + MIN_MATCH <= data.len() {
+        let hash = hash(&data[pos..]);
+        prev[pos % WINDOW_SIZE] = head[hash];
+        head[hash] = pos;
+    }
+}
+
+/// Compress data into a zlib stream
+///
+/// Uses a single fixed-Huffman block with greedy LZ77 matching, which is
+/// simple and fast while still shrinking the repetitive payloads sent over
+/// the connection considerably.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: vec![0x78, 0x01],
+        bit_buf: 0,
+        bit_count: 0,
+    };
+
+    // Final block, fixed Huffman codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if pos + MIN_MATCH <= data.len() {
+            let max_len = (data.len() - pos).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[pos..])];
+
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+                    break;
+                }
+
+                let len = data[candidate..candidate + max_len].iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+
+                let next = prev[candidate % WINDOW_SIZE];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            write_match(&mut writer, best_len, best_dist);
+            for offset in 0..best_len {
+                insert(&mut head, &mut prev, data, pos + offset);
+            }
+            pos += best_len;
+        } else {
+            write_literal(&mut writer, data[pos] as usize);
+            insert(&mut head, &mut prev, data, pos);
+            pos += 1;
+        }
+    }
+
+    write_literal(&mut writer, 256);
+    let mut out = writer.finish();
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// Text compressed by zlib into a dynamic-Huffman block
+    fn dynamic_text() -> Vec<u8> {
+        (0..12)
+            .flat_map(|i| format!("Whatsandra frame {} carries node <iq id='{}' type='get'/> to s.whatsapp.net; ", i, i * 7).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_stored_block() {
+        let stream = hex::decode("7801010c00f3ff73746f72656420626c6f636b1f8004bd").unwrap();
+        assert_eq!(decompress(&stream).unwrap(), b"stored block");
+    }
+
+    #[test]
+    fn decodes_fixed_huffman_block() {
+        let stream = hex::decode("78dacb48cdc9c9d751c840a214caf38b7252007487091d").unwrap();
+        assert_eq!(decompress(&stream).unwrap(), b"hello, hello, hello world");
+    }
+
+    #[test]
+    fn decodes_dynamic_huffman_block() {
+        let stream = hex::decode(concat!(
+            "78da9dd24d0ac2301040e1abcc2ebbdafcb768bd46d7c18c9a85694d02e2ed059732019903bc6ff5d67b6835e458025c",
+            "4b78208c7009a524ac90b788704a4f487111a380f6de7111376ce27086b6411d5edf78df878ced08eb2f2569ca332845",
+            "53d2302c4d5b4a322cd3b1268665694b5b86e568cb2886e53bd6ccb026dab28e61cdb4e53467d5cef69ef57d6ffc3fce",
+            "ff0001f33261",
+        )).unwrap();
+        assert_eq!(decompress(&stream).unwrap(), dynamic_text());
+    }
+
+    #[test]
+    fn round_trips_compressed_data() {
+        let window_spanning: Vec<u8> = (0..3 * WINDOW_SIZE as u32).map(|i| (i * 31 % 251) as u8).collect();
+        for data in [Vec::new(), b"a".to_vec(), vec![0u8; 1000], dynamic_text(), window_spanning] {
+            let compressed = compress(&data);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        let mut stream = compress(&dynamic_text());
+        let last = stream.len() - 1;
+        stream[last] ^= 1;
+        assert!(decompress(&stream).is_err(), "bad checksum accepted");
+
+        let stream = compress(&dynamic_text());
+        assert!(decompress(&stream[..stream.len() / 2]).is_err(), "truncated stream accepted");
+        assert!(decompress(&[0x78, 0x02]).is_err(), "bad header accepted");
+    }
+}